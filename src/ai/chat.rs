@@ -69,6 +69,26 @@ pub(crate) async fn chat(
     connect_timeout: Option<u32>,
     read_timeout: Option<u32>,
     result_sender: ResultSender<'_, StreamingResponseData>,
+) -> Result<()> {
+    let now = std::time::Instant::now();
+    let r = chat_inner(
+        robot_id,
+        chat_history,
+        connect_timeout,
+        read_timeout,
+        result_sender,
+    )
+    .await;
+    crate::analytics::crud::record_llm_call(robot_id, now.elapsed(), r.is_err());
+    r
+}
+
+async fn chat_inner(
+    robot_id: &str,
+    chat_history: Option<Vec<Prompt>>,
+    connect_timeout: Option<u32>,
+    read_timeout: Option<u32>,
+    result_sender: ResultSender<'_, StreamingResponseData>,
 ) -> Result<()> {
     if let Some(settings) = settings::get_settings(robot_id)? {
         // log::info!("{:?}", &settings.chat_provider.provider);
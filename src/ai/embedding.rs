@@ -25,17 +25,16 @@ pub(crate) async fn embedding(robot_id: &str, s: &str) -> Result<(Vec<f32>, f32)
     if let Some(settings) = settings::get_settings(robot_id)? {
         let v = match settings.sentence_embedding_provider.provider {
             SentenceEmbeddingProvider::HuggingFace(m) => hugging_face(robot_id, &m.get_info(), s),
-            SentenceEmbeddingProvider::OpenAI(m) => {
-                open_ai(
-                    &m,
-                    s,
-                    &settings.sentence_embedding_provider.api_key,
-                    settings.sentence_embedding_provider.connect_timeout_millis,
-                    settings.sentence_embedding_provider.read_timeout_millis,
-                    &settings.sentence_embedding_provider.proxy_url,
-                )
-                .await
-            }
+            SentenceEmbeddingProvider::OpenAI(m) => open_ai(
+                &m,
+                &[s],
+                &settings.sentence_embedding_provider.api_key,
+                settings.sentence_embedding_provider.connect_timeout_millis,
+                settings.sentence_embedding_provider.read_timeout_millis,
+                &settings.sentence_embedding_provider.proxy_url,
+            )
+            .await
+            .map(|mut v| v.pop().unwrap_or_default()),
             SentenceEmbeddingProvider::Ollama(m) => {
                 ollama(
                     &settings.sentence_embedding_provider.api_url,
@@ -57,6 +56,53 @@ pub(crate) async fn embedding(robot_id: &str, s: &str) -> Result<(Vec<f32>, f32)
     }
 }
 
+// Vectors of texts in one request or forward pass, in the order of texts
+pub(crate) async fn embedding_batch(robot_id: &str, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+    if texts.is_empty() {
+        return Ok(vec![]);
+    }
+    let Some(settings) = settings::get_settings(robot_id)? else {
+        return Err(Error::WithMessage(format!(
+            "Can not find settings of {}",
+            robot_id
+        )));
+    };
+    let p = &settings.sentence_embedding_provider;
+    match &p.provider {
+        SentenceEmbeddingProvider::HuggingFace(m) => {
+            hugging_face_batch(robot_id, &m.get_info(), texts)
+        }
+        SentenceEmbeddingProvider::OpenAI(m) => {
+            open_ai(
+                m,
+                texts,
+                &p.api_key,
+                p.connect_timeout_millis,
+                p.read_timeout_millis,
+                &p.proxy_url,
+            )
+            .await
+        }
+        // The embeddings API of Ollama takes one prompt at a time
+        SentenceEmbeddingProvider::Ollama(m) => {
+            let mut vectors: Vec<Vec<f32>> = Vec::with_capacity(texts.len());
+            for t in texts.iter() {
+                let v = ollama(
+                    &p.api_url,
+                    m,
+                    t,
+                    p.connect_timeout_millis,
+                    p.read_timeout_millis,
+                    &p.proxy_url,
+                )
+                .await?;
+                vectors.push(v);
+            }
+            Ok(vectors)
+        }
+    }
+}
+
 static EMBEDDING_MODEL: OnceLock<Mutex<HashMap<String, (BertModel, Tokenizer)>>> = OnceLock::new();
 
 pub(crate) fn replace_model_cache(robot_id: &str, c: (BertModel, Tokenizer)) {
@@ -104,6 +150,54 @@ fn hugging_face(robot_id: &str, info: &HuggingFaceModelInfo, s: &str) -> Result<
     Ok(r)
 }
 
+// Padded tokens are masked, so vectors are the same as embedding texts one by one
+fn hugging_face_batch(
+    robot_id: &str,
+    info: &HuggingFaceModelInfo,
+    texts: &[&str],
+) -> Result<Vec<Vec<f32>>> {
+    let lock = EMBEDDING_MODEL.get_or_init(|| Mutex::new(HashMap::with_capacity(32)));
+    let mut model = lock.lock().unwrap_or_else(|e| {
+        log::warn!("{:#?}", &e);
+        e.into_inner()
+    });
+    if !model.contains_key(robot_id) {
+        let r = load_bert_model_files(info.repository)?;
+        model.insert(String::from(robot_id), r);
+    };
+    let (m, t) = model.get_mut(robot_id).unwrap();
+    let encodings = t
+        .encode_batch(texts.to_vec(), true)
+        .map_err(|e| Error::WithMessage(format!("{}", &e)))?;
+    let max_len = encodings.iter().map(|e| e.len()).max().unwrap_or(0);
+    let pad_id = t.token_to_id("[PAD]").unwrap_or(0);
+    let mut token_ids: Vec<Tensor> = Vec::with_capacity(encodings.len());
+    let mut attention_mask: Vec<Tensor> = Vec::with_capacity(encodings.len());
+    for e in encodings.iter() {
+        let mut ids = e.get_ids().to_vec();
+        ids.resize(max_len, pad_id);
+        let mut mask = e.get_attention_mask().to_vec();
+        mask.resize(max_len, 0);
+        token_ids.push(Tensor::new(ids.as_slice(), &m.device)?);
+        attention_mask.push(Tensor::new(mask.as_slice(), &m.device)?);
+    }
+    let token_ids = Tensor::stack(&token_ids, 0)?;
+    let attention_mask = Tensor::stack(&attention_mask, 0)?;
+    let token_type_ids = token_ids.zeros_like()?;
+    let outputs = m.forward(&token_ids, &token_type_ids, Some(&attention_mask))?;
+    let mask = attention_mask.to_dtype(outputs.dtype())?.unsqueeze(2)?;
+    let embeddings = outputs
+        .broadcast_mul(&mask)?
+        .sum(1)?
+        .broadcast_div(&mask.sum(1)?)?;
+    let embeddings = embeddings.broadcast_div(&embeddings.sqr()?.sum_keepdim(1)?.sqrt()?)?;
+    let mut vectors: Vec<Vec<f32>> = Vec::with_capacity(texts.len());
+    for i in 0..texts.len() {
+        vectors.push(embeddings.i(i)?.to_vec1::<f32>()?);
+    }
+    Ok(vectors)
+}
+
 // fn tt() {
 //     let prs = vec![0.1f32,0.1f32,0.1f32,0.1f32,];
 //     let mut top: Vec<_> = prs.iter().enumerate().collect();
@@ -121,19 +215,22 @@ fn hugging_face(robot_id: &str, info: &HuggingFaceModelInfo, s: &str) -> Result<
 
 async fn open_ai(
     m: &str,
-    s: &str,
+    s: &[&str],
     api_key: &str,
     connect_timeout_millis: u32,
     read_timeout_millis: u32,
     proxy_url: &str,
-) -> Result<Vec<f32>> {
+) -> Result<Vec<Vec<f32>>> {
     let client = crate::external::http::get_client(
         connect_timeout_millis.into(),
         read_timeout_millis.into(),
         proxy_url,
     )?;
     let mut map = Map::new();
-    map.insert(String::from("input"), Value::String(String::from(s)));
+    map.insert(
+        String::from("input"),
+        Value::Array(s.iter().map(|t| Value::from(*t)).collect()),
+    );
     map.insert(String::from("model"), Value::String(String::from(m)));
     let obj = Value::Object(map);
     let authorization = format!("Bearer {api_key}");
@@ -149,9 +246,14 @@ async fn open_ai(
         .text()
         .await?;
     let v: Value = serde_json::from_str(&r)?;
-    let mut embedding_result: Vec<f32> = Vec::with_capacity(3072);
+    let mut embedding_results: Vec<Vec<f32>> = vec![vec![]; s.len()];
     if let Some(d) = v["data"].as_array() {
-        for item in d.iter() {
+        for (idx, item) in d.iter().enumerate() {
+            // Items carry the index of their input
+            let idx = item["index"].as_u64().map_or(idx, |i| i as usize);
+            let Some(embedding_result) = embedding_results.get_mut(idx) else {
+                continue;
+            };
             if let Some(embedding) = item["embedding"].as_array() {
                 for e in embedding.iter() {
                     if let Some(n) = e.as_number() {
@@ -164,7 +266,7 @@ async fn open_ai(
             }
         }
     }
-    Ok(embedding_result)
+    Ok(embedding_results)
 }
 
 async fn ollama(
@@ -12,6 +12,9 @@ use candle_transformers::models::gemma::{Config as GemmaConfig, Model as GemmaMo
 use candle_transformers::models::llama::{Cache as LlamaCache, Llama, LlamaConfig, LlamaEosToks};
 use candle_transformers::models::parler_tts::{Config as ParlerTtsConfig, Model as ParlerTtsModel};
 use candle_transformers::models::phi3::{Config as Phi3Config, Model as Phi3};
+use candle_transformers::models::xlm_roberta::{
+    Config as XLMRobertaConfig, XLMRobertaForSequenceClassification,
+};
 use futures_util::StreamExt;
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
@@ -35,6 +38,8 @@ pub(crate) enum HuggingFaceModel {
     MultilingualE5Base,
     MultilingualE5Large,
     MxbaiEmbedLargeV1,
+    BgeRerankerBase,
+    BgeRerankerV2M3,
     Phi3Mini4kInstruct,
     TinyLlama1_1bChatV1_0,
     Gemma2bInstruct,
@@ -67,6 +72,12 @@ impl LoadedHuggingFaceModel {
             HuggingFaceModelType::Bert => {
                 LoadedHuggingFaceModel::Bert(load_bert_model_files(info.repository)?)
             }
+            HuggingFaceModelType::XlmRoberta => {
+                return Err(Error::WithMessage(format!(
+                    "{} is a reranking model and can't generate text.",
+                    info.repository
+                )));
+            }
         };
         Ok(m)
     }
@@ -75,6 +86,8 @@ impl LoadedHuggingFaceModel {
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub(crate) enum HuggingFaceModelType {
     Bert,
+    // Cross-encoders for reranking
+    XlmRoberta,
     Llama,
     Gemma,
     Phi3,
@@ -114,7 +127,7 @@ impl HuggingFaceModelInfo {
             }
         }
         match self.model_type {
-            HuggingFaceModelType::Bert => {
+            HuggingFaceModelType::Bert | HuggingFaceModelType::XlmRoberta => {
                 let m = String::from("Bert model doesn't support prompt.");
                 log::warn!("{}", &m);
                 Err(Error::WithMessage(m))
@@ -323,6 +336,24 @@ impl HuggingFaceModel {
                 dimenssions: 1024,
                 model_type: HuggingFaceModelType::Bert,
             },
+            HuggingFaceModel::BgeRerankerBase => HuggingFaceModelInfo {
+                repository: "BAAI/bge-reranker-base",
+                mirror: "BAAI/bge-reranker-base",
+                model_files: get_common_model_files(),
+                model_index_file: "",
+                tokenizer_filename: "tokenizer.json",
+                dimenssions: 1,
+                model_type: HuggingFaceModelType::XlmRoberta,
+            },
+            HuggingFaceModel::BgeRerankerV2M3 => HuggingFaceModelInfo {
+                repository: "BAAI/bge-reranker-v2-m3",
+                mirror: "BAAI/bge-reranker-v2-m3",
+                model_files: get_common_model_files(),
+                model_index_file: "",
+                tokenizer_filename: "tokenizer.json",
+                dimenssions: 1,
+                model_type: HuggingFaceModelType::XlmRoberta,
+            },
             HuggingFaceModel::Phi3Mini4kInstruct => HuggingFaceModelInfo {
                 repository: "microsoft/Phi-3-mini-4k-instruct",
                 mirror: "microsoft/Phi-3-mini-4k-instruct",
@@ -838,6 +869,17 @@ fn set_special_tokens_map(mirror: &str, tokenizer: &mut Tokenizer) -> Result<()>
     Ok(())
 }
 
+// Tokenizer without padding and truncation, for measuring text length
+pub(crate) fn load_tokenizer(mirror: &str) -> Result<Tokenizer> {
+    let mut tokenizer = init_tokenizer(mirror)?;
+    set_special_tokens_map(mirror, &mut tokenizer)?;
+    tokenizer
+        .with_padding(None)
+        .with_truncation(None)
+        .map_err(|e| Error::WithMessage(format!("{}", &e)))?;
+    Ok(tokenizer)
+}
+
 pub(crate) fn load_bert_model_files(mirror: &str) -> Result<(BertModel, Tokenizer)> {
     let f = construct_model_file_path(mirror, "config.json");
     let config = std::fs::read_to_string(&f)?;
@@ -853,6 +895,25 @@ pub(crate) fn load_bert_model_files(mirror: &str) -> Result<(BertModel, Tokenize
     Ok((model, tokenizer))
 }
 
+pub(crate) fn load_xlm_roberta_model_files(
+    mirror: &str,
+) -> Result<(XLMRobertaForSequenceClassification, Tokenizer, Device)> {
+    let f = construct_model_file_path(mirror, "config.json");
+    let config = std::fs::read_to_string(&f)?;
+    let config: serde_json::Value = serde_json::from_str(&config)?;
+    let pad_token_id = config["pad_token_id"].as_u64().unwrap_or(1) as u32;
+    let config: XLMRobertaConfig = serde_json::from_value(config)?;
+    let tokenizer = init_tokenizer(mirror)?;
+    let mut tokenizer = set_tokenizer_config(mirror, tokenizer, pad_token_id)?;
+    set_special_tokens_map(mirror, &mut tokenizer)?;
+    let device = device()?;
+    let f = construct_model_file_path(mirror, "model.safetensors");
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[&f], DType::F32, &device)? };
+    // Rerankers have a single relevance label
+    let model = XLMRobertaForSequenceClassification::new(1, &config, vb)?;
+    Ok((model, tokenizer, device))
+}
+
 fn load_safetensors(mirror: &str, json_file: &str) -> Result<Vec<String>> {
     let json_file = construct_model_file_path(mirror, json_file);
     let json_file = std::fs::File::open(json_file)?;
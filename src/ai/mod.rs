@@ -10,5 +10,6 @@ pub(super) mod huggingface;
 pub(super) mod llama;
 pub(super) mod phi3;
 pub(super) mod qwen3;
+pub(crate) mod rerank;
 mod token_output_stream;
 pub(crate) mod tts;
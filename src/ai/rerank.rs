@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::vec::Vec;
+
+use candle::{DType, Device, Tensor};
+use candle_transformers::models::xlm_roberta::XLMRobertaForSequenceClassification;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokenizers::Tokenizer;
+
+use super::huggingface::{HuggingFaceModel, load_xlm_roberta_model_files};
+use crate::man::settings;
+use crate::result::{Error, Result};
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "id", content = "model")]
+pub(crate) enum RerankProvider {
+    HuggingFace(HuggingFaceModel),
+    // APIs compatible with Cohere rerank, like Jina, SiliconFlow or vLLM
+    Cohere(String),
+}
+
+type CrossEncoder = (XLMRobertaForSequenceClassification, Tokenizer, Device);
+
+static RERANK_MODEL: LazyLock<Mutex<HashMap<String, CrossEncoder>>> =
+    LazyLock::new(|| Mutex::new(HashMap::with_capacity(32)));
+
+pub(crate) fn replace_model_cache(robot_id: &str, m: &HuggingFaceModel) -> Result<()> {
+    let c = load_xlm_roberta_model_files(m.get_info().repository)?;
+    let mut cache = RERANK_MODEL.lock()?;
+    cache.insert(String::from(robot_id), c);
+    Ok(())
+}
+
+// Relevance scores of documents to the query, in the order of documents
+pub(crate) async fn rerank(
+    robot_id: &str,
+    s: &settings::RerankProvider,
+    query: &str,
+    documents: &[&str],
+) -> Result<Vec<f32>> {
+    if documents.is_empty() {
+        return Ok(vec![]);
+    }
+    match &s.provider {
+        RerankProvider::HuggingFace(m) => {
+            // Inference is CPU bound and holds the model cache lock, so it's kept off the runtime
+            let robot_id = String::from(robot_id);
+            let m = m.clone();
+            let query = String::from(query);
+            let documents: Vec<String> = documents.iter().map(|d| String::from(*d)).collect();
+            tokio::task::spawn_blocking(move || {
+                let documents: Vec<&str> = documents.iter().map(|d| d.as_str()).collect();
+                hugging_face(&robot_id, &m, &query, &documents)
+            })
+            .await?
+        }
+        RerankProvider::Cohere(m) => cohere(s, m, query, documents).await,
+    }
+}
+
+fn hugging_face(
+    robot_id: &str,
+    m: &HuggingFaceModel,
+    query: &str,
+    documents: &[&str],
+) -> Result<Vec<f32>> {
+    let mut cache = RERANK_MODEL.lock().unwrap_or_else(|e| {
+        log::warn!("{:#?}", &e);
+        e.into_inner()
+    });
+    if !cache.contains_key(robot_id) {
+        let r = load_xlm_roberta_model_files(m.get_info().repository)?;
+        cache.insert(String::from(robot_id), r);
+    }
+    let (model, tokenizer, device) = cache.get(robot_id).unwrap();
+    let pairs: Vec<(&str, &str)> = documents.iter().map(|d| (query, *d)).collect();
+    let encodings = tokenizer
+        .encode_batch(pairs, true)
+        .map_err(|e| Error::WithMessage(format!("{}", &e)))?;
+    let mut token_ids: Vec<Tensor> = Vec::with_capacity(encodings.len());
+    let mut attention_mask: Vec<Tensor> = Vec::with_capacity(encodings.len());
+    for e in encodings.iter() {
+        token_ids.push(Tensor::new(e.get_ids(), device)?);
+        attention_mask.push(Tensor::new(e.get_attention_mask(), device)?);
+    }
+    let token_ids = Tensor::stack(&token_ids, 0)?;
+    let attention_mask = Tensor::stack(&attention_mask, 0)?;
+    let token_type_ids = token_ids.zeros_like()?;
+    let logits = model.forward(&token_ids, &attention_mask, &token_type_ids)?;
+    let scores = candle_nn::ops::sigmoid(&logits.to_dtype(DType::F32)?)?;
+    Ok(scores.flatten_all()?.to_vec1::<f32>()?)
+}
+
+async fn cohere(
+    s: &settings::RerankProvider,
+    m: &str,
+    query: &str,
+    documents: &[&str],
+) -> Result<Vec<f32>> {
+    let client = crate::external::http::get_client(
+        s.connect_timeout_millis.into(),
+        s.read_timeout_millis.into(),
+        &s.proxy_url,
+    )?;
+    let mut map = Map::new();
+    map.insert(String::from("model"), Value::String(String::from(m)));
+    map.insert(String::from("query"), Value::String(String::from(query)));
+    map.insert(
+        String::from("documents"),
+        Value::Array(documents.iter().map(|d| Value::from(*d)).collect()),
+    );
+    map.insert(String::from("top_n"), Value::from(documents.len()));
+    let mut req = client
+        .post(&s.api_url)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(&Value::Object(map))?);
+    if !s.api_key.is_empty() {
+        req = req.header("Authorization", format!("Bearer {}", &s.api_key));
+    }
+    let r = req.send().await?.text().await?;
+    let v: Value = serde_json::from_str(&r)?;
+    let Some(results) = v["results"].as_array() else {
+        return Err(Error::WithMessage(format!("Invalid rerank response: {r}")));
+    };
+    let mut scores = vec![0f32; documents.len()];
+    for item in results.iter() {
+        let idx = item["index"].as_u64().unwrap_or(u64::MAX) as usize;
+        if let Some(score) = item["relevance_score"].as_f64()
+            && let Some(s) = scores.get_mut(idx)
+        {
+            *s = score as f32;
+        }
+    }
+    Ok(scores)
+}
@@ -0,0 +1,301 @@
+use core::time::Duration;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{LazyLock, Mutex};
+use std::vec::Vec;
+
+use axum::extract::Query;
+use axum::response::IntoResponse;
+use redb::TableDefinition;
+
+use super::dto::{
+    AnalyticsData, AnalyticsQuery, Bucket, BucketUnit, ConversationStats, IntentStats,
+    KnowledgeBaseStats, LlmCallRecord, LlmStats, NodeDropOff,
+};
+use crate::db;
+use crate::db_executor;
+use crate::man::clock::now_millis;
+use crate::man::settings;
+use crate::result::Result;
+use crate::transcript::crud as transcript;
+use crate::transcript::dto::{SessionOutcome, TranscriptSession, TranscriptTurn};
+use crate::web::server::to_res;
+
+pub(crate) const LLM_CALL_TABLE_SUFFIX: &str = "_llm_calls";
+const LLM_CALL_RETENTION_MILLIS: u64 = 90 * 86400 * 1000;
+const LLM_CALL_PRUNE_INTERVAL_MILLIS: u64 = 3600 * 1000;
+
+// When the LLM calls of each robot were pruned last time
+static LLM_CALL_PRUNED_AT: LazyLock<Mutex<HashMap<String, u64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::with_capacity(16)));
+
+fn rate(n: u64, total: u64) -> f64 {
+    if total == 0 {
+        0f64
+    } else {
+        n as f64 / total as f64
+    }
+}
+
+pub(crate) fn record_llm_call(robot_id: &str, latency: Duration, failed: bool) {
+    let r = LlmCallRecord {
+        timestamp: now_millis(),
+        latency_millis: latency.as_millis() as u64,
+        failed,
+    };
+    // Timestamp prefix keeps records in time order, the suffix avoids collisions
+    let key = format!("{:020}-{}", r.timestamp, scru128::new_string());
+    if let Err(e) = db_executor!(db::write, robot_id, LLM_CALL_TABLE_SUFFIX, &key, &r) {
+        log::warn!("Recording LLM call failed, err: {e:?}");
+    }
+    if let Err(e) = prune_llm_calls(robot_id, r.timestamp) {
+        log::warn!("Pruning LLM calls failed, err: {e:?}");
+    }
+}
+
+// Records older than the retention are removed, at most once an interval
+fn prune_llm_calls(robot_id: &str, now: u64) -> Result<()> {
+    {
+        let mut pruned_at = LLM_CALL_PRUNED_AT.lock()?;
+        let last = pruned_at.entry(String::from(robot_id)).or_insert(0);
+        if now < *last + LLM_CALL_PRUNE_INTERVAL_MILLIS {
+            return Ok(());
+        }
+        *last = now;
+    }
+    let cutoff = format!("{:020}", now.saturating_sub(LLM_CALL_RETENTION_MILLIS));
+    let table_name = format!("{robot_id}{LLM_CALL_TABLE_SUFFIX}");
+    let table: TableDefinition<&str, &[u8]> = TableDefinition::new(&table_name);
+    let write_txn = db::DB.begin_write()?;
+    {
+        let mut table = write_txn.open_table(table)?;
+        table.retain_in(..cutoff.as_str(), |_, _| false)?;
+    }
+    write_txn.commit()?;
+    Ok(())
+}
+
+pub(crate) fn remove_tables(robot_id: &str) -> Result<()> {
+    if let Ok(mut pruned_at) = LLM_CALL_PRUNED_AT.lock() {
+        pruned_at.remove(robot_id);
+    }
+    db_executor!(db::delete_table, robot_id, LLM_CALL_TABLE_SUFFIX,)
+}
+
+// The end time is inclusive, like for sessions and turns
+fn get_llm_calls(q: &AnalyticsQuery) -> Result<Vec<LlmCallRecord>> {
+    let begin = format!("{:020}", q.start_time.unwrap_or(0));
+    let end = format!(
+        "{:020}",
+        q.end_time.map_or(u64::MAX, |t| t.saturating_add(1))
+    );
+    let r: Result<Vec<LlmCallRecord>> = db_executor!(
+        db::range,
+        &q.robot_id,
+        LLM_CALL_TABLE_SUFFIX,
+        begin.as_str()..end.as_str()
+    );
+    match r {
+        Err(e) if db::is_table_not_exists(&e) => Ok(vec![]),
+        r => r,
+    }
+}
+
+fn get_sessions(q: &AnalyticsQuery) -> Result<Vec<TranscriptSession>> {
+    let mut sessions = transcript::get_sessions(&q.robot_id)?;
+    sessions.retain(|s| {
+        q.in_range(s.started_at)
+            && q.main_flow_id
+                .as_ref()
+                .is_none_or(|id| s.main_flow_id.eq(id))
+    });
+    Ok(sessions)
+}
+
+fn get_turns(q: &AnalyticsQuery) -> Result<Vec<TranscriptTurn>> {
+    let mut turns = transcript::get_turns_between(
+        &q.robot_id,
+        q.start_time.unwrap_or(0),
+        q.end_time.map_or(u64::MAX, |t| t.saturating_add(1)),
+    )?;
+    turns.retain(|t| {
+        q.main_flow_id
+            .as_ref()
+            .is_none_or(|id| t.main_flow_id.eq(id))
+    });
+    Ok(turns)
+}
+
+fn aggregate<I, T: Default>(
+    items: &[I],
+    bucket: Option<BucketUnit>,
+    timestamp: impl Fn(&I) -> u64,
+    add: impl Fn(&mut T, &I),
+    finish: impl Fn(&mut T),
+) -> AnalyticsData<T> {
+    let mut total = T::default();
+    let mut buckets: BTreeMap<u64, T> = BTreeMap::new();
+    for item in items.iter() {
+        add(&mut total, item);
+        if let Some(unit) = bucket {
+            let t = timestamp(item);
+            let start = t - t % unit.millis();
+            add(buckets.entry(start).or_default(), item);
+        }
+    }
+    finish(&mut total);
+    let buckets = buckets
+        .into_iter()
+        .map(|(bucket_start, mut stats)| {
+            finish(&mut stats);
+            Bucket {
+                bucket_start,
+                stats,
+            }
+        })
+        .collect();
+    AnalyticsData { total, buckets }
+}
+
+// A session is dropped when it neither completed nor was transferred, and it has been idle for
+// longer than the session would be kept
+fn is_dropped(s: &TranscriptSession, now: u64, idle_millis: u64) -> bool {
+    s.outcome != SessionOutcome::Completed
+        && s.outcome != SessionOutcome::Transferred
+        && now.saturating_sub(s.last_active_at) > idle_millis
+}
+
+fn idle_millis(robot_id: &str) -> Result<u64> {
+    let idle_sec = settings::get_settings(robot_id)?.map_or(1800, |s| s.max_session_idle_sec);
+    Ok(idle_sec.min(86400) as u64 * 1000)
+}
+
+fn conversation_stats(q: &AnalyticsQuery) -> Result<AnalyticsData<ConversationStats>> {
+    let sessions = get_sessions(q)?;
+    let now = now_millis();
+    let idle = idle_millis(&q.robot_id)?;
+    Ok(aggregate(
+        &sessions,
+        q.bucket,
+        |s| s.started_at,
+        |stats: &mut ConversationStats, s| {
+            stats.conversations += 1;
+            stats.turns += s.turns as u64;
+            match s.outcome {
+                SessionOutcome::Completed => stats.completed += 1,
+                SessionOutcome::Transferred => stats.transferred += 1,
+                SessionOutcome::Error => stats.failed += 1,
+                SessionOutcome::Ongoing => {}
+            }
+            if is_dropped(s, now, idle) {
+                stats.dropped += 1;
+            }
+        },
+        |stats| stats.avg_turns = rate(stats.turns, stats.conversations),
+    ))
+}
+
+fn drop_off_stats(q: &AnalyticsQuery) -> Result<Vec<NodeDropOff>> {
+    let sessions = get_sessions(q)?;
+    let now = now_millis();
+    let idle = idle_millis(&q.robot_id)?;
+    let mut nodes: HashMap<&str, u64> = HashMap::with_capacity(32);
+    let mut total = 0u64;
+    for s in sessions.iter().filter(|s| is_dropped(s, now, idle)) {
+        total += 1;
+        *nodes.entry(s.last_node_id.as_str()).or_default() += 1;
+    }
+    let mut r: Vec<NodeDropOff> = nodes
+        .into_iter()
+        .map(|(node_id, dropped)| NodeDropOff {
+            node_id: String::from(node_id),
+            dropped,
+            rate: rate(dropped, total),
+        })
+        .collect();
+    r.sort_by(|a, b| {
+        b.dropped
+            .cmp(&a.dropped)
+            .then_with(|| a.node_id.cmp(&b.node_id))
+    });
+    Ok(r)
+}
+
+fn intent_stats(q: &AnalyticsQuery) -> Result<AnalyticsData<IntentStats>> {
+    let mut turns = get_turns(q)?;
+    // Only turns with user input go through intent detection
+    turns.retain(|t| !t.user_input_timeout && !t.user_input.is_empty());
+    Ok(aggregate(
+        &turns,
+        q.bucket,
+        |t| t.timestamp,
+        |stats: &mut IntentStats, t| {
+            stats.turns += 1;
+            match &t.intent {
+                Some(i) => *stats.distribution.entry(i.clone()).or_default() += 1,
+                None => stats.none_intent += 1,
+            }
+        },
+        |stats| stats.none_intent_rate = rate(stats.none_intent, stats.turns),
+    ))
+}
+
+fn kb_stats(q: &AnalyticsQuery) -> Result<AnalyticsData<KnowledgeBaseStats>> {
+    let mut turns = get_turns(q)?;
+    turns.retain(|t| t.kb_no_recall.is_some());
+    Ok(aggregate(
+        &turns,
+        q.bucket,
+        |t| t.timestamp,
+        |stats: &mut KnowledgeBaseStats, t| {
+            stats.queries += 1;
+            if t.kb_no_recall == Some(true) {
+                stats.no_recall += 1;
+            }
+        },
+        |stats| stats.no_recall_rate = rate(stats.no_recall, stats.queries),
+    ))
+}
+
+fn llm_stats(q: &AnalyticsQuery) -> Result<AnalyticsData<LlmStats>> {
+    let calls = get_llm_calls(q)?;
+    Ok(aggregate(
+        &calls,
+        q.bucket,
+        |c| c.timestamp,
+        |stats: &mut LlmStats, c| {
+            stats.calls += 1;
+            if c.failed {
+                stats.errors += 1;
+            }
+            // Sum for now, it becomes an average in finishing
+            stats.avg_latency_millis += c.latency_millis as f64;
+            stats.max_latency_millis = stats.max_latency_millis.max(c.latency_millis);
+        },
+        |stats| {
+            stats.error_rate = rate(stats.errors, stats.calls);
+            if stats.calls > 0 {
+                stats.avg_latency_millis /= stats.calls as f64;
+            }
+        },
+    ))
+}
+
+pub(crate) async fn conversations(Query(q): Query<AnalyticsQuery>) -> impl IntoResponse {
+    to_res(conversation_stats(&q))
+}
+
+pub(crate) async fn drop_off(Query(q): Query<AnalyticsQuery>) -> impl IntoResponse {
+    to_res(drop_off_stats(&q))
+}
+
+pub(crate) async fn intents(Query(q): Query<AnalyticsQuery>) -> impl IntoResponse {
+    to_res(intent_stats(&q))
+}
+
+pub(crate) async fn knowledge_base(Query(q): Query<AnalyticsQuery>) -> impl IntoResponse {
+    to_res(kb_stats(&q))
+}
+
+pub(crate) async fn llm(Query(q): Query<AnalyticsQuery>) -> impl IntoResponse {
+    to_res(llm_stats(&q))
+}
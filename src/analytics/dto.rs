@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Deserialize)]
+pub(crate) enum BucketUnit {
+    Hour,
+    Day,
+    Week,
+}
+
+impl BucketUnit {
+    pub(crate) fn millis(&self) -> u64 {
+        match self {
+            Self::Hour => 3_600_000,
+            Self::Day => 86_400_000,
+            Self::Week => 604_800_000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AnalyticsQuery {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "mainFlowId")]
+    pub(crate) main_flow_id: Option<String>,
+    // Unix timestamp in milliseconds
+    #[serde(rename = "startTime")]
+    pub(crate) start_time: Option<u64>,
+    #[serde(rename = "endTime")]
+    pub(crate) end_time: Option<u64>,
+    pub(crate) bucket: Option<BucketUnit>,
+}
+
+impl AnalyticsQuery {
+    pub(crate) fn in_range(&self, t: u64) -> bool {
+        self.start_time.is_none_or(|s| t >= s) && self.end_time.is_none_or(|e| t <= e)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct LlmCallRecord {
+    pub(crate) timestamp: u64,
+    #[serde(rename = "latencyMillis")]
+    pub(crate) latency_millis: u64,
+    pub(crate) failed: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Bucket<T> {
+    #[serde(rename = "bucketStart")]
+    pub(crate) bucket_start: u64,
+    #[serde(flatten)]
+    pub(crate) stats: T,
+}
+
+#[derive(Serialize)]
+pub(crate) struct AnalyticsData<T> {
+    pub(crate) total: T,
+    // Empty if no bucket unit was given
+    pub(crate) buckets: Vec<Bucket<T>>,
+}
+
+#[derive(Default, Serialize)]
+pub(crate) struct ConversationStats {
+    pub(crate) conversations: u64,
+    pub(crate) turns: u64,
+    #[serde(rename = "avgTurns")]
+    pub(crate) avg_turns: f64,
+    pub(crate) completed: u64,
+    pub(crate) transferred: u64,
+    pub(crate) failed: u64,
+    pub(crate) dropped: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct NodeDropOff {
+    #[serde(rename = "nodeId")]
+    pub(crate) node_id: String,
+    pub(crate) dropped: u64,
+    pub(crate) rate: f64,
+}
+
+#[derive(Default, Serialize)]
+pub(crate) struct IntentStats {
+    pub(crate) turns: u64,
+    #[serde(rename = "noneIntent")]
+    pub(crate) none_intent: u64,
+    #[serde(rename = "noneIntentRate")]
+    pub(crate) none_intent_rate: f64,
+    pub(crate) distribution: BTreeMap<String, u64>,
+}
+
+#[derive(Default, Serialize)]
+pub(crate) struct KnowledgeBaseStats {
+    pub(crate) queries: u64,
+    #[serde(rename = "noRecall")]
+    pub(crate) no_recall: u64,
+    #[serde(rename = "noRecallRate")]
+    pub(crate) no_recall_rate: f64,
+}
+
+#[derive(Default, Serialize)]
+pub(crate) struct LlmStats {
+    pub(crate) calls: u64,
+    pub(crate) errors: u64,
+    #[serde(rename = "errorRate")]
+    pub(crate) error_rate: f64,
+    #[serde(rename = "avgLatencyMillis")]
+    pub(crate) avg_latency_millis: f64,
+    #[serde(rename = "maxLatencyMillis")]
+    pub(crate) max_latency_millis: u64,
+}
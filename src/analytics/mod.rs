@@ -0,0 +1,2 @@
+pub(crate) mod crud;
+pub(crate) mod dto;
@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{LazyLock, Mutex, RwLock};
+use std::vec::Vec;
+
+use argon2::Argon2;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use axum::extract::{ConnectInfo, Extension, Query};
+use axum::http::{HeaderMap, HeaderValue, header};
+use axum::response::{Html, Response};
+use axum::{Json, response::IntoResponse};
+use redb::TableDefinition;
+use sha2::{Digest, Sha256};
+
+use super::dto::{
+    ApiKey, ApiKeyData, ApiKeyQuery, ApiKeyRecord, AuthSettings, AuthToken, CurrentUser, LoginData,
+    LoginResult, NewApiKey, User, UserData, UserInfo, UserQuery, UserRole,
+};
+use crate::db;
+use crate::man::clock::now_millis;
+use crate::man::settings;
+use crate::result::{Error, Result};
+use crate::web::server::to_res;
+
+const USER_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("auth_users");
+const TOKEN_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("auth_tokens");
+const API_KEY_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("auth_api_keys");
+const AUTH_SETTINGS_KEY: &str = "auth-settings";
+const TOKEN_PREFIX: &str = "dft_";
+const API_KEY_PREFIX: &str = "dfk_";
+const MIN_PASSWORD_LEN: usize = 8;
+// Signs in the bundled web UI, which doesn't send the Authorization header
+pub(crate) const TOKEN_COOKIE: &str = "dft";
+const MAX_FAILED_LOGINS: u32 = 5;
+const LOCKOUT_MILLIS: u64 = 15 * 60 * 1000;
+const MAX_TRACKED_LOGINS: usize = 10000;
+
+static AUTH_SETTINGS: LazyLock<RwLock<AuthSettings>> =
+    LazyLock::new(|| RwLock::new(AuthSettings::default()));
+// Unknown usernames are verified against it, so that they take as long as the known ones
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password(&gen_secret("")).unwrap_or_default());
+type LoginKey = (String, IpAddr);
+
+// Failed sign-in attempts of each username from each client IP, and when the last one happened.
+// Keyed by both, so that others can't lock a user out by guessing its password
+static FAILED_LOGINS: LazyLock<Mutex<HashMap<LoginKey, (u32, u64)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::with_capacity(16)));
+
+pub(crate) fn init() -> Result<()> {
+    db::init_table(USER_TABLE)?;
+    db::init_table(TOKEN_TABLE)?;
+    db::init_table(API_KEY_TABLE)?;
+    let s: AuthSettings = db::query(settings::TABLE, AUTH_SETTINGS_KEY)?.unwrap_or_default();
+    *AUTH_SETTINGS.write()? = s;
+    Ok(())
+}
+
+// Used by the `-rs` startup argument, so that a locked out administrator can get in again
+pub(crate) fn reset_settings() -> Result<()> {
+    let s = AuthSettings::default();
+    db::write(settings::TABLE, AUTH_SETTINGS_KEY, &s)?;
+    *AUTH_SETTINGS.write()? = s;
+    Ok(())
+}
+
+pub(crate) fn get_settings() -> AuthSettings {
+    // A poisoned lock must not turn authentication off
+    match AUTH_SETTINGS.read() {
+        Ok(s) => s.clone(),
+        Err(e) => e.into_inner().clone(),
+    }
+}
+
+fn save_settings(data: AuthSettings) -> Result<()> {
+    if data.enabled && !has_admin(None)? {
+        return Err(Error::WithMessage(String::from(
+            "Please add an administrator before enabling authentication",
+        )));
+    }
+    if data.token_expire_sec == 0 {
+        return Err(Error::WithMessage(String::from(
+            "Token expiration must be greater than 0",
+        )));
+    }
+    db::write(settings::TABLE, AUTH_SETTINGS_KEY, &data)?;
+    *AUTH_SETTINGS.write()? = data;
+    Ok(())
+}
+
+pub(crate) fn is_origin_allowed(origin: &[u8]) -> bool {
+    let s = get_settings();
+    s.allowed_origins
+        .iter()
+        .any(|o| o.eq("*") || o.as_bytes().eq_ignore_ascii_case(origin))
+}
+
+fn sha256_hex(s: &str) -> String {
+    let digest = Sha256::digest(s.as_bytes());
+    let mut h = String::with_capacity(digest.len() * 2);
+    for b in digest.iter() {
+        let _ = write!(h, "{b:02x}");
+    }
+    h
+}
+
+fn gen_secret(prefix: &str) -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let mut s = String::with_capacity(prefix.len() + bytes.len() * 2);
+    s.push_str(prefix);
+    for b in bytes.iter() {
+        let _ = write!(s, "{b:02x}");
+    }
+    s
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| Error::WithMessage(format!("Hashing password failed: {e:?}")))
+}
+
+// Argon2 takes a while, so it doesn't run on async workers
+async fn hash_password_blocking(password: &str) -> Result<String> {
+    let password = String::from(password);
+    tokio::task::spawn_blocking(move || hash_password(&password)).await?
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(h) => Argon2::default()
+            .verify_password(password.as_bytes(), &h)
+            .is_ok(),
+        Err(e) => {
+            log::error!("Invalid password hash: {e:?}");
+            false
+        }
+    }
+}
+
+// Whether there is an administrator other than the excluded one
+fn has_admin(excluded: Option<&str>) -> Result<bool> {
+    let users: Vec<User> = db::get_all(USER_TABLE)?;
+    Ok(users
+        .iter()
+        .any(|u| u.role == UserRole::Admin && excluded.is_none_or(|n| !u.username.eq(n))))
+}
+
+fn check_last_admin(username: &str) -> Result<()> {
+    if get_settings().enabled && !has_admin(Some(username))? {
+        return Err(Error::WithMessage(String::from(
+            "The last administrator can't be removed while authentication is enabled",
+        )));
+    }
+    Ok(())
+}
+
+async fn save_user(data: UserData) -> Result<()> {
+    let username = data.username.trim();
+    if username.is_empty() {
+        return Err(Error::WithMessage(String::from("Username can't be empty")));
+    }
+    if let Some(p) = &data.password
+        && p.chars().count() < MIN_PASSWORD_LEN
+    {
+        return Err(Error::WithMessage(format!(
+            "Password must have at least {MIN_PASSWORD_LEN} characters"
+        )));
+    }
+    let existing: Option<User> = db::query(USER_TABLE, username)?;
+    let user = match existing {
+        Some(mut u) => {
+            if u.role == UserRole::Admin && data.role != UserRole::Admin {
+                check_last_admin(username)?;
+            }
+            u.role = data.role;
+            if let Some(p) = &data.password {
+                u.password_hash = hash_password_blocking(p).await?;
+                // Signs out all the sessions of the user
+                remove_tokens(username)?;
+            }
+            u
+        }
+        None => {
+            let Some(p) = &data.password else {
+                return Err(Error::WithMessage(String::from("Password can't be empty")));
+            };
+            User {
+                username: String::from(username),
+                password_hash: hash_password_blocking(p).await?,
+                role: data.role,
+                created_at: now_millis(),
+            }
+        }
+    };
+    db::write(USER_TABLE, username, &user)
+}
+
+fn delete_user(username: &str) -> Result<()> {
+    let user: Option<User> = db::query(USER_TABLE, username)?;
+    if let Some(u) = user
+        && u.role == UserRole::Admin
+    {
+        check_last_admin(username)?;
+    }
+    remove_tokens(username)?;
+    db::remove(USER_TABLE, username)
+}
+
+fn list_users() -> Result<Vec<UserInfo>> {
+    let users: Vec<User> = db::get_all(USER_TABLE)?;
+    Ok(users.into_iter().map(UserInfo::from).collect())
+}
+
+fn remove_tokens(username: &str) -> Result<()> {
+    let now = now_millis();
+    let tokens: Vec<AuthToken> = db::get_all(TOKEN_TABLE)?;
+    for t in tokens.iter() {
+        // Expired tokens are removed along the way
+        if t.username.eq(username) || t.expires_at <= now {
+            db::remove(TOKEN_TABLE, t.token_hash.as_str())?;
+        }
+    }
+    Ok(())
+}
+
+fn check_lockout(key: &LoginKey) -> Result<()> {
+    let failed = FAILED_LOGINS.lock()?;
+    if let Some((count, last)) = failed.get(key)
+        && *count >= MAX_FAILED_LOGINS
+        && now_millis() < last + LOCKOUT_MILLIS
+    {
+        return Err(Error::WithMessage(String::from(
+            "Too many failed attempts, please try again later",
+        )));
+    }
+    Ok(())
+}
+
+fn record_login(key: LoginKey, succeeded: bool) -> Result<()> {
+    let mut failed = FAILED_LOGINS.lock()?;
+    if succeeded {
+        failed.remove(&key);
+        return Ok(());
+    }
+    let now = now_millis();
+    if failed.len() >= MAX_TRACKED_LOGINS {
+        failed.retain(|_, (_, last)| now < *last + LOCKOUT_MILLIS);
+    }
+    let entry = failed.entry(key).or_insert((0, now));
+    // Attempts are counted again once the lockout has passed
+    if now >= entry.1 + LOCKOUT_MILLIS {
+        entry.0 = 0;
+    }
+    entry.0 += 1;
+    entry.1 = now;
+    Ok(())
+}
+
+async fn login(data: LoginData, ip: IpAddr) -> Result<LoginResult> {
+    let key = (data.username.clone(), ip);
+    check_lockout(&key)?;
+    let user: Option<User> = db::query(USER_TABLE, data.username.as_str())?;
+    let hash = user.as_ref().map(|u| u.password_hash.clone());
+    let password = data.password;
+    let verified = tokio::task::spawn_blocking(move || {
+        let hash = hash.as_deref().unwrap_or(DUMMY_HASH.as_str());
+        verify_password(&password, hash)
+    })
+    .await?;
+    record_login(key, verified && user.is_some())?;
+    let user = match user {
+        Some(u) if verified => u,
+        _ => {
+            log::warn!(
+                "Failed login attempt for user {} from {}",
+                &data.username,
+                ip
+            );
+            return Err(Error::WithMessage(String::from(
+                "Invalid username or password",
+            )));
+        }
+    };
+    let token = gen_secret(TOKEN_PREFIX);
+    let t = AuthToken {
+        token_hash: sha256_hex(&token),
+        username: user.username,
+        expires_at: now_millis() + get_settings().token_expire_sec as u64 * 1000,
+    };
+    db::write(TOKEN_TABLE, t.token_hash.as_str(), &t)?;
+    Ok(LoginResult {
+        token,
+        role: user.role,
+        expires_at: t.expires_at,
+    })
+}
+
+pub(crate) fn find_user_by_token(token: &str) -> Result<Option<CurrentUser>> {
+    if !token.starts_with(TOKEN_PREFIX) {
+        return Ok(None);
+    }
+    let token_hash = sha256_hex(token);
+    let t: Option<AuthToken> = db::query(TOKEN_TABLE, token_hash.as_str())?;
+    let Some(t) = t else {
+        return Ok(None);
+    };
+    if t.expires_at <= now_millis() {
+        db::remove(TOKEN_TABLE, token_hash.as_str())?;
+        return Ok(None);
+    }
+    // Role is read every time, so that changes take effect immediately
+    let user: Option<User> = db::query(USER_TABLE, t.username.as_str())?;
+    Ok(user.map(|u| CurrentUser {
+        username: u.username,
+        role: u.role,
+        token_hash,
+    }))
+}
+
+pub(crate) fn find_api_key(key: &str) -> Result<Option<ApiKey>> {
+    if !key.starts_with(API_KEY_PREFIX) {
+        return Ok(None);
+    }
+    let r: Option<ApiKeyRecord> = db::query(API_KEY_TABLE, sha256_hex(key).as_str())?;
+    Ok(r.map(|r| r.info))
+}
+
+fn create_api_key(data: &ApiKeyData) -> Result<NewApiKey> {
+    if crate::robot::crud::get(&data.robot_id)?.is_none() {
+        return Err(Error::WithMessage(format!(
+            "Robot {} doesn't exist",
+            &data.robot_id
+        )));
+    }
+    let key = gen_secret(API_KEY_PREFIX);
+    let r = ApiKeyRecord {
+        key_hash: sha256_hex(&key),
+        info: ApiKey {
+            id: scru128::new_string(),
+            robot_id: data.robot_id.clone(),
+            name: String::from(data.name.trim()),
+            key_prefix: String::from(&key[..API_KEY_PREFIX.len() + 6]),
+            created_at: now_millis(),
+        },
+    };
+    db::write(API_KEY_TABLE, r.key_hash.as_str(), &r)?;
+    Ok(NewApiKey { info: r.info, key })
+}
+
+fn get_api_keys(robot_id: &str) -> Result<Vec<ApiKeyRecord>> {
+    let mut keys: Vec<ApiKeyRecord> = db::get_all(API_KEY_TABLE)?;
+    keys.retain(|k| k.info.robot_id.eq(robot_id));
+    Ok(keys)
+}
+
+fn delete_api_key(robot_id: &str, id: Option<&str>) -> Result<()> {
+    for k in get_api_keys(robot_id)?.iter() {
+        if id.is_none_or(|id| k.info.id.eq(id)) {
+            db::remove(API_KEY_TABLE, k.key_hash.as_str())?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn remove_api_keys(robot_id: &str) -> Result<()> {
+    delete_api_key(robot_id, None)
+}
+
+// The server speaks plain HTTP, a Secure cookie would be dropped by browsers unless the
+// request came through a proxy terminating HTTPS
+fn is_https(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("https"))
+}
+
+fn with_token_cookie(
+    mut res: Response,
+    headers: &HeaderMap,
+    token: &str,
+    max_age_sec: u64,
+) -> Response {
+    let secure = if is_https(headers) { " Secure;" } else { "" };
+    let cookie = format!(
+        "{TOKEN_COOKIE}={token}; Path=/; Max-Age={max_age_sec}; HttpOnly;{secure} SameSite=Strict"
+    );
+    match HeaderValue::from_str(&cookie) {
+        Ok(v) => {
+            res.headers_mut().insert(header::SET_COOKIE, v);
+        }
+        Err(e) => log::error!("Invalid cookie: {e:?}"),
+    }
+    res
+}
+
+pub(crate) async fn login_page() -> impl IntoResponse {
+    Html(include_str!("login.html"))
+}
+
+pub(crate) async fn rest_login(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(data): Json<LoginData>,
+) -> Response {
+    let r = login(data, addr.ip()).await;
+    let token = r.as_ref().ok().map(|l| l.token.clone());
+    let res = to_res(r).into_response();
+    match token {
+        Some(t) => with_token_cookie(res, &headers, &t, get_settings().token_expire_sec as u64),
+        None => res,
+    }
+}
+
+pub(crate) async fn rest_logout(
+    user: Option<Extension<CurrentUser>>,
+    headers: HeaderMap,
+) -> Response {
+    let r = match user {
+        Some(Extension(u)) => db::remove(TOKEN_TABLE, u.token_hash.as_str()),
+        None => Ok(()),
+    };
+    with_token_cookie(to_res(r).into_response(), &headers, "", 0)
+}
+
+pub(crate) async fn me(user: Option<Extension<CurrentUser>>) -> impl IntoResponse {
+    // Anonymous when authentication is disabled
+    to_res(Ok(user.map(|Extension(u)| UserInfo {
+        username: u.username,
+        role: u.role,
+        created_at: 0,
+    })))
+}
+
+pub(crate) async fn rest_list_users() -> impl IntoResponse {
+    to_res(list_users())
+}
+
+pub(crate) async fn rest_save_user(Json(data): Json<UserData>) -> impl IntoResponse {
+    to_res(save_user(data).await)
+}
+
+pub(crate) async fn rest_delete_user(Query(q): Query<UserQuery>) -> impl IntoResponse {
+    to_res(delete_user(&q.username))
+}
+
+pub(crate) async fn list_api_keys(Query(q): Query<ApiKeyQuery>) -> impl IntoResponse {
+    let r = get_api_keys(&q.robot_id).map(|v| v.into_iter().map(|k| k.info).collect::<Vec<_>>());
+    to_res(r)
+}
+
+pub(crate) async fn rest_create_api_key(Json(data): Json<ApiKeyData>) -> impl IntoResponse {
+    to_res(create_api_key(&data))
+}
+
+pub(crate) async fn rest_delete_api_key(Query(q): Query<ApiKeyQuery>) -> impl IntoResponse {
+    let r = match &q.id {
+        Some(id) => delete_api_key(&q.robot_id, Some(id)),
+        None => Err(Error::WithMessage(String::from("Missing API key id"))),
+    };
+    to_res(r)
+}
+
+pub(crate) async fn rest_get_settings() -> impl IntoResponse {
+    to_res(Ok(get_settings()))
+}
+
+pub(crate) async fn rest_save_settings(Json(data): Json<AuthSettings>) -> impl IntoResponse {
+    to_res(save_settings(data))
+}
@@ -0,0 +1,154 @@
+use std::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+// Declaration order matters, a role includes all the permissions of the roles before it
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum UserRole {
+    Viewer,
+    Editor,
+    Admin,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct AuthSettings {
+    pub(crate) enabled: bool,
+    // "*" allows any origin, web pages embedding the chat SDK need their origins listed
+    #[serde(rename = "allowedOrigins")]
+    pub(crate) allowed_origins: Vec<String>,
+    #[serde(rename = "tokenExpireSec")]
+    pub(crate) token_expire_sec: u32,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        AuthSettings {
+            enabled: false,
+            allowed_origins: vec![
+                String::from("http://localhost:12715"),
+                String::from("http://127.0.0.1:12715"),
+            ],
+            token_expire_sec: 86400,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct User {
+    pub(crate) username: String,
+    #[serde(rename = "passwordHash")]
+    pub(crate) password_hash: String,
+    pub(crate) role: UserRole,
+    #[serde(rename = "createdAt")]
+    pub(crate) created_at: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct UserInfo {
+    pub(crate) username: String,
+    pub(crate) role: UserRole,
+    #[serde(rename = "createdAt")]
+    pub(crate) created_at: u64,
+}
+
+impl From<User> for UserInfo {
+    fn from(u: User) -> Self {
+        UserInfo {
+            username: u.username,
+            role: u.role,
+            created_at: u.created_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UserData {
+    pub(crate) username: String,
+    // Keeps the current password when updating an existing user
+    pub(crate) password: Option<String>,
+    pub(crate) role: UserRole,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UserQuery {
+    pub(crate) username: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LoginData {
+    pub(crate) username: String,
+    pub(crate) password: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct LoginResult {
+    pub(crate) token: String,
+    pub(crate) role: UserRole,
+    #[serde(rename = "expiresAt")]
+    pub(crate) expires_at: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct AuthToken {
+    #[serde(rename = "tokenHash")]
+    pub(crate) token_hash: String,
+    pub(crate) username: String,
+    #[serde(rename = "expiresAt")]
+    pub(crate) expires_at: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct ApiKey {
+    pub(crate) id: String,
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    pub(crate) name: String,
+    // First characters of the key, helps users to tell keys apart
+    #[serde(rename = "keyPrefix")]
+    pub(crate) key_prefix: String,
+    #[serde(rename = "createdAt")]
+    pub(crate) created_at: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct ApiKeyRecord {
+    #[serde(rename = "keyHash")]
+    pub(crate) key_hash: String,
+    #[serde(flatten)]
+    pub(crate) info: ApiKey,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ApiKeyData {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    pub(crate) name: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct NewApiKey {
+    #[serde(flatten)]
+    pub(crate) info: ApiKey,
+    // Plain key, it is only returned once on creation
+    pub(crate) key: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ApiKeyQuery {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    pub(crate) id: Option<String>,
+}
+
+// Request extensions inserted by the guard
+#[derive(Clone)]
+pub(crate) struct CurrentUser {
+    pub(crate) username: String,
+    pub(crate) role: UserRole,
+    pub(crate) token_hash: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct ApiKeyScope {
+    pub(crate) robot_id: String,
+}
@@ -0,0 +1,160 @@
+use axum::extract::Request;
+use axum::http::{HeaderMap, Method, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use super::crud;
+use super::dto::{ApiKeyScope, CurrentUser, UserRole};
+use crate::result::{Error, Result};
+use crate::web::server::to_err_res;
+
+pub(crate) const API_KEY_HEADER: &str = "x-api-key";
+// Browsers can't set headers on WebSocket handshakes, so the key may be offered as the
+// sub-protocol following this one: new WebSocket(url, ["dialogflowai-api-key", key])
+pub(crate) const WS_API_KEY_PROTOCOL: &str = "dialogflowai-api-key";
+
+enum Access {
+    Public,
+    // Either an API key of the robot or a signed in user
+    Runtime,
+    Management(UserRole),
+}
+
+fn required_access(method: &Method, path: &str) -> Access {
+    match path {
+        "/version.json" | "/check-new-version.json" | "/auth/login" => Access::Public,
+        "/flow/answer" | "/flow/answer/sse" | "/flow/ws" => Access::Runtime,
+        "/auth/logout" | "/auth/me" => Access::Management(UserRole::Viewer),
+        // Settings hold SMTP passwords and API keys of model providers
+        "/management/global-settings" => Access::Management(UserRole::Admin),
+        p if p.starts_with("/management/settings") => Access::Management(UserRole::Admin),
+        p if p.starts_with("/auth/") => Access::Management(UserRole::Admin),
+        // These GET routes change data
+        "/mainflow/release" | "/intent/phrase/regenerate-all" => {
+            Access::Management(UserRole::Editor)
+        }
+        // These POST routes only read data
+        "/intent/detect" => Access::Management(UserRole::Viewer),
+        _ if method == Method::GET => Access::Management(UserRole::Viewer),
+        _ => Access::Management(UserRole::Editor),
+    }
+}
+
+// The web UI is signed in with a cookie, other clients send the Authorization header
+fn session_token(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    bearer.or_else(|| {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .find_map(|c| c.trim().strip_prefix(crud::TOKEN_COOKIE)?.strip_prefix('='))
+    })
+}
+
+// Keys are only accepted in headers, so that they don't end up in logs of URLs
+fn api_key(req: &Request) -> Option<&str> {
+    match req.headers().get(API_KEY_HEADER) {
+        Some(v) => v.to_str().ok(),
+        None => ws_protocol_api_key(req.headers()),
+    }
+}
+
+fn ws_protocol_api_key(headers: &HeaderMap) -> Option<&str> {
+    let mut protocols = headers
+        .get(header::SEC_WEBSOCKET_PROTOCOL)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .map(str::trim);
+    protocols.find(|p| *p == WS_API_KEY_PROTOCOL)?;
+    protocols.next()
+}
+
+fn current_user(req: &Request) -> Result<Option<CurrentUser>> {
+    match session_token(req.headers()) {
+        Some(t) => crud::find_user_by_token(t),
+        None => Ok(None),
+    }
+}
+
+// Whether the web UI can be opened
+pub(crate) fn signed_in(headers: &HeaderMap) -> bool {
+    !crud::get_settings().enabled
+        || session_token(headers)
+            .is_some_and(|t| crud::find_user_by_token(t).is_ok_and(|u| u.is_some()))
+}
+
+pub(crate) async fn authorize(mut req: Request, next: Next) -> Response {
+    if !crud::get_settings().enabled {
+        return next.run(req).await;
+    }
+    let access = required_access(req.method(), req.uri().path());
+    let r = match access {
+        Access::Public => Ok(()),
+        Access::Runtime => match api_key(&req).map(crud::find_api_key) {
+            Some(Ok(Some(k))) => {
+                req.extensions_mut().insert(ApiKeyScope {
+                    robot_id: k.robot_id,
+                });
+                Ok(())
+            }
+            Some(Err(e)) => Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+            // Signed in users can try the dialogs of all robots
+            Some(Ok(None)) | None => match current_user(&req) {
+                Ok(Some(u)) => {
+                    req.extensions_mut().insert(u);
+                    Ok(())
+                }
+                Ok(None) => Err(unauthorized()),
+                Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+            },
+        },
+        Access::Management(role) => match current_user(&req) {
+            Ok(Some(u)) if u.role >= role => {
+                req.extensions_mut().insert(u);
+                Ok(())
+            }
+            Ok(Some(u)) => {
+                log::warn!(
+                    "User {} has no permission to {} {}",
+                    &u.username,
+                    req.method(),
+                    req.uri().path()
+                );
+                Err((
+                    StatusCode::FORBIDDEN,
+                    Error::WithMessage(String::from("Permission denied")),
+                ))
+            }
+            Ok(None) => Err(unauthorized()),
+            Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+        },
+    };
+    match r {
+        Ok(_) => next.run(req).await,
+        Err((status, e)) => to_err_res(status, e),
+    }
+}
+
+fn unauthorized() -> (StatusCode, Error) {
+    (
+        StatusCode::UNAUTHORIZED,
+        Error::WithMessage(String::from("Missing or invalid credentials")),
+    )
+}
+
+// API keys can only talk to the robot they were created for
+pub(crate) fn check_robot_scope(scope: Option<&ApiKeyScope>, robot_id: &str) -> Result<()> {
+    match scope {
+        Some(s) if !s.robot_id.eq(robot_id) => Err(Error::WithMessage(String::from(
+            "The API key doesn't belong to this robot",
+        ))),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,3 @@
+pub(crate) mod crud;
+pub(crate) mod dto;
+pub(crate) mod guard;
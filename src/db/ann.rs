@@ -0,0 +1,889 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicBool, AtomicU64};
+use std::sync::{
+    Arc, LazyLock, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+};
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::result::{Error, Result};
+
+// Tables with fewer rows are searched exactly
+const EXACT_SEARCH_MAX_ROWS: usize = 5000;
+// Links of a node on upper layers, layer 0 has twice as many
+const M: usize = 16;
+const MAX_LEVEL: usize = 16;
+const EF_CONSTRUCTION: usize = 100;
+const EF_SEARCH: usize = 128;
+const SAVE_INTERVAL_SECS: u64 = 10;
+// Changes applied under one write lock, searches meanwhile fall back to exact search
+const APPLY_BATCH: usize = 64;
+const FILE_MAGIC: &[u8; 4] = b"HNSW";
+
+// Vectors of a robot are in the table `{robot_id}{table_suffix}`, with an integer `id` column
+pub(crate) struct Store {
+    pub(crate) name: &'static str,
+    pub(crate) table_suffix: &'static str,
+    pub(crate) column: &'static str,
+}
+
+impl Store {
+    fn key(&self, robot_id: &str) -> String {
+        format!("{}/{robot_id}", self.name)
+    }
+
+    fn table(&self, robot_id: &str) -> String {
+        format!("{robot_id}{}", self.table_suffix)
+    }
+}
+
+#[derive(Clone)]
+enum Op {
+    Upsert(i64, Vec<f32>),
+    Remove(i64),
+}
+
+struct Shared {
+    index: RwLock<Index>,
+    // Changes waiting to be applied off the async workers
+    pending: Mutex<Vec<Op>>,
+    applying: AtomicBool,
+}
+
+enum Entry {
+    // Changes made while loading are applied to the loaded index
+    Loading(u64, Vec<Op>),
+    Ready(Arc<Shared>),
+}
+
+static INDEXES: LazyLock<Mutex<HashMap<String, Entry>>> =
+    LazyLock::new(|| Mutex::new(HashMap::with_capacity(32)));
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn path(key: &str) -> PathBuf {
+    Path::new(".")
+        .join("data")
+        .join("ann")
+        .join(format!("{key}.idx"))
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| {
+        log::warn!("{:#?}", &e);
+        e.into_inner()
+    })
+}
+
+fn lock_indexes() -> MutexGuard<'static, HashMap<String, Entry>> {
+    lock(&INDEXES)
+}
+
+fn read(index: &RwLock<Index>) -> RwLockReadGuard<'_, Index> {
+    index.read().unwrap_or_else(|e| {
+        log::warn!("{:#?}", &e);
+        e.into_inner()
+    })
+}
+
+fn write(index: &RwLock<Index>) -> RwLockWriteGuard<'_, Index> {
+    index.write().unwrap_or_else(|e| {
+        log::warn!("{:#?}", &e);
+        e.into_inner()
+    })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Scored(f32, u32);
+
+impl Eq for Scored {}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn normalize(v: &[f32]) -> Vec<f32> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0. {
+        v.iter().map(|x| x / norm).collect()
+    } else {
+        v.to_vec()
+    }
+}
+
+// Cosine distance of normalized vectors, summed in lanes so it can be vectorized
+fn distance(a: &[f32], b: &[f32]) -> f32 {
+    let mut lanes = [0f32; 8];
+    let (a_chunks, a_rest) = a.as_chunks::<8>();
+    let (b_chunks, b_rest) = b.as_chunks::<8>();
+    for (x, y) in a_chunks.iter().zip(b_chunks.iter()) {
+        for ((l, x), y) in lanes.iter_mut().zip(x.iter()).zip(y.iter()) {
+            *l += x * y;
+        }
+    }
+    let rest: f32 = a_rest.iter().zip(b_rest.iter()).map(|(x, y)| x * y).sum();
+    1. - lanes.iter().sum::<f32>() - rest
+}
+
+fn max_links(layer: usize) -> usize {
+    if layer == 0 { M * 2 } else { M }
+}
+
+// Same input gives the same level, so rebuilt graphs are reproducible
+fn random_level(id: i64, seq: usize) -> usize {
+    let mut x = (id as u64) ^ (seq as u64).rotate_left(32);
+    x = x.wrapping_add(0x9E3779B97F4A7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D049BB133111EB);
+    x ^= x >> 31;
+    let u = ((x >> 11) as f64 / (1u64 << 53) as f64).max(f64::MIN_POSITIVE);
+    ((-u.ln() / (M as f64).ln()) as usize).min(MAX_LEVEL)
+}
+
+struct Node {
+    id: i64,
+    vector: Vec<f32>,
+    // Neighbours on each layer, from layer 0 to the level of the node
+    links: Vec<Vec<u32>>,
+    deleted: bool,
+}
+
+// HNSW graph, removed nodes are kept for traversal until the index is compacted
+pub(crate) struct Index {
+    dim: usize,
+    nodes: Vec<Node>,
+    slots: HashMap<i64, u32>,
+    entry: Option<u32>,
+    dirty: AtomicBool,
+    // Changes made while a compacted copy is being built
+    journal: Option<Vec<Op>>,
+}
+
+impl Index {
+    fn new(dim: usize) -> Self {
+        Index {
+            dim,
+            nodes: Vec::with_capacity(128),
+            slots: HashMap::with_capacity(128),
+            entry: None,
+            dirty: AtomicBool::new(false),
+            journal: None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn level_of(&self, slot: u32) -> usize {
+        self.nodes[slot as usize].links.len() - 1
+    }
+
+    fn upsert(&mut self, id: i64, vector: &[f32]) {
+        if self.dim != vector.len() {
+            if !self.slots.is_empty() {
+                log::warn!(
+                    "Vector dimension changed from {} to {}, index was reset",
+                    self.dim,
+                    vector.len()
+                );
+            }
+            // A compaction in progress still needs the changes made meanwhile
+            let journal = self.journal.take();
+            *self = Index::new(vector.len());
+            self.journal = journal;
+        }
+        self.remove(id);
+        let slot = self.nodes.len() as u32;
+        let level = random_level(id, self.nodes.len());
+        let query = normalize(vector);
+        self.nodes.push(Node {
+            id,
+            vector: query.clone(),
+            links: vec![vec![]; level + 1],
+            deleted: false,
+        });
+        self.slots.insert(id, slot);
+        *self.dirty.get_mut() = true;
+        let Some(entry) = self.entry else {
+            self.entry = Some(slot);
+            return;
+        };
+        let top = self.level_of(entry);
+        let mut ep = entry;
+        for layer in (level + 1..=top).rev() {
+            ep = self.greedy(&query, ep, layer);
+        }
+        let mut eps = vec![ep];
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(&query, &eps, EF_CONSTRUCTION, layer);
+            let neighbours = self.select_neighbours(&found, max_links(layer));
+            for n in neighbours.iter() {
+                self.connect(*n, slot, layer);
+            }
+            self.nodes[slot as usize].links[layer] = neighbours;
+            eps = found.iter().map(|s| s.1).collect();
+        }
+        if level > top {
+            self.entry = Some(slot);
+        }
+    }
+
+    fn remove(&mut self, id: i64) {
+        if let Some(slot) = self.slots.remove(&id) {
+            self.nodes[slot as usize].deleted = true;
+            *self.dirty.get_mut() = true;
+        }
+    }
+
+    // Candidates closer to a selected neighbour than to the node are skipped,
+    // so links spread in different directions
+    fn select_neighbours(&self, candidates: &[Scored], m: usize) -> Vec<u32> {
+        let mut selected: Vec<u32> = Vec::with_capacity(m);
+        for c in candidates.iter() {
+            let v = &self.nodes[c.1 as usize].vector;
+            if selected
+                .iter()
+                .all(|s| distance(v, &self.nodes[*s as usize].vector) > c.0)
+            {
+                selected.push(c.1);
+                if selected.len() >= m {
+                    break;
+                }
+            }
+        }
+        selected
+    }
+
+    fn connect(&mut self, from: u32, to: u32, layer: usize) {
+        let node = &self.nodes[from as usize];
+        let mut links = node.links[layer].clone();
+        links.push(to);
+        if links.len() > max_links(layer) {
+            let mut scored: Vec<Scored> = links
+                .iter()
+                .map(|l| Scored(distance(&node.vector, &self.nodes[*l as usize].vector), *l))
+                .collect();
+            scored.sort();
+            links = self.select_neighbours(&scored, max_links(layer));
+        }
+        self.nodes[from as usize].links[layer] = links;
+    }
+
+    fn greedy(&self, query: &[f32], ep: u32, layer: usize) -> u32 {
+        let mut current = ep;
+        let mut current_distance = distance(query, &self.nodes[ep as usize].vector);
+        loop {
+            let mut changed = false;
+            for n in self.nodes[current as usize].links[layer].iter() {
+                let d = distance(query, &self.nodes[*n as usize].vector);
+                if d < current_distance {
+                    current = *n;
+                    current_distance = d;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return current;
+            }
+        }
+    }
+
+    // Closest nodes on the layer, nearest first
+    fn search_layer(&self, query: &[f32], eps: &[u32], ef: usize, layer: usize) -> Vec<Scored> {
+        let mut visited = vec![false; self.nodes.len()];
+        for ep in eps.iter() {
+            visited[*ep as usize] = true;
+        }
+        let mut candidates: BinaryHeap<Reverse<Scored>> = BinaryHeap::with_capacity(ef * 2);
+        let mut found: BinaryHeap<Scored> = BinaryHeap::with_capacity(ef + 1);
+        for ep in eps.iter() {
+            let s = Scored(distance(query, &self.nodes[*ep as usize].vector), *ep);
+            candidates.push(Reverse(s));
+            found.push(s);
+            if found.len() > ef {
+                found.pop();
+            }
+        }
+        while let Some(Reverse(c)) = candidates.pop() {
+            if found.len() >= ef
+                && let Some(farthest) = found.peek()
+                && c.0 > farthest.0
+            {
+                break;
+            }
+            for n in self.nodes[c.1 as usize].links[layer].iter() {
+                if visited[*n as usize] {
+                    continue;
+                }
+                visited[*n as usize] = true;
+                let d = distance(query, &self.nodes[*n as usize].vector);
+                if found.len() < ef || found.peek().is_some_and(|f| d < f.0) {
+                    candidates.push(Reverse(Scored(d, *n)));
+                    found.push(Scored(d, *n));
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        found.into_sorted_vec()
+    }
+
+    fn search(&self, query: &[f32], k: usize) -> Vec<i64> {
+        let Some(entry) = self.entry else {
+            return vec![];
+        };
+        let query = normalize(query);
+        let mut ep = entry;
+        for layer in (1..=self.level_of(entry)).rev() {
+            ep = self.greedy(&query, ep, layer);
+        }
+        self.search_layer(&query, &[ep], EF_SEARCH.max(k * 2), 0)
+            .into_iter()
+            .map(|s| &self.nodes[s.1 as usize])
+            .filter(|n| !n.deleted)
+            .take(k)
+            .map(|n| n.id)
+            .collect()
+    }
+
+    fn needs_compaction(&self) -> bool {
+        self.nodes.len() - self.slots.len() > self.slots.len().max(1000)
+    }
+
+    // Brings a saved index in step with the rows of its table, returns the number of changes
+    fn reconcile(&mut self, rows: &[(i64, Vec<f32>)]) -> usize {
+        let ids: HashSet<i64> = rows.iter().map(|(id, _)| *id).collect();
+        let removed: Vec<i64> = self
+            .slots
+            .keys()
+            .filter(|id| !ids.contains(id))
+            .copied()
+            .collect();
+        let mut changes = removed.len();
+        for id in removed.into_iter() {
+            self.remove(id);
+        }
+        for (id, vector) in rows.iter() {
+            let unchanged = self
+                .slots
+                .get(id)
+                .is_some_and(|s| self.nodes[*s as usize].vector == normalize(vector));
+            if !unchanged {
+                self.upsert(*id, vector);
+                changes += 1;
+            }
+        }
+        changes
+    }
+
+    fn save(&self, p: &Path) -> Result<()> {
+        if let Some(dir) = p.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = p.with_extension("tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        w.write_all(FILE_MAGIC)?;
+        w.write_all(&(self.dim as u32).to_le_bytes())?;
+        w.write_all(&self.entry.map_or(-1i64, i64::from).to_le_bytes())?;
+        w.write_all(&(self.nodes.len() as u64).to_le_bytes())?;
+        for n in self.nodes.iter() {
+            w.write_all(&n.id.to_le_bytes())?;
+            w.write_all(&[n.deleted as u8, n.links.len() as u8])?;
+            for x in n.vector.iter() {
+                w.write_all(&x.to_le_bytes())?;
+            }
+            for links in n.links.iter() {
+                w.write_all(&(links.len() as u32).to_le_bytes())?;
+                for l in links.iter() {
+                    w.write_all(&l.to_le_bytes())?;
+                }
+            }
+        }
+        w.flush()?;
+        drop(w);
+        std::fs::rename(&tmp, p)?;
+        self.dirty.store(false, atomic::Ordering::Release);
+        Ok(())
+    }
+
+    fn load(p: &Path) -> Result<Self> {
+        fn bytes<const N: usize>(r: &mut impl Read) -> Result<[u8; N]> {
+            let mut b = [0u8; N];
+            r.read_exact(&mut b)?;
+            Ok(b)
+        }
+        let invalid = || Error::WithMessage(format!("Invalid vector index file {}", p.display()));
+        let mut r = BufReader::new(File::open(p)?);
+        if &bytes::<4>(&mut r)? != FILE_MAGIC {
+            return Err(invalid());
+        }
+        let dim = u32::from_le_bytes(bytes(&mut r)?) as usize;
+        let entry = i64::from_le_bytes(bytes(&mut r)?);
+        let count = u64::from_le_bytes(bytes(&mut r)?) as usize;
+        let mut index = Index::new(dim);
+        index.nodes.reserve(count);
+        for slot in 0..count {
+            let id = i64::from_le_bytes(bytes(&mut r)?);
+            let [deleted, levels] = bytes::<2>(&mut r)?;
+            let mut vector: Vec<f32> = Vec::with_capacity(dim);
+            for _ in 0..dim {
+                vector.push(f32::from_le_bytes(bytes(&mut r)?));
+            }
+            let mut links: Vec<Vec<u32>> = Vec::with_capacity(levels as usize);
+            for _ in 0..levels {
+                let len = u32::from_le_bytes(bytes(&mut r)?) as usize;
+                if len > max_links(0) {
+                    return Err(invalid());
+                }
+                let mut layer: Vec<u32> = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let l = u32::from_le_bytes(bytes(&mut r)?);
+                    if l as usize >= count {
+                        return Err(invalid());
+                    }
+                    layer.push(l);
+                }
+                links.push(layer);
+            }
+            if links.is_empty() {
+                return Err(invalid());
+            }
+            if deleted == 0 {
+                index.slots.insert(id, slot as u32);
+            }
+            index.nodes.push(Node {
+                id,
+                vector,
+                links,
+                deleted: deleted != 0,
+            });
+        }
+        if entry >= count as i64 || (entry < 0 && count > 0) {
+            return Err(invalid());
+        }
+        index.entry = u32::try_from(entry).ok();
+        Ok(index)
+    }
+}
+
+// The saved index is brought in step with the table, as changes after it was last saved
+// are lost when the process exits. Without a saved index it's built from the table.
+async fn build(
+    store: &Store,
+    db: &turso::Database,
+    robot_id: &str,
+    reuse_file: bool,
+) -> Result<Index> {
+    let conn = db.connect()?;
+    let table = store.table(robot_id);
+    let p = path(&store.key(robot_id));
+    let sql = format!("SELECT id, vector_extract({}) FROM {table}", store.column);
+    let mut rows = conn.query(&sql, ()).await?;
+    let mut vectors: Vec<(i64, Vec<f32>)> = Vec::with_capacity(1024);
+    while let Some(row) = rows.next().await? {
+        vectors.push((
+            *row.get_value(0)?.as_integer().unwrap(),
+            serde_json::from_str(row.get_value(1)?.as_text().unwrap())?,
+        ));
+    }
+    let index = tokio::task::spawn_blocking(move || {
+        if reuse_file && p.exists() {
+            match Index::load(&p) {
+                Ok(mut index) => {
+                    let changes = index.reconcile(&vectors);
+                    if changes > 0 {
+                        log::info!("Vector index {} had {changes} stale rows", p.display());
+                    }
+                    return index;
+                }
+                Err(e) => log::warn!("Loading vector index {} failed {e:?}", p.display()),
+            }
+        }
+        log::info!(
+            "Building vector index {} of {} rows",
+            p.display(),
+            vectors.len()
+        );
+        let mut index = Index::new(vectors.first().map_or(0, |(_, v)| v.len()));
+        for (id, vector) in vectors.iter() {
+            index.upsert(*id, vector);
+        }
+        index
+    })
+    .await?;
+    Ok(index)
+}
+
+fn apply(index: &mut Index, ops: Vec<Op>) {
+    for op in ops.into_iter() {
+        match &op {
+            Op::Upsert(id, vector) => index.upsert(*id, vector),
+            Op::Remove(id) => index.remove(*id),
+        }
+        if let Some(journal) = index.journal.as_mut() {
+            journal.push(op);
+        }
+    }
+}
+
+// Changes are applied in order by one blocking task at a time
+fn apply_pending(shared: Arc<Shared>) {
+    if shared.applying.swap(true, atomic::Ordering::AcqRel) {
+        return;
+    }
+    tokio::task::spawn_blocking(move || {
+        loop {
+            let ops: Vec<Op> = {
+                let mut pending = lock(&shared.pending);
+                let n = pending.len().min(APPLY_BATCH);
+                pending.drain(..n).collect()
+            };
+            if !ops.is_empty() {
+                apply(&mut write(&shared.index), ops);
+                continue;
+            }
+            shared.applying.store(false, atomic::Ordering::Release);
+            // Changes queued before the flag was cleared are picked up here
+            if lock(&shared.pending).is_empty()
+                || shared.applying.swap(true, atomic::Ordering::AcqRel)
+            {
+                return;
+            }
+        }
+    });
+}
+
+fn install(key: &str, generation: u64, mut index: Index) {
+    let mut indexes = lock_indexes();
+    match indexes.remove(key) {
+        Some(Entry::Loading(g, ops)) if g == generation => {
+            apply(&mut index, ops);
+            let shared = Shared {
+                index: RwLock::new(index),
+                pending: Mutex::new(vec![]),
+                applying: AtomicBool::new(false),
+            };
+            indexes.insert(String::from(key), Entry::Ready(Arc::new(shared)));
+        }
+        // Removed or rebuilt meanwhile
+        Some(entry) => {
+            indexes.insert(String::from(key), entry);
+        }
+        None => {}
+    }
+}
+
+fn abandon(key: &str, generation: u64) {
+    let mut indexes = lock_indexes();
+    if let Some(Entry::Loading(g, _)) = indexes.get(key)
+        && *g == generation
+    {
+        indexes.remove(key);
+    }
+}
+
+fn start_loading(
+    indexes: &mut HashMap<String, Entry>,
+    store: &'static Store,
+    db: &'static turso::Database,
+    robot_id: &str,
+    ops: Vec<Op>,
+) {
+    let key = store.key(robot_id);
+    let generation = GENERATION.fetch_add(1, atomic::Ordering::Relaxed);
+    indexes.insert(key.clone(), Entry::Loading(generation, ops));
+    let robot_id = String::from(robot_id);
+    tokio::spawn(async move {
+        match build(store, db, &robot_id, true).await {
+            Ok(index) => install(&key, generation, index),
+            Err(e) => {
+                log::error!("Loading vector index {key} failed {e:?}");
+                abandon(&key, generation);
+            }
+        }
+    });
+}
+
+// Ids of the approximate nearest rows, None means the table should be searched exactly
+pub(crate) fn search(
+    store: &'static Store,
+    db: &'static turso::Database,
+    robot_id: &str,
+    query: &[f32],
+    k: usize,
+) -> Option<Vec<i64>> {
+    let index = {
+        let mut indexes = lock_indexes();
+        match indexes.get(&store.key(robot_id)) {
+            Some(Entry::Ready(shared)) => Arc::clone(shared),
+            Some(Entry::Loading(..)) => return None,
+            None => {
+                start_loading(&mut indexes, store, db, robot_id, vec![]);
+                return None;
+            }
+        }
+    };
+    // Searches run on async workers, so they don't wait for changes being applied
+    let index = match index.index.try_read() {
+        Ok(index) => index,
+        Err(TryLockError::Poisoned(e)) => {
+            log::warn!("{:#?}", &e);
+            e.into_inner()
+        }
+        Err(TryLockError::WouldBlock) => return None,
+    };
+    if index.len() < EXACT_SEARCH_MAX_ROWS || index.dim != query.len() {
+        return None;
+    }
+    let ids = index.search(query, k);
+    if ids.is_empty() { None } else { Some(ids) }
+}
+
+fn change(store: &'static Store, db: &'static turso::Database, robot_id: &str, ops: Vec<Op>) {
+    let shared = {
+        let mut indexes = lock_indexes();
+        match indexes.get_mut(&store.key(robot_id)) {
+            Some(Entry::Ready(shared)) => Arc::clone(shared),
+            Some(Entry::Loading(_, pending)) => {
+                pending.extend(ops);
+                return;
+            }
+            None => {
+                start_loading(&mut indexes, store, db, robot_id, ops);
+                return;
+            }
+        }
+    };
+    lock(&shared.pending).extend(ops);
+    apply_pending(shared);
+}
+
+// Should be called after the rows were committed
+pub(crate) fn upsert(
+    store: &'static Store,
+    db: &'static turso::Database,
+    robot_id: &str,
+    vectors: Vec<(i64, Vec<f32>)>,
+) {
+    if !vectors.is_empty() {
+        let ops = vectors
+            .into_iter()
+            .map(|(id, v)| Op::Upsert(id, v))
+            .collect();
+        change(store, db, robot_id, ops);
+    }
+}
+
+pub(crate) fn remove(
+    store: &'static Store,
+    db: &'static turso::Database,
+    robot_id: &str,
+    ids: &[i64],
+) {
+    if !ids.is_empty() {
+        change(
+            store,
+            db,
+            robot_id,
+            ids.iter().map(|id| Op::Remove(*id)).collect(),
+        );
+    }
+}
+
+// Builds the index from the table again, exact search is used until it's done
+pub(crate) async fn rebuild(
+    store: &'static Store,
+    db: &'static turso::Database,
+    robot_id: &str,
+) -> Result<()> {
+    let key = store.key(robot_id);
+    let generation = GENERATION.fetch_add(1, atomic::Ordering::Relaxed);
+    lock_indexes().insert(key.clone(), Entry::Loading(generation, vec![]));
+    match build(store, db, robot_id, false).await {
+        Ok(index) => {
+            install(&key, generation, index);
+            Ok(())
+        }
+        Err(e) => {
+            abandon(&key, generation);
+            Err(e)
+        }
+    }
+}
+
+pub(crate) fn remove_index(store: &Store, robot_id: &str) -> Result<()> {
+    let key = store.key(robot_id);
+    lock_indexes().remove(&key);
+    let p = path(&key);
+    if p.exists() {
+        std::fs::remove_file(p)?;
+    }
+    Ok(())
+}
+
+// The compacted copy is built without blocking searches and changes, changes made meanwhile
+// are applied to it before it's swapped in
+fn compact(shared: &Shared) {
+    let (dim, live) = {
+        let mut index = write(&shared.index);
+        index.journal = Some(vec![]);
+        let live: Vec<(i64, Vec<f32>)> = index
+            .nodes
+            .iter()
+            .filter(|n| !n.deleted)
+            .map(|n| (n.id, n.vector.clone()))
+            .collect();
+        (index.dim, live)
+    };
+    let mut compacted = Index::new(dim);
+    for (id, vector) in live.iter() {
+        compacted.upsert(*id, vector);
+    }
+    let mut index = write(&shared.index);
+    let journal = index.journal.take().unwrap_or_default();
+    apply(&mut compacted, journal);
+    *compacted.dirty.get_mut() = true;
+    *index = compacted;
+}
+
+// Changed indexes are written to disk, compacting those with too many removed nodes
+pub(crate) fn save_all() {
+    let indexes: Vec<(String, Arc<Shared>)> = lock_indexes()
+        .iter()
+        .filter_map(|(key, entry)| match entry {
+            Entry::Ready(shared) => Some((key.clone(), Arc::clone(shared))),
+            Entry::Loading(..) => None,
+        })
+        .collect();
+    for (key, shared) in indexes.iter() {
+        if read(&shared.index).needs_compaction() {
+            compact(shared);
+        }
+        let index = read(&shared.index);
+        if !index.dirty.load(atomic::Ordering::Acquire) {
+            continue;
+        }
+        if let Err(e) = index.save(&path(key)) {
+            log::error!("Saving vector index {key} failed {e:?}");
+        }
+    }
+}
+
+pub(crate) async fn save_periodically() {
+    let mut interval = tokio::time::interval(Duration::from_secs(SAVE_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        if let Err(e) = tokio::task::spawn_blocking(save_all).await {
+            log::error!("{e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(seed: u64) -> Vec<f32> {
+        let mut x = seed.wrapping_mul(0x9E3779B97F4A7C15) | 1;
+        (0..16)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                (x % 1000) as f32 / 1000. - 0.5
+            })
+            .collect()
+    }
+
+    fn shared(index: Index) -> Shared {
+        Shared {
+            index: RwLock::new(index),
+            pending: Mutex::new(vec![]),
+            applying: AtomicBool::new(false),
+        }
+    }
+
+    #[test]
+    fn finds_nearest() {
+        let mut index = Index::new(16);
+        for id in 0..500 {
+            index.upsert(id, &vector(id as u64));
+        }
+        for id in [0i64, 137, 499] {
+            assert_eq!(index.search(&vector(id as u64), 1), vec![id]);
+        }
+        index.remove(137);
+        assert!(!index.search(&vector(137), 5).contains(&137));
+    }
+
+    #[test]
+    fn reconciles_with_rows() {
+        let mut index = Index::new(16);
+        for id in 0..10 {
+            index.upsert(id, &vector(id as u64));
+        }
+        // Row 3 was removed, row 4 changed and row 10 added after the index was saved
+        let rows: Vec<(i64, Vec<f32>)> = (0..11)
+            .filter(|id| *id != 3)
+            .map(|id| (id, vector(if id == 4 { 100 } else { id as u64 })))
+            .collect();
+        assert_eq!(index.reconcile(&rows), 3);
+        assert_eq!(index.len(), 10);
+        assert_eq!(index.search(&vector(100), 1), vec![4]);
+        assert_eq!(index.reconcile(&rows), 0);
+    }
+
+    #[test]
+    fn compacts_removed_nodes() {
+        let mut index = Index::new(16);
+        for id in 0..100 {
+            index.upsert(id, &vector(id as u64));
+        }
+        for id in 0..50 {
+            index.remove(id);
+        }
+        let shared = shared(index);
+        compact(&shared);
+        let index = read(&shared.index);
+        assert_eq!(index.nodes.len(), 50);
+        assert_eq!(index.search(&vector(70), 1), vec![70]);
+    }
+
+    #[test]
+    fn keeps_journal_when_dimension_changes() {
+        let mut index = Index::new(16);
+        index.upsert(1, &vector(1));
+        index.journal = Some(vec![]);
+        apply(&mut index, vec![Op::Upsert(2, vec![1., 0., 0.])]);
+        assert_eq!(index.dim, 3);
+        assert_eq!(index.journal.as_ref().map(Vec::len), Some(1));
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let mut index = Index::new(16);
+        for id in 0..50 {
+            index.upsert(id, &vector(id as u64));
+        }
+        index.remove(7);
+        let p = std::env::temp_dir().join(format!("ann-test-{}.idx", std::process::id()));
+        index.save(&p).unwrap();
+        let loaded = Index::load(&p).unwrap();
+        std::fs::remove_file(&p).unwrap();
+        assert_eq!(loaded.len(), 49);
+        assert_eq!(loaded.search(&vector(20), 1), vec![20]);
+    }
+}
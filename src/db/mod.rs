@@ -1,5 +1,6 @@
 // pub(crate) mod embedding;
 // pub(crate) mod embedding_sqlite;
+pub(crate) mod ann;
 
 use std::borrow::Borrow;
 use std::sync::LazyLock;
@@ -86,14 +87,21 @@ pub(crate) async fn init() -> Result<GlobalSettings> {
 
     // Settings
     settings::init_table()?;
+    crate::auth::crud::init()?;
+    crate::kb::ingest::init_table()?;
+    crate::kb::crawler::init_table()?;
+    crate::intent::eval::init_table()?;
     mainflow::init_default_names(is_en)?;
     if settings::exists()? {
+        crate::flow::rt::crud::upgrade_runtime_nodes()?;
+        crate::transcript::crud::upgrade_tables()?;
         return Ok(settings::get_global_settings()?.unwrap());
     }
     let settings = settings::init_global()?;
     robot::init(is_en).await?;
     // 流程上下文
     context::init()?;
+    crate::flow::rt::crud::upgrade_runtime_nodes()?;
     Ok(settings)
 }
 
@@ -356,6 +364,11 @@ where
     Ok(())
 }
 
+// Tables of robot created by older versions may be missing until the first write
+pub(crate) fn is_table_not_exists(e: &Error) -> bool {
+    matches!(e, Error::Db(e) if matches!(**e, redb::Error::TableDoesNotExist(_)))
+}
+
 pub(crate) fn delete_table<'a, K, V>(table: redb::TableDefinition<K, V>) -> Result<()>
 where
     K: redb::Key,
@@ -2,10 +2,63 @@ use std::sync::LazyLock;
 
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use time::{Date, Duration, Month, OffsetDateTime, Weekday};
+
+use crate::variable::dto::VariableValue;
 
 static NUMBER_REGEX: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"[1-9]([\d]+)?(.[\d]+)?").unwrap());
 
+// ASCII digits only, the check code is computed on bytes
+static ID_CARD_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(^|[^0-9Xx])([0-9]{17}[0-9Xx])($|[^0-9Xx])").unwrap());
+
+static PHONE_NUMBER_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\+?\(?\d[\d\-\s()]{5,18}\d").unwrap());
+
+static EMAIL_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"[A-Za-z0-9._%+\-]+@[A-Za-z0-9\-]+(\.[A-Za-z0-9\-]+)*\.[A-Za-z]{2,}").unwrap()
+});
+
+static DATE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(\d{4})\s*[\-/.年]\s*(\d{1,2})\s*[\-/.月]\s*(\d{1,2})\s*[日号]?").unwrap()
+});
+
+static MONTH_DAY_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(\d{1,2})\s*月\s*(\d{1,2})\s*[日号]").unwrap());
+
+static TIME_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)(\d{1,2})\s*(?::\s*(\d{2})|点\s*(?:(\d{1,2})\s*分?|(半))?)\s*(am|pm)?").unwrap()
+});
+
+static IN_DAYS_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)(?:in\s+(\d{1,3})\s+days?|(\d{1,3})\s*天[以之]?后)").unwrap()
+});
+
+static NEXT_WEEKDAY_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)(?:next\s+(monday|tuesday|wednesday|thursday|friday|saturday|sunday)|下(?:个)?(?:周|星期|礼拜)([一二三四五六日天]))",
+    )
+    .unwrap()
+});
+
+// Letter units must end the word, so that "3 widgets" or "5 kg" keep their numbers
+static CURRENCY_AMOUNT_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)(\d{1,3}(?:,\d{3})+|\d+)(\.\d+)?\s*(?:(k|w)(?-u:\b)|(万|千))?").unwrap()
+});
+
+const ID_CARD_WEIGHTS: [u32; 17] = [7, 9, 10, 5, 8, 4, 2, 1, 6, 3, 7, 9, 10, 5, 8, 4, 2];
+const ID_CARD_CHECK_CODES: [u8; 11] = *b"10X98765432";
+
+const YES_WORDS: &[&str] = &[
+    "yes", "yeah", "yep", "sure", "ok", "okay", "correct", "right", "agree", "是", "对", "好",
+    "可以", "行", "嗯", "确认",
+];
+const NO_WORDS: &[&str] = &[
+    "no", "nope", "nah", "not", "don't", "cancel", "wrong", "不是", "不对", "不要", "不行",
+    "不好", "不用", "否", "没有",
+];
+
 #[derive(Clone, Deserialize, Serialize, rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
 #[rkyv(compare(PartialEq))]
 pub(crate) enum CollectType {
@@ -13,27 +66,366 @@ pub(crate) enum CollectType {
     Number,
     IdCard,
     CustomizeRegex(String),
+    PhoneNumber,
+    Email,
+    DateTime,
+    CurrencyAmount,
+    YesNo,
+    Choice(Vec<String>),
 }
 
-pub(crate) fn collect<'a>(s: &'a str, collect_type: &CollectType) -> Option<&'a str> {
+pub(crate) fn collect(s: &str, collect_type: &CollectType) -> Option<VariableValue> {
     match collect_type {
-        CollectType::UserInput => Some(s),
+        CollectType::UserInput => Some(VariableValue::Str(String::from(s))),
         CollectType::Number => {
             if let Some(cap) = NUMBER_REGEX.captures(s) {
                 if let Some(m) = cap.get(0) {
-                    return Some(&s[m.start()..m.end()]);
+                    return match m.as_str().parse::<f64>() {
+                        Ok(n) => Some(VariableValue::Num(n)),
+                        Err(_) => Some(VariableValue::Str(String::from(m.as_str()))),
+                    };
                 }
             }
             None
         }
-        CollectType::IdCard => todo!(),
+        CollectType::IdCard => collect_id_card(s).map(VariableValue::Str),
         CollectType::CustomizeRegex(regex) => {
             if let Ok(re) = Regex::new(regex) {
                 if let Some(m) = re.find(s) {
-                    return Some(&s[m.start()..m.end()]);
+                    return Some(VariableValue::Str(String::from(m.as_str())));
                 }
             }
             None
         }
+        CollectType::PhoneNumber => collect_phone_number(s).map(VariableValue::Str),
+        CollectType::Email => EMAIL_REGEX
+            .find(s)
+            .map(|m| VariableValue::Str(m.as_str().to_lowercase())),
+        CollectType::DateTime => collect_date_time(s, OffsetDateTime::now_utc().date())
+            .map(VariableValue::Str),
+        CollectType::CurrencyAmount => collect_currency_amount(s).map(VariableValue::Num),
+        CollectType::YesNo => collect_yes_no(s).map(|b| {
+            let v = if b { "yes" } else { "no" };
+            VariableValue::Str(String::from(v))
+        }),
+        CollectType::Choice(choices) => collect_choice(s, choices).map(VariableValue::Str),
+    }
+}
+
+// GB 11643-1999 resident identity card number, the last one is the ISO 7064 MOD 11-2 check code
+fn collect_id_card(s: &str) -> Option<String> {
+    for cap in ID_CARD_REGEX.captures_iter(s) {
+        let id = cap.get(2).unwrap().as_str().to_uppercase();
+        let bytes = id.as_bytes();
+        let mut sum = 0u32;
+        for (i, w) in ID_CARD_WEIGHTS.iter().enumerate() {
+            sum += (bytes[i] - b'0') as u32 * w;
+        }
+        if ID_CARD_CHECK_CODES[(sum % 11) as usize] != bytes[17] {
+            continue;
+        }
+        let year: i32 = id[6..10].parse().ok()?;
+        let month: u8 = id[10..12].parse().ok()?;
+        let day: u8 = id[12..14].parse().ok()?;
+        let birthday = Month::try_from(month)
+            .ok()
+            .and_then(|m| Date::from_calendar_date(year, m, day).ok());
+        if let Some(d) = birthday
+            && year >= 1900
+            && d <= OffsetDateTime::now_utc().date()
+        {
+            return Some(id);
+        }
+    }
+    None
+}
+
+fn collect_phone_number(s: &str) -> Option<String> {
+    // Dates such as 2024-01-05 have as many digits as a phone number
+    let rest = DATE_REGEX.replace_all(s, " ");
+    for m in PHONE_NUMBER_REGEX.find_iter(&rest) {
+        let mut phone = String::with_capacity(m.len());
+        if m.as_str().starts_with('+') {
+            phone.push('+');
+        }
+        m.as_str()
+            .chars()
+            .filter(|c| c.is_ascii_digit())
+            .for_each(|c| phone.push(c));
+        let digits = phone.trim_start_matches('+').len();
+        if (7..=15).contains(&digits) {
+            return Some(phone);
+        }
+    }
+    None
+}
+
+fn chinese_weekday(c: &str) -> Option<Weekday> {
+    match c {
+        "一" => Some(Weekday::Monday),
+        "二" => Some(Weekday::Tuesday),
+        "三" => Some(Weekday::Wednesday),
+        "四" => Some(Weekday::Thursday),
+        "五" => Some(Weekday::Friday),
+        "六" => Some(Weekday::Saturday),
+        "日" | "天" => Some(Weekday::Sunday),
+        _ => None,
+    }
+}
+
+fn english_weekday(s: &str) -> Option<Weekday> {
+    match s.to_lowercase().as_str() {
+        "monday" => Some(Weekday::Monday),
+        "tuesday" => Some(Weekday::Tuesday),
+        "wednesday" => Some(Weekday::Wednesday),
+        "thursday" => Some(Weekday::Thursday),
+        "friday" => Some(Weekday::Friday),
+        "saturday" => Some(Weekday::Saturday),
+        "sunday" => Some(Weekday::Sunday),
+        _ => None,
+    }
+}
+
+fn relative_date(s: &str, today: Date) -> Option<Date> {
+    let lower = s.to_lowercase();
+    // Longer phrases must be checked before the shorter ones they contain
+    let days = if lower.contains("day after tomorrow") || s.contains("后天") {
+        Some(2)
+    } else if lower.contains("day before yesterday") || s.contains("前天") {
+        Some(-2)
+    } else if lower.contains("tomorrow") || s.contains("明天") || s.contains("明日") {
+        Some(1)
+    } else if lower.contains("yesterday") || s.contains("昨天") || s.contains("昨日") {
+        Some(-1)
+    } else if lower.contains("today") || s.contains("今天") || s.contains("今日") {
+        Some(0)
+    } else if let Some(cap) = IN_DAYS_REGEX.captures(s) {
+        cap.get(1)
+            .or(cap.get(2))
+            .and_then(|m| m.as_str().parse::<i64>().ok())
+    } else {
+        None
+    };
+    if let Some(d) = days {
+        return today.checked_add(Duration::days(d));
+    }
+    if let Some(cap) = NEXT_WEEKDAY_REGEX.captures(s) {
+        let weekday = if let Some(m) = cap.get(1) {
+            english_weekday(m.as_str())
+        } else {
+            cap.get(2).and_then(|m| chinese_weekday(m.as_str()))
+        }?;
+        return Some(today.next_occurrence(weekday));
+    }
+    None
+}
+
+fn collect_date_time(s: &str, today: Date) -> Option<String> {
+    let date = if let Some(cap) = DATE_REGEX.captures(s) {
+        let year: i32 = cap[1].parse().ok()?;
+        let month = Month::try_from(cap[2].parse::<u8>().ok()?).ok()?;
+        Date::from_calendar_date(year, month, cap[3].parse().ok()?).ok()
+    } else if let Some(cap) = MONTH_DAY_REGEX.captures(s) {
+        let month = Month::try_from(cap[1].parse::<u8>().ok()?).ok()?;
+        Date::from_calendar_date(today.year(), month, cap[2].parse().ok()?).ok()
+    } else {
+        relative_date(s, today)
+    };
+    // Strips the date part, so that its digits won't be treated as a time
+    let rest = DATE_REGEX.replace_all(s, "");
+    let time = TIME_REGEX.captures(&rest).and_then(|cap| {
+        let mut hour: u8 = cap[1].parse().ok()?;
+        let minute: u8 = if let Some(m) = cap.get(2).or(cap.get(3)) {
+            m.as_str().parse().ok()?
+        } else if cap.get(4).is_some() {
+            30
+        } else {
+            0
+        };
+        let pm = cap.get(5).map(|m| m.as_str().eq_ignore_ascii_case("pm"));
+        if pm == Some(true) && hour < 12 {
+            hour += 12;
+        } else if pm == Some(false) && hour == 12 {
+            hour = 0;
+        } else if pm.is_none() && hour < 12 && (rest.contains("下午") || rest.contains("晚上"))
+        {
+            hour += 12;
+        }
+        if hour < 24 && minute < 60 {
+            Some((hour, minute))
+        } else {
+            None
+        }
+    });
+    match (date, time) {
+        (Some(d), Some((h, m))) => Some(format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}",
+            d.year(),
+            d.month() as u8,
+            d.day(),
+            h,
+            m
+        )),
+        (Some(d), None) => Some(format!(
+            "{:04}-{:02}-{:02}",
+            d.year(),
+            d.month() as u8,
+            d.day()
+        )),
+        (None, Some((h, m))) => Some(format!("{h:02}:{m:02}")),
+        (None, None) => None,
+    }
+}
+
+fn collect_currency_amount(s: &str) -> Option<f64> {
+    let cap = CURRENCY_AMOUNT_REGEX.captures(s)?;
+    let mut n = String::with_capacity(16);
+    n.push_str(&cap[1].replace(',', ""));
+    if let Some(m) = cap.get(2) {
+        n.push_str(m.as_str());
+    }
+    let amount: f64 = n.parse().ok()?;
+    let multiple = match cap.get(3).or(cap.get(4)).map(|m| m.as_str().to_lowercase()) {
+        Some(u) if u.eq("k") || u.eq("千") => 1000f64,
+        Some(u) if u.eq("w") || u.eq("万") => 10000f64,
+        _ => 1f64,
+    };
+    Some(amount * multiple)
+}
+
+fn collect_yes_no(s: &str) -> Option<bool> {
+    let lower = s.trim().to_lowercase();
+    let words: Vec<&str> = lower
+        .split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .filter(|w| !w.is_empty())
+        .collect();
+    let matched = |candidates: &[&str]| {
+        candidates.iter().any(|c| {
+            if c.is_ascii() {
+                words.contains(c)
+            } else {
+                lower.contains(c)
+            }
+        })
+    };
+    // A Chinese positive word after 不 or 没 is negated, such as 不可以 or 没确认
+    let negated = YES_WORDS
+        .iter()
+        .filter(|c| !c.is_ascii())
+        .any(|c| lower.contains(&format!("不{c}")) || lower.contains(&format!("没{c}")));
+    // Negative words come first, because most of the Chinese ones contain a positive word
+    if negated || matched(NO_WORDS) {
+        Some(false)
+    } else if matched(YES_WORDS) {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+fn collect_choice(s: &str, choices: &[String]) -> Option<String> {
+    let input = s.trim();
+    if let Ok(idx) = input.parse::<usize>()
+        && idx > 0
+        && idx <= choices.len()
+    {
+        return Some(choices[idx - 1].clone());
+    }
+    if let Some(c) = choices.iter().find(|c| unicase::eq(c.as_str(), input)) {
+        return Some(c.clone());
+    }
+    let lower = input.to_lowercase();
+    choices
+        .iter()
+        .filter(|c| !c.is_empty() && lower.contains(&c.to_lowercase()))
+        .max_by_key(|c| c.len())
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yes_no_checks_negations_first() {
+        assert_eq!(collect_yes_no("可以"), Some(true));
+        assert_eq!(collect_yes_no("不可以"), Some(false));
+        assert_eq!(collect_yes_no("不是的"), Some(false));
+        assert_eq!(collect_yes_no("Yes, please"), Some(true));
+        assert_eq!(collect_yes_no("I don't"), Some(false));
+        assert_eq!(collect_yes_no("maybe later"), None);
+    }
+
+    #[test]
+    fn currency_units_end_the_word() {
+        assert_eq!(collect_currency_amount("3 widgets"), Some(3f64));
+        assert_eq!(collect_currency_amount("5 kg"), Some(5f64));
+        assert_eq!(collect_currency_amount("budget 5k"), Some(5000f64));
+        assert_eq!(collect_currency_amount("1.5w元"), Some(15000f64));
+        assert_eq!(collect_currency_amount("3千块"), Some(3000f64));
+        assert_eq!(collect_currency_amount("1,299.50 dollars"), Some(1299.5f64));
+        assert_eq!(collect_currency_amount("none"), None);
+    }
+
+    #[test]
+    fn phone_numbers_skip_dates() {
+        assert_eq!(collect_phone_number("2024-01-05"), None);
+        assert_eq!(
+            collect_phone_number("call me on 2024-01-05 at 138-1234-5678"),
+            Some(String::from("13812345678"))
+        );
+        assert_eq!(
+            collect_phone_number("+86 138 1234 5678"),
+            Some(String::from("+8613812345678"))
+        );
+        assert_eq!(collect_phone_number("12345"), None);
+    }
+
+    #[test]
+    fn dates_and_times() {
+        let today = Date::from_calendar_date(2024, Month::January, 5).unwrap();
+        assert_eq!(
+            collect_date_time("2024年2月3日下午3点半", today),
+            Some(String::from("2024-02-03 15:30"))
+        );
+        assert_eq!(
+            collect_date_time("tomorrow 9:15 am", today),
+            Some(String::from("2024-01-06 09:15"))
+        );
+        assert_eq!(
+            collect_date_time("next monday", today),
+            Some(String::from("2024-01-08"))
+        );
+        assert_eq!(collect_date_time("whenever", today), None);
+    }
+
+    #[test]
+    fn id_card_check_code() {
+        assert_eq!(
+            collect_id_card("11010519491231002X"),
+            Some(String::from("11010519491231002X"))
+        );
+        assert_eq!(collect_id_card("110105194912310021"), None);
+        assert_eq!(collect_id_card("１１０１０５１９４９１２３１００２X"), None);
+        assert_eq!(collect_id_card("١١٠١٠٥١٩٤٩١٢٣١٠٠٢X"), None);
+        assert_eq!(
+            collect_id_card("号码１11010519491231002X"),
+            Some(String::from("11010519491231002X"))
+        );
+    }
+
+    #[test]
+    fn choices_by_index_or_name() {
+        let choices = vec![String::from("Red"), String::from("Dark red")];
+        assert_eq!(
+            collect_choice("2", &choices),
+            Some(String::from("Dark red"))
+        );
+        assert_eq!(collect_choice("red", &choices), Some(String::from("Red")));
+        assert_eq!(
+            collect_choice("I like dark red", &choices),
+            Some(String::from("Dark red"))
+        );
+        assert_eq!(collect_choice("blue", &choices), None);
     }
 }
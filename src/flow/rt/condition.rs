@@ -6,6 +6,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::flow::rt::context::Context;
 use crate::flow::rt::dto::{Request, UserInputResult};
+use crate::flow::rt::javascript::{self, ScriptInput};
 use crate::variable::crud as variable;
 use crate::variable::dto::VariableType;
 
@@ -19,6 +20,10 @@ pub(crate) enum ConditionType {
     FlowVariable,
     CustomJavascript,
     CustomRegex,
+    // Score of the detected intent, compared with target data as a number
+    UserIntentConfidence,
+    // Top two intents are too close, optionally the intents named by ref data and target data
+    UserIntentAmbiguous,
 }
 
 #[derive(
@@ -390,13 +395,66 @@ impl ConditionData {
                 // }
                 _ => false,
             },
-            ConditionType::CustomJavascript => todo!(),
+            ConditionType::CustomJavascript => {
+                let script = self.get_target_data(req, ctx).await;
+                let input = ScriptInput {
+                    vars: &ctx.vars,
+                    user_input: &req.user_input,
+                    intent: req.user_input_intent.as_deref(),
+                    response: None,
+                };
+                match javascript::eval_condition(&script, &input).await {
+                    Ok(r) => r,
+                    Err(e) => {
+                        log::warn!("{:?}", &e);
+                        false
+                    }
+                }
+            }
             ConditionType::CustomRegex => {
                 if let Ok(re) = Regex::new(&self.get_target_data(req, ctx).await) {
                     return re.is_match(&req.user_input);
                 }
                 false
             }
+            ConditionType::UserIntentConfidence => {
+                let confidence = match &req.intent_detection {
+                    Some(d) => d.confidence,
+                    // Intent was sent by the client
+                    None if req.user_input_intent.is_some() => 1.,
+                    None => 0.,
+                };
+                let target = match self.get_target_data(req, ctx).await.trim().parse::<f64>() {
+                    Ok(n) => n,
+                    Err(e) => {
+                        log::warn!("{:?}", &e);
+                        return false;
+                    }
+                };
+                match self.compare_type {
+                    CompareType::NGT => confidence > target,
+                    CompareType::NGTE => confidence >= target,
+                    CompareType::NLT => confidence < target,
+                    CompareType::NLTE => confidence <= target,
+                    _ => false,
+                }
+            }
+            ConditionType::UserIntentAmbiguous => {
+                let Some(d) = req.intent_detection.as_ref().filter(|d| d.ambiguous) else {
+                    return false;
+                };
+                let target = self.get_target_data(req, ctx).await;
+                let top: Vec<&str> = d
+                    .candidates
+                    .iter()
+                    .take(2)
+                    .map(|c| c.intent_name.as_str())
+                    .collect();
+                [self.ref_data.as_str(), target.as_str()]
+                    .iter()
+                    .filter(|name| !name.is_empty())
+                    .all(|name| top.contains(name))
+            }
         }
     }
 }
@@ -8,7 +8,7 @@ use std::vec::Vec;
 use serde::{Deserialize, Serialize};
 use tokio::time::{Duration, interval};
 
-use super::node::RuntimeNodeEnum;
+use super::node::{RuntimeNodeEnum, SubFlowCallParam};
 use crate::ai::completion::Prompt;
 use crate::db;
 use crate::man::settings;
@@ -24,11 +24,18 @@ pub(crate) const CONTEXT_KEY: &str = "contexts";
 //     session_id: String,
 // }
 
+#[derive(Deserialize, Serialize)]
+pub(crate) struct CallFrame {
+    pub(in crate::flow::rt) main_flow_id: String,
+    pub(in crate::flow::rt) return_node_id: String,
+    pub(in crate::flow::rt) output_params: Vec<SubFlowCallParam>,
+}
+
 #[derive(Deserialize, Serialize)]
 pub(crate) struct Context {
     robot_id: String,
     pub(in crate::flow::rt) main_flow_id: String,
-    session_id: String,
+    pub(in crate::flow::rt) session_id: String,
     pub(in crate::flow::rt) node: Option<Vec<u8>>,
     pub(in crate::flow::rt) nodes: LinkedList<String>,
     pub(crate) vars: HashMap<String, VariableValue>,
@@ -38,6 +45,15 @@ pub(crate) struct Context {
     pub(crate) none_persistent_data: HashMap<String, String>,
     last_active_time: u64,
     pub(crate) chat_history: Vec<Prompt>,
+    #[serde(default)]
+    pub(in crate::flow::rt) call_stack: Vec<CallFrame>,
+    #[serde(skip)]
+    pub(in crate::flow::rt) visited_nodes: Vec<String>,
+    // Last node run by the previous request, which intent scopes refer to
+    #[serde(default)]
+    pub(in crate::flow::rt) last_node_id: String,
+    #[serde(skip)]
+    pub(in crate::flow::rt) kb_no_recall: Option<bool>,
 }
 
 impl Context {
@@ -84,6 +100,10 @@ impl Context {
                 .unwrap()
                 .as_secs(),
             chat_history: Vec::with_capacity(16),
+            call_stack: Vec::new(),
+            visited_nodes: Vec::new(),
+            last_node_id: String::new(),
+            kb_no_recall: None,
         }
     }
 
@@ -129,6 +149,7 @@ impl Context {
         }
         if let Some(node_id) = self.nodes.pop_front() {
             // log::info!("main_flow_id {} node_id {}", &self.main_flow_id, &node_id);
+            self.visited_nodes.push(node_id.clone());
             if let Ok(r) = super::crud::get_runtime_node(&self.main_flow_id, &node_id) {
                 // log::info!("pop_node time {:?}", now.elapsed());
                 return r;
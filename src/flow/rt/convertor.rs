@@ -4,8 +4,8 @@ use std::vec::Vec;
 use super::condition::ConditionData;
 use super::node::{
     CollectNode, ConditionNode, ExternalHttpCallNode, GotoAnotherNode, GotoMainFlowNode,
-    KnowledgeBaseAnswerNode, LlmChatNode, LlmGenTextNode, RuntimeNodeEnum, SendEmailNode,
-    TerminateNode, TextNode,
+    KnowledgeBaseAnswerNode, LlmChatNode, LlmGenTextNode, ReturnNode, RuntimeNodeEnum,
+    SendEmailNode, SetVariableNode, SubFlowCallNode, TerminateNode, TextNode,
 };
 use crate::db;
 use crate::db_executor;
@@ -25,6 +25,7 @@ pub(crate) fn convert_flow(is_en: bool, robot_id: &str, mainflow_id: &str) -> Re
         }
         r.unwrap()
     };
+    let subflow_ids: Vec<&str> = flows.iter().map(|f| f.id.as_str()).collect();
     // let mut idx = 0;
     for (idx, f) in flows.iter().enumerate() {
         // if !f.valid {
@@ -33,7 +34,7 @@ pub(crate) fn convert_flow(is_en: bool, robot_id: &str, mainflow_id: &str) -> Re
         //         f.name
         //     )));
         // }
-        convert_subflow(mainflow_id, idx, f)?;
+        convert_subflow(mainflow_id, idx, f, &subflow_ids)?;
         // idx += 1;
     }
     Ok(())
@@ -79,6 +80,9 @@ fn check_first_node(
                     Node::SendEmailNode(n) => n.node_id = String::from(first_node_id),
                     Node::EndNode(n) => n.node_id = String::from(first_node_id),
                     Node::KnowledgeBaseAnswerNode(n) => n.node_id = String::from(first_node_id),
+                    Node::SubFlowCallNode(n) => n.node_id = String::from(first_node_id),
+                    Node::ReturnNode(n) => n.node_id = String::from(first_node_id),
+                    Node::SetVariableNode(n) => n.node_id = String::from(first_node_id),
                 };
             }
         }
@@ -91,7 +95,36 @@ fn check_first_node(
     }
 }
 
-fn convert_subflow(mainflow_id: &str, flow_idx: usize, f: &SubFlowDetail) -> Result<()> {
+fn check_call_targets(
+    mainflow_id: &str,
+    subflow_ids: &[&str],
+    f: &SubFlowDetail,
+    nodes: &mut Vec<&mut Node>,
+) -> Result<()> {
+    for node in nodes.iter_mut() {
+        if let Node::SubFlowCallNode(n) = node {
+            match subflow_ids.iter().position(|id| n.call_subflow_id.eq(id)) {
+                // The first node of the first sub-flow was renamed to main flow id
+                Some(0) => n.call_subflow_id = String::from(mainflow_id),
+                Some(_) => {}
+                None => {
+                    return Err(Error::WithMessage(format!(
+                        "Sub flow: {} node: {} call target: {} not found",
+                        f.name, n.node_name, n.call_subflow_name
+                    )));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn convert_subflow(
+    mainflow_id: &str,
+    flow_idx: usize,
+    f: &SubFlowDetail,
+    subflow_ids: &[&str],
+) -> Result<()> {
     // println!("{}", &f.nodes);
     let mut cells: CanvasCells = serde_json::from_str(&f.canvas)?;
     let mut branches_link: HashMap<String, String> = HashMap::with_capacity(32);
@@ -145,13 +178,14 @@ fn convert_subflow(mainflow_id: &str, flow_idx: usize, f: &SubFlowDetail) -> Res
     // let mut nodes: Vec<Node> = serde_json::from_str(&f.nodes)?;
     validate_nodes(f, &nodes)?;
     check_first_node(mainflow_id, flow_idx, f, &mut nodes)?;
+    check_call_targets(mainflow_id, subflow_ids, f, &mut nodes)?;
     for node in nodes {
-        convert_node(mainflow_id, node)?;
+        convert_node(mainflow_id, &f.id, node)?;
     }
     Ok(())
 }
 
-fn convert_node(main_flow_id: &str, node: &mut Node) -> Result<()> {
+fn convert_node(main_flow_id: &str, subflow_id: &str, node: &mut Node) -> Result<()> {
     let mut nodes: Vec<(String, rkyv::util::AlignedVec)> = Vec::with_capacity(32);
     match node {
         Node::DialogNode(n) => {
@@ -160,6 +194,7 @@ fn convert_node(main_flow_id: &str, node: &mut Node) -> Result<()> {
                     let node = TextNode {
                         text: std::mem::take(&mut n.dialog_text),
                         text_type: n.dialog_text_type.clone(),
+                        payload: n.dialog_payload.take(),
                         ret: NextActionType::WaitUserResponse == n.next_step,
                         next_node_id: n.branches[0].target_node_id.clone(),
                     };
@@ -171,6 +206,11 @@ fn convert_node(main_flow_id: &str, node: &mut Node) -> Result<()> {
                     nodes.push((n.node_id.clone(), bytes));
                 }
                 crate::flow::subflow::dto::DialogTextSource::LlmGenText => {
+                    let ret = NextActionType::WaitUserResponse == n.next_step;
+                    let next_node_id = n.branches[0].target_node_id.clone();
+                    // Payload goes to an appended text node, which is shown after generated text
+                    let payload_node_id = format!("{}-2", &n.node_id);
+                    let payload = n.dialog_payload.take();
                     let node = LlmGenTextNode {
                         prompt: std::mem::take(&mut n.dialog_llm_gen_prompt),
                         fallback_text: std::mem::take(&mut n.dialog_fallback_text),
@@ -178,12 +218,28 @@ fn convert_node(main_flow_id: &str, node: &mut Node) -> Result<()> {
                         connect_timeout: n.connect_timeout,
                         read_timeout: n.read_timeout,
                         response_streaming: n.response_streaming,
-                        ret: NextActionType::WaitUserResponse == n.next_step,
-                        next_node_id: n.branches[0].target_node_id.clone(),
+                        ret: ret && payload.is_none(),
+                        next_node_id: if payload.is_some() {
+                            payload_node_id.clone()
+                        } else {
+                            next_node_id.clone()
+                        },
                     };
                     let r = RuntimeNodeEnum::LlmGenTextNode(node);
                     let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&r).unwrap();
                     nodes.push((n.node_id.clone(), bytes));
+                    if payload.is_some() {
+                        let node = TextNode {
+                            text: String::new(),
+                            text_type: super::dto::AnswerContentType::TextPlain,
+                            payload,
+                            ret,
+                            next_node_id,
+                        };
+                        let r = RuntimeNodeEnum::TextNode(node);
+                        let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&r).unwrap();
+                        nodes.push((payload_node_id, bytes));
+                    }
                 }
             }
         }
@@ -365,6 +421,7 @@ fn convert_node(main_flow_id: &str, node: &mut Node) -> Result<()> {
                 let node = TextNode {
                     text: n.ending_text.clone(),
                     text_type: super::dto::AnswerContentType::TextPlain,
+                    payload: None,
                     ret: false,
                     next_node_id: end_node_id.clone(),
                 };
@@ -379,6 +436,10 @@ fn convert_node(main_flow_id: &str, node: &mut Node) -> Result<()> {
                 recall_distance: 1f64 - n.recall_thresholds as f64 / 100f64,
                 retrieve_answer_sources: n.retrieve_answer_sources.clone(),
                 no_recall_then: n.no_answer_then.clone(),
+                top_k: if n.top_k == 0 { 3 } else { n.top_k },
+                answer_mode: n.answer_mode.clone(),
+                context_len: n.context_length,
+                response_streaming: n.response_streaming,
                 next_node_id: n.branches[0].target_node_id.clone(),
             };
             let r = RuntimeNodeEnum::KnowledgeBaseAnswerNode(node);
@@ -386,6 +447,31 @@ fn convert_node(main_flow_id: &str, node: &mut Node) -> Result<()> {
             // bytes.push(RuntimeNodeTypeId::CollectNode as u8);
             nodes.push((n.node_id.clone(), bytes));
         }
+        Node::SubFlowCallNode(n) => {
+            let node = SubFlowCallNode {
+                subflow_node_id: std::mem::take(&mut n.call_subflow_id),
+                input_params: std::mem::take(&mut n.input_params),
+                output_params: std::mem::take(&mut n.output_params),
+                return_node_id: std::mem::take(&mut n.branches[0].target_node_id),
+            };
+            let r = RuntimeNodeEnum::SubFlowCallNode(node);
+            let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&r).unwrap();
+            nodes.push((n.node_id.clone(), bytes));
+        }
+        Node::ReturnNode(n) => {
+            let r = RuntimeNodeEnum::ReturnNode(ReturnNode {});
+            let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&r).unwrap();
+            nodes.push((n.node_id.clone(), bytes));
+        }
+        Node::SetVariableNode(n) => {
+            let node = SetVariableNode {
+                assignments: std::mem::take(&mut n.assignments),
+                next_node_id: std::mem::take(&mut n.branches[0].target_node_id),
+            };
+            let r = RuntimeNodeEnum::SetVariableNode(node);
+            let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&r).unwrap();
+            nodes.push((n.node_id.clone(), bytes));
+        }
     };
     // let mut nodes: Vec<(&str, &[u8])> = Vec::with_capacity(box_nodes.len());
     // for n in box_nodes.iter() {
@@ -393,7 +479,7 @@ fn convert_node(main_flow_id: &str, node: &mut Node) -> Result<()> {
     // println!("saved {}", &n.0);
     // }
 
-    super::crud::save_runtime_nodes(main_flow_id, nodes)
+    super::crud::save_runtime_nodes(main_flow_id, subflow_id, nodes)
 }
 
 /*
@@ -1,12 +1,26 @@
 use redb::{ReadableDatabase, ReadableTable, TableDefinition};
 
 use crate::db;
+use crate::db_executor;
+use crate::flow::mainflow::dto::MainFlowDetail;
 use crate::result::Result;
 
+// Holds the layout of all released nodes under LAYOUT_KEY and the layout of each released
+// main flow under its id
+const LAYOUT_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("runtimeNodeLayout");
+const LAYOUT_KEY: &str = "version";
+// Bump it whenever the archived layout of RuntimeNodeEnum changes
+const LAYOUT_VERSION: u32 = 2;
+
 fn get_table_name(main_flow_id: &str) -> String {
     format!("RTN{main_flow_id}")
 }
 
+// Sub-flow id of each runtime node
+fn get_subflow_table_name(main_flow_id: &str) -> String {
+    format!("RTS{main_flow_id}")
+}
+
 pub(crate) fn get_runtime_node(
     main_flow_id: &str,
     key: &str,
@@ -24,19 +38,119 @@ pub(crate) fn get_runtime_node(
     Ok(None)
 }
 
+fn has_runtime_nodes(main_flow_id: &str) -> Result<bool> {
+    let table_name = get_table_name(main_flow_id);
+    let table: TableDefinition<&str, &[u8]> = TableDefinition::new(&table_name);
+    let read_txn = db::DB.begin_read()?;
+    match read_txn.open_table(table) {
+        Ok(_) => Ok(true),
+        Err(redb::TableError::TableDoesNotExist(_)) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Nodes released by an older version can not be read any more, so they are converted to the
+// current layout. Drafts are not released again, they may hold edits which are not released yet
+pub(crate) fn upgrade_runtime_nodes() -> Result<()> {
+    db::init_table(LAYOUT_TABLE)?;
+    let version: Option<u32> = db::query(LAYOUT_TABLE, LAYOUT_KEY)?;
+    if version == Some(LAYOUT_VERSION) {
+        return Ok(());
+    }
+    for robot in crate::robot::crud::get_all()? {
+        let main_flows: Vec<MainFlowDetail> = db_executor!(
+            db::get_all,
+            &robot.robot_id,
+            crate::flow::mainflow::crud::TABLE_SUFFIX,
+        )?;
+        for f in main_flows.iter() {
+            if has_runtime_nodes(&f.id)? {
+                log::info!("Converting released main flow {}", &f.id);
+                convert_runtime_nodes(&f.id)?;
+            }
+        }
+    }
+    db::write(LAYOUT_TABLE, LAYOUT_KEY, &LAYOUT_VERSION)
+}
+
+fn convert_runtime_nodes(main_flow_id: &str) -> Result<()> {
+    let table_name = get_table_name(main_flow_id);
+    let table: TableDefinition<&str, &[u8]> = TableDefinition::new(&table_name);
+    let write_txn = db::DB.begin_write()?;
+    {
+        let mut layout_table = write_txn.open_table(LAYOUT_TABLE)?;
+        // Flows released before layouts were recorded have the first layout
+        let version: u32 = match layout_table.get(main_flow_id)? {
+            Some(v) => serde_json::from_slice(v.value())?,
+            None => 1,
+        };
+        if version == LAYOUT_VERSION {
+            return Ok(());
+        }
+        let mut table = write_txn.open_table(table)?;
+        let mut nodes: Vec<(String, rkyv::util::AlignedVec)> = Vec::new();
+        for r in table.iter()? {
+            let (k, v) = r?;
+            match crate::flow::rt::node::upgrade_node(v.value()) {
+                Ok(bytes) => nodes.push((String::from(k.value()), bytes)),
+                // Kept as it is, the flow works again once it is released
+                Err(e) => log::warn!(
+                    "Node {} of main flow {} can not be converted, release the flow again, err: {:?}",
+                    k.value(),
+                    main_flow_id,
+                    &e
+                ),
+            }
+        }
+        for (k, bytes) in nodes.iter() {
+            table.insert(k.as_str(), bytes.as_slice())?;
+        }
+        layout_table.insert(
+            main_flow_id,
+            serde_json::to_vec(&LAYOUT_VERSION)?.as_slice(),
+        )?;
+    }
+    write_txn.commit()?;
+    Ok(())
+}
+
+pub(crate) fn get_runtime_node_subflow(main_flow_id: &str, key: &str) -> Result<Option<String>> {
+    let table_name = get_subflow_table_name(main_flow_id);
+    let table: TableDefinition<&str, &str> = TableDefinition::new(&table_name);
+    let read_txn = db::DB.begin_read()?;
+    // Flows released before sub-flows were recorded
+    let table = match read_txn.open_table(table) {
+        Ok(t) => t,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let record = table.get(key)?;
+    Ok(record.map(|r| String::from(r.value())))
+}
+
 pub(crate) fn save_runtime_nodes(
     main_flow_id: &str,
+    subflow_id: &str,
     nodes: Vec<(String, rkyv::util::AlignedVec)>,
 ) -> Result<()> {
     let table_name = get_table_name(main_flow_id);
     let table: TableDefinition<&str, &[u8]> = TableDefinition::new(&table_name);
+    let subflow_table_name = get_subflow_table_name(main_flow_id);
+    let subflow_table: TableDefinition<&str, &str> = TableDefinition::new(&subflow_table_name);
     let write_txn = db::DB.begin_write()?;
     // println!("save_runtime_nodes {}", main_flow_id);
     {
         let mut table = write_txn.open_table(table)?;
+        let mut subflow_table = write_txn.open_table(subflow_table)?;
         for j in nodes.iter() {
             table.insert(j.0.as_str(), j.1.as_slice())?;
+            subflow_table.insert(j.0.as_str(), subflow_id)?;
         }
+        let mut layout_table = write_txn.open_table(LAYOUT_TABLE)?;
+        layout_table.insert(
+            main_flow_id,
+            serde_json::to_vec(&LAYOUT_VERSION)?.as_slice(),
+        )?;
     }
     write_txn.commit()?;
     Ok(())
@@ -45,8 +159,12 @@ pub(crate) fn save_runtime_nodes(
 pub(crate) fn remove_runtime_nodes(main_flow_id: &str) -> Result<()> {
     let table_name = get_table_name(main_flow_id);
     let table: TableDefinition<&str, &[u8]> = TableDefinition::new(&table_name);
+    let subflow_table_name = get_subflow_table_name(main_flow_id);
+    let subflow_table: TableDefinition<&str, &str> = TableDefinition::new(&subflow_table_name);
     let write_txn = db::DB.begin_write()?;
     let _ = write_txn.delete_table(table)?;
+    let _ = write_txn.delete_table(subflow_table)?;
+    write_txn.open_table(LAYOUT_TABLE)?.remove(main_flow_id)?;
     write_txn.commit()?;
     Ok(())
 }
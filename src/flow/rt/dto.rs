@@ -2,6 +2,7 @@ use std::vec::Vec;
 
 use serde::{Deserialize, Serialize};
 
+use crate::intent::dto::IntentDetection;
 use crate::{flow::subflow::dto::NextActionType, variable::dto::SimpleVariable};
 
 #[derive(Deserialize, PartialEq, Eq)]
@@ -26,9 +27,30 @@ pub(crate) struct Request {
     pub(crate) import_variables: Option<Vec<SimpleVariable>>,
     #[serde(rename = "userInputIntent")]
     pub(crate) user_input_intent: Option<String>,
+    #[serde(rename = "userInputTimeoutSec")]
+    pub(crate) user_input_timeout_sec: Option<u32>,
+    // Ranked result of detecting `user_input_intent`, None if the intent was sent by the client
+    #[serde(skip)]
+    pub(crate) intent_detection: Option<IntentDetection>,
 }
 
-#[derive(Serialize)]
+impl Request {
+    pub(crate) fn new_timeout_request(&self) -> Self {
+        Self {
+            robot_id: self.robot_id.clone(),
+            main_flow_id: self.main_flow_id.clone(),
+            session_id: self.session_id.clone(),
+            user_input_result: UserInputResult::Timeout,
+            user_input: String::new(),
+            import_variables: None,
+            user_input_intent: None,
+            user_input_timeout_sec: self.user_input_timeout_sec,
+            intent_detection: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub(crate) struct CollectData {
     #[serde(rename = "varName")]
     pub(crate) var_name: String,
@@ -40,13 +62,167 @@ pub(crate) struct CollectData {
 pub(crate) enum AnswerContentType {
     TextPlain,
     TextHtml,
+    QuickReplies,
+    Card,
+    Carousel,
+    File,
+    Location,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Deserialize, Serialize, rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
+#[rkyv(compare(PartialEq))]
+pub(crate) struct AnswerButton {
+    pub(crate) label: String,
+    // Sent back as `userInput` when the button was clicked
+    #[serde(rename = "userInput")]
+    pub(crate) user_input: String,
+    // Sent back as `userInputIntent`, so intent detection is skipped
+    pub(crate) intent: Option<String>,
+    pub(crate) link: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize, rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
+#[rkyv(compare(PartialEq))]
+pub(crate) struct AnswerCard {
+    pub(crate) title: String,
+    #[serde(default)]
+    pub(crate) description: String,
+    #[serde(rename = "imageUrl", default)]
+    pub(crate) image_url: String,
+    #[serde(default)]
+    pub(crate) link: String,
+    #[serde(default)]
+    pub(crate) buttons: Vec<AnswerButton>,
+}
+
+#[derive(Clone, Deserialize, Serialize, rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
+#[rkyv(compare(PartialEq))]
+pub(crate) struct AnswerFile {
+    pub(crate) name: String,
+    pub(crate) url: String,
+    #[serde(rename = "mimeType", default)]
+    pub(crate) mime_type: String,
+    pub(crate) size: Option<u64>,
+}
+
+#[derive(Clone, Deserialize, Serialize, rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
+#[rkyv(compare(PartialEq))]
+pub(crate) struct AnswerLocation {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) address: String,
+    pub(crate) latitude: f64,
+    pub(crate) longitude: f64,
+}
+
+#[derive(Clone, Deserialize, Serialize, rkyv::Archive, rkyv::Deserialize, rkyv::Serialize)]
+#[rkyv(compare(PartialEq))]
+#[serde(tag = "payloadType")]
+pub(crate) enum AnswerPayload {
+    QuickReplies { buttons: Vec<AnswerButton> },
+    Card(AnswerCard),
+    Carousel { cards: Vec<AnswerCard> },
+    File(AnswerFile),
+    Location(AnswerLocation),
+}
+
+impl AnswerPayload {
+    pub(crate) fn content_type(&self) -> AnswerContentType {
+        match self {
+            Self::QuickReplies { .. } => AnswerContentType::QuickReplies,
+            Self::Card(_) => AnswerContentType::Card,
+            Self::Carousel { .. } => AnswerContentType::Carousel,
+            Self::File(_) => AnswerContentType::File,
+            Self::Location(_) => AnswerContentType::Location,
+        }
+    }
+
+    // Plain text version of the payload, for channels which can not render it natively
+    pub(crate) fn fallback_text(&self) -> String {
+        fn buttons_text(buttons: &[AnswerButton]) -> String {
+            buttons
+                .iter()
+                .enumerate()
+                .map(|(idx, b)| format!("{}. {}", idx + 1, &b.label))
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+        fn card_text(c: &AnswerCard) -> String {
+            let mut s = c.title.clone();
+            for t in [&c.description, &c.link] {
+                if !t.is_empty() {
+                    s.push('\n');
+                    s.push_str(t);
+                }
+            }
+            if !c.buttons.is_empty() {
+                s.push('\n');
+                s.push_str(&buttons_text(&c.buttons));
+            }
+            s
+        }
+        match self {
+            Self::QuickReplies { buttons } => buttons_text(buttons),
+            Self::Card(c) => card_text(c),
+            Self::Carousel { cards } => cards
+                .iter()
+                .map(card_text)
+                .collect::<Vec<String>>()
+                .join("\n\n"),
+            Self::File(f) => format!("{}: {}", &f.name, &f.url),
+            Self::Location(l) => {
+                if l.address.is_empty() {
+                    format!("{} ({}, {})", &l.name, l.latitude, l.longitude)
+                } else {
+                    format!("{} {}", &l.name, &l.address)
+                }
+            }
+        }
+    }
+
+    // All texts that may contain variables
+    pub(crate) fn texts_mut(&mut self) -> Vec<&mut String> {
+        fn button_texts<'a>(buttons: &'a mut [AnswerButton], texts: &mut Vec<&'a mut String>) {
+            for b in buttons.iter_mut() {
+                texts.push(&mut b.label);
+                texts.push(&mut b.user_input);
+                if let Some(l) = b.link.as_mut() {
+                    texts.push(l);
+                }
+            }
+        }
+        fn card_texts<'a>(c: &'a mut AnswerCard, texts: &mut Vec<&'a mut String>) {
+            texts.push(&mut c.title);
+            texts.push(&mut c.description);
+            texts.push(&mut c.image_url);
+            texts.push(&mut c.link);
+            button_texts(&mut c.buttons, texts);
+        }
+        let mut texts: Vec<&mut String> = Vec::with_capacity(8);
+        match self {
+            Self::QuickReplies { buttons } => button_texts(buttons, &mut texts),
+            Self::Card(c) => card_texts(c, &mut texts),
+            Self::Carousel { cards } => cards.iter_mut().for_each(|c| card_texts(c, &mut texts)),
+            Self::File(f) => {
+                texts.push(&mut f.name);
+                texts.push(&mut f.url);
+            }
+            Self::Location(l) => {
+                texts.push(&mut l.name);
+                texts.push(&mut l.address);
+            }
+        }
+        texts
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub(crate) struct AnswerData {
     pub(crate) content: String,
     #[serde(rename = "contentType")]
     pub(crate) content_type: AnswerContentType,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub(crate) payload: Option<AnswerPayload>,
 }
 
 pub(crate) struct ResponseChannelWrapper {
@@ -132,3 +308,32 @@ pub(crate) struct ExtraData {
     #[serde(rename = "externalLink")]
     pub(crate) external_link: String,
 }
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "pushType")]
+pub(crate) enum ServerPushData {
+    ExternalHttpCompleted {
+        #[serde(rename = "httpApiId")]
+        http_api_id: String,
+        #[serde(rename = "statusCode")]
+        status_code: Option<u16>,
+        err: Option<String>,
+    },
+    EmailSent {
+        subject: String,
+        err: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", content = "data")]
+pub(crate) enum SessionMessage {
+    #[serde(rename = "chunk")]
+    Chunk(StreamingResponseData),
+    #[serde(rename = "response")]
+    Response(ResponseData),
+    #[serde(rename = "error")]
+    Error(crate::result::Error),
+    #[serde(rename = "push")]
+    Push(ServerPushData),
+}
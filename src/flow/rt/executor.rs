@@ -1,14 +1,21 @@
+use std::collections::BTreeMap;
 use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use regex::Regex;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
 
 use super::context::Context;
-use super::dto::{Request, ResponseChannelWrapper, ResponseData};
+use super::dto::{AnswerContentType, AnswerData, Request, ResponseChannelWrapper, ResponseData};
 use crate::ai::completion::Prompt;
 use crate::flow::rt::dto::{StreamingResponseData, UserInputResult};
 use crate::flow::rt::node::RuntimeNode;
-use crate::intent::detector;
+use crate::flow::subflow::dto::NextActionType;
+use crate::intent::detector::{self, DialogPosition};
 use crate::result::{Error, Result};
+use crate::transcript::crud as transcript;
+use crate::transcript::dto::TranscriptTurn;
 
 pub(crate) static HTML_TAG_REGEX: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(r"<[^>]+>").unwrap());
@@ -18,12 +25,35 @@ pub(in crate::flow::rt) async fn process(
 ) -> Result<(
     ResponseData,
     Option<tokio::sync::mpsc::Receiver<StreamingResponseData>>,
+)> {
+    let channel = ResponseChannelWrapper {
+        sender: None,
+        receiver: None,
+    };
+    process_with_channel(req, channel).await
+}
+
+pub(in crate::flow::rt) async fn process_with_channel(
+    req: &mut Request,
+    mut channel: ResponseChannelWrapper,
+) -> Result<(
+    ResponseData,
+    Option<tokio::sync::mpsc::Receiver<StreamingResponseData>>,
 )> {
     // log::info!("user input: {}", &req.user_input);
     // let now = std::time::Instant::now();
+    let started = std::time::Instant::now();
     if req.session_id.is_none() || req.session_id.as_ref().unwrap().is_empty() {
         req.session_id = Some(scru128::new_string());
     }
+    // Streamed answers go through a tee, which records their text once the stream has finished
+    let (turn_sender, turn_receiver) = oneshot::channel::<(u32, usize)>();
+    let mut turn_receiver = Some(turn_receiver);
+    if let Some(client) = channel.sender.take() {
+        let (s, r) = mpsc::channel::<StreamingResponseData>(5);
+        channel.sender = Some(s);
+        tee_stream(r, client, req, turn_receiver.take().unwrap());
+    }
     let mut ctx = Context::get(&req.robot_id, req.session_id.as_ref().unwrap());
     // log::info!("get ctx {:?}", now.elapsed());
     // let now = std::time::Instant::now();
@@ -39,7 +69,17 @@ pub(in crate::flow::rt) async fn process(
         && req.user_input_result == UserInputResult::Successful
         && !req.user_input.is_empty()
     {
-        req.user_input_intent = detector::detect(&req.robot_id, &req.user_input).await?;
+        let position = DialogPosition {
+            main_flow_id: ctx.main_flow_id.clone(),
+            node_id: ctx.last_node_id.clone(),
+        };
+        let detection =
+            detector::rank(&req.robot_id, &req.user_input, None, Some(&position)).await?;
+        req.user_input_intent = detection.intent.clone();
+        for slot in detection.slots.iter() {
+            ctx.vars.insert(slot.var_name.clone(), slot.value.clone());
+        }
+        req.intent_detection = Some(detection);
         // println!("{:?}", req.user_input_intent);
     }
     // log::info!("Intent detection took {:?}", now.elapsed());
@@ -58,7 +98,23 @@ pub(in crate::flow::rt) async fn process(
         role: String::from("user"),
         content: HTML_TAG_REGEX.replace_all(&req.user_input, "").to_string(),
     });
-    let r = exec(req, &mut ctx).await;
+    let history_len = ctx.chat_history.len();
+    let mut r = exec(req, &mut ctx, channel).await;
+    // Nodes created the channel themselves, it is returned to the caller through the tee
+    if let Ok((_, receiver)) = &mut r
+        && let Some(node_receiver) = receiver.take()
+        && let Some(turn_receiver) = turn_receiver.take()
+    {
+        let (s, client_receiver) = mpsc::channel::<StreamingResponseData>(5);
+        tee_stream(node_receiver, s, req, turn_receiver);
+        *receiver = Some(client_receiver);
+    }
+    if let Some(seq) = record_transcript(req, &ctx, &r, history_len, started) {
+        let _ = turn_sender.send((seq, history_len));
+    }
+    if let Some(node_id) = ctx.visited_nodes.last() {
+        ctx.last_node_id = node_id.clone();
+    }
     if r.is_ok() {
         let (res, _receiver) = r.as_ref().unwrap();
         if !res.answers.is_empty() {
@@ -77,19 +133,109 @@ pub(in crate::flow::rt) async fn process(
     r
 }
 
+fn record_transcript(
+    req: &Request,
+    ctx: &Context,
+    r: &Result<(ResponseData, Option<Receiver<StreamingResponseData>>)>,
+    history_len: usize,
+    started: std::time::Instant,
+) -> Option<u32> {
+    // Answers sent through the streaming channel are only kept in chat history
+    let mut answers: Vec<AnswerData> = ctx
+        .chat_history
+        .iter()
+        .skip(history_len)
+        .map(|p| AnswerData {
+            content: p.content.clone(),
+            content_type: AnswerContentType::TextPlain,
+            payload: None,
+        })
+        .collect();
+    let (collect_data, next_action, error) = match r {
+        Ok((res, _)) => {
+            answers.extend(res.answers.iter().cloned());
+            (res.collect_data.clone(), res.next_action.clone(), None)
+        }
+        Err(e) => (vec![], NextActionType::None, Some(format!("{e:?}"))),
+    };
+    let turn = TranscriptTurn {
+        session_id: ctx.session_id.clone(),
+        seq: 0,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64),
+        main_flow_id: ctx.main_flow_id.clone(),
+        user_input: req.user_input.clone(),
+        user_input_timeout: req.user_input_result == UserInputResult::Timeout,
+        intent: req.user_input_intent.clone(),
+        visited_nodes: ctx.visited_nodes.clone(),
+        collect_data,
+        answers,
+        next_action,
+        latency_millis: started.elapsed().as_millis() as u64,
+        error,
+        kb_no_recall: ctx.kb_no_recall,
+    };
+    match transcript::record(&req.robot_id, turn) {
+        Ok(seq) => Some(seq),
+        Err(e) => {
+            log::warn!("Recording transcript failed, err: {e:?}");
+            None
+        }
+    }
+}
+
+// Forwards streamed answers to the client and gathers their text. Once all the streaming tasks
+// have finished, the text is recorded in the turn, whose sequence is sent after it was recorded
+fn tee_stream(
+    mut receiver: Receiver<StreamingResponseData>,
+    client: Sender<StreamingResponseData>,
+    req: &Request,
+    turn: oneshot::Receiver<(u32, usize)>,
+) {
+    let robot_id = req.robot_id.clone();
+    let session_id = req.session_id.clone().unwrap_or_default();
+    tokio::spawn(async move {
+        let mut streamed: BTreeMap<usize, String> = BTreeMap::new();
+        let mut connected = true;
+        while let Some(d) = receiver.recv().await {
+            // Chunks without a sequence carry the serialized response
+            if let Some(seq) = d.content_seq {
+                streamed.entry(seq).or_default().push_str(&d.content);
+            }
+            if connected && client.send(d).await.is_err() {
+                connected = false;
+            }
+        }
+        let Ok((turn_seq, history_len)) = turn.await else {
+            return;
+        };
+        // Content sequence of an answer is the index of the chat history entry before it
+        let streamed: Vec<(usize, String)> = streamed
+            .into_iter()
+            .filter_map(|(seq, s)| Some(((seq + 1).checked_sub(history_len)?, s)))
+            .collect();
+        if streamed.is_empty() {
+            return;
+        }
+        if let Err(e) =
+            transcript::fill_streamed_answers(&robot_id, &session_id, turn_seq, streamed)
+        {
+            log::warn!("Recording streamed answers failed, err: {e:?}");
+        }
+    });
+}
+
 pub(in crate::flow::rt) async fn exec(
     req: &Request,
     ctx: &mut Context,
+    mut sender_wapper: ResponseChannelWrapper,
 ) -> Result<(
     ResponseData,
     Option<tokio::sync::mpsc::Receiver<StreamingResponseData>>,
 )> {
     // let now = std::time::Instant::now();
     let mut response = ResponseData::new(req);
-    let mut sender_wapper = ResponseChannelWrapper {
-        sender: None,
-        receiver: None,
-    };
     for _i in 0..100 {
         // let now = std::time::Instant::now();
         if let Some(mut n) = ctx.pop_node() {
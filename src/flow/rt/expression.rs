@@ -0,0 +1,811 @@
+use std::collections::HashMap;
+
+use time::{Date, Duration, Month, OffsetDateTime, PrimitiveDateTime, Time};
+
+use crate::result::{Error, Result};
+use crate::variable::dto::VariableValue;
+
+// Expressions are evaluated by a small interpreter, there is no way to call anything outside of
+// the built-in functions below, the limits keep a bad expression from hogging CPU or memory.
+const MAX_EXPRESSION_LEN: usize = 4096;
+const MAX_NESTING_DEPTH: usize = 64;
+const MAX_STRING_LEN: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(f64),
+    Str(String),
+    Ident(String),
+    Op(&'static str),
+    LeftParen,
+    RightParen,
+    Comma,
+}
+
+pub(crate) enum Expr {
+    Num(f64),
+    Str(String),
+    Bool(bool),
+    Var(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Num(f64),
+    Str(String),
+    Bool(bool),
+    Array(Vec<VariableValue>),
+}
+
+fn err<T>(m: impl Into<String>) -> Result<T> {
+    Err(Error::WithMessage(m.into()))
+}
+
+const OPERATORS: [&str; 15] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "+", "-", "*", "/", "%", "!", "=",
+];
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut tokens = Vec::with_capacity(16);
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()))
+        {
+            let begin = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let n: String = chars[begin..i].iter().collect();
+            match n.parse::<f64>() {
+                Ok(n) => tokens.push(Token::Num(n)),
+                Err(_) => return err(format!("Invalid number: {n}")),
+            }
+        } else if c == '"' || c == '\'' {
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return err("Unterminated string literal"),
+                    Some(&q) if q == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        match chars.get(i) {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some(&e) => text.push(e),
+                            None => return err("Unterminated string literal"),
+                        }
+                    }
+                    Some(&ch) => text.push(ch),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::Str(text));
+        } else if c == '`' {
+            // Same wrapping symbol as variables in dialog text, for names containing spaces
+            let begin = i + 1;
+            i = begin;
+            while i < chars.len() && chars[i] != '`' {
+                i += 1;
+            }
+            if i >= chars.len() {
+                return err("Unterminated variable name");
+            }
+            tokens.push(Token::Ident(chars[begin..i].iter().collect()));
+            i += 1;
+        } else if c.is_alphabetic() || c == '_' {
+            let begin = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[begin..i].iter().collect()));
+        } else if c == '(' {
+            tokens.push(Token::LeftParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::RightParen);
+            i += 1;
+        } else if c == ',' {
+            tokens.push(Token::Comma);
+            i += 1;
+        } else {
+            let op = OPERATORS.iter().find(|op| {
+                op.chars()
+                    .enumerate()
+                    .all(|(idx, ch)| chars.get(i + idx) == Some(&ch))
+            });
+            match op {
+                // A single `=` is treated as comparison, just like in condition nodes
+                Some(&"=") => tokens.push(Token::Op("==")),
+                Some(op) => tokens.push(Token::Op(op)),
+                None => return err(format!("Unexpected character: {c}")),
+            }
+            i += op.map_or(1, |op| op.len());
+        }
+    }
+    Ok(tokens)
+}
+
+fn precedence(op: &str) -> Option<u8> {
+    match op {
+        "||" => Some(1),
+        "&&" => Some(2),
+        "==" | "!=" => Some(3),
+        "<" | "<=" | ">" | ">=" => Some(4),
+        "+" | "-" => Some(5),
+        "*" | "/" | "%" => Some(6),
+        _ => None,
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn expect(&mut self, t: Token) -> Result<()> {
+        if self.next().as_ref() == Some(&t) {
+            Ok(())
+        } else {
+            err(format!("Expected {t:?}"))
+        }
+    }
+
+    fn binary(&mut self, min_prec: u8) -> Result<Expr> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING_DEPTH {
+            return err("Expression nested too deeply");
+        }
+        let mut left = self.unary()?;
+        while let Some(Token::Op(op)) = self.peek() {
+            let op = *op;
+            let prec = match precedence(op) {
+                Some(p) if p >= min_prec => p,
+                _ => break,
+            };
+            self.pos += 1;
+            let right = self.binary(prec + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        self.depth -= 1;
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.peek() {
+            Some(Token::Op(op)) if *op == "-" || *op == "!" => {
+                let op = *op;
+                self.pos += 1;
+                self.depth += 1;
+                if self.depth > MAX_NESTING_DEPTH {
+                    return err("Expression nested too deeply");
+                }
+                let e = self.unary()?;
+                self.depth -= 1;
+                Ok(Expr::Unary(op, Box::new(e)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Str(s)) => Ok(Expr::Str(s)),
+            Some(Token::Ident(name)) => {
+                if self.peek() == Some(&Token::LeftParen) {
+                    self.pos += 1;
+                    let mut args = Vec::with_capacity(3);
+                    if self.peek() == Some(&Token::RightParen) {
+                        self.pos += 1;
+                    } else {
+                        loop {
+                            args.push(self.binary(0)?);
+                            match self.next() {
+                                Some(Token::Comma) => {}
+                                Some(Token::RightParen) => break,
+                                _ => return err(format!("Invalid arguments of: {name}")),
+                            }
+                        }
+                    }
+                    Ok(Expr::Call(name.to_lowercase(), args))
+                } else if name.eq("true") {
+                    Ok(Expr::Bool(true))
+                } else if name.eq("false") {
+                    Ok(Expr::Bool(false))
+                } else {
+                    Ok(Expr::Var(name))
+                }
+            }
+            Some(Token::LeftParen) => {
+                let e = self.binary(0)?;
+                self.expect(Token::RightParen)?;
+                Ok(e)
+            }
+            Some(t) => err(format!("Unexpected token: {t:?}")),
+            None => err("Unexpected end of expression"),
+        }
+    }
+}
+
+pub(crate) fn parse(s: &str) -> Result<Expr> {
+    if s.len() > MAX_EXPRESSION_LEN {
+        return err("Expression was too long");
+    }
+    let tokens = tokenize(s)?;
+    if tokens.is_empty() {
+        return err("Expression is empty");
+    }
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        depth: 0,
+    };
+    let e = parser.binary(0)?;
+    if parser.pos < parser.tokens.len() {
+        return err(format!(
+            "Unexpected token: {:?}",
+            &parser.tokens[parser.pos]
+        ));
+    }
+    Ok(e)
+}
+
+impl Expr {
+    pub(crate) fn var_names(&self, names: &mut Vec<String>) {
+        match self {
+            Expr::Var(n) if !names.contains(n) => names.push(n.clone()),
+            Expr::Unary(_, e) => e.var_names(names),
+            Expr::Binary(_, l, r) => {
+                l.var_names(names);
+                r.var_names(names);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|a| a.var_names(names)),
+            _ => {}
+        }
+    }
+
+    fn eval_inner(&self, vars: &HashMap<String, VariableValue>) -> Result<Value> {
+        match self {
+            Expr::Num(n) => Ok(Value::Num(*n)),
+            Expr::Str(s) => Ok(Value::Str(s.clone())),
+            Expr::Bool(b) => Ok(Value::Bool(*b)),
+            Expr::Var(n) => match vars.get(n) {
+                Some(VariableValue::Str(s)) => Ok(Value::Str(s.clone())),
+                Some(VariableValue::Num(n)) => Ok(Value::Num(*n)),
+                Some(VariableValue::Array(a)) => Ok(Value::Array(a.clone())),
+                None => err(format!("Variable: {n} has no value")),
+            },
+            Expr::Unary(op, e) => {
+                let v = e.eval_inner(vars)?;
+                if *op == "-" {
+                    Ok(Value::Num(-v.to_num()?))
+                } else {
+                    Ok(Value::Bool(!v.is_truthy()))
+                }
+            }
+            Expr::Binary(op, l, r) => {
+                let left = l.eval_inner(vars)?;
+                // Short-circuit evaluation
+                match *op {
+                    "&&" if !left.is_truthy() => return Ok(Value::Bool(false)),
+                    "||" if left.is_truthy() => return Ok(Value::Bool(true)),
+                    _ => {}
+                }
+                let right = r.eval_inner(vars)?;
+                binary(op, left, right)
+            }
+            Expr::Call(name, args) => {
+                // `if` only evaluates the chosen branch
+                if name.eq("if") {
+                    if args.len() != 3 {
+                        return err("Function: if needs 3 arguments");
+                    }
+                    return if args[0].eval_inner(vars)?.is_truthy() {
+                        args[1].eval_inner(vars)
+                    } else {
+                        args[2].eval_inner(vars)
+                    };
+                }
+                let mut values = Vec::with_capacity(args.len());
+                for a in args.iter() {
+                    values.push(a.eval_inner(vars)?);
+                }
+                call(name, values)
+            }
+        }
+    }
+
+    pub(crate) fn eval(&self, vars: &HashMap<String, VariableValue>) -> Result<VariableValue> {
+        Ok(match self.eval_inner(vars)? {
+            Value::Num(n) => VariableValue::Num(n),
+            Value::Str(s) => VariableValue::Str(s),
+            Value::Bool(b) => VariableValue::Str(b.to_string()),
+            Value::Array(a) => VariableValue::Array(a),
+        })
+    }
+}
+
+impl Value {
+    fn to_num(&self) -> Result<f64> {
+        match self {
+            Value::Num(n) => Ok(*n),
+            Value::Bool(b) => Ok(if *b { 1f64 } else { 0f64 }),
+            Value::Str(s) => match s.trim().parse::<f64>() {
+                Ok(n) => Ok(n),
+                Err(_) => err(format!("Can not convert: {s} to a number")),
+            },
+            Value::Array(_) => err("Can not convert an array to a number"),
+        }
+    }
+
+    fn to_str(&self) -> String {
+        match self {
+            Value::Num(n) => n.to_string(),
+            Value::Str(s) => s.clone(),
+            Value::Bool(b) => b.to_string(),
+            Value::Array(a) => VariableValue::Array(a.clone()).val_to_string(),
+        }
+    }
+
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Num(n) => *n != 0f64,
+            Value::Str(s) => !s.is_empty() && !s.eq_ignore_ascii_case("false"),
+            Value::Bool(b) => *b,
+            Value::Array(a) => !a.is_empty(),
+        }
+    }
+}
+
+fn check_str_len(s: String) -> Result<Value> {
+    if s.len() > MAX_STRING_LEN {
+        err("Expression result was too large")
+    } else {
+        Ok(Value::Str(s))
+    }
+}
+
+fn binary(op: &str, left: Value, right: Value) -> Result<Value> {
+    match op {
+        "+" => match (&left, &right) {
+            (Value::Num(l), Value::Num(r)) => Ok(Value::Num(l + r)),
+            _ => {
+                let mut s = left.to_str();
+                s.push_str(&right.to_str());
+                check_str_len(s)
+            }
+        },
+        "-" => Ok(Value::Num(left.to_num()? - right.to_num()?)),
+        "*" => Ok(Value::Num(left.to_num()? * right.to_num()?)),
+        "/" | "%" => {
+            let r = right.to_num()?;
+            if r == 0f64 {
+                return err("Division by zero");
+            }
+            let l = left.to_num()?;
+            Ok(Value::Num(if op == "/" { l / r } else { l % r }))
+        }
+        "==" | "!=" => {
+            let eq = match (&left, &right) {
+                (Value::Str(_), Value::Str(_)) | (Value::Array(_), Value::Array(_)) => {
+                    left == right
+                }
+                (Value::Bool(_), _) | (_, Value::Bool(_)) => {
+                    left.is_truthy() == right.is_truthy()
+                }
+                _ => match (left.to_num(), right.to_num()) {
+                    (Ok(l), Ok(r)) => l == r,
+                    _ => left.to_str() == right.to_str(),
+                },
+            };
+            Ok(Value::Bool(if op == "==" { eq } else { !eq }))
+        }
+        "<" | "<=" | ">" | ">=" => {
+            let ord = match (&left, &right) {
+                (Value::Str(l), Value::Str(r)) => l.partial_cmp(r),
+                _ => left.to_num()?.partial_cmp(&right.to_num()?),
+            };
+            let ord = match ord {
+                Some(o) => o,
+                None => return Ok(Value::Bool(false)),
+            };
+            Ok(Value::Bool(match op {
+                "<" => ord.is_lt(),
+                "<=" => ord.is_le(),
+                ">" => ord.is_gt(),
+                _ => ord.is_ge(),
+            }))
+        }
+        "&&" | "||" => Ok(Value::Bool(right.is_truthy())),
+        _ => err(format!("Unsupported operator: {op}")),
+    }
+}
+
+fn parse_date_time(s: &str) -> Result<(PrimitiveDateTime, bool)> {
+    let invalid = || Error::WithMessage(format!("Invalid date time: {s}"));
+    let mut parts = s.split_whitespace();
+    let date: Vec<&str> = parts.next().ok_or_else(invalid)?.split('-').collect();
+    if date.len() != 3 {
+        return Err(invalid());
+    }
+    let month: u8 = date[1].parse().map_err(|_| invalid())?;
+    let date = Date::from_calendar_date(
+        date[0].parse().map_err(|_| invalid())?,
+        Month::try_from(month).map_err(|_| invalid())?,
+        date[2].parse().map_err(|_| invalid())?,
+    )
+    .map_err(|_| invalid())?;
+    let (time, date_only) = match parts.next() {
+        Some(t) => {
+            let t: Vec<u8> = t
+                .split(':')
+                .map(|n| n.parse::<u8>())
+                .collect::<core::result::Result<_, _>>()
+                .map_err(|_| invalid())?;
+            if t.len() < 2 || t.len() > 3 {
+                return Err(invalid());
+            }
+            let time = Time::from_hms(t[0], t[1], t.get(2).copied().unwrap_or(0))
+                .map_err(|_| invalid())?;
+            (time, false)
+        }
+        None => (Time::MIDNIGHT, true),
+    };
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+    Ok((PrimitiveDateTime::new(date, time), date_only))
+}
+
+fn format_date_time(d: PrimitiveDateTime, date_only: bool) -> String {
+    if date_only {
+        format!("{:04}-{:02}-{:02}", d.year(), d.month() as u8, d.day())
+    } else {
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            d.year(),
+            d.month() as u8,
+            d.day(),
+            d.hour(),
+            d.minute(),
+            d.second()
+        )
+    }
+}
+
+fn args_len(name: &str, args: &[Value], min: usize, max: usize) -> Result<()> {
+    if args.len() < min || args.len() > max {
+        err(format!("Wrong number of arguments of function: {name}"))
+    } else {
+        Ok(())
+    }
+}
+
+fn call(name: &str, args: Vec<Value>) -> Result<Value> {
+    match name {
+        "len" => {
+            args_len(name, &args, 1, 1)?;
+            Ok(Value::Num(match &args[0] {
+                Value::Array(a) => a.len(),
+                v => v.to_str().chars().count(),
+            } as f64))
+        }
+        "upper" => {
+            args_len(name, &args, 1, 1)?;
+            Ok(Value::Str(args[0].to_str().to_uppercase()))
+        }
+        "lower" => {
+            args_len(name, &args, 1, 1)?;
+            Ok(Value::Str(args[0].to_str().to_lowercase()))
+        }
+        "trim" => {
+            args_len(name, &args, 1, 1)?;
+            Ok(Value::Str(String::from(args[0].to_str().trim())))
+        }
+        "contains" => {
+            args_len(name, &args, 2, 2)?;
+            let needle = args[1].to_str();
+            Ok(Value::Bool(match &args[0] {
+                Value::Array(a) => a.iter().any(|v| v.val_to_string() == needle),
+                v => v.to_str().contains(&needle),
+            }))
+        }
+        "substr" => {
+            args_len(name, &args, 2, 3)?;
+            let s = args[0].to_str();
+            let start = args[1].to_num()?.max(0f64) as usize;
+            let len = match args.get(2) {
+                Some(l) => l.to_num()?.max(0f64) as usize,
+                None => usize::MAX,
+            };
+            Ok(Value::Str(s.chars().skip(start).take(len).collect()))
+        }
+        "str" => {
+            args_len(name, &args, 1, 1)?;
+            Ok(Value::Str(args[0].to_str()))
+        }
+        "num" => {
+            args_len(name, &args, 1, 1)?;
+            Ok(Value::Num(args[0].to_num()?))
+        }
+        "abs" => {
+            args_len(name, &args, 1, 1)?;
+            Ok(Value::Num(args[0].to_num()?.abs()))
+        }
+        "round" => {
+            args_len(name, &args, 1, 2)?;
+            let n = args[0].to_num()?;
+            let digits = match args.get(1) {
+                Some(d) => d.to_num()?.clamp(0f64, 10f64) as i32,
+                None => 0,
+            };
+            let factor = 10f64.powi(digits);
+            Ok(Value::Num((n * factor).round() / factor))
+        }
+        "floor" => {
+            args_len(name, &args, 1, 1)?;
+            Ok(Value::Num(args[0].to_num()?.floor()))
+        }
+        "ceil" => {
+            args_len(name, &args, 1, 1)?;
+            Ok(Value::Num(args[0].to_num()?.ceil()))
+        }
+        "min" | "max" => {
+            args_len(name, &args, 1, 64)?;
+            let mut r = args[0].to_num()?;
+            for a in args.iter().skip(1) {
+                let n = a.to_num()?;
+                r = if name == "min" { r.min(n) } else { r.max(n) };
+            }
+            Ok(Value::Num(r))
+        }
+        "now" => {
+            args_len(name, &args, 0, 0)?;
+            let now = OffsetDateTime::now_utc();
+            let now = PrimitiveDateTime::new(now.date(), now.time());
+            Ok(Value::Str(format_date_time(now, false)))
+        }
+        "today" => {
+            args_len(name, &args, 0, 0)?;
+            let today = PrimitiveDateTime::new(OffsetDateTime::now_utc().date(), Time::MIDNIGHT);
+            Ok(Value::Str(format_date_time(today, true)))
+        }
+        "date_add" => {
+            args_len(name, &args, 2, 3)?;
+            let (d, date_only) = parse_date_time(&args[0].to_str())?;
+            let amount = args[1].to_num()?;
+            if !amount.is_finite() {
+                return err("Date time out of range");
+            }
+            let unit = args.get(2).map_or(String::from("day"), |u| u.to_str());
+            let (unit_seconds, date_only) = match unit.trim_end_matches('s') {
+                "week" => (604800f64, date_only),
+                "day" => (86400f64, date_only),
+                "hour" => (3600f64, false),
+                "minute" => (60f64, false),
+                "second" => (1f64, false),
+                _ => return err(format!("Unsupported date unit: {unit}")),
+            };
+            // Fractional amounts are scaled to seconds, e.g. 1.5 hours is 5400 seconds.
+            // Building the duration panics on overflow, so it's checked in seconds first
+            let seconds = amount * unit_seconds;
+            if seconds.fract() != 0f64 {
+                return err(format!("Not a whole number of seconds: {amount} {unit}"));
+            }
+            if seconds.abs() >= i64::MAX as f64 {
+                return err("Date time out of range");
+            }
+            let seconds = seconds as i64;
+            // Part of a day keeps the time, even if the date had none
+            let date_only = date_only && seconds % 86400 == 0;
+            match d.checked_add(Duration::seconds(seconds)) {
+                Some(d) => Ok(Value::Str(format_date_time(d, date_only))),
+                None => err("Date time out of range"),
+            }
+        }
+        "date_diff" => {
+            args_len(name, &args, 2, 2)?;
+            let (d1, _) = parse_date_time(&args[0].to_str())?;
+            let (d2, _) = parse_date_time(&args[1].to_str())?;
+            Ok(Value::Num((d1 - d2).whole_days() as f64))
+        }
+        _ => err(format!("Unknown function: {name}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(s: &str, vars: &[(&str, VariableValue)]) -> Result<VariableValue> {
+        let vars: HashMap<String, VariableValue> = vars
+            .iter()
+            .map(|(k, v)| (String::from(*k), v.clone()))
+            .collect();
+        parse(s)?.eval(&vars)
+    }
+
+    fn num(s: &str) -> f64 {
+        match eval(s, &[]) {
+            Ok(VariableValue::Num(n)) => n,
+            r => panic!("{s} evaluated to {r:?}"),
+        }
+    }
+
+    fn text(s: &str) -> String {
+        match eval(s, &[]) {
+            Ok(VariableValue::Str(t)) => t,
+            r => panic!("{s} evaluated to {r:?}"),
+        }
+    }
+
+    fn fails(s: &str) -> String {
+        match eval(s, &[]) {
+            Err(Error::WithMessage(m)) => m,
+            r => panic!("{s} evaluated to {r:?}"),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(num("1 + 2 * 3"), 7f64);
+        assert_eq!(num("(1 + 2) * 3"), 9f64);
+        assert_eq!(num("10 - 4 - 3"), 3f64);
+        assert_eq!(num("12 / 3 / 2"), 2f64);
+        assert_eq!(num("7 % 4 * 2"), 6f64);
+        assert_eq!(num("-2 * 3"), -6f64);
+        assert_eq!(num("--2"), 2f64);
+        assert_eq!(text("1 + 1 == 2 && 2 < 1 || 3 >= 3"), "true");
+        assert_eq!(text("1 < 2 == 2 < 3"), "true");
+        assert_eq!(text("!0 && !''"), "true");
+        assert_eq!(text("1 = 1"), "true");
+    }
+
+    #[test]
+    fn coercion() {
+        let vars = [
+            ("age", VariableValue::Str(String::from(" 18 "))),
+            ("n", VariableValue::Num(3f64)),
+            (
+                "tags",
+                VariableValue::Array(vec![
+                    VariableValue::Str(String::from("a")),
+                    VariableValue::Str(String::from("b")),
+                ]),
+            ),
+        ];
+        let e = |s| eval(s, &vars).unwrap();
+        assert_eq!(e("age * 2"), VariableValue::Num(36f64));
+        assert_eq!(e("age + 1"), VariableValue::Str(String::from(" 18 1")));
+        assert_eq!(e("n + 1"), VariableValue::Num(4f64));
+        assert_eq!(e("'n=' + n"), VariableValue::Str(String::from("n=3")));
+        assert_eq!(e("age == 18"), VariableValue::Str(String::from("true")));
+        assert_eq!(e("'10' < '9'"), VariableValue::Str(String::from("true")));
+        assert_eq!(e("'10' < 9"), VariableValue::Str(String::from("false")));
+        assert_eq!(e("'abc' == 0"), VariableValue::Str(String::from("false")));
+        assert_eq!(e("'FALSE' || 0"), VariableValue::Str(String::from("false")));
+        assert_eq!(e("true + 1"), VariableValue::Str(String::from("true1")));
+        assert_eq!(e("true * 2"), VariableValue::Num(2f64));
+        assert_eq!(e("len(tags)"), VariableValue::Num(2f64));
+        assert_eq!(
+            e("contains(tags, 'b')"),
+            VariableValue::Str(String::from("true"))
+        );
+        assert_eq!(e("`n` * n"), VariableValue::Num(9f64));
+        assert!(eval("'abc' * 2", &[]).is_err());
+        assert!(eval("tags * 2", &vars).is_err());
+        assert!(eval("missing + 1", &vars).is_err());
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(text("upper(trim('  ab '))"), "AB");
+        assert_eq!(text("substr('你好世界', 1, 2)"), "好世");
+        assert_eq!(num("len('你好')"), 2f64);
+        assert_eq!(num("round(2.345, 2)"), 2.35);
+        assert_eq!(num("max(1, '5', 3) - min(4, 2)"), 3f64);
+        assert_eq!(num("if(1 > 2, 1 / 0, 7)"), 7f64);
+        assert_eq!(num("date_diff('2024-03-01', '2024-02-01')"), 29f64);
+        assert_eq!(fails("nope(1)"), "Unknown function: nope");
+        assert_eq!(
+            fails("upper('a', 'b')"),
+            "Wrong number of arguments of function: upper"
+        );
+        assert_eq!(fails("if(1, 2)"), "Function: if needs 3 arguments");
+        assert_eq!(fails("1 / 0"), "Division by zero");
+        assert_eq!(fails("5 % (2 - 2)"), "Division by zero");
+        assert!(eval("date_diff('2024-13-01', '2024-01-01')", &[]).is_err());
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert!(parse("").is_err());
+        assert!(parse("1 +").is_err());
+        assert!(parse("(1 + 2").is_err());
+        assert!(parse("1 2").is_err());
+        assert!(parse("'open").is_err());
+        assert!(parse("`open").is_err());
+        assert!(parse("1 # 2").is_err());
+        assert!(parse("1.2.3").is_err());
+        assert!(parse("f(1,").is_err());
+    }
+
+    #[test]
+    fn limits() {
+        let nested = format!("{}1{}", "(".repeat(100), ")".repeat(100));
+        assert_eq!(fails(&nested), "Expression nested too deeply");
+        assert_eq!(
+            fails(&format!("{}1", "-".repeat(100))),
+            "Expression nested too deeply"
+        );
+        assert_eq!(num(&format!("{}1{}", "(".repeat(30), ")".repeat(30))), 1f64);
+        assert_eq!(
+            fails(&format!("'{}'", "a".repeat(MAX_EXPRESSION_LEN))),
+            "Expression was too long"
+        );
+        // Doubling a string a few times must stop at the result size limit
+        let vars = [("s", VariableValue::Str("a".repeat(1024)))];
+        let grow = std::iter::repeat_n("s", 64).collect::<Vec<_>>().join(" + ");
+        assert_eq!(
+            eval(&grow, &vars).unwrap(),
+            VariableValue::Str("a".repeat(64 * 1024))
+        );
+        let grow = format!("{grow} + 'a'");
+        assert!(eval(&grow, &vars).is_err());
+    }
+
+    #[test]
+    fn date_add() {
+        assert_eq!(text("date_add('2024-02-28', 1)"), "2024-02-29");
+        assert_eq!(text("date_add('2024-02-28', 2, 'days')"), "2024-03-01");
+        assert_eq!(text("date_add('2024-01-01', -1, 'week')"), "2023-12-25");
+        assert_eq!(
+            text("date_add('2024-01-01', 90, 'minutes')"),
+            "2024-01-01 01:30:00"
+        );
+        assert_eq!(
+            text("date_add('2024-01-01 23:00', 2, 'hour')"),
+            "2024-01-02 01:00:00"
+        );
+        // Fractional amounts are not truncated
+        assert_eq!(
+            text("date_add('2024-01-01', 1.5, 'hour')"),
+            "2024-01-01 01:30:00"
+        );
+        assert_eq!(text("date_add('2024-01-01', 0.5)"), "2024-01-01 12:00:00");
+        assert_eq!(text("date_add('2024-01-01', 2.0)"), "2024-01-03");
+        assert!(eval("date_add('2024-01-01', 0.5, 'second')", &[]).is_err());
+        assert!(eval("date_add('2024-01-01', 1, 'month')", &[]).is_err());
+        assert!(
+            eval(
+                "date_add('2024-01-01', 1000000 * 1000000 * 1000000 * 1000000)",
+                &[]
+            )
+            .is_err()
+        );
+        assert!(eval("date_add('2024-01-01', 99999999999999)", &[]).is_err());
+        assert!(eval("date_add('2024-01-01 25:00', 1)", &[]).is_err());
+    }
+}
@@ -1,20 +1,92 @@
+use core::time::Duration;
 use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{LazyLock, Mutex};
 
 use axum::Json;
+use axum::extract::Extension;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::response::IntoResponse;
-use tokio::sync::mpsc::Sender;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::SinkExt;
+use futures::stream::{self, Stream};
+use tokio::sync::mpsc::{Sender, WeakSender};
+use tokio::task::AbortHandle;
+use tokio_stream::StreamExt as _;
+use tokio_stream::wrappers::ReceiverStream;
 
-use super::dto::Request;
+use super::dto::{
+    Request, ResponseChannelWrapper, ServerPushData, SessionMessage, StreamingResponseData,
+};
 use super::executor;
+use crate::auth::dto::ApiKeyScope;
+use crate::auth::guard::{self, check_robot_scope};
+use crate::flow::subflow::dto::NextActionType;
 use crate::result::Result;
 use crate::web::server::to_res2;
 
-static ANSWER_SSE_SESSIONS: LazyLock<Mutex<HashMap<String, Sender<String>>>> =
+// Senders of each session by connection, so that connections sharing a session id neither
+// replace nor remove each other's sender
+type Connections<T> = HashMap<String, HashMap<u64, T>>;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+// Only weak senders are kept here, so that a registered session never keeps the stream open
+// after the dialog turn and all the spawned LLM tasks have finished.
+static ANSWER_SSE_SESSIONS: LazyLock<Mutex<Connections<WeakSender<StreamingResponseData>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::with_capacity(128)));
+
+static WS_SESSIONS: LazyLock<Mutex<Connections<Sender<SessionMessage>>>> =
     LazyLock::new(|| Mutex::new(HashMap::with_capacity(128)));
 
-pub(crate) async fn answer(Json(mut req): Json<Request>) -> impl IntoResponse {
+fn register<T>(sessions: &Mutex<Connections<T>>, session_id: &str, connection_id: u64, sender: T) {
+    match sessions.lock() {
+        Ok(mut l) => {
+            l.entry(String::from(session_id))
+                .or_default()
+                .insert(connection_id, sender);
+        }
+        Err(e) => log::warn!("Registering session {} failed {:?}", session_id, &e),
+    }
+}
+
+fn unregister<T>(sessions: &Mutex<Connections<T>>, session_id: &str, connection_id: u64) {
+    match sessions.lock() {
+        Ok(mut l) => {
+            if let Some(connections) = l.get_mut(session_id) {
+                connections.remove(&connection_id);
+                if connections.is_empty() {
+                    l.remove(session_id);
+                }
+            }
+        }
+        Err(e) => log::warn!("Removing session {} failed {:?}", session_id, &e),
+    }
+}
+
+// Dropped with the stream, the dialog turn is aborted if the client disconnected before it finished
+struct SseSessionGuard {
+    session_id: String,
+    connection_id: u64,
+    turn: AbortHandle,
+}
+
+impl Drop for SseSessionGuard {
+    fn drop(&mut self) {
+        self.turn.abort();
+        unregister(&ANSWER_SSE_SESSIONS, &self.session_id, self.connection_id);
+    }
+}
+
+pub(crate) async fn answer(
+    scope: Option<Extension<ApiKeyScope>>,
+    Json(mut req): Json<Request>,
+) -> impl IntoResponse {
     let now = std::time::Instant::now();
+    if let Err(e) = check_robot_scope(scope.as_deref(), &req.robot_id) {
+        return to_res2::<()>(Err(e));
+    }
     let r = executor::process(&mut req).await;
     // println!("exec used time:{:?}", now.elapsed());
     let res = to_res2(r);
@@ -22,20 +94,260 @@ pub(crate) async fn answer(Json(mut req): Json<Request>) -> impl IntoResponse {
     res
 }
 
-pub(crate) async fn answer_sse(Json(req): Json<Request>) -> impl IntoResponse {
+pub(crate) async fn answer_sse(
+    scope: Option<Extension<ApiKeyScope>>,
+    Json(mut req): Json<Request>,
+) -> Sse<impl Stream<Item = core::result::Result<Event, Infallible>>> {
+    if req.session_id.is_none() || req.session_id.as_ref().unwrap().is_empty() {
+        req.session_id = Some(scru128::new_string());
+    }
+    let session_id = req.session_id.clone().unwrap();
+    let (sender, receiver) = tokio::sync::mpsc::channel::<StreamingResponseData>(5);
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    register(
+        &ANSWER_SSE_SESSIONS,
+        &session_id,
+        connection_id,
+        sender.downgrade(),
+    );
+    let (result_sender, result_receiver) = tokio::sync::oneshot::channel();
+    let turn = tokio::spawn(async move {
+        let now = std::time::Instant::now();
+        let channel = ResponseChannelWrapper {
+            sender: Some(sender),
+            receiver: None,
+        };
+        let r = match check_robot_scope(scope.as_deref(), &req.robot_id) {
+            Ok(_) => executor::process_with_channel(&mut req, channel).await,
+            Err(e) => Err(e),
+        };
+        log::info!("Response used time:{:?}", now.elapsed());
+        if result_sender.send(r).is_err() {
+            log::warn!("SSE client disconnected before the dialog finished");
+        }
+    });
+    let guard = SseSessionGuard {
+        session_id,
+        connection_id,
+        turn: turn.abort_handle(),
+    };
+    let chunks = ReceiverStream::new(receiver).map(|d| {
+        let event = Event::default()
+            .event("chunk")
+            .data(serde_json::to_string(&d).unwrap());
+        Ok::<Event, Infallible>(event)
+    });
+    let last = stream::once(async move {
+        let event = match result_receiver.await {
+            Ok(Ok((res, _))) => Event::default()
+                .event("response")
+                .data(serde_json::to_string(&res).unwrap()),
+            Ok(Err(e)) => Event::default()
+                .event("error")
+                .data(serde_json::to_string(&e).unwrap()),
+            Err(e) => Event::default()
+                .event("error")
+                .data(format!("{{\"message\":\"{e}\"}}")),
+        };
+        Ok::<Event, Infallible>(event)
+    });
+    let stream = chunks.chain(last).map(move |e| {
+        // Keeps the session registered until the client disconnects or the stream ends
+        let _ = &guard;
+        e
+    });
+    Sse::new(stream).keep_alive(
+        KeepAlive::new()
+            .interval(Duration::from_secs(30))
+            .text("keep-alive-text"),
+    )
+}
+
+pub(crate) async fn answer_ws(
+    scope: Option<Extension<ApiKeyScope>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let scope = scope.map(|Extension(s)| s);
+    // The key protocol is echoed back, browsers close the connection otherwise
+    ws.protocols([guard::WS_API_KEY_PROTOCOL])
+        .on_upgrade(move |socket| handle_ws(socket, scope))
+}
+
+async fn handle_ws(socket: WebSocket, scope: Option<ApiKeyScope>) {
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let (mut ws_sender, mut ws_receiver) = futures::StreamExt::split(socket);
+    let (sender, mut receiver) = tokio::sync::mpsc::channel::<SessionMessage>(16);
+    let writer = tokio::spawn(async move {
+        while let Some(m) = receiver.recv().await {
+            let s = serde_json::to_string(&m).unwrap();
+            if let Err(e) = ws_sender.send(Message::Text(s.into())).await {
+                log::warn!("Sending WebSocket message failed, err: {:?}", &e);
+                break;
+            }
+        }
+    });
+    // Frames are read by their own task, so that a disconnection is noticed while a turn runs
+    let (frame_sender, mut frames) = tokio::sync::mpsc::channel::<Message>(16);
+    let mut reader = tokio::spawn(async move {
+        while let Some(m) = futures::StreamExt::next(&mut ws_receiver).await {
+            match m {
+                Ok(Message::Close(_)) => break,
+                Ok(m) => {
+                    if frame_sender.send(m).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Receiving WebSocket message failed, err: {:?}", &e);
+                    break;
+                }
+            }
+        }
+    });
+    let mut session_ids: Vec<String> = Vec::with_capacity(1);
+    let mut timeout_req: Option<Request> = None;
+    let mut deadline: Option<tokio::time::Instant> = None;
+    loop {
+        let idle = async move {
+            match deadline {
+                Some(d) => tokio::time::sleep_until(d).await,
+                None => std::future::pending::<()>().await,
+            }
+        };
+        let mut req = tokio::select! {
+            m = frames.recv() => match m {
+                Some(Message::Text(t)) => match serde_json::from_str::<Request>(t.as_str()) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        let e = crate::result::Error::InvalidJsonStructure(Box::new(e));
+                        if sender.send(SessionMessage::Error(e)).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                },
+                Some(_) => continue,
+                None => break,
+            },
+            _ = idle => match timeout_req.take() {
+                Some(r) => r,
+                None => {
+                    deadline = None;
+                    continue;
+                }
+            },
+        };
+        deadline = None;
+        if let Err(e) = check_robot_scope(scope.as_ref(), &req.robot_id) {
+            if sender.send(SessionMessage::Error(e)).await.is_err() {
+                break;
+            }
+            continue;
+        }
+        if req.session_id.is_none() || req.session_id.as_ref().unwrap().is_empty() {
+            req.session_id = Some(scru128::new_string());
+        }
+        let session_id = req.session_id.as_ref().unwrap();
+        if !session_ids.contains(session_id) {
+            register(&WS_SESSIONS, session_id, connection_id, sender.clone());
+            session_ids.push(session_id.clone());
+        }
+        // The turn is dropped, so aborted, if the client disconnects before it finished
+        let terminated = tokio::select! {
+            terminated = ws_turn(&mut req, &sender) => terminated,
+            _ = &mut reader => {
+                log::info!("WebSocket client disconnected before the dialog finished");
+                break;
+            }
+        };
+        if !terminated
+            && let Some(sec) = req.user_input_timeout_sec
+            && sec > 0
+        {
+            deadline = Some(tokio::time::Instant::now() + Duration::from_secs(sec as u64));
+            timeout_req = Some(req.new_timeout_request());
+        } else {
+            timeout_req = None;
+        }
+    }
+    reader.abort();
+    for id in session_ids.iter() {
+        unregister(&WS_SESSIONS, id, connection_id);
+    }
+    drop(sender);
+    if let Err(e) = writer.await {
+        log::warn!("{:?}", &e);
+    }
+}
+
+// Runs one dialog turn and forwards all the streaming chunks before the final response, both are
+// sent before the next frame is read. Returns `true` if the dialog has been terminated.
+async fn ws_turn(req: &mut Request, sender: &Sender<SessionMessage>) -> bool {
     let now = std::time::Instant::now();
-    let (s, r) = tokio::sync::mpsc::channel::<String>(1);
-    let mut l = ANSWER_SSE_SESSIONS.lock().unwrap();
-    l.insert(String::new(), s);
-    log::info!("Response used time:{:?}", now.elapsed());
-    ""
+    let (s, mut r) = tokio::sync::mpsc::channel::<StreamingResponseData>(5);
+    let channel = ResponseChannelWrapper {
+        sender: Some(s),
+        receiver: None,
+    };
+    let process = async {
+        let result = executor::process_with_channel(req, channel).await;
+        log::info!("Response used time:{:?}", now.elapsed());
+        result
+    };
+    // Streaming may go on after processing returned, until every chunk has been forwarded
+    let forward = async {
+        while let Some(d) = r.recv().await {
+            if sender.send(SessionMessage::Chunk(d)).await.is_err() {
+                break;
+            }
+        }
+        // Stops the streaming if the connection was closed
+        drop(r);
+    };
+    let (result, _) = tokio::join!(process, forward);
+    let (m, terminated) = match result {
+        Ok((res, _)) => {
+            let terminated = res.next_action == NextActionType::Terminate;
+            (SessionMessage::Response(res), terminated)
+        }
+        Err(e) => (SessionMessage::Error(e), false),
+    };
+    if let Err(e) = sender.send(m).await {
+        log::warn!("Sending WebSocket response failed, err: {:?}", &e);
+    }
+    terminated
 }
 
-pub(super) fn get_sender(session_id: &str) -> Result<Option<Sender<String>>> {
-    let l = ANSWER_SSE_SESSIONS.lock()?;
-    if l.contains_key(session_id) {
-        let s = l.get(session_id).unwrap();
-        return Ok(Some(s.clone()));
+// Pushed to every connection of the session
+pub(crate) fn push(session_id: &str, data: ServerPushData) {
+    let senders: Vec<Sender<SessionMessage>> = match WS_SESSIONS.lock() {
+        Ok(l) => l
+            .get(session_id)
+            .map(|connections| connections.values().cloned().collect())
+            .unwrap_or_default(),
+        Err(e) => {
+            log::warn!("{:?}", &e);
+            vec![]
+        }
+    };
+    for s in senders.iter() {
+        if let Err(e) = s.try_send(SessionMessage::Push(data.clone())) {
+            log::warn!(
+                "Pushing message to session {session_id} failed, err: {:?}",
+                &e
+            );
+        }
     }
-    Ok(None)
+}
+
+// Sender of the latest connection of the session
+pub(super) fn get_sender(session_id: &str) -> Result<Option<Sender<StreamingResponseData>>> {
+    let l = ANSWER_SSE_SESSIONS.lock()?;
+    let sender = l.get(session_id).and_then(|connections| {
+        connections
+            .iter()
+            .max_by_key(|(id, _)| **id)
+            .and_then(|(_, s)| s.upgrade())
+    });
+    Ok(sender)
 }
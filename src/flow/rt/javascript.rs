@@ -0,0 +1,382 @@
+use core::time::Duration;
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::pin::pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context as TaskContext, Poll, Waker};
+
+use boa_engine::context::{ContextBuilder, HostHooks};
+use boa_engine::{Context as JsContext, JsValue, Script, Source};
+use serde_json::Value;
+use tokio::sync::Semaphore;
+
+use crate::result::{Error, Result};
+use crate::variable::dto::VariableValue;
+
+// Scripts are evaluated in a fresh engine without any host objects (no file system, network or
+// timers). The following limits keep a bad script from hogging memory or a blocking thread. The
+// instruction budget covers the instructions of the script and the functions it calls, code run
+// by builtins like `Array.prototype.map` is bounded by the loop and recursion limits, and a
+// script is also interrupted once it runs past the timeout. Memory is counted by the allocator
+// and checked whenever the script yields.
+const MAX_SCRIPT_LEN: usize = 64 * 1024;
+const MAX_INPUT_LEN: usize = 4 * 1024 * 1024;
+const MAX_OUTPUT_LEN: usize = 1024 * 1024;
+const LOOP_ITERATION_LIMIT: u64 = 1_000_000;
+const RECURSION_LIMIT: usize = 256;
+const STACK_SIZE_LIMIT: usize = 64 * 1024;
+const INSTRUCTION_BUDGET: usize = 20_000_000;
+// Instructions run between two checks of the interruption and the heap. A string doubles at
+// most every few instructions, so this keeps the heap within a few times the limit
+const YIELD_BUDGET: u32 = 8;
+const MAX_HEAP_BYTES: isize = 32 * 1024 * 1024;
+const EXECUTION_TIMEOUT_MILLIS: u64 = 500;
+// Largest array buffer, string or array built by a single builtin call
+const MAX_ALLOC_LEN: usize = 4 * 1024 * 1024;
+const MAX_RUNNING_SCRIPTS: usize = 16;
+// How long a script waits for one of the running ones to finish
+const ACQUIRE_TIMEOUT_MILLIS: u64 = 1000;
+
+// Builtins which allocate in a single call are limited, other allocations are bounded by the
+// heap limit
+const SANDBOX_PRELUDE: &str = r#"(() => {
+  const check = (n) => { if (n > MAX_ALLOC_LEN) throw new RangeError('Allocation too large'); };
+  const repeat = String.prototype.repeat;
+  String.prototype.repeat = function (count) {
+    check(String(this).length * Number(count));
+    return repeat.call(this, count);
+  };
+  for (const name of ['padStart', 'padEnd']) {
+    const pad = String.prototype[name];
+    String.prototype[name] = function (len, fill) {
+      check(Number(len));
+      return pad.call(this, len, fill);
+    };
+  }
+  for (const name of ['fill', 'join']) {
+    const f = Array.prototype[name];
+    Array.prototype[name] = function (...args) {
+      check(this.length);
+      return f.apply(this, args);
+    };
+  }
+})();
+"#;
+
+thread_local! {
+    // Bytes allocated minus bytes freed by this thread
+    static HEAP_USED: Cell<isize> = const { Cell::new(0) };
+}
+
+fn count_heap(delta: isize) {
+    // The thread may be exiting
+    let _ = HEAP_USED.try_with(|h| h.set(h.get().wrapping_add(delta)));
+}
+
+fn heap_used() -> isize {
+    HEAP_USED.try_with(Cell::get).unwrap_or(0)
+}
+
+// Counts the heap of each thread, so that scripts can be stopped once they allocate too much
+pub(crate) struct ScriptHeap<A>(pub(crate) A);
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ScriptHeap<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = unsafe { self.0.alloc(layout) };
+        if !p.is_null() {
+            count_heap(layout.size() as isize);
+        }
+        p
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let p = unsafe { self.0.alloc_zeroed(layout) };
+        if !p.is_null() {
+            count_heap(layout.size() as isize);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.0.dealloc(ptr, layout) };
+        count_heap(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let p = unsafe { self.0.realloc(ptr, layout, new_size) };
+        if !p.is_null() {
+            count_heap(new_size as isize - layout.size() as isize);
+        }
+        p
+    }
+}
+
+// Running scripts, including the ones abandoned after the timeout which have not stopped yet
+static RUNNING_SCRIPTS: Semaphore = Semaphore::const_new(MAX_RUNNING_SCRIPTS);
+
+struct SandboxHooks;
+
+impl HostHooks for SandboxHooks {
+    fn max_buffer_size(&self, _context: &mut JsContext) -> u64 {
+        MAX_ALLOC_LEN as u64
+    }
+}
+
+pub(crate) struct ScriptInput<'a> {
+    pub(crate) vars: &'a HashMap<String, VariableValue>,
+    pub(crate) user_input: &'a str,
+    pub(crate) intent: Option<&'a str>,
+    pub(crate) response: Option<&'a str>,
+}
+
+enum ScriptOutput {
+    Bool,
+    Text,
+}
+
+enum ScriptResult {
+    Bool(bool),
+    Text(String),
+}
+
+fn var_to_json(v: &VariableValue) -> Value {
+    match v {
+        VariableValue::Str(s) => Value::String(s.clone()),
+        VariableValue::Num(n) => {
+            serde_json::Number::from_f64(*n).map_or(Value::Null, Value::Number)
+        }
+        VariableValue::Array(arr) => Value::Array(arr.iter().map(var_to_json).collect()),
+    }
+}
+
+fn build_prelude(input: &ScriptInput) -> Result<String> {
+    let vars: serde_json::Map<String, Value> = input
+        .vars
+        .iter()
+        .map(|(k, v)| (k.clone(), var_to_json(v)))
+        .collect();
+    let response_json = input
+        .response
+        .and_then(|s| serde_json::from_str::<Value>(s).ok())
+        .unwrap_or(Value::Null);
+    let mut prelude = String::with_capacity(1024);
+    prelude.push_str("const vars = Object.freeze(");
+    prelude.push_str(&serde_json::to_string(&vars)?);
+    prelude.push_str(");\nconst userInput = ");
+    prelude.push_str(&serde_json::to_string(input.user_input)?);
+    prelude.push_str(";\nconst intent = ");
+    prelude.push_str(&serde_json::to_string(&input.intent)?);
+    prelude.push_str(";\nconst response = ");
+    prelude.push_str(&serde_json::to_string(&input.response)?);
+    prelude.push_str(";\nconst responseJson = ");
+    prelude.push_str(&serde_json::to_string(&response_json)?);
+    prelude.push_str(";\n");
+    if prelude.len() > MAX_INPUT_LEN {
+        return Err(Error::WithMessage(String::from(
+            "Script input data were too large",
+        )));
+    }
+    Ok(prelude)
+}
+
+// Evaluates the script a slice of instructions at a time, so that it stops once interrupted,
+// out of the instruction budget or over the heap limit, counted from `heap_start`
+fn evaluate(
+    context: &mut JsContext,
+    script: &str,
+    interrupted: &AtomicBool,
+    heap_start: isize,
+) -> Result<JsValue> {
+    let script = Script::parse(Source::from_bytes(script.as_bytes()), None, context)
+        .map_err(|e| Error::WithMessage(format!("Script execution failed: {e}")))?;
+    let mut evaluation = pin!(script.evaluate_async_with_budget(context, YIELD_BUDGET));
+    let mut cx = TaskContext::from_waker(Waker::noop());
+    for _ in 0..INSTRUCTION_BUDGET / YIELD_BUDGET as usize {
+        if let Poll::Ready(r) = evaluation.as_mut().poll(&mut cx) {
+            return r.map_err(|e| Error::WithMessage(format!("Script execution failed: {e}")));
+        }
+        if interrupted.load(Ordering::Relaxed) {
+            return Err(Error::WithMessage(String::from("Script execution timeout")));
+        }
+        if heap_used().wrapping_sub(heap_start) > MAX_HEAP_BYTES {
+            return Err(Error::WithMessage(String::from(
+                "Script ran out of the memory limit",
+            )));
+        }
+    }
+    Err(Error::WithMessage(String::from(
+        "Script ran out of the instruction budget",
+    )))
+}
+
+fn run(
+    prelude: String,
+    script: String,
+    output: ScriptOutput,
+    interrupted: &AtomicBool,
+) -> Result<ScriptResult> {
+    let heap_start = heap_used();
+    let mut context = ContextBuilder::new()
+        .host_hooks(Rc::new(SandboxHooks))
+        .build()
+        .map_err(|e| Error::WithMessage(format!("Creating script engine failed: {e}")))?;
+    let limits = context.runtime_limits_mut();
+    limits.set_loop_iteration_limit(LOOP_ITERATION_LIMIT);
+    limits.set_recursion_limit(RECURSION_LIMIT);
+    limits.set_stack_size_limit(STACK_SIZE_LIMIT);
+    let sandbox = SANDBOX_PRELUDE.replace("MAX_ALLOC_LEN", &MAX_ALLOC_LEN.to_string());
+    context
+        .eval(Source::from_bytes(sandbox.as_bytes()))
+        .and_then(|_| context.eval(Source::from_bytes(prelude.as_bytes())))
+        .map_err(|e| Error::WithMessage(format!("Script prelude failed: {e}")))?;
+    // The completion value of the last statement is the result, so both a single expression
+    // and a block of statements work.
+    let v = evaluate(&mut context, &script, interrupted, heap_start)?;
+    match output {
+        ScriptOutput::Bool => Ok(ScriptResult::Bool(v.to_boolean())),
+        ScriptOutput::Text => {
+            if v.is_undefined() || v.is_null() {
+                return Ok(ScriptResult::Text(String::new()));
+            }
+            let s = if let Some(s) = v.as_string() {
+                s.to_std_string_escaped()
+            } else {
+                v.to_json(&mut context)
+                    .map_err(|e| Error::WithMessage(format!("Script execution failed: {e}")))?
+                    .map_or_else(String::new, |j| j.to_string())
+            };
+            if s.len() > MAX_OUTPUT_LEN {
+                return Err(Error::WithMessage(String::from(
+                    "Script result was too large",
+                )));
+            }
+            Ok(ScriptResult::Text(s))
+        }
+    }
+}
+
+async fn exec(script: &str, input: &ScriptInput<'_>, output: ScriptOutput) -> Result<ScriptResult> {
+    if script.len() > MAX_SCRIPT_LEN {
+        return Err(Error::WithMessage(String::from("Script was too long")));
+    }
+    let prelude = build_prelude(input)?;
+    let script = String::from(script);
+    let acquire = RUNNING_SCRIPTS.acquire();
+    let permit = tokio::time::timeout(Duration::from_millis(ACQUIRE_TIMEOUT_MILLIS), acquire)
+        .await
+        .map_err(|_| {
+            Error::WithMessage(String::from(
+                "Script execution timeout, too many scripts are running",
+            ))
+        })?
+        .map_err(|e| Error::WithMessage(format!("{e:?}")))?;
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    let handle = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        run(prelude, script, output, &flag)
+    });
+    match tokio::time::timeout(Duration::from_millis(EXECUTION_TIMEOUT_MILLIS), handle).await {
+        Ok(r) => r?,
+        Err(_) => {
+            interrupted.store(true, Ordering::Relaxed);
+            Err(Error::WithMessage(String::from("Script execution timeout")))
+        }
+    }
+}
+
+pub(crate) async fn eval_condition(script: &str, input: &ScriptInput<'_>) -> Result<bool> {
+    match exec(script, input, ScriptOutput::Bool).await? {
+        ScriptResult::Bool(b) => Ok(b),
+        ScriptResult::Text(s) => Ok(!s.is_empty()),
+    }
+}
+
+pub(crate) async fn eval_text(script: &str, input: &ScriptInput<'_>) -> Result<String> {
+    match exec(script, input, ScriptOutput::Text).await? {
+        ScriptResult::Bool(b) => Ok(b.to_string()),
+        ScriptResult::Text(s) => Ok(s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_text(script: &str) -> Result<String> {
+        let vars = HashMap::from([(String::from("n"), VariableValue::Num(2.0))]);
+        let input = ScriptInput {
+            vars: &vars,
+            user_input: "hi",
+            intent: None,
+            response: None,
+        };
+        let prelude = build_prelude(&input)?;
+        let interrupted = AtomicBool::new(false);
+        match run(
+            prelude,
+            String::from(script),
+            ScriptOutput::Text,
+            &interrupted,
+        )? {
+            ScriptResult::Text(s) => Ok(s),
+            ScriptResult::Bool(b) => Ok(b.to_string()),
+        }
+    }
+
+    #[test]
+    fn evaluates_statements() {
+        assert_eq!(run_text("vars.n * 3").unwrap(), "6");
+        assert_eq!(run_text("let s = userInput; s + '!'").unwrap(), "hi!");
+        assert_eq!(run_text("({a: [1, 2]})").unwrap(), r#"{"a":[1,2]}"#);
+        assert_eq!(run_text("undefined").unwrap(), "");
+    }
+
+    #[test]
+    fn stops_nested_loops_and_recursion() {
+        assert!(run_text("let c = 0; for (;;) { for (let i = 0; i < 1000; i++) c++; }").is_err());
+        assert!(run_text("function f() { for (let i = 0; i < 1e5; i++); f(); } f()").is_err());
+        assert!(run_text("[1, 2].map(() => { for (;;); })").is_err());
+    }
+
+    #[test]
+    fn stops_after_instruction_budget() {
+        let r = run_text("function f() { for (let i = 0; i < 9e5; i++); } for (;;) f();");
+        assert!(matches!(r, Err(Error::WithMessage(m)) if m.contains("instruction budget")));
+    }
+
+    #[test]
+    fn limits_bulk_allocations() {
+        assert!(run_text("'x'.repeat(1e9).length").is_err());
+        assert!(run_text("''.padEnd(1e9).length").is_err());
+        assert!(run_text("new ArrayBuffer(1e9).byteLength").is_err());
+        assert_eq!(run_text("'ab'.repeat(2)").unwrap(), "abab");
+    }
+
+    #[test]
+    fn stops_growing_strings() {
+        let r = run_text("let s = 'x'; for (let i = 0; i < 30; i++) s = s + s; s.length");
+        assert!(matches!(r, Err(Error::WithMessage(m)) if m.contains("memory limit")));
+        let r = run_text("let s = 'x'; for (let i = 0; i < 30; i++) s = s.concat(s, s); s.length");
+        assert!(matches!(r, Err(Error::WithMessage(m)) if m.contains("memory limit")));
+        assert_eq!(
+            run_text("let s = 'x'; for (let i = 0; i < 10; i++) s = s + s; s.length").unwrap(),
+            "1024"
+        );
+    }
+
+    #[test]
+    fn stops_when_interrupted() {
+        let interrupted = AtomicBool::new(true);
+        let r = run(
+            String::new(),
+            String::from("for (;;);"),
+            ScriptOutput::Bool,
+            &interrupted,
+        );
+        assert!(r.is_err());
+    }
+}
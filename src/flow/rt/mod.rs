@@ -5,7 +5,9 @@ pub(crate) mod convertor;
 pub(crate) mod crud;
 pub(crate) mod dto;
 pub(crate) mod executor;
+pub(crate) mod expression;
 pub(crate) mod facade;
+pub(crate) mod javascript;
 pub(crate) mod node;
 // pub(crate) mod node_impl;
 // pub(crate) mod request;
@@ -1,4 +1,5 @@
 use core::time::Duration;
+use std::collections::HashMap;
 // use std::ops::DerefMut;
 
 // use enum_dispatch::enum_dispatch;
@@ -6,22 +7,25 @@ use lettre::transport::smtp::PoolConfig;
 use rkyv::{Archive, Deserialize, Serialize, util::AlignedVec};
 
 use super::condition::ConditionData;
-use super::context::Context;
+use super::context::{CallFrame, Context};
 use super::dto::{
-    AnswerContentType, AnswerData, CollectData, Request, ResponseChannelWrapper, ResponseData,
-    StreamingResponseData,
+    AnswerContentType, AnswerData, AnswerPayload, CollectData, Request, ResponseChannelWrapper,
+    ResponseData, ServerPushData, StreamingResponseData,
 };
 use crate::ai::chat::{ResultSender, SenderWrapper};
 use crate::ai::completion::Prompt;
 use crate::external::http::client as http;
 use crate::flow::rt::collector;
+use crate::flow::rt::expression;
 use crate::flow::subflow::dto::NextActionType;
+use crate::kb::rag::{self, Passage};
 use crate::man::settings::get_settings;
-use crate::result::Result;
+use crate::result::{Error, Result};
 use crate::variable::crud as variable;
 use crate::variable::dto::{VariableType, VariableValue};
 
 const VAR_WRAP_SYMBOL: char = '`';
+const MAX_SUBFLOW_CALL_DEPTH: usize = 16;
 
 // #[repr(u8)]
 // #[derive(PartialEq)]
@@ -48,6 +52,9 @@ pub(crate) enum RuntimeNodeEnum {
     SendEmailNode(SendEmailNode),
     LlmChatNode(LlmChatNode),
     KnowledgeBaseAnswerNode(KnowledgeBaseAnswerNode),
+    SubFlowCallNode(SubFlowCallNode),
+    ReturnNode(ReturnNode),
+    SetVariableNode(SetVariableNode),
 }
 
 impl RuntimeNode for RuntimeNodeEnum {
@@ -76,6 +83,9 @@ impl RuntimeNode for RuntimeNodeEnum {
             RuntimeNodeEnum::KnowledgeBaseAnswerNode(n) => {
                 n.exec(req, ctx, response, channel_sender).await
             }
+            RuntimeNodeEnum::SubFlowCallNode(n) => n.exec(req, ctx, response, channel_sender).await,
+            RuntimeNodeEnum::ReturnNode(n) => n.exec(req, ctx, response, channel_sender).await,
+            RuntimeNodeEnum::SetVariableNode(n) => n.exec(req, ctx, response, channel_sender).await,
         }
     }
 }
@@ -151,11 +161,28 @@ fn add_next_node(ctx: &mut Context, next_node_id: &str) {
     ctx.add_node(next_node_id);
 }
 
+async fn assign_params(params: &[SubFlowCallParam], req: &Request, ctx: &mut Context) {
+    for p in params.iter() {
+        match replace_vars(&p.value, req, ctx).await {
+            Ok(v) => {
+                let var_type = match variable::get(&req.robot_id, &p.var_name) {
+                    Ok(Some(var)) => var.var_type,
+                    _ => VariableType::Str,
+                };
+                ctx.vars
+                    .insert(p.var_name.clone(), VariableValue::new(&v, &var_type));
+            }
+            Err(e) => log::error!("{e:?}"),
+        }
+    }
+}
+
 #[derive(Archive, Deserialize, Serialize)]
 #[rkyv(compare(PartialEq))]
 pub(crate) struct TextNode {
     pub(super) text: String,
     pub(crate) text_type: AnswerContentType,
+    pub(super) payload: Option<AnswerPayload>,
     pub(super) ret: bool,
     pub(super) next_node_id: String,
 }
@@ -170,24 +197,41 @@ impl RuntimeNode for TextNode {
     ) -> bool {
         // log::info!("Into TextNode {}", &self.text);
         // let now = std::time::Instant::now();
-        match replace_vars(&self.text, req, ctx).await {
-            Ok(answer) => {
-                if channel_sender.sender.is_some() {
-                    let sender = channel_sender.sender.as_ref().unwrap().clone();
-                    let streaming = StreamingResponseData {
-                        content_seq: Some(ctx.add_answer_history(&answer)),
-                        content: answer,
-                    };
-                    crate::sse_send!(sender, streaming);
-                } else {
-                    response.answers.push(AnswerData {
-                        content: answer,
-                        content_type: self.text_type.clone(),
-                    })
+        if !self.text.is_empty() || self.payload.is_none() {
+            match replace_vars(&self.text, req, ctx).await {
+                Ok(answer) => {
+                    if channel_sender.sender.is_some() {
+                        let sender = channel_sender.sender.as_ref().unwrap().clone();
+                        let streaming = StreamingResponseData {
+                            content_seq: Some(ctx.add_answer_history(&answer)),
+                            content: answer,
+                        };
+                        crate::sse_send!(sender, streaming);
+                    } else {
+                        response.answers.push(AnswerData {
+                            content: answer,
+                            content_type: self.text_type.clone(),
+                            payload: None,
+                        })
+                    }
+                }
+                Err(e) => log::error!("{e:?}"),
+            };
+        }
+        if let Some(mut payload) = self.payload.take() {
+            for text in payload.texts_mut() {
+                match replace_vars(text, req, ctx).await {
+                    Ok(t) => *text = t,
+                    Err(e) => log::error!("{e:?}"),
                 }
             }
-            Err(e) => log::error!("{e:?}"),
-        };
+            // Structured payloads are not streamed, they are always in the final response
+            response.answers.push(AnswerData {
+                content: payload.fallback_text(),
+                content_type: payload.content_type(),
+                payload: Some(payload),
+            })
+        }
         // log::info!("add {}", &self.next_node_id);
         add_next_node(ctx, &self.next_node_id);
         // log::info!("TextNode used time:{:?}", now.elapsed());
@@ -304,11 +348,13 @@ impl RuntimeNode for LlmGenTextNode {
                     response.answers.push(AnswerData {
                         content: self.fallback_text.clone(),
                         content_type: AnswerContentType::TextPlain,
+                        payload: None,
                     });
                 } else {
                     response.answers.push(AnswerData {
                         content: s,
                         content_type: AnswerContentType::TextPlain,
+                        payload: None,
                     });
                 }
             }
@@ -422,14 +468,13 @@ impl RuntimeNode for CollectNode {
         _channel_sender: &mut ResponseChannelWrapper,
     ) -> bool {
         // println!("Into CollectNode");
-        if let Some(r) = collector::collect(&req.user_input, &self.collect_type) {
+        if let Some(v) = collector::collect(&req.user_input, &self.collect_type) {
             // println!("{} {}", &self.var_name, r);
-            let v = VariableValue::new(r, &VariableType::Str);
-            ctx.vars.insert(self.var_name.clone(), v);
             let collect_data = CollectData {
                 var_name: self.var_name.clone(),
-                value: String::from(r),
+                value: v.val_to_string(),
             };
+            ctx.vars.insert(self.var_name.clone(), v);
             response.collect_data.push(collect_data);
             add_next_node(ctx, &self.successful_node_id);
             // println!("{} {}", r, &self.successful_node_id);
@@ -520,11 +565,25 @@ impl RuntimeNode for ExternalHttpCallNode {
             crate::external::http::crud::get_detail(&req.robot_id, self.http_api_id.as_str())
         {
             if self.async_req {
-                tokio::spawn(http::status_code(
-                    api,
-                    self.timeout_milliseconds,
-                    ctx.vars.clone(),
-                ));
+                let session_id = req.session_id.clone().unwrap_or_default();
+                let http_api_id = self.http_api_id.clone();
+                let timeout_milliseconds = self.timeout_milliseconds;
+                let vars = ctx.vars.clone();
+                tokio::spawn(async move {
+                    let data = match http::status_code(api, timeout_milliseconds, vars).await {
+                        Ok(r) => ServerPushData::ExternalHttpCompleted {
+                            http_api_id,
+                            status_code: Some(r),
+                            err: None,
+                        },
+                        Err(e) => ServerPushData::ExternalHttpCompleted {
+                            http_api_id,
+                            status_code: None,
+                            err: Some(format!("{e:?}")),
+                        },
+                    };
+                    super::facade::push(&session_id, data);
+                });
             } else {
                 match http::status_code(api, self.timeout_milliseconds, ctx.vars.clone()).await {
                     Ok(r) => {
@@ -559,7 +618,11 @@ pub(crate) struct SendEmailNode {
 }
 
 impl SendEmailNode {
-    fn send_email(&self, settings: &crate::man::settings::Settings) -> Result<()> {
+    fn send_email(
+        &self,
+        settings: &crate::man::settings::Settings,
+        session_id: &str,
+    ) -> Result<()> {
         use lettre::transport::smtp::authentication::Credentials;
         use lettre::{
             AsyncSmtpTransport, AsyncTransport, SmtpTransport, Tokio1Executor, Transport,
@@ -612,11 +675,18 @@ impl SendEmailNode {
                 )))
                 .pool_config(pool)
                 .build();
+            let session_id = String::from(session_id);
+            let subject = self.subject.clone();
             tokio::spawn(async move {
                 // mailer.send(email) // will be wrong
-                if let Err(e) = mailer.send(email).await {
-                    log::error!("Failed to send email, failure reason is: {e:?}");
-                }
+                let err = match mailer.send(email).await {
+                    Ok(_) => None,
+                    Err(e) => {
+                        log::error!("Failed to send email, failure reason is: {e:?}");
+                        Some(format!("{e:?}"))
+                    }
+                };
+                super::facade::push(&session_id, ServerPushData::EmailSent { subject, err });
             });
             Ok(())
         } else {
@@ -646,7 +716,8 @@ impl RuntimeNode for SendEmailNode {
         // println!("Into SendEmailNode");
         if let Ok(Some(settings)) = get_settings(&req.robot_id) {
             if !settings.smtp_host.is_empty() {
-                match self.send_email(&settings) {
+                let session_id = req.session_id.as_deref().unwrap_or_default();
+                match self.send_email(&settings, session_id) {
                     Ok(_) => add_next_node(ctx, &self.successful_node_id),
                     Err(_) => add_next_node(ctx, self.goto_node_id.as_ref().unwrap()),
                 }
@@ -802,6 +873,7 @@ impl LlmChatNode {
                     response.answers.push(AnswerData {
                         content: s,
                         content_type: AnswerContentType::TextPlain,
+                        payload: None,
                     });
                     if contains_certain_str {
                         return false;
@@ -979,6 +1051,7 @@ impl RuntimeNode for LlmChatNode {
                     response.answers.push(AnswerData {
                         content: s,
                         content_type: AnswerContentType::TextPlain,
+                        payload: None,
                     });
                     if contains_certain_str {
                         add_next_node(ctx, &self.next_node_id);
@@ -1047,31 +1120,50 @@ pub(crate) enum KnowledgeBaseAnswerSource {
     Doc,
 }
 
+#[derive(Archive, Clone, Default, Deserialize, Serialize, serde::Deserialize)]
+#[rkyv(compare(PartialEq))]
+pub(crate) enum KnowledgeBaseAnswerMode {
+    // Stored answers of QnA, or answers generated from the best matched document chunks
+    #[default]
+    Verbatim,
+    // Answers generated from all the retrieved candidates with citations
+    Generative,
+}
+
 #[derive(Archive, Clone, Deserialize, Serialize)]
 #[rkyv(compare(PartialEq))]
 pub(crate) struct KnowledgeBaseAnswerNode {
     pub(super) recall_distance: f64,
     pub(super) retrieve_answer_sources: Vec<crate::flow::rt::node::KnowledgeBaseAnswerSource>,
     pub(super) no_recall_then: KnowledgeBaseAnswerNoRecallThen,
+    pub(super) top_k: u8,
+    pub(super) answer_mode: KnowledgeBaseAnswerMode,
+    pub(super) context_len: u8,
+    pub(super) response_streaming: bool,
     pub(super) next_node_id: String,
 }
 
 impl KnowledgeBaseAnswerNode {
     async fn retrieve_qa_answer(&self, req: &Request) -> Option<String> {
-        match crate::kb::qa::retrieve_answer(&req.robot_id, &req.user_input).await {
-            Ok((answer, distance)) => {
-                log::info!(
-                    "distance {} recall_distance {}",
-                    distance,
-                    self.recall_distance
-                );
-                if let Some(a) = answer
-                    && distance <= self.recall_distance
-                {
-                    Some(a.answer)
-                } else {
-                    None
+        let r = crate::kb::qa::retrieve_answers(
+            &req.robot_id,
+            &req.user_input,
+            self.recall_distance,
+            self.top_k as usize,
+        )
+        .await;
+        match r {
+            Ok(candidates) => {
+                for c in candidates.iter() {
+                    log::info!(
+                        "QnA candidate distance {} keyword {:?} score {} recall_distance {}",
+                        c.distance,
+                        c.keyword_score,
+                        c.score,
+                        self.recall_distance
+                    );
                 }
+                candidates.into_iter().next().map(|c| c.qa.answer)
             }
             Err(e) => {
                 log::error!("KnowledgeBaseAnswerNode retrieve QnA failed: {:?}", &e);
@@ -1084,6 +1176,7 @@ impl KnowledgeBaseAnswerNode {
             &req.robot_id,
             &req.user_input,
             self.recall_distance,
+            self.top_k as usize,
             1000,
             5000,
         )
@@ -1096,7 +1189,145 @@ impl KnowledgeBaseAnswerNode {
             }
         }
     }
+    async fn retrieve_passages(&self, req: &Request) -> Vec<Passage> {
+        let mut passages: Vec<Passage> = Vec::with_capacity(self.top_k as usize);
+        for answer_source in &self.retrieve_answer_sources {
+            let r = match answer_source {
+                KnowledgeBaseAnswerSource::QnA => crate::kb::qa::retrieve_answers(
+                    &req.robot_id,
+                    &req.user_input,
+                    self.recall_distance,
+                    self.top_k as usize,
+                )
+                .await
+                .map(|c| c.into_iter().map(Passage::from).collect::<Vec<Passage>>()),
+                KnowledgeBaseAnswerSource::Doc => crate::kb::doc::search(
+                    &req.robot_id,
+                    &req.user_input,
+                    self.recall_distance,
+                    self.top_k as usize,
+                )
+                .await
+                .map(|c| c.into_iter().map(Passage::from).collect::<Vec<Passage>>()),
+            };
+            match r {
+                Ok(p) => passages.extend(p),
+                Err(e) => {
+                    log::warn!("KnowledgeBaseAnswerNode retrieve {answer_source:?} failed {e:?}")
+                }
+            }
+        }
+        passages
+    }
+    // Returns false if nothing was retrieved or the model found no answer in the passages
+    async fn generate_answer(
+        &self,
+        req: &Request,
+        ctx: &mut Context,
+        response: &mut ResponseData,
+        channel_sender: &mut ResponseChannelWrapper,
+    ) -> bool {
+        let passages = self.retrieve_passages(req).await;
+        if passages.is_empty() {
+            return false;
+        }
+        // The last one is current user input
+        let len = ctx.chat_history.len();
+        let history_start = len.saturating_sub(self.context_len as usize + 1);
+        let history = &ctx.chat_history[history_start..len.saturating_sub(1)];
+        let prompts = rag::build_prompts(&passages, history, &req.user_input);
+        if self.response_streaming
+            && let Some(client_sender) = channel_sender.sender.clone()
+        {
+            let (s, mut r) = tokio::sync::mpsc::channel::<StreamingResponseData>(8);
+            let robot_id = req.robot_id.clone();
+            tokio::task::spawn(async move {
+                let sender_wrapper = SenderWrapper {
+                    sender: s,
+                    content_seq: 0,
+                };
+                if let Err(e) = crate::ai::chat::chat(
+                    &robot_id,
+                    Some(prompts),
+                    None,
+                    None,
+                    ResultSender::ChannelSender(sender_wrapper),
+                )
+                .await
+                {
+                    log::warn!("KnowledgeBaseAnswerNode response failed, err: {:?}", &e);
+                }
+            });
+            let mut answer = String::with_capacity(1024);
+            let mut unsent = String::with_capacity(64);
+            let mut filter = rag::MarkerFilter::default();
+            let mut content_seq: Option<usize> = None;
+            while let Some(d) = r.recv().await {
+                answer.push_str(&d.content);
+                unsent.push_str(&filter.push(&d.content));
+                // Nothing is sent before the answer turns out not to be the marker
+                if unsent.is_empty() || (content_seq.is_none() && unsent.trim().is_empty()) {
+                    continue;
+                }
+                let seq = *content_seq.get_or_insert_with(|| ctx.add_answer_history(""));
+                let data = StreamingResponseData {
+                    content_seq: Some(seq),
+                    content: std::mem::take(&mut unsent),
+                };
+                if let Err(e) = client_sender.send(data).await {
+                    log::warn!("KnowledgeBaseAnswerNode streaming failed, err: {:?}", &e);
+                }
+            }
+            unsent.push_str(&filter.finish());
+            if content_seq.is_none() && rag::is_no_answer(&answer) {
+                return false;
+            }
+            unsent.push_str(&rag::sources(&answer, &passages));
+            if !unsent.is_empty() {
+                let seq = content_seq.unwrap_or_else(|| ctx.add_answer_history(""));
+                let data = StreamingResponseData {
+                    content_seq: Some(seq),
+                    content: unsent,
+                };
+                if let Err(e) = client_sender.send(data).await {
+                    log::warn!("KnowledgeBaseAnswerNode streaming failed, err: {:?}", &e);
+                }
+            }
+            let answer = answer.replace(rag::NO_ANSWER, "");
+            if let Some(p) = ctx.chat_history.last_mut() {
+                p.content = super::executor::HTML_TAG_REGEX
+                    .replace_all(&answer, "")
+                    .to_string();
+            }
+            true
+        } else {
+            let mut s = String::with_capacity(1024);
+            if let Err(e) = crate::ai::chat::chat(
+                &req.robot_id,
+                Some(prompts),
+                None,
+                None,
+                ResultSender::StrBuf(&mut s),
+            )
+            .await
+            {
+                log::error!("KnowledgeBaseAnswerNode response failed, err: {:?}", &e);
+                return false;
+            }
+            if rag::is_no_answer(&s) {
+                return false;
+            }
+            s.push_str(&rag::sources(&s, &passages));
+            response.answers.push(AnswerData {
+                content: s,
+                content_type: AnswerContentType::TextPlain,
+                payload: None,
+            });
+            true
+        }
+    }
     fn fallback_answer(&self, ctx: &mut Context, response: &mut ResponseData) -> bool {
+        ctx.kb_no_recall = Some(true);
         match &self.no_recall_then {
             KnowledgeBaseAnswerNoRecallThen::GotoAnotherNode => {
                 add_next_node(ctx, &self.next_node_id);
@@ -1106,6 +1337,7 @@ impl KnowledgeBaseAnswerNode {
                 response.answers.push(AnswerData {
                     content: s.clone(),
                     content_type: AnswerContentType::TextPlain,
+                    payload: None,
                 });
                 let r = RuntimeNodeEnum::KnowledgeBaseAnswerNode(self.clone());
                 let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&r).unwrap();
@@ -1122,9 +1354,17 @@ impl RuntimeNode for KnowledgeBaseAnswerNode {
         req: &Request,
         ctx: &mut Context,
         response: &mut ResponseData,
-        _channel_sender: &mut ResponseChannelWrapper,
+        channel_sender: &mut ResponseChannelWrapper,
     ) -> bool {
         // log::info!("Into LlmChaKnowledgeBaseAnswerNodetNode");
+        if let KnowledgeBaseAnswerMode::Generative = self.answer_mode {
+            if self.generate_answer(req, ctx, response, channel_sender).await {
+                ctx.kb_no_recall.get_or_insert(false);
+                add_next_node(ctx, &self.next_node_id);
+                return false;
+            }
+            return self.fallback_answer(ctx, response);
+        }
         for answer_source in &self.retrieve_answer_sources {
             log::info!("answer_source={:?}", &answer_source);
             let r = match answer_source {
@@ -1137,7 +1377,9 @@ impl RuntimeNode for KnowledgeBaseAnswerNode {
                 response.answers.push(AnswerData {
                     content: content,
                     content_type: AnswerContentType::TextPlain,
+                    payload: None,
                 });
+                ctx.kb_no_recall.get_or_insert(false);
                 add_next_node(ctx, &self.next_node_id);
                 return false;
             }
@@ -1177,13 +1419,246 @@ impl RuntimeNode for KnowledgeBaseAnswerNode {
     }
 }
 
+#[derive(Archive, Clone, Deserialize, Serialize, serde::Deserialize, serde::Serialize)]
+#[rkyv(compare(PartialEq))]
+pub(crate) struct SubFlowCallParam {
+    #[serde(rename = "varName")]
+    pub(crate) var_name: String,
+    pub(crate) value: String,
+}
+
+#[derive(Archive, Deserialize, Serialize)]
+#[rkyv(compare(PartialEq))]
+pub(crate) struct SubFlowCallNode {
+    pub(super) subflow_node_id: String,
+    pub(super) input_params: Vec<SubFlowCallParam>,
+    pub(super) output_params: Vec<SubFlowCallParam>,
+    pub(super) return_node_id: String,
+}
+
+impl RuntimeNode for SubFlowCallNode {
+    async fn exec(
+        &mut self,
+        req: &Request,
+        ctx: &mut Context,
+        _response: &mut ResponseData,
+        _channel_sender: &mut ResponseChannelWrapper,
+    ) -> bool {
+        // log::info!("Into SubFlowCallNode");
+        if ctx.call_stack.len() >= MAX_SUBFLOW_CALL_DEPTH {
+            log::warn!(
+                "Sub-flow call depth exceeds {MAX_SUBFLOW_CALL_DEPTH}, skipped calling {}",
+                &self.subflow_node_id
+            );
+            add_next_node(ctx, &self.return_node_id);
+            return false;
+        }
+        assign_params(&self.input_params, req, ctx).await;
+        ctx.call_stack.push(CallFrame {
+            main_flow_id: ctx.main_flow_id.clone(),
+            return_node_id: std::mem::take(&mut self.return_node_id),
+            output_params: std::mem::take(&mut self.output_params),
+        });
+        add_next_node(ctx, &self.subflow_node_id);
+        false
+    }
+}
+
+#[derive(Archive, Deserialize, Serialize)]
+#[rkyv(compare(PartialEq))]
+pub(crate) struct ReturnNode {}
+
+impl RuntimeNode for ReturnNode {
+    async fn exec(
+        &mut self,
+        req: &Request,
+        ctx: &mut Context,
+        response: &mut ResponseData,
+        channel_sender: &mut ResponseChannelWrapper,
+    ) -> bool {
+        // log::info!("Into ReturnNode");
+        if let Some(frame) = ctx.call_stack.pop() {
+            assign_params(&frame.output_params, req, ctx).await;
+            ctx.main_flow_id = frame.main_flow_id;
+            add_next_node(ctx, &frame.return_node_id);
+            false
+        } else {
+            // Nothing to return to, so it works like an end node
+            response.next_action = NextActionType::Terminate;
+            if channel_sender.sender.is_some() {
+                channel_sender.send_response(response);
+            }
+            true
+        }
+    }
+}
+
+#[derive(Archive, Clone, Deserialize, Serialize, serde::Deserialize, serde::Serialize)]
+#[rkyv(compare(PartialEq))]
+pub(crate) struct VariableAssignment {
+    #[serde(rename = "varName")]
+    pub(crate) var_name: String,
+    pub(crate) expression: String,
+}
+
+#[derive(Archive, Deserialize, Serialize)]
+#[rkyv(compare(PartialEq))]
+pub(crate) struct SetVariableNode {
+    pub(super) assignments: Vec<VariableAssignment>,
+    pub(super) next_node_id: String,
+}
+
+impl SetVariableNode {
+    async fn assign(a: &VariableAssignment, req: &Request, ctx: &mut Context) -> Result<()> {
+        let expr = expression::parse(&a.expression)?;
+        let mut names: Vec<String> = Vec::with_capacity(4);
+        expr.var_names(&mut names);
+        let mut vars: HashMap<String, VariableValue> = HashMap::with_capacity(names.len());
+        for name in names {
+            // Values assigned in this session take precedence over the variable definitions
+            let v = if let Some(v) = ctx.vars.get(&name) {
+                Some(v.clone())
+            } else if let Some(var) = variable::get(&req.robot_id, &name)? {
+                var.get_value2(req, ctx).await.cloned()
+            } else {
+                None
+            };
+            if let Some(v) = v {
+                vars.insert(name, v);
+            }
+        }
+        let v = expr.eval(&vars)?;
+        let v = match variable::get(&req.robot_id, &a.var_name)? {
+            Some(var) => match (var.var_type, v) {
+                (VariableType::Str, VariableValue::Num(n)) => VariableValue::Str(n.to_string()),
+                (VariableType::Num, VariableValue::Str(s)) => match s.trim().parse::<f64>() {
+                    Ok(n) => VariableValue::Num(n),
+                    Err(_) => {
+                        return Err(Error::WithMessage(format!(
+                            "Can not assign: {s} to number variable: {}",
+                            &a.var_name
+                        )));
+                    }
+                },
+                (_, v) => v,
+            },
+            None => v,
+        };
+        ctx.vars.insert(a.var_name.clone(), v);
+        Ok(())
+    }
+}
+
+impl RuntimeNode for SetVariableNode {
+    async fn exec(
+        &mut self,
+        req: &Request,
+        ctx: &mut Context,
+        _response: &mut ResponseData,
+        _channel_sender: &mut ResponseChannelWrapper,
+    ) -> bool {
+        // log::info!("Into SetVariableNode");
+        for a in self.assignments.iter() {
+            if let Err(e) = Self::assign(a, req, ctx).await {
+                log::warn!("Set variable: {} failed, err: {:?}", &a.var_name, &e);
+            }
+        }
+        add_next_node(ctx, &self.next_node_id);
+        false
+    }
+}
+
 pub(crate) fn deser_node(bytes: &[u8]) -> Result<RuntimeNodeEnum> {
     // let now = std::time::Instant::now();
     let mut v = AlignedVec::<256>::with_capacity(bytes.len());
     v.extend_from_slice(bytes);
-    let r = rkyv::from_bytes::<RuntimeNodeEnum, rkyv::rancor::Error>(&v).unwrap();
+    let r = rkyv::from_bytes::<RuntimeNodeEnum, rkyv::rancor::Error>(&v)
+        .map_err(|e| Error::WithMessage(format!("Invalid runtime node: {e}")))?;
     // let archived = rkyv::access::<ArchivedRuntimeNnodeEnum, rkyv::rancor::Error>(bytes).unwrap();
     // let deserialized = rkyv::deserialize::<RuntimeNnodeEnum, rkyv::rancor::Error>(archived).unwrap();
     // log::info!("deser_node time {:?}", now.elapsed());
     Ok(r)
 }
+
+// Layout of the nodes released before runtime node layouts were versioned, only the nodes
+// listed here were changed since then
+mod v1 {
+    use rkyv::{Archive, Deserialize};
+
+    use super::{
+        AnswerContentType, CollectNode, ConditionNode, ExternalHttpCallNode, GotoAnotherNode,
+        GotoMainFlowNode, KnowledgeBaseAnswerNoRecallThen, KnowledgeBaseAnswerSource, LlmChatNode,
+        LlmGenTextNode, SendEmailNode, TerminateNode,
+    };
+
+    #[derive(Archive, Deserialize)]
+    pub(super) struct TextNode {
+        pub(super) text: String,
+        pub(super) text_type: AnswerContentType,
+        pub(super) ret: bool,
+        pub(super) next_node_id: String,
+    }
+
+    #[derive(Archive, Deserialize)]
+    pub(super) struct KnowledgeBaseAnswerNode {
+        pub(super) recall_distance: f64,
+        pub(super) retrieve_answer_sources: Vec<KnowledgeBaseAnswerSource>,
+        pub(super) no_recall_then: KnowledgeBaseAnswerNoRecallThen,
+        pub(super) next_node_id: String,
+    }
+
+    #[derive(Archive, Deserialize)]
+    pub(super) enum RuntimeNodeEnum {
+        TextNode(TextNode),
+        LlmGenTextNode(LlmGenTextNode),
+        ConditionNode(ConditionNode),
+        GotoAnotherNode(GotoAnotherNode),
+        GotoMainFlowNode(GotoMainFlowNode),
+        CollectNode(CollectNode),
+        ExternalHttpCallNode(ExternalHttpCallNode),
+        TerminateNode(TerminateNode),
+        SendEmailNode(SendEmailNode),
+        LlmChatNode(LlmChatNode),
+        KnowledgeBaseAnswerNode(KnowledgeBaseAnswerNode),
+    }
+}
+
+// Converts a node released with the previous layout, new fields get the defaults of the convertor
+pub(crate) fn upgrade_node(bytes: &[u8]) -> Result<AlignedVec> {
+    let mut v = AlignedVec::<256>::with_capacity(bytes.len());
+    v.extend_from_slice(bytes);
+    let n = rkyv::from_bytes::<v1::RuntimeNodeEnum, rkyv::rancor::Error>(&v)
+        .map_err(|e| Error::WithMessage(format!("Invalid runtime node: {e}")))?;
+    let n = match n {
+        v1::RuntimeNodeEnum::TextNode(n) => RuntimeNodeEnum::TextNode(TextNode {
+            text: n.text,
+            text_type: n.text_type,
+            payload: None,
+            ret: n.ret,
+            next_node_id: n.next_node_id,
+        }),
+        v1::RuntimeNodeEnum::LlmGenTextNode(n) => RuntimeNodeEnum::LlmGenTextNode(n),
+        v1::RuntimeNodeEnum::ConditionNode(n) => RuntimeNodeEnum::ConditionNode(n),
+        v1::RuntimeNodeEnum::GotoAnotherNode(n) => RuntimeNodeEnum::GotoAnotherNode(n),
+        v1::RuntimeNodeEnum::GotoMainFlowNode(n) => RuntimeNodeEnum::GotoMainFlowNode(n),
+        v1::RuntimeNodeEnum::CollectNode(n) => RuntimeNodeEnum::CollectNode(n),
+        v1::RuntimeNodeEnum::ExternalHttpCallNode(n) => RuntimeNodeEnum::ExternalHttpCallNode(n),
+        v1::RuntimeNodeEnum::TerminateNode(n) => RuntimeNodeEnum::TerminateNode(n),
+        v1::RuntimeNodeEnum::SendEmailNode(n) => RuntimeNodeEnum::SendEmailNode(n),
+        v1::RuntimeNodeEnum::LlmChatNode(n) => RuntimeNodeEnum::LlmChatNode(n),
+        v1::RuntimeNodeEnum::KnowledgeBaseAnswerNode(n) => {
+            RuntimeNodeEnum::KnowledgeBaseAnswerNode(KnowledgeBaseAnswerNode {
+                recall_distance: n.recall_distance,
+                retrieve_answer_sources: n.retrieve_answer_sources,
+                no_recall_then: n.no_recall_then,
+                top_k: 3,
+                answer_mode: KnowledgeBaseAnswerMode::Verbatim,
+                context_len: 0,
+                response_streaming: false,
+                next_node_id: n.next_node_id,
+            })
+        }
+    };
+    rkyv::to_bytes::<rkyv::rancor::Error>(&n)
+        .map_err(|e| Error::WithMessage(format!("Invalid runtime node: {e}")))
+}
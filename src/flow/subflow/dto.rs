@@ -6,6 +6,7 @@ use serde_json::Value;
 
 use crate::flow::rt::collector::CollectType;
 use crate::flow::rt::condition::{CompareType, ConditionType, TargetDataVariant};
+use crate::flow::rt::dto::{AnswerButton, AnswerPayload};
 use crate::result::{Error, Result};
 
 #[derive(Deserialize)]
@@ -64,6 +65,65 @@ pub(crate) enum Node {
     SendEmailNode(SendEmailNode),
     EndNode(EndNode),
     KnowledgeBaseAnswerNode(KnowledgeBaseAnswerNode),
+    SubFlowCallNode(SubFlowCallNode),
+    ReturnNode(ReturnNode),
+    SetVariableNode(SetVariableNode),
+}
+
+fn check_buttons(buttons: &[AnswerButton]) -> core::result::Result<(), &'static str> {
+    if buttons.iter().any(|b| b.label.is_empty()) {
+        Err("button label not filled in")
+    } else if buttons
+        .iter()
+        .any(|b| b.user_input.is_empty() && b.intent.is_none() && b.link.is_none())
+    {
+        Err("button needs a user input, an intent or a link")
+    } else {
+        Ok(())
+    }
+}
+
+fn check_payload(p: &AnswerPayload) -> core::result::Result<(), &'static str> {
+    match p {
+        AnswerPayload::QuickReplies { buttons } => {
+            if buttons.is_empty() {
+                Err("No quick reply button added")
+            } else {
+                check_buttons(buttons)
+            }
+        }
+        AnswerPayload::Card(c) => {
+            if c.title.is_empty() {
+                Err("card title not filled in")
+            } else {
+                check_buttons(&c.buttons)
+            }
+        }
+        AnswerPayload::Carousel { cards } => {
+            if cards.is_empty() {
+                Err("No carousel card added")
+            } else if cards.iter().any(|c| c.title.is_empty()) {
+                Err("card title not filled in")
+            } else {
+                cards.iter().try_for_each(|c| check_buttons(&c.buttons))
+            }
+        }
+        AnswerPayload::File(f) => {
+            if f.url.is_empty() {
+                Err("file url not filled in")
+            } else {
+                Ok(())
+            }
+        }
+        AnswerPayload::Location(l) => {
+            if !(-90f64..=90f64).contains(&l.latitude) || !(-180f64..=180f64).contains(&l.longitude)
+            {
+                Err("location coordinate is invalid")
+            } else {
+                Ok(())
+            }
+        }
+    }
 }
 
 impl Node {
@@ -90,8 +150,11 @@ impl Node {
                     Self::err(f, t, &n.node_name, "node name not filled in")
                 } else if n.dialog_text_source == DialogTextSource::FixedText
                     && n.dialog_text.is_empty()
+                    && n.dialog_payload.is_none()
                 {
                     Self::err(f, t, &n.node_name, "No dialog text filled in")
+                } else if let Some(Err(m)) = n.dialog_payload.as_ref().map(check_payload) {
+                    Self::err(f, t, &n.node_name, m)
                 } else if n.dialog_text_source == DialogTextSource::LlmGenText
                     && n.dialog_llm_gen_prompt.is_empty()
                 {
@@ -234,6 +297,65 @@ impl Node {
                     Ok(())
                 }
             }
+            Node::SubFlowCallNode(n) => {
+                let t = "Call sub-flow";
+                if !n.valid {
+                    Self::err(f, t, &n.node_name, "verification failed")
+                } else if n.node_name.is_empty() {
+                    Self::err(f, t, &n.node_name, "node name not filled in")
+                } else if n.call_subflow_id.is_empty() {
+                    Self::err(f, t, &n.node_name, "No sub-flow selected")
+                } else if n.call_subflow_id.eq(&f.id) {
+                    Self::err(f, t, &n.node_name, "can not call the sub-flow itself")
+                } else if n
+                    .input_params
+                    .iter()
+                    .chain(n.output_params.iter())
+                    .any(|p| p.var_name.is_empty())
+                {
+                    Self::err(f, t, &n.node_name, "parameter variable not selected")
+                } else if n.branches.len() != 1 {
+                    Self::err(f, t, &n.node_name, "Branch information is incorrect")
+                } else {
+                    Ok(())
+                }
+            }
+            Node::ReturnNode(n) => {
+                let t = "Return";
+                if !n.valid {
+                    Self::err(f, t, &n.node_name, "verification failed")
+                } else if n.node_name.is_empty() {
+                    Self::err(f, t, &n.node_name, "node name not filled in")
+                } else {
+                    Ok(())
+                }
+            }
+            Node::SetVariableNode(n) => {
+                let t = "Set variable";
+                if !n.valid {
+                    Self::err(f, t, &n.node_name, "verification failed")
+                } else if n.node_name.is_empty() {
+                    Self::err(f, t, &n.node_name, "node name not filled in")
+                } else if n.assignments.is_empty() {
+                    Self::err(f, t, &n.node_name, "No variable assignment added")
+                } else if n.branches.len() != 1 {
+                    Self::err(f, t, &n.node_name, "Branch information is incorrect")
+                } else {
+                    for a in n.assignments.iter() {
+                        if a.var_name.is_empty() {
+                            return Self::err(f, t, &n.node_name, "variable not selected");
+                        }
+                        if let Err(e) = crate::flow::rt::expression::parse(&a.expression) {
+                            let m = format!(
+                                "expression of variable: {} is invalid, {:?}",
+                                a.var_name, e
+                            );
+                            return Self::err(f, t, &n.node_name, &m);
+                        }
+                    }
+                    Ok(())
+                }
+            }
         }
     }
 
@@ -248,6 +370,9 @@ impl Node {
             Self::SendEmailNode(n) => n.node_id.clone(),
             Self::EndNode(n) => n.node_id.clone(),
             Self::KnowledgeBaseAnswerNode(n) => n.node_id.clone(),
+            Self::SubFlowCallNode(n) => n.node_id.clone(),
+            Self::ReturnNode(n) => n.node_id.clone(),
+            Self::SetVariableNode(n) => n.node_id.clone(),
         }
     }
 
@@ -264,7 +389,7 @@ impl Node {
                     .iter()
                     .for_each(|b| ids.push(b.target_node_id.clone()));
             }
-            Self::EndNode(_) | Self::GotoNode(_) => {}
+            Self::EndNode(_) | Self::GotoNode(_) | Self::ReturnNode(_) => {}
             Self::ConditionNode(n) => {
                 n.branches
                     .iter()
@@ -290,6 +415,16 @@ impl Node {
                     .iter()
                     .for_each(|b| ids.push(b.target_node_id.clone()));
             }
+            Self::SubFlowCallNode(n) => {
+                n.branches
+                    .iter()
+                    .for_each(|b| ids.push(b.target_node_id.clone()));
+            }
+            Self::SetVariableNode(n) => {
+                n.branches
+                    .iter()
+                    .for_each(|b| ids.push(b.target_node_id.clone()));
+            }
         };
         ids
     }
@@ -298,12 +433,14 @@ impl Node {
         match self {
             Self::DialogNode(n) => Some(&mut n.branches),
             Self::LlmChatNode(n) => Some(&mut n.branches),
-            Self::EndNode(_) | Self::GotoNode(_) => None,
+            Self::EndNode(_) | Self::GotoNode(_) | Self::ReturnNode(_) => None,
             Self::ConditionNode(n) => Some(&mut n.branches),
             Self::CollectNode(n) => Some(&mut n.branches),
             Self::ExternalHttpNode(n) => Some(&mut n.branches),
             Self::SendEmailNode(n) => Some(&mut n.branches),
             Self::KnowledgeBaseAnswerNode(n) => Some(&mut n.branches),
+            Self::SubFlowCallNode(n) => Some(&mut n.branches),
+            Self::SetVariableNode(n) => Some(&mut n.branches),
         }
     }
 }
@@ -365,6 +502,8 @@ pub(crate) struct DialogNode {
     pub(crate) dialog_text: String,
     #[serde(rename = "dialogTextType")]
     pub(crate) dialog_text_type: crate::flow::rt::dto::AnswerContentType,
+    #[serde(rename = "dialogPayload", default)]
+    pub(crate) dialog_payload: Option<AnswerPayload>,
     #[serde(rename = "dialogLlmGenPrompt")]
     pub(crate) dialog_llm_gen_prompt: String,
     #[serde(rename = "dialogFallbackText")]
@@ -429,7 +568,7 @@ pub(crate) struct CollectNode {
     pub(crate) branches: Vec<Branch>,
 }
 
-#[derive(Deserialize, PartialEq, Eq, Serialize)]
+#[derive(Clone, Deserialize, PartialEq, Eq, Serialize)]
 pub(crate) enum NextActionType {
     None,
     GotoMainFlow,
@@ -524,4 +663,52 @@ pub(crate) struct KnowledgeBaseAnswerNode {
     pub(crate) no_answer_then: crate::flow::rt::node::KnowledgeBaseAnswerNoRecallThen,
     #[serde(rename = "retrieveAnswerSources")]
     pub(crate) retrieve_answer_sources: Vec<crate::flow::rt::node::KnowledgeBaseAnswerSource>,
+    // Number of retrieved candidates, documents answers are generated from all of them
+    #[serde(rename = "topK", default)]
+    pub(crate) top_k: u8,
+    #[serde(rename = "answerMode", default)]
+    pub(crate) answer_mode: crate::flow::rt::node::KnowledgeBaseAnswerMode,
+    // Number of recent chat history messages passed to the model in generative mode
+    #[serde(rename = "contextLength", default)]
+    pub(crate) context_length: u8,
+    #[serde(rename = "responseStreaming", default)]
+    pub(crate) response_streaming: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SubFlowCallNode {
+    pub(crate) valid: bool,
+    #[serde(rename = "nodeId")]
+    pub(crate) node_id: String,
+    #[serde(rename = "nodeName")]
+    pub(crate) node_name: String,
+    #[serde(rename = "callSubFlowId")]
+    pub(crate) call_subflow_id: String,
+    #[serde(rename = "callSubFlowName")]
+    pub(crate) call_subflow_name: String,
+    #[serde(rename = "inputParams", default)]
+    pub(crate) input_params: Vec<crate::flow::rt::node::SubFlowCallParam>,
+    #[serde(rename = "outputParams", default)]
+    pub(crate) output_params: Vec<crate::flow::rt::node::SubFlowCallParam>,
+    pub(crate) branches: Vec<Branch>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ReturnNode {
+    pub(crate) valid: bool,
+    #[serde(rename = "nodeId")]
+    pub(crate) node_id: String,
+    #[serde(rename = "nodeName")]
+    pub(crate) node_name: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SetVariableNode {
+    pub(crate) valid: bool,
+    #[serde(rename = "nodeId")]
+    pub(crate) node_id: String,
+    #[serde(rename = "nodeName")]
+    pub(crate) node_name: String,
+    pub(crate) assignments: Vec<crate::flow::rt::node::VariableAssignment>,
+    pub(crate) branches: Vec<Branch>,
 }
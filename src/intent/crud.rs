@@ -2,15 +2,24 @@ use std::collections::HashMap;
 use std::vec::Vec;
 
 use axum::Json;
-use axum::extract::Query;
-use axum::response::IntoResponse;
+use axum::extract::{Multipart, Query};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
 
-use super::detector;
-use super::dto::{IntentDetail, IntentFormData, IntentPhraseData};
+use super::detector::{self, DialogPosition};
+use super::dto::{
+    EvalJobQuery, EvalSetQuery, IntentDetail, IntentDetectData, IntentExportQuery, IntentFormData,
+    IntentImportError, IntentImportReport, IntentPhraseData, IntentScopeData, IntentSlotsData,
+    PhraseSpansData, PhraseUpdateData,
+};
+use super::eval::{self, EvalSet};
+use super::intent_file::{self, IntentFileFormat, IntentRecord};
+use super::slot;
 use crate::db;
 use crate::db_executor;
 use crate::result::{Error, Result};
-use crate::web::server::to_res;
+use crate::robot::dto::RobotQuery;
+use crate::web::server::{to_err_res, to_res};
 
 // pub(crate) const TABLE: redb::TableDefinition<&str, &[u8]> =
 //     redb::TableDefinition::new(INTENT_LIST_KEY);
@@ -173,6 +182,12 @@ fn add_intent(robot_id: &str, intent_name: &str) -> Result<()> {
 }
 
 pub(crate) async fn remove(Json(params): Json<IntentFormData>) -> impl IntoResponse {
+    if let Ok(Some(d)) = get_detail_by_id(&params.robot_id, params.id.as_str()) {
+        let ids: Vec<i64> = d.negative_phrases.iter().map(|p| p.id).collect();
+        if let Err(e) = super::phrase::remove_negatives(&params.robot_id, &ids).await {
+            return to_res(Err(e));
+        }
+    }
     let r = super::phrase::remove_by_intent_id(&params.robot_id, params.id.as_str())
         .await
         .and_then(|_| {
@@ -324,6 +339,7 @@ pub(crate) async fn add_phrase(
             d.phrases.push(IntentPhraseData {
                 id: vec_row_id,
                 phrase: String::from(params.data.as_str()),
+                spans: vec![],
             });
             // log::info!("intent detail {} {}", intent_id, serde_json::to_string(&d).unwrap());
             db_executor!(db::write, &params.robot_id, TABLE_SUFFIX, intent_id, &d)
@@ -426,8 +442,155 @@ pub(crate) async fn remove_phrase(Json(params): Json<IntentFormData>) -> impl In
     // to_res(r)
 }
 
-pub(crate) async fn detect(Json(params): Json<IntentFormData>) -> impl IntoResponse {
-    to_res(detector::detect(&params.robot_id, &params.data).await)
+pub(crate) async fn add_negative_phrase(Json(params): Json<IntentFormData>) -> impl IntoResponse {
+    to_res(insert_negative_phrase(params).await)
+}
+
+async fn insert_negative_phrase(params: IntentFormData) -> Result<()> {
+    let key = params.id.as_str();
+    let Some(mut d) = get_detail_by_id(&params.robot_id, key)? else {
+        return Err(Error::WithMessage(String::from(
+            "Can NOT find intention detail",
+        )));
+    };
+    let id = super::phrase::add_negative(&params.robot_id, None, key, &params.data).await?;
+    d.negative_phrases.push(IntentPhraseData {
+        id,
+        phrase: params.data,
+        spans: vec![],
+    });
+    db_executor!(db::write, &params.robot_id, TABLE_SUFFIX, key, &d)
+}
+
+pub(crate) async fn remove_negative_phrase(
+    Json(params): Json<IntentFormData>,
+) -> impl IntoResponse {
+    to_res(delete_negative_phrase(params).await)
+}
+
+async fn delete_negative_phrase(params: IntentFormData) -> Result<()> {
+    let idx = params.data.parse::<usize>().map_err(|e| {
+        log::error!("{e:?}");
+        Error::WithMessage(String::from("Invalid parameter"))
+    })?;
+    let key = params.id.as_str();
+    let Some(mut d) = get_detail_by_id(&params.robot_id, key)? else {
+        return Ok(());
+    };
+    if idx >= d.negative_phrases.len() {
+        return Err(Error::WithMessage(String::from("Invalid parameter")));
+    }
+    let phrase = d.negative_phrases.remove(idx);
+    super::phrase::remove_negatives(&params.robot_id, &[phrase.id]).await?;
+    db_executor!(db::write, &params.robot_id, TABLE_SUFFIX, key, &d)
+}
+
+pub(crate) async fn save_scope(Json(params): Json<IntentScopeData>) -> impl IntoResponse {
+    to_res(update_scope(params))
+}
+
+fn update_scope(params: IntentScopeData) -> Result<()> {
+    let key = params.intent_id.as_str();
+    let Some(mut d) = get_detail_by_id(&params.robot_id, key)? else {
+        return Err(Error::WithMessage(String::from(
+            "Can NOT find intention detail",
+        )));
+    };
+    d.scope = params.scope;
+    db_executor!(db::write, &params.robot_id, TABLE_SUFFIX, key, &d)
+}
+
+// Annotations of removed slots are dropped
+pub(crate) async fn save_slots(Json(params): Json<IntentSlotsData>) -> impl IntoResponse {
+    to_res(update_slots(params))
+}
+
+fn update_slots(params: IntentSlotsData) -> Result<()> {
+    slot::validate(&params.slots)?;
+    let key = params.intent_id.as_str();
+    let Some(mut d) = get_detail_by_id(&params.robot_id, key)? else {
+        return Err(Error::WithMessage(String::from(
+            "Can NOT find intention detail",
+        )));
+    };
+    d.slots = params.slots;
+    for phrase in d.phrases.iter_mut() {
+        phrase
+            .spans
+            .retain(|sp| d.slots.iter().any(|s| s.name.eq(&sp.slot)));
+    }
+    db_executor!(db::write, &params.robot_id, TABLE_SUFFIX, key, &d)
+}
+
+pub(crate) async fn save_phrase_spans(Json(params): Json<PhraseSpansData>) -> impl IntoResponse {
+    to_res(update_phrase_spans(params))
+}
+
+fn update_phrase_spans(params: PhraseSpansData) -> Result<()> {
+    let key = params.intent_id.as_str();
+    let Some(mut d) = get_detail_by_id(&params.robot_id, key)? else {
+        return Err(Error::WithMessage(String::from(
+            "Can NOT find intention detail",
+        )));
+    };
+    let Some(phrase) = d.phrases.iter_mut().find(|p| p.id == params.phrase_id) else {
+        return Err(Error::WithMessage(String::from("Can NOT find the phrase")));
+    };
+    phrase.spans = slot::check_spans(&phrase.phrase, params.spans, &d.slots)?;
+    db_executor!(db::write, &params.robot_id, TABLE_SUFFIX, key, &d)
+}
+
+// Annotations follow their values into the edited phrase
+pub(crate) async fn update_phrase(Json(params): Json<PhraseUpdateData>) -> impl IntoResponse {
+    to_res(change_phrase(params).await)
+}
+
+async fn change_phrase(params: PhraseUpdateData) -> Result<()> {
+    let key = params.intent_id.as_str();
+    let text = params.phrase.trim();
+    if text.is_empty() {
+        return Err(Error::WithMessage(String::from("Phrase is empty.")));
+    }
+    let Some(mut d) = get_detail_by_id(&params.robot_id, key)? else {
+        return Err(Error::WithMessage(String::from(
+            "Can NOT find intention detail",
+        )));
+    };
+    let Some(idx) = d.phrases.iter().position(|p| p.id == params.phrase_id) else {
+        return Err(Error::WithMessage(String::from("Can NOT find the phrase")));
+    };
+    super::phrase::add(
+        &params.robot_id,
+        Some(params.phrase_id),
+        key,
+        &d.intent_name,
+        text,
+    )
+    .await?;
+    let phrase = &mut d.phrases[idx];
+    phrase.spans = slot::relocate_spans(&phrase.phrase, text, &phrase.spans);
+    phrase.phrase = String::from(text);
+    db_executor!(db::write, &params.robot_id, TABLE_SUFFIX, key, &d)
+}
+
+// Only the intent name is returned unless `topN` is given
+pub(crate) async fn detect(Json(params): Json<IntentDetectData>) -> Response {
+    let position = params.main_flow_id.map(|main_flow_id| DialogPosition {
+        main_flow_id,
+        node_id: params.node_id.unwrap_or_default(),
+    });
+    let r = detector::rank(
+        &params.robot_id,
+        &params.data,
+        params.top_n,
+        position.as_ref(),
+    )
+    .await;
+    if params.top_n.is_some() {
+        to_res(r).into_response()
+    } else {
+        to_res(r.map(|d| d.intent)).into_response()
+    }
 }
 
 pub(crate) async fn regenerate_embeddings(
@@ -449,5 +612,209 @@ pub(crate) async fn regenerate_embeddings(
     // let array: Vec<&str> = d.phrases.iter().map(|v| v.phrase.as_ref()).collect();
     let r =
         super::phrase::batch_add(&params.robot_id, &params.data, &d.intent_name, &d.phrases).await;
-    to_res(r)
+    if r.is_err() {
+        return to_res(r);
+    }
+    for p in d.negative_phrases.iter() {
+        let r = super::phrase::add_negative(&params.robot_id, Some(p.id), &d.intent_id, &p.phrase)
+            .await;
+        if let Err(e) = r {
+            return to_res(Err(e));
+        }
+    }
+    to_res(super::phrase::rebuild_index(&params.robot_id).await)
+}
+
+pub(crate) async fn list_eval_sets(Query(q): Query<RobotQuery>) -> impl IntoResponse {
+    to_res(eval::list_sets(&q.robot_id))
+}
+
+pub(crate) async fn save_eval_set(
+    Query(q): Query<RobotQuery>,
+    Json(set): Json<EvalSet>,
+) -> impl IntoResponse {
+    to_res(eval::save_set(&q.robot_id, set))
+}
+
+// Name of the set is the file name
+pub(crate) async fn upload_eval_set(
+    Query(q): Query<RobotQuery>,
+    multipart: Multipart,
+) -> impl IntoResponse {
+    to_res(read_eval_set(&q.robot_id, multipart).await)
+}
+
+async fn read_eval_set(robot_id: &str, mut multipart: Multipart) -> Result<EvalSet> {
+    let Some(field) = multipart.next_field().await? else {
+        return Err(Error::WithMessage(String::from("File not found.")));
+    };
+    let Some(file_name) = field.file_name() else {
+        return Err(Error::WithMessage(String::from("File name is missing.")));
+    };
+    let file_name = file_name.to_string();
+    let data = field.bytes().await?;
+    let set = EvalSet {
+        id: String::new(),
+        robot_id: String::from(robot_id),
+        name: file_name.clone(),
+        samples: eval::read_samples(&file_name, data.as_ref())?,
+        created_at: 0,
+    };
+    eval::save_set(robot_id, set)
+}
+
+pub(crate) async fn delete_eval_set(Query(q): Query<EvalSetQuery>) -> impl IntoResponse {
+    to_res(eval::delete_set(&q.robot_id, &q.set_id))
+}
+
+pub(crate) async fn run_eval(Query(q): Query<EvalSetQuery>) -> impl IntoResponse {
+    to_res(eval::submit(&q.robot_id, &q.set_id))
+}
+
+pub(crate) async fn list_eval_jobs(Query(q): Query<RobotQuery>) -> impl IntoResponse {
+    to_res(eval::list_jobs(&q.robot_id))
+}
+
+pub(crate) async fn eval_job_status(Query(q): Query<EvalJobQuery>) -> impl IntoResponse {
+    to_res(eval::status(&q.robot_id, &q.job_id))
+}
+
+pub(crate) async fn cancel_eval_job(Query(q): Query<EvalJobQuery>) -> impl IntoResponse {
+    to_res(eval::cancel(&q.robot_id, &q.job_id))
+}
+
+pub(crate) async fn delete_eval_job(Query(q): Query<EvalJobQuery>) -> impl IntoResponse {
+    to_res(eval::delete_job(&q.robot_id, &q.job_id))
+}
+
+pub(crate) async fn import(Query(q): Query<RobotQuery>, multipart: Multipart) -> impl IntoResponse {
+    to_res(import_file(&q.robot_id, multipart).await)
+}
+
+async fn import_file(robot_id: &str, mut multipart: Multipart) -> Result<IntentImportReport> {
+    let Some(field) = multipart.next_field().await? else {
+        return Err(Error::WithMessage(String::from("File not found.")));
+    };
+    let Some(file_name) = field.file_name() else {
+        return Err(Error::WithMessage(String::from("File name is missing.")));
+    };
+    let file_name = file_name.to_string();
+    let data = field.bytes().await?;
+    log::info!("Importing intents from `{file_name}`, {} bytes", data.len());
+    let records = intent_file::read(&file_name, data.as_ref())?;
+    import_records(robot_id, records).await
+}
+
+// Records are merged into intents of the same name, an intent failing won't stop the others
+async fn import_records(robot_id: &str, records: Vec<IntentRecord>) -> Result<IntentImportReport> {
+    let mut intents: Vec<IntentDetail> = db_executor!(db::get_all, robot_id, TABLE_SUFFIX,)?;
+    let mut report = IntentImportReport {
+        intents: records.len(),
+        ..Default::default()
+    };
+    for r in records.into_iter() {
+        let name = r.name.clone();
+        if let Err(e) = merge_record(robot_id, &mut intents, r, &mut report).await {
+            log::warn!("Importing intent {name} failed {e:?}");
+            report.errors.push(IntentImportError {
+                intent: name,
+                message: format!("{e:?}"),
+            });
+        }
+    }
+    Ok(report)
+}
+
+async fn merge_record(
+    robot_id: &str,
+    intents: &mut Vec<IntentDetail>,
+    r: IntentRecord,
+    report: &mut IntentImportReport,
+) -> Result<()> {
+    let existing = intents.iter().position(|d| d.intent_name.eq(&r.name));
+    let mut d = match existing {
+        Some(idx) => intents[idx].clone(),
+        None => IntentDetail::new(&r.name),
+    };
+    let keywords = d.keywords.len();
+    for k in r.keywords.into_iter() {
+        if !k.is_empty() && !d.keywords.contains(&k) {
+            d.keywords.push(k);
+        }
+    }
+    let regexes = d.regexes.len();
+    for re in r.regexes.into_iter() {
+        if !re.is_empty() && !d.regexes.contains(&re) {
+            regex::Regex::new(&re)?;
+            d.regexes.push(re);
+        }
+    }
+    let slots = d.slots.len();
+    for s in r.slots.into_iter() {
+        if d.slots.iter().all(|e| e.name.ne(&s.name)) {
+            d.slots.push(s);
+        }
+    }
+    slot::validate(&d.slots)?;
+    let mut phrases: Vec<(String, Vec<_>)> = Vec::with_capacity(r.phrases.len());
+    for (text, mut spans) in r.phrases.into_iter() {
+        let text = String::from(text.trim_end());
+        if text.is_empty()
+            || d.phrases.iter().any(|p| p.phrase.eq(&text))
+            || phrases.iter().any(|(p, _)| p.eq(&text))
+        {
+            continue;
+        }
+        spans.retain(|sp| d.slots.iter().any(|s| s.name.eq(&sp.slot)));
+        let spans = slot::check_spans(&text, spans, &d.slots)?;
+        phrases.push((text, spans));
+    }
+    let texts: Vec<&str> = phrases.iter().map(|(p, _)| p.as_str()).collect();
+    let ids = super::phrase::add_all(robot_id, &d.intent_id, &d.intent_name, &texts).await?;
+    let added = ids.len();
+    for (&id, (phrase, spans)) in ids.iter().zip(phrases) {
+        d.phrases.push(IntentPhraseData { id, phrase, spans });
+    }
+    // Phrases are not left behind without the intent referring to them
+    if let Err(e) = db_executor!(db::write, robot_id, TABLE_SUFFIX, d.intent_id.as_str(), &d) {
+        if let Err(e) = super::phrase::remove_all(robot_id, &ids).await {
+            log::warn!("Removing imported phrases failed {e:?}");
+        }
+        return Err(e);
+    }
+    report.phrases += added;
+    report.keywords += d.keywords.len() - keywords;
+    report.regexes += d.regexes.len() - regexes;
+    report.slots += d.slots.len() - slots;
+    match existing {
+        Some(idx) => intents[idx] = d,
+        None => {
+            report.created += 1;
+            intents.push(d);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn export(Query(q): Query<IntentExportQuery>) -> Response {
+    let Some(format) = IntentFileFormat::from_name(&q.format) else {
+        let e = Error::WithMessage(format!("Unsupported export format `{}`", &q.format));
+        return to_err_res(StatusCode::BAD_REQUEST, e);
+    };
+    let r: Result<Vec<IntentDetail>> = db_executor!(db::get_all, &q.robot_id, TABLE_SUFFIX,);
+    let r = r.and_then(|mut intents| {
+        intents.sort_by(|a, b| a.intent_name.cmp(&b.intent_name));
+        let records: Vec<IntentRecord> = intents.iter().map(IntentRecord::from).collect();
+        intent_file::write(format, &records)
+    });
+    match r {
+        Ok(b) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, format.content_type().parse().unwrap());
+            let disposition = format!("attachment; filename=\"{}\"", format.file_name(&q.robot_id));
+            headers.insert(header::CONTENT_DISPOSITION, disposition.parse().unwrap());
+            (StatusCode::OK, headers, b).into_response()
+        }
+        Err(e) => to_err_res(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
 }
@@ -1,14 +1,32 @@
-use regex::Regex;
+use std::collections::{HashMap, HashSet};
+
 use unicase::UniCase;
 
-use super::dto::IntentDetail;
+use super::dto::{IntentCandidate, IntentDetail, IntentDetection, IntentMatchStage, IntentScope};
 use super::phrase;
+use super::regex_cache;
+use super::slot;
 use crate::ai::embedding::embedding;
 use crate::db;
 use crate::db_executor;
+use crate::man::settings;
 use crate::result::Result;
 
-pub(crate) async fn detect(robot_id: &str, s: &str) -> Result<Option<String>> {
+// Where the session is in the dialog
+pub(crate) struct DialogPosition {
+    pub(crate) main_flow_id: String,
+    // Last runtime node run before the user input
+    pub(crate) node_id: String,
+}
+
+// Keyword and regex matches win over embedding search, which is skipped if any of them matched.
+// Intents scoped elsewhere are left out unless position is None
+pub(crate) async fn rank(
+    robot_id: &str,
+    s: &str,
+    top_n: Option<usize>,
+    position: Option<&DialogPosition>,
+) -> Result<IntentDetection> {
     // let now = std::time::Instant::now();
     let r: Result<Vec<IntentDetail>> =
         db_executor!(db::get_all, robot_id, super::crud::TABLE_SUFFIX,);
@@ -16,71 +34,312 @@ pub(crate) async fn detect(robot_id: &str, s: &str) -> Result<Option<String>> {
         Ok(v) => {
             // log::info!("v.len {}", v.len());
             if v.is_empty() {
-                return Ok(None);
+                return Ok(IntentDetection::default());
             }
             v
         }
         Err(e) => {
             log::warn!("Detecting intent failed: {:?}", &e);
-            return Ok(None);
+            return Ok(IntentDetection::default());
         }
     };
+    let config = settings::get_settings(robot_id)?
+        .map(|s| s.intent_detection)
+        .unwrap_or_default();
+    let top_n = top_n.unwrap_or(config.top_n as usize).max(1);
+    let excluded = out_of_scope(&intents, position);
     // log::info!("intents.len {}", intents.len());
+    let mut candidates: Vec<IntentCandidate> = Vec::with_capacity(top_n);
     let mut empty_phrase = true;
+    let mut embedded: Option<(Vec<f32>, f64)> = None;
+    let unicase_s = UniCase::new(s);
+    let len = s.chars().count().max(1);
     for detail in intents.iter() {
+        if excluded.contains(detail.intent_id.as_str()) {
+            continue;
+        }
         // log::info!("intent detail {} {}", detail.intent_id, serde_json::to_string(&detail).unwrap());
         // log::info!("detail.keywords.len {}", detail.keywords.len());
-        let unicase_s = UniCase::new(s);
-        for k in detail.keywords.iter() {
-            // log::info!("intent compare {} {}", k, s);
-            if UniCase::new(k) == unicase_s {
-                // println!("{} {} {}", s, k, &i.name);
-                return Ok(Some(detail.intent_name.clone()));
+        let mut matched = None;
+        if detail.keywords.iter().any(|k| UniCase::new(k) == unicase_s) {
+            matched = Some((IntentMatchStage::Keyword, 1.));
+        } else {
+            // Regexes covering more of the utterance are more specific
+            let mut longest = None;
+            for r in detail.regexes.iter() {
+                // An invalid regex only disables itself, not the detection of other intents
+                let re = match regex_cache::get(r) {
+                    Ok(re) => re,
+                    Err(e) => {
+                        log::warn!("Invalid regex {r} of intent {}: {e:?}", &detail.intent_name);
+                        continue;
+                    }
+                };
+                // Empty matches, e.g. of `a*`, don't match anything of the utterance
+                let l = re
+                    .find_iter(s)
+                    .filter(|m| m.start() < m.end())
+                    .map(|m| m.as_str().chars().count())
+                    .max();
+                longest = longest.max(l);
             }
-        }
-        for r in detail.regexes.iter() {
-            let re = Regex::new(r)?;
-            if re.is_match(s) {
-                return Ok(Some(detail.intent_name.clone()));
+            if let Some(l) = longest {
+                matched = Some((IntentMatchStage::Regex, l as f64 / len as f64));
             }
         }
+        if let Some((stage, score)) = matched {
+            candidates.push(IntentCandidate {
+                intent_id: detail.intent_id.clone(),
+                intent_name: detail.intent_name.clone(),
+                score,
+                stage,
+            });
+        }
         empty_phrase = empty_phrase && detail.phrases.is_empty();
     }
+    if !candidates.is_empty() {
+        // Keyword matches come before regex matches and keep the order of intents
+        candidates.sort_by(|a, b| {
+            (a.stage != IntentMatchStage::Keyword)
+                .cmp(&(b.stage != IntentMatchStage::Keyword))
+                .then(b.score.total_cmp(&a.score))
+        });
+        // The utterance is only embedded if there are negative phrases to compare with
+        if candidates
+            .iter()
+            .any(|c| has_negatives(&intents, &c.intent_id))
+        {
+            embedded = embed(robot_id, s).await;
+            if let Some((vectors, threshold)) = embedded.as_ref() {
+                candidates =
+                    suppress_negatives(robot_id, vectors, *threshold, candidates, &intents).await;
+            }
+        }
+        if !candidates.is_empty() {
+            candidates.truncate(top_n);
+            let detection = conclude_exact(candidates, config.ambiguity_margin as f64);
+            return Ok(fill_slots(detection, &intents, s));
+        }
+    }
     if empty_phrase {
-        return Ok(None);
+        return Ok(IntentDetection::default());
+    }
+    if embedded.is_none() {
+        embedded = embed(robot_id, s).await;
     }
-    let embedding = embedding(robot_id, s).await;
-    let embedding = match embedding {
+    let Some((search_vector, similarity_threshold)) = embedded else {
+        return Ok(IntentDetection::default());
+    };
+    // Searches more intents to make up for the ones which may be dropped
+    let negative_num = intents
+        .iter()
+        .filter(|d| !d.negative_phrases.is_empty())
+        .count();
+    let search_n = top_n + excluded.len() + negative_num;
+    let result = phrase::search(robot_id, &search_vector, search_n).await?;
+    // log::info!("Searching vector took {:?}", now.elapsed());
+    for (intent_id, intent_name, distance) in result.into_iter() {
+        if !excluded.contains(intent_id.as_str()) {
+            candidates.push(IntentCandidate {
+                intent_id,
+                intent_name,
+                score: 1f64 - distance,
+                stage: IntentMatchStage::Embedding,
+            });
+        }
+    }
+    let mut candidates = suppress_negatives(
+        robot_id,
+        &search_vector,
+        similarity_threshold,
+        candidates,
+        &intents,
+    )
+    .await;
+    candidates.truncate(top_n);
+    let detection = conclude(
+        candidates,
+        similarity_threshold,
+        config.ambiguity_margin as f64,
+    );
+    Ok(fill_slots(detection, &intents, s))
+}
+
+// Ids of the intents which can't be detected at the position
+fn out_of_scope<'a>(
+    intents: &'a [IntentDetail],
+    position: Option<&DialogPosition>,
+) -> HashSet<&'a str> {
+    let Some(position) = position else {
+        return HashSet::new();
+    };
+    let scoped = || intents.iter().filter(|d| !d.scope.is_global());
+    // Looked up only if it's needed
+    let subflow_id = if scoped().any(|d| !d.scope.sub_flow_ids.is_empty()) {
+        crate::flow::rt::crud::get_runtime_node_subflow(&position.main_flow_id, &position.node_id)
+            .unwrap_or_else(|e| {
+                log::warn!(
+                    "Finding sub-flow of node {} failed {e:?}",
+                    &position.node_id
+                );
+                None
+            })
+    } else {
+        None
+    };
+    scoped()
+        .filter(|d| !in_scope(&d.scope, position, subflow_id.as_deref()))
+        .map(|d| d.intent_id.as_str())
+        .collect()
+}
+
+fn in_scope(scope: &IntentScope, position: &DialogPosition, subflow_id: Option<&str>) -> bool {
+    (scope.main_flow_ids.is_empty() || scope.main_flow_ids.contains(&position.main_flow_id))
+        && (scope.sub_flow_ids.is_empty()
+            || subflow_id.is_some_and(|id| scope.sub_flow_ids.iter().any(|s| s == id)))
+        && (scope.after_node_ids.is_empty()
+            || scope
+                .after_node_ids
+                .iter()
+                .any(|id| is_same_node(&position.node_id, id)))
+}
+
+// Some nodes are converted into several runtime nodes, whose ids are the node id plus `-n`
+fn is_same_node(runtime_node_id: &str, node_id: &str) -> bool {
+    runtime_node_id.strip_prefix(node_id).is_some_and(|rest| {
+        rest.is_empty()
+            || rest.strip_prefix('-').is_some_and(|n| {
+                !n.is_empty() && n.len() <= 3 && n.bytes().all(|b| b.is_ascii_digit())
+            })
+    })
+}
+
+async fn embed(robot_id: &str, s: &str) -> Option<(Vec<f32>, f64)> {
+    // let now = std::time::Instant::now();
+    match embedding(robot_id, s).await {
         Ok(embedding) => {
-            if embedding.0.is_empty() {
-                return Ok(None);
-            }
-            embedding
+            // log::info!("Generate embedding cost {:?}", now.elapsed());
+            (!embedding.0.is_empty()).then_some((embedding.0, embedding.1 as f64))
         }
         Err(e) => {
             log::warn!("Detecting intent failed: {:?}", &e);
-            return Ok(None);
+            None
+        }
+    }
+}
+
+fn has_negatives(intents: &[IntentDetail], intent_id: &str) -> bool {
+    intents
+        .iter()
+        .any(|d| d.intent_id == intent_id && !d.negative_phrases.is_empty())
+}
+
+// Drops the matches which are closer to one of the negative phrases of their intents. Keyword and
+// regex matches have no similarity, so the negative phrase must reach the threshold and be closer
+// than the phrases of the intent
+async fn suppress_negatives(
+    robot_id: &str,
+    vectors: &Vec<f32>,
+    threshold: f64,
+    mut candidates: Vec<IntentCandidate>,
+    intents: &[IntentDetail],
+) -> Vec<IntentCandidate> {
+    let ids: Vec<&str> = candidates
+        .iter()
+        .filter(|c| has_negatives(intents, &c.intent_id))
+        .map(|c| c.intent_id.as_str())
+        .collect();
+    if ids.is_empty() {
+        return candidates;
+    }
+    let negatives = match phrase::negative_similarities(robot_id, vectors, &ids).await {
+        Ok(s) => s,
+        Err(e) => {
+            log::warn!("Comparing negative phrases failed: {:?}", &e);
+            return candidates;
         }
     };
-    // log::info!("Generate embedding cost {:?}", now.elapsed());
-    // let s = format!("{:?}", &embedding);
-    // let regex = regex::Regex::new(r"\s").unwrap();
-    // log::info!("detect embedding {}", regex.replace_all(&s, ""));
-    // let now = std::time::Instant::now();
-    let search_vector: Vec<f32> = embedding.0;
-    let similarity_threshold = embedding.1 as f64;
-    let mut result = phrase::search(robot_id, &search_vector).await?;
-    // log::info!("Searching vector took {:?}", now.elapsed());
-    if !result.is_empty() {
-        if let Some(record) = result.get_mut(0) {
-            // log::info!("Record distance: {}", record.1);
-            if (1f64 - record.1) >= similarity_threshold {
-                let s = std::mem::take(&mut record.0);
-                return Ok(Some(s));
-            }
+    let exact_ids: Vec<&str> = candidates
+        .iter()
+        .filter(|c| c.stage != IntentMatchStage::Embedding && negatives.contains_key(&c.intent_id))
+        .map(|c| c.intent_id.as_str())
+        .collect();
+    let positives = if exact_ids.is_empty() {
+        HashMap::new()
+    } else {
+        phrase::similarities(robot_id, vectors, &exact_ids)
+            .await
+            .unwrap_or_else(|e| {
+                log::warn!("Comparing phrases failed: {:?}", &e);
+                HashMap::new()
+            })
+    };
+    candidates.retain(|c| {
+        let Some(negative) = negatives.get(&c.intent_id) else {
+            return true;
+        };
+        if c.stage == IntentMatchStage::Embedding {
+            *negative <= c.score
+        } else {
+            *negative < threshold || *negative <= positives.get(&c.intent_id).copied().unwrap_or(0.)
         }
+    });
+    candidates
+}
+
+fn fill_slots(
+    mut detection: IntentDetection,
+    intents: &[IntentDetail],
+    s: &str,
+) -> IntentDetection {
+    if detection.intent.is_some()
+        && let Some(top) = detection.candidates.first()
+        && let Some(detail) = intents.iter().find(|d| d.intent_id == top.intent_id)
+        && !detail.slots.is_empty()
+    {
+        detection.slots = slot::extract(detail, s);
+    }
+    detection
+}
+
+// Keyword and regex matches are always accepted. The first keyword match wins as the same
+// keyword in several intents is a mistake in configuration, only close regex matches are ambiguous
+fn conclude_exact(candidates: Vec<IntentCandidate>, margin: f64) -> IntentDetection {
+    let Some(top) = candidates.first() else {
+        return IntentDetection::default();
+    };
+    let ambiguous = top.stage == IntentMatchStage::Regex
+        && candidates
+            .get(1)
+            .is_some_and(|c| top.score - c.score <= margin);
+    IntentDetection {
+        intent: Some(top.intent_name.clone()),
+        confidence: top.score,
+        stage: Some(top.stage),
+        ambiguous,
+        candidates,
+        slots: vec![],
+    }
+}
+
+// Candidates are sorted by score
+fn conclude(candidates: Vec<IntentCandidate>, threshold: f64, margin: f64) -> IntentDetection {
+    let Some(top) = candidates.first() else {
+        return IntentDetection::default();
+    };
+    let accepted = top.score >= threshold;
+    let ambiguous = accepted
+        && candidates
+            .get(1)
+            .is_some_and(|c| c.score >= threshold && top.score - c.score <= margin);
+    IntentDetection {
+        intent: accepted.then(|| top.intent_name.clone()),
+        confidence: top.score,
+        stage: accepted.then_some(top.stage),
+        ambiguous,
+        candidates,
+        slots: vec![],
     }
-    Ok(None)
 }
 
 /*
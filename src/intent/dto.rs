@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::variable::dto::VariableValue;
+
 #[derive(Deserialize, Debug)]
 pub(crate) struct IntentFormData {
     #[serde(rename = "robotId")]
@@ -46,13 +48,80 @@ pub(crate) struct IntentFormData {
 //     }
 // }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub(crate) struct IntentPhraseData {
     pub(crate) id: i64,
     pub(crate) phrase: String,
+    #[serde(default)]
+    pub(crate) spans: Vec<SlotSpan>,
+}
+
+// Annotates a slot value in a training phrase, offsets are in chars
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct SlotSpan {
+    pub(crate) slot: String,
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct EnumEntry {
+    pub(crate) value: String,
+    #[serde(default)]
+    pub(crate) synonyms: Vec<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "data")]
+pub(crate) enum EntityType {
+    Number,
+    Date,
+    Enum(Vec<EnumEntry>),
+    Regex(String),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct IntentSlot {
+    pub(crate) name: String,
+    #[serde(rename = "entityType")]
+    pub(crate) entity_type: EntityType,
+    // Session variable to fill, the slot name is used if it's empty
+    #[serde(rename = "varName", default)]
+    pub(crate) var_name: String,
+}
+
+impl IntentSlot {
+    pub(crate) fn var_name(&self) -> &str {
+        if self.var_name.is_empty() {
+            &self.name
+        } else {
+            &self.var_name
+        }
+    }
+}
+
+// Where in the dialog an intent can be detected, each non-empty list must contain the
+// session's position, so the intent is global if all of them are empty
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+pub(crate) struct IntentScope {
+    #[serde(rename = "mainFlowIds", default)]
+    pub(crate) main_flow_ids: Vec<String>,
+    #[serde(rename = "subFlowIds", default)]
+    pub(crate) sub_flow_ids: Vec<String>,
+    // Nodes which answered right before the user input
+    #[serde(rename = "afterNodeIds", default)]
+    pub(crate) after_node_ids: Vec<String>,
+}
+
+impl IntentScope {
+    pub(crate) fn is_global(&self) -> bool {
+        self.main_flow_ids.is_empty()
+            && self.sub_flow_ids.is_empty()
+            && self.after_node_ids.is_empty()
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub(crate) struct IntentDetail {
     pub(crate) intent_id: String,
     pub(crate) intent_name: String,
@@ -60,6 +129,13 @@ pub(crate) struct IntentDetail {
     pub(crate) regexes: Vec<String>,
     phrase_vec_row_id: i64,
     pub(crate) phrases: Vec<IntentPhraseData>,
+    #[serde(default)]
+    pub(crate) slots: Vec<IntentSlot>,
+    #[serde(default)]
+    pub(crate) scope: IntentScope,
+    // The intent is not detected if the utterance is closer to one of them than to its phrases
+    #[serde(default)]
+    pub(crate) negative_phrases: Vec<IntentPhraseData>,
 }
 
 impl IntentDetail {
@@ -71,6 +147,149 @@ impl IntentDetail {
             regexes: vec![],
             phrase_vec_row_id: 0,
             phrases: vec![],
+            slots: vec![],
+            scope: IntentScope::default(),
+            negative_phrases: vec![],
         }
     }
 }
+
+#[derive(Deserialize)]
+pub(crate) struct IntentDetectData {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    pub(crate) data: String,
+    // Ranked result is returned if it's set, otherwise only the intent name
+    #[serde(rename = "topN")]
+    pub(crate) top_n: Option<usize>,
+    // Detects as if the session was there, scoped intents are not filtered if they're missing
+    #[serde(rename = "mainFlowId")]
+    pub(crate) main_flow_id: Option<String>,
+    #[serde(rename = "nodeId")]
+    pub(crate) node_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IntentExportQuery {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    pub(crate) format: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct IntentImportError {
+    pub(crate) intent: String,
+    pub(crate) message: String,
+}
+
+// Items already in intents are skipped
+#[derive(Default, Serialize)]
+pub(crate) struct IntentImportReport {
+    pub(crate) intents: usize,
+    pub(crate) created: usize,
+    pub(crate) phrases: usize,
+    pub(crate) keywords: usize,
+    pub(crate) regexes: usize,
+    pub(crate) slots: usize,
+    pub(crate) errors: Vec<IntentImportError>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EvalSetQuery {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "setId")]
+    pub(crate) set_id: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EvalJobQuery {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "jobId")]
+    pub(crate) job_id: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IntentSlotsData {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "intentId")]
+    pub(crate) intent_id: String,
+    pub(crate) slots: Vec<IntentSlot>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IntentScopeData {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "intentId")]
+    pub(crate) intent_id: String,
+    pub(crate) scope: IntentScope,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PhraseUpdateData {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "intentId")]
+    pub(crate) intent_id: String,
+    #[serde(rename = "phraseId")]
+    pub(crate) phrase_id: i64,
+    pub(crate) phrase: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PhraseSpansData {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "intentId")]
+    pub(crate) intent_id: String,
+    #[serde(rename = "phraseId")]
+    pub(crate) phrase_id: i64,
+    pub(crate) spans: Vec<SlotSpan>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) enum IntentMatchStage {
+    Keyword,
+    Regex,
+    Embedding,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct IntentCandidate {
+    #[serde(rename = "intentId")]
+    pub(crate) intent_id: String,
+    #[serde(rename = "intentName")]
+    pub(crate) intent_name: String,
+    // Keyword matches score 1, regex matches by the share of the utterance they cover and
+    // embedding matches by cosine similarity
+    pub(crate) score: f64,
+    pub(crate) stage: IntentMatchStage,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct IntentDetection {
+    // Top candidate if its score reaches the similarity threshold
+    pub(crate) intent: Option<String>,
+    // Score of the top candidate, 0 if there is none
+    pub(crate) confidence: f64,
+    pub(crate) stage: Option<IntentMatchStage>,
+    // The top two candidates are both accepted and too close to tell apart
+    pub(crate) ambiguous: bool,
+    pub(crate) candidates: Vec<IntentCandidate>,
+    // Slots of the detected intent found in the utterance
+    pub(crate) slots: Vec<SlotValue>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct SlotValue {
+    pub(crate) name: String,
+    #[serde(rename = "varName")]
+    pub(crate) var_name: String,
+    pub(crate) value: VariableValue,
+    // Char offsets in the utterance
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
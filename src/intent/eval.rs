@@ -0,0 +1,656 @@
+use std::collections::{BTreeSet, HashMap};
+use std::vec::Vec;
+
+use redb::TableDefinition;
+use serde::{Deserialize, Serialize};
+
+use super::detector;
+use super::dto::{IntentDetection, IntentMatchStage};
+use crate::db;
+use crate::kb::{parser, qa_file};
+use crate::man::clock::now_secs;
+use crate::man::job::{Job, JobQueue, JobStatus};
+use crate::man::settings;
+use crate::result::{Error, Result};
+
+const SET_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("intent_eval_sets");
+
+// Progress is saved after this many samples
+const PROGRESS_INTERVAL: usize = 20;
+// Swept similarity thresholds in hundredths
+const SWEEP_FROM: u32 = 50;
+const SWEEP_TO: u32 = 99;
+
+// Jobs are run one at a time
+static QUEUE: JobQueue = JobQueue::new("intent_eval_jobs", "Evaluation", 1);
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct EvalSample {
+    pub(crate) utterance: String,
+    // Empty if no intent should be detected
+    #[serde(default)]
+    pub(crate) intent: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct EvalSet {
+    #[serde(default)]
+    pub(crate) id: String,
+    #[serde(rename = "robotId", default)]
+    pub(crate) robot_id: String,
+    pub(crate) name: String,
+    pub(crate) samples: Vec<EvalSample>,
+    #[serde(rename = "createdAt", default)]
+    pub(crate) created_at: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct EvalJob {
+    pub(crate) id: String,
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "setId")]
+    pub(crate) set_id: String,
+    #[serde(rename = "setName")]
+    pub(crate) set_name: String,
+    pub(crate) status: JobStatus,
+    pub(crate) total: usize,
+    pub(crate) processed: usize,
+    pub(crate) err: String,
+    // Only returned with a single job
+    pub(crate) report: Option<EvalReport>,
+    #[serde(rename = "createdAt")]
+    pub(crate) created_at: u64,
+    #[serde(rename = "updatedAt")]
+    pub(crate) updated_at: u64,
+}
+
+impl Job for EvalJob {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn status(&self) -> JobStatus {
+        self.status
+    }
+
+    fn set_status(&mut self, status: JobStatus) {
+        self.status = status;
+    }
+
+    fn set_err(&mut self, err: String) {
+        self.err = err;
+    }
+
+    fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    fn updated_at(&self) -> u64 {
+        self.updated_at
+    }
+
+    fn set_updated_at(&mut self, t: u64) {
+        self.updated_at = t;
+    }
+
+    // Unfinished jobs start over
+    fn restart(&mut self) {
+        self.processed = 0;
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct IntentMetrics {
+    pub(crate) intent: String,
+    // Samples labelled with this intent
+    pub(crate) support: usize,
+    // Samples detected as this intent
+    pub(crate) predicted: usize,
+    pub(crate) correct: usize,
+    pub(crate) precision: f64,
+    pub(crate) recall: f64,
+    pub(crate) f1: f64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct UnmatchedSample {
+    pub(crate) utterance: String,
+    pub(crate) expected: String,
+    pub(crate) predicted: Option<String>,
+    // The top candidate and its score, which may be below the threshold
+    #[serde(rename = "topCandidate")]
+    pub(crate) top_candidate: Option<String>,
+    pub(crate) confidence: f64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct ThresholdPoint {
+    pub(crate) threshold: f64,
+    pub(crate) accuracy: f64,
+    #[serde(rename = "macroPrecision")]
+    pub(crate) macro_precision: f64,
+    #[serde(rename = "macroRecall")]
+    pub(crate) macro_recall: f64,
+    #[serde(rename = "macroF1")]
+    pub(crate) macro_f1: f64,
+    // Samples with no intent detected
+    #[serde(rename = "noMatch")]
+    pub(crate) no_match: usize,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct EvalReport {
+    #[serde(rename = "embeddingModel")]
+    pub(crate) embedding_model: String,
+    // Similarity threshold in settings when the job ran
+    pub(crate) threshold: f64,
+    pub(crate) accuracy: f64,
+    pub(crate) intents: Vec<IntentMetrics>,
+    // Rows are labelled intents and columns are detected ones, empty label means no intent
+    pub(crate) labels: Vec<String>,
+    pub(crate) confusion: Vec<Vec<usize>>,
+    pub(crate) unmatched: Vec<UnmatchedSample>,
+    pub(crate) sweep: Vec<ThresholdPoint>,
+    // Threshold with the highest macro F1
+    #[serde(rename = "bestThreshold")]
+    pub(crate) best_threshold: f64,
+}
+
+// Top candidate of a sample, before the threshold is applied
+struct Prediction {
+    intent: Option<String>,
+    score: f64,
+    stage: Option<IntentMatchStage>,
+}
+
+impl Prediction {
+    fn new(detection: IntentDetection) -> Self {
+        match detection.candidates.into_iter().next() {
+            Some(c) => Prediction {
+                intent: Some(c.intent_name),
+                score: c.score,
+                stage: Some(c.stage),
+            },
+            None => Prediction {
+                intent: None,
+                score: 0.,
+                stage: None,
+            },
+        }
+    }
+
+    // Keyword and regex matches don't depend on the threshold
+    fn at(&self, threshold: f64) -> Option<&str> {
+        match self.stage {
+            Some(IntentMatchStage::Embedding) if self.score < threshold => None,
+            _ => self.intent.as_deref(),
+        }
+    }
+}
+
+pub(crate) fn init_table() -> Result<()> {
+    db::init_table(SET_TABLE)?;
+    QUEUE.init_table()
+}
+
+pub(crate) fn list_sets(robot_id: &str) -> Result<Vec<EvalSet>> {
+    let mut sets: Vec<EvalSet> = db::get_all(SET_TABLE)?;
+    sets.retain(|s| s.robot_id.eq(robot_id));
+    sets.sort_by_key(|s| std::cmp::Reverse(s.created_at));
+    Ok(sets)
+}
+
+fn get_set(robot_id: &str, set_id: &str) -> Result<EvalSet> {
+    match db::query::<_, _, _, EvalSet>(SET_TABLE, set_id)? {
+        Some(s) if s.robot_id.eq(robot_id) => Ok(s),
+        _ => Err(Error::WithMessage(format!("Test set {set_id} not found."))),
+    }
+}
+
+// A new set is created if id is empty
+pub(crate) fn save_set(robot_id: &str, mut set: EvalSet) -> Result<EvalSet> {
+    set.samples.retain(|s| !s.utterance.trim().is_empty());
+    if set.samples.is_empty() {
+        return Err(Error::WithMessage(String::from("Test set has no samples.")));
+    }
+    if set.id.is_empty() {
+        set.id = scru128::new_string();
+        set.created_at = now_secs();
+    } else {
+        set.created_at = get_set(robot_id, &set.id)?.created_at;
+    }
+    set.robot_id = String::from(robot_id);
+    db::write(SET_TABLE, &set.id, &set)?;
+    Ok(set)
+}
+
+// Columns are `utterance` and `intent`, or JSON array of samples
+pub(crate) fn read_samples(file_name: &str, b: &[u8]) -> Result<Vec<EvalSample>> {
+    if file_name.to_lowercase().ends_with(".json") {
+        return Ok(serde_json::from_slice(b)?);
+    }
+    let s = parser::decode_text(b);
+    let mut rows = qa_file::parse_csv(&s, qa_file::detect_delimiter(&s))?.into_iter();
+    let Some(header) = rows.next() else {
+        return Ok(vec![]);
+    };
+    let position = |name: &str| {
+        header
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(name))
+    };
+    let (Some(utterance_idx), Some(intent_idx)) = (position("utterance"), position("intent"))
+    else {
+        return Err(Error::WithMessage(String::from(
+            "CSV header must have `utterance` and `intent` columns.",
+        )));
+    };
+    let samples = rows
+        .map(|row| EvalSample {
+            utterance: row.get(utterance_idx).cloned().unwrap_or_default(),
+            intent: row
+                .get(intent_idx)
+                .map(|s| String::from(s.trim()))
+                .unwrap_or_default(),
+        })
+        .collect();
+    Ok(samples)
+}
+
+pub(crate) fn delete_set(robot_id: &str, set_id: &str) -> Result<()> {
+    get_set(robot_id, set_id)?;
+    db::remove(SET_TABLE, set_id)
+}
+
+fn update<F: FnOnce(&mut EvalJob) -> Result<()>>(job_id: &str, f: F) -> Result<EvalJob> {
+    QUEUE.update(job_id, f)
+}
+
+pub(crate) fn start_worker() -> Result<()> {
+    QUEUE.start_worker::<EvalJob, _, _>(|job_id| async move { run(&job_id).await })
+}
+
+pub(crate) fn submit(robot_id: &str, set_id: &str) -> Result<EvalJob> {
+    let set = get_set(robot_id, set_id)?;
+    let now = now_secs();
+    let job = EvalJob {
+        id: scru128::new_string(),
+        robot_id: String::from(robot_id),
+        set_id: set.id,
+        set_name: set.name,
+        status: JobStatus::Pending,
+        total: set.samples.len(),
+        processed: 0,
+        err: String::new(),
+        report: None,
+        created_at: now,
+        updated_at: now,
+    };
+    QUEUE.submit(&job)?;
+    Ok(job)
+}
+
+async fn run(job_id: &str) -> Result<()> {
+    let mut started = false;
+    let job = update(job_id, |j| {
+        if j.status == JobStatus::Pending {
+            j.status = JobStatus::Running;
+            started = true;
+        }
+        Ok(())
+    })?;
+    if !started {
+        return Ok(());
+    }
+    let Some(set): Option<EvalSet> = db::query(SET_TABLE, job.set_id.as_str())? else {
+        return Err(Error::WithMessage(String::from("Test set was deleted.")));
+    };
+    let Some(settings) = settings::get_settings(&job.robot_id)? else {
+        return Err(Error::WithMessage(format!(
+            "Can not find settings of {}",
+            &job.robot_id
+        )));
+    };
+    let embedding = &settings.sentence_embedding_provider;
+    let total = set.samples.len();
+    let mut predictions: Vec<Prediction> = Vec::with_capacity(total);
+    for (idx, sample) in set.samples.iter().enumerate() {
+        let detection = detector::rank(&job.robot_id, &sample.utterance, Some(1), None).await?;
+        predictions.push(Prediction::new(detection));
+        if (idx + 1) % PROGRESS_INTERVAL == 0 {
+            let job = update(job_id, |j| {
+                j.total = total;
+                j.processed = idx + 1;
+                Ok(())
+            })?;
+            if job.status == JobStatus::Cancelled {
+                log::info!("Evaluation job {job_id} was cancelled");
+                return Ok(());
+            }
+        }
+    }
+    let report = EvalReport::new(
+        &set.samples,
+        &predictions,
+        serde_json::to_string(&embedding.provider)?,
+        embedding.similarity_threshold as f64,
+    );
+    update(job_id, |j| {
+        if j.status == JobStatus::Running {
+            j.status = JobStatus::Completed;
+            j.total = total;
+            j.processed = total;
+            j.report = Some(report);
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+fn get_job(robot_id: &str, job_id: &str) -> Result<EvalJob> {
+    match QUEUE.get::<EvalJob>(job_id)? {
+        Some(j) if j.robot_id.eq(robot_id) => Ok(j),
+        _ => Err(Error::WithMessage(format!(
+            "Evaluation job {job_id} not found."
+        ))),
+    }
+}
+
+pub(crate) fn list_jobs(robot_id: &str) -> Result<Vec<EvalJob>> {
+    let mut jobs: Vec<EvalJob> = QUEUE.get_all()?;
+    jobs.retain(|j| j.robot_id.eq(robot_id));
+    jobs.sort_by_key(|j| std::cmp::Reverse(j.created_at));
+    jobs.iter_mut().for_each(|j| j.report = None);
+    Ok(jobs)
+}
+
+pub(crate) fn status(robot_id: &str, job_id: &str) -> Result<EvalJob> {
+    get_job(robot_id, job_id)
+}
+
+pub(crate) fn cancel(robot_id: &str, job_id: &str) -> Result<EvalJob> {
+    get_job(robot_id, job_id)?;
+    update(job_id, |j| {
+        if !j.status.is_unfinished() {
+            return Err(Error::WithMessage(String::from(
+                "Only pending or running jobs can be cancelled.",
+            )));
+        }
+        j.status = JobStatus::Cancelled;
+        Ok(())
+    })
+}
+
+pub(crate) fn delete_job(robot_id: &str, job_id: &str) -> Result<()> {
+    let job = get_job(robot_id, job_id)?;
+    if job.status.is_unfinished() {
+        return Err(Error::WithMessage(String::from(
+            "Cancel the job before deleting it.",
+        )));
+    }
+    QUEUE.remove(job_id)
+}
+
+// Label of each sample and what was detected at a threshold
+struct Outcomes<'a> {
+    pairs: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Outcomes<'a> {
+    fn new(samples: &'a [EvalSample], predictions: &'a [Prediction], threshold: f64) -> Self {
+        let pairs = samples
+            .iter()
+            .zip(predictions.iter())
+            .map(|(s, p)| (s.intent.as_str(), p.at(threshold).unwrap_or_default()))
+            .collect();
+        Outcomes { pairs }
+    }
+
+    fn accuracy(&self) -> f64 {
+        let correct = self.pairs.iter().filter(|(e, p)| e == p).count();
+        ratio(correct, self.pairs.len())
+    }
+
+    // Samples labelled with no intent only count against the intents detected for them
+    fn intents(&self) -> Vec<IntentMetrics> {
+        let mut counts: HashMap<&str, (usize, usize, usize)> = HashMap::new();
+        for &(expected, predicted) in self.pairs.iter() {
+            if !expected.is_empty() {
+                counts.entry(expected).or_default().0 += 1;
+            }
+            if !predicted.is_empty() {
+                counts.entry(predicted).or_default().1 += 1;
+            }
+            if !expected.is_empty() && expected == predicted {
+                counts.entry(expected).or_default().2 += 1;
+            }
+        }
+        let mut metrics: Vec<IntentMetrics> = counts
+            .into_iter()
+            .map(|(intent, (support, predicted, correct))| {
+                let precision = ratio(correct, predicted);
+                let recall = ratio(correct, support);
+                IntentMetrics {
+                    intent: String::from(intent),
+                    support,
+                    predicted,
+                    correct,
+                    precision,
+                    recall,
+                    f1: f1(precision, recall),
+                }
+            })
+            .collect();
+        metrics.sort_by(|a, b| a.intent.cmp(&b.intent));
+        metrics
+    }
+}
+
+fn ratio(n: usize, d: usize) -> f64 {
+    if d == 0 { 0. } else { n as f64 / d as f64 }
+}
+
+fn f1(precision: f64, recall: f64) -> f64 {
+    if precision + recall == 0. {
+        0.
+    } else {
+        2. * precision * recall / (precision + recall)
+    }
+}
+
+impl EvalReport {
+    fn new(
+        samples: &[EvalSample],
+        predictions: &[Prediction],
+        embedding_model: String,
+        threshold: f64,
+    ) -> Self {
+        let outcomes = Outcomes::new(samples, predictions, threshold);
+        let mut labels: BTreeSet<&str> = BTreeSet::new();
+        for &(expected, predicted) in outcomes.pairs.iter() {
+            labels.insert(expected);
+            labels.insert(predicted);
+        }
+        let labels: Vec<&str> = labels.into_iter().collect();
+        let mut confusion = vec![vec![0usize; labels.len()]; labels.len()];
+        for (expected, predicted) in outcomes.pairs.iter() {
+            let row = labels.binary_search(expected).unwrap_or_default();
+            let col = labels.binary_search(predicted).unwrap_or_default();
+            confusion[row][col] += 1;
+        }
+        let unmatched = samples
+            .iter()
+            .zip(predictions.iter())
+            .filter(|(s, p)| p.at(threshold).unwrap_or_default() != s.intent)
+            .map(|(s, p)| UnmatchedSample {
+                utterance: s.utterance.clone(),
+                expected: s.intent.clone(),
+                predicted: p.at(threshold).map(String::from),
+                top_candidate: p.intent.clone(),
+                confidence: p.score,
+            })
+            .collect();
+        let sweep: Vec<ThresholdPoint> = (SWEEP_FROM..=SWEEP_TO)
+            .map(|t| {
+                let t = t as f64 / 100.;
+                let outcomes = Outcomes::new(samples, predictions, t);
+                let intents = outcomes.intents();
+                let labelled: Vec<&IntentMetrics> =
+                    intents.iter().filter(|m| m.support > 0).collect();
+                let mean = |f: fn(&IntentMetrics) -> f64| {
+                    labelled.iter().copied().map(f).sum::<f64>() / labelled.len().max(1) as f64
+                };
+                ThresholdPoint {
+                    threshold: t,
+                    accuracy: outcomes.accuracy(),
+                    macro_precision: mean(|m| m.precision),
+                    macro_recall: mean(|m| m.recall),
+                    macro_f1: mean(|m| m.f1),
+                    no_match: outcomes.pairs.iter().filter(|(_, p)| p.is_empty()).count(),
+                }
+            })
+            .collect();
+        let best_threshold = sweep
+            .iter()
+            .reduce(|best, p| {
+                if p.macro_f1 > best.macro_f1
+                    || (p.macro_f1 == best.macro_f1 && p.accuracy > best.accuracy)
+                {
+                    p
+                } else {
+                    best
+                }
+            })
+            .map_or(threshold, |p| p.threshold);
+        EvalReport {
+            embedding_model,
+            threshold,
+            accuracy: outcomes.accuracy(),
+            intents: outcomes.intents(),
+            labels: labels.into_iter().map(String::from).collect(),
+            confusion,
+            unmatched,
+            sweep,
+            best_threshold,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(utterance: &str, intent: &str) -> EvalSample {
+        EvalSample {
+            utterance: String::from(utterance),
+            intent: String::from(intent),
+        }
+    }
+
+    fn prediction(intent: &str, stage: IntentMatchStage, score: f64) -> Prediction {
+        Prediction {
+            intent: Some(String::from(intent)),
+            score,
+            stage: Some(stage),
+        }
+    }
+
+    fn fixture() -> (Vec<EvalSample>, Vec<Prediction>) {
+        let samples = vec![
+            sample("hi", "greet"),
+            sample("hello", "greet"),
+            sample("bye", "farewell"),
+            sample("weather?", ""),
+        ];
+        let predictions = vec![
+            prediction("greet", IntentMatchStage::Embedding, 0.9),
+            prediction("greet", IntentMatchStage::Embedding, 0.6),
+            // Keyword matches are kept at any threshold
+            prediction("greet", IntentMatchStage::Keyword, 1.),
+            prediction("farewell", IntentMatchStage::Embedding, 0.7),
+        ];
+        (samples, predictions)
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn outcomes_count_each_intent() {
+        let (samples, predictions) = fixture();
+        let outcomes = Outcomes::new(&samples, &predictions, 0.8);
+        assert!(close(outcomes.accuracy(), 0.5));
+        let intents = outcomes.intents();
+        assert_eq!(intents.len(), 2);
+        let farewell = &intents[0];
+        assert_eq!(farewell.intent, "farewell");
+        assert_eq!(
+            (farewell.support, farewell.predicted, farewell.correct),
+            (1, 0, 0)
+        );
+        assert!(close(farewell.precision, 0.) && close(farewell.recall, 0.));
+        assert!(close(farewell.f1, 0.));
+        let greet = &intents[1];
+        assert_eq!(greet.intent, "greet");
+        assert_eq!((greet.support, greet.predicted, greet.correct), (2, 2, 1));
+        assert!(close(greet.precision, 0.5) && close(greet.recall, 0.5));
+        assert!(close(greet.f1, 0.5));
+
+        // The unlabelled sample only counts against the intent detected for it
+        let intents = Outcomes::new(&samples, &predictions, 0.5).intents();
+        let farewell = &intents[0];
+        assert_eq!(
+            (farewell.support, farewell.predicted, farewell.correct),
+            (1, 1, 0)
+        );
+        let greet = &intents[1];
+        assert_eq!((greet.support, greet.predicted, greet.correct), (2, 3, 2));
+        assert!(close(greet.precision, 2. / 3.) && close(greet.recall, 1.));
+        assert!(close(greet.f1, 0.8));
+    }
+
+    #[test]
+    fn empty_outcomes_have_no_scores() {
+        let outcomes = Outcomes::new(&[], &[], 0.8);
+        assert!(close(outcomes.accuracy(), 0.));
+        assert!(outcomes.intents().is_empty());
+        assert!(close(f1(0., 0.), 0.));
+    }
+
+    #[test]
+    fn report_sweeps_thresholds() {
+        let (samples, predictions) = fixture();
+        let report = EvalReport::new(&samples, &predictions, String::new(), 0.8);
+        assert!(close(report.accuracy, 0.5));
+        assert_eq!(report.labels, vec!["", "farewell", "greet"]);
+        assert_eq!(
+            report.confusion,
+            vec![vec![1, 0, 0], vec![0, 0, 1], vec![1, 0, 1]]
+        );
+        let unmatched: Vec<&str> = report
+            .unmatched
+            .iter()
+            .map(|u| u.utterance.as_str())
+            .collect();
+        assert_eq!(unmatched, vec!["hello", "bye"]);
+        assert_eq!(report.unmatched[0].predicted, None);
+        assert_eq!(report.unmatched[0].top_candidate.as_deref(), Some("greet"));
+
+        assert_eq!(report.sweep.len(), (SWEEP_TO - SWEEP_FROM + 1) as usize);
+        let at = |t: f64| report.sweep.iter().find(|p| close(p.threshold, t)).unwrap();
+        // Macro averages are over the labelled intents only
+        let p = at(0.5);
+        assert!(close(p.macro_precision, 1. / 3.) && close(p.macro_recall, 0.5));
+        assert!(close(p.macro_f1, 0.4) && close(p.accuracy, 0.5));
+        assert_eq!(p.no_match, 0);
+        let p = at(0.8);
+        assert!(close(p.macro_f1, 0.25) && close(p.accuracy, 0.5));
+        assert_eq!(p.no_match, 2);
+        let p = at(0.95);
+        assert!(close(p.macro_f1, 0.) && close(p.accuracy, 0.25));
+        // The first threshold with the highest macro F1
+        assert!(close(report.best_threshold, 0.5));
+    }
+}
@@ -0,0 +1,886 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Write};
+use std::sync::LazyLock;
+use std::vec::Vec;
+
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use serde_yaml::Value as YamlValue;
+use zip::write::SimpleFileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use super::dto::{EntityType, EnumEntry, IntentDetail, IntentSlot, SlotSpan};
+use crate::kb::{parser, qa_file};
+use crate::result::{Error, Result};
+
+// `[text](entity)`, `[text](entity:value)` or `[text]{"entity": "name", "value": "v"}`
+static RASA_ENTITY_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\[([^\]]+?)\](?:\(([^:)]+?)(?::([^)]+))?\)|\{([^}]+?)\})").unwrap()
+});
+
+#[derive(Clone, Copy)]
+pub(crate) enum IntentFileFormat {
+    Dialogflow,
+    Rasa,
+    Csv,
+}
+
+impl IntentFileFormat {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "dialogflow" | "zip" => Some(Self::Dialogflow),
+            "rasa" | "yml" | "yaml" => Some(Self::Rasa),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+
+    fn from_extension(file_name: &str) -> Option<Self> {
+        let (_, ext) = file_name.rsplit_once('.')?;
+        Self::from_name(ext)
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            Self::Dialogflow => "application/zip",
+            Self::Rasa => "application/yaml; charset=utf-8",
+            Self::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub(crate) fn file_name(&self, robot_id: &str) -> String {
+        match self {
+            Self::Dialogflow => format!("{robot_id}-dialogflow.zip"),
+            Self::Rasa => String::from("nlu.yml"),
+            Self::Csv => format!("{robot_id}-intents.csv"),
+        }
+    }
+}
+
+// An intent read from or written to files, which is merged into the intent of the same name
+#[derive(Default)]
+pub(crate) struct IntentRecord {
+    pub(crate) name: String,
+    pub(crate) keywords: Vec<String>,
+    pub(crate) regexes: Vec<String>,
+    pub(crate) phrases: Vec<(String, Vec<SlotSpan>)>,
+    pub(crate) slots: Vec<IntentSlot>,
+}
+
+impl From<&IntentDetail> for IntentRecord {
+    fn from(d: &IntentDetail) -> Self {
+        IntentRecord {
+            name: d.intent_name.clone(),
+            keywords: d.keywords.clone(),
+            regexes: d.regexes.clone(),
+            phrases: d
+                .phrases
+                .iter()
+                .map(|p| (p.phrase.clone(), p.spans.clone()))
+                .collect(),
+            slots: d.slots.clone(),
+        }
+    }
+}
+
+pub(crate) fn read(file_name: &str, b: &[u8]) -> Result<Vec<IntentRecord>> {
+    match IntentFileFormat::from_extension(file_name) {
+        Some(IntentFileFormat::Dialogflow) => read_dialogflow(b),
+        Some(IntentFileFormat::Rasa) => read_rasa(&parser::decode_text(b)),
+        Some(IntentFileFormat::Csv) => read_csv(&parser::decode_text(b)),
+        None => Err(Error::WithMessage(format!(
+            "Unsupported file format of `{file_name}`, supported formats are Dialogflow ES agent zip, Rasa NLU YAML and CSV"
+        ))),
+    }
+}
+
+pub(crate) fn write(format: IntentFileFormat, records: &[IntentRecord]) -> Result<Vec<u8>> {
+    match format {
+        IntentFileFormat::Dialogflow => write_dialogflow(records),
+        IntentFileFormat::Rasa => Ok(write_rasa(records).into_bytes()),
+        IntentFileFormat::Csv => Ok(write_csv(records).into_bytes()),
+    }
+}
+
+fn find_record<'a>(records: &'a mut Vec<IntentRecord>, name: &str) -> &'a mut IntentRecord {
+    let idx = match records.iter().position(|r| r.name.eq(name)) {
+        Some(idx) => idx,
+        None => {
+            records.push(IntentRecord {
+                name: String::from(name),
+                ..Default::default()
+            });
+            records.len() - 1
+        }
+    };
+    &mut records[idx]
+}
+
+fn push_enum_value(entries: &mut Vec<EnumEntry>, value: &str, synonym: Option<&str>) {
+    let idx = match entries
+        .iter()
+        .position(|e| e.value.to_lowercase() == value.to_lowercase())
+    {
+        Some(idx) => idx,
+        None => {
+            entries.push(EnumEntry {
+                value: String::from(value),
+                synonyms: vec![],
+            });
+            entries.len() - 1
+        }
+    };
+    if let Some(s) = synonym
+        && !s.eq_ignore_ascii_case(value)
+        && !entries[idx].synonyms.iter().any(|e| e.eq(s))
+    {
+        entries[idx].synonyms.push(String::from(s));
+    }
+}
+
+// Rows are `intent,phrase` with an optional `type` column of phrase, keyword or regex
+fn read_csv(s: &str) -> Result<Vec<IntentRecord>> {
+    let mut rows = qa_file::parse_csv(s, qa_file::detect_delimiter(s))?;
+    if rows
+        .first()
+        .and_then(|r| r.first())
+        .is_some_and(|c| c.trim().eq_ignore_ascii_case("intent"))
+    {
+        rows.remove(0);
+    }
+    let mut records: Vec<IntentRecord> = Vec::with_capacity(32);
+    for row in rows.iter() {
+        let cell = |idx: usize| row.get(idx).map(|c| c.trim()).unwrap_or_default();
+        if cell(0).is_empty() {
+            continue;
+        }
+        let r = find_record(&mut records, cell(0));
+        let text = cell(1);
+        if text.is_empty() {
+            continue;
+        }
+        match cell(2).to_lowercase().as_str() {
+            "keyword" => r.keywords.push(String::from(text)),
+            "regex" => r.regexes.push(String::from(text)),
+            _ => r.phrases.push((String::from(text), vec![])),
+        }
+    }
+    Ok(records)
+}
+
+fn write_csv(records: &[IntentRecord]) -> String {
+    let mut s = String::with_capacity(records.len() * 1024);
+    s.push('\u{feff}');
+    s.push_str("intent,phrase,type\r\n");
+    let mut push_row = |intent: &str, text: &str, t: &str| {
+        s.push_str(&qa_file::csv_field(intent));
+        s.push(',');
+        s.push_str(&qa_file::csv_field(text));
+        s.push(',');
+        s.push_str(t);
+        s.push_str("\r\n");
+    };
+    for r in records.iter() {
+        if r.phrases.is_empty() && r.keywords.is_empty() && r.regexes.is_empty() {
+            push_row(&r.name, "", "phrase");
+        }
+        r.phrases
+            .iter()
+            .for_each(|(p, _)| push_row(&r.name, p, "phrase"));
+        r.keywords
+            .iter()
+            .for_each(|k| push_row(&r.name, k, "keyword"));
+        r.regexes
+            .iter()
+            .for_each(|re| push_row(&r.name, re, "regex"));
+    }
+    s
+}
+
+fn join_patterns(patterns: &[&str]) -> String {
+    if patterns.len() == 1 {
+        return String::from(patterns[0]);
+    }
+    let patterns: Vec<String> = patterns.iter().map(|p| format!("(?:{p})")).collect();
+    patterns.join("|")
+}
+
+// Annotations are removed from the example, spans of them are in chars
+fn parse_rasa_example(s: &str) -> (String, Vec<(SlotSpan, Option<String>)>) {
+    let mut text = String::with_capacity(s.len());
+    let mut spans = Vec::new();
+    let mut last = 0;
+    for cap in RASA_ENTITY_REGEX.captures_iter(s) {
+        let m = cap.get(0).unwrap();
+        text.push_str(&s[last..m.start()]);
+        last = m.end();
+        let value = &cap[1];
+        let (entity, synonym_of) = if let Some(e) = cap.get(2) {
+            (
+                String::from(e.as_str().trim()),
+                cap.get(3).map(|v| String::from(v.as_str())),
+            )
+        } else {
+            let v: Value = serde_json::from_str(&format!("{{{}}}", &cap[4])).unwrap_or_default();
+            (
+                String::from(v["entity"].as_str().unwrap_or_default()),
+                v["value"].as_str().map(String::from),
+            )
+        };
+        let start = text.chars().count();
+        text.push_str(value);
+        if !entity.is_empty() {
+            let span = SlotSpan {
+                slot: entity,
+                start,
+                end: start + value.chars().count(),
+            };
+            spans.push((span, synonym_of));
+        }
+    }
+    text.push_str(&s[last..]);
+    (text, spans)
+}
+
+struct RasaItem {
+    kind: String,
+    name: String,
+    examples: Vec<String>,
+    keywords: Vec<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct RasaNlu {
+    #[serde(default)]
+    nlu: Vec<RasaYamlItem>,
+}
+
+#[derive(Deserialize)]
+struct RasaYamlItem {
+    intent: Option<YamlValue>,
+    synonym: Option<YamlValue>,
+    regex: Option<YamlValue>,
+    lookup: Option<YamlValue>,
+    #[serde(default)]
+    examples: Option<RasaExamples>,
+    #[serde(default)]
+    metadata: Option<YamlValue>,
+}
+
+// A block of `- example` lines, or a list of examples which may have metadata
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RasaExamples {
+    Block(String),
+    List(Vec<RasaExample>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RasaExample {
+    Text(String),
+    WithMetadata { text: String },
+}
+
+// Names like `yes` or `1` are read as other scalars
+fn yaml_string(v: &YamlValue) -> Option<String> {
+    match v {
+        YamlValue::String(s) => Some(s.clone()),
+        YamlValue::Bool(b) => Some(b.to_string()),
+        YamlValue::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn rasa_items(s: &str) -> Result<Vec<RasaItem>> {
+    let nlu: Option<RasaNlu> = serde_yaml::from_str(s)
+        .map_err(|e| Error::WithMessage(format!("Invalid Rasa NLU data: {e}")))?;
+    let mut items: Vec<RasaItem> = Vec::with_capacity(64);
+    for item in nlu.unwrap_or_default().nlu.into_iter() {
+        let kinds = [
+            ("intent", &item.intent),
+            ("synonym", &item.synonym),
+            ("regex", &item.regex),
+            ("lookup", &item.lookup),
+        ];
+        let Some((kind, name)) = kinds
+            .into_iter()
+            .find_map(|(kind, v)| v.as_ref().and_then(yaml_string).map(|n| (kind, n)))
+        else {
+            continue;
+        };
+        let examples = match item.examples {
+            Some(RasaExamples::Block(b)) => b
+                .lines()
+                .filter_map(|l| l.trim().strip_prefix('-'))
+                .map(|e| String::from(e.trim()))
+                .filter(|e| !e.is_empty())
+                .collect(),
+            Some(RasaExamples::List(list)) => list
+                .into_iter()
+                .map(|e| match e {
+                    RasaExample::Text(t) | RasaExample::WithMetadata { text: t } => t,
+                })
+                .collect(),
+            None => vec![],
+        };
+        let keywords = item
+            .metadata
+            .as_ref()
+            .and_then(|m| m.get("keywords"))
+            .and_then(|k| k.as_sequence())
+            .map(|k| k.iter().filter_map(yaml_string).collect())
+            .unwrap_or_default();
+        items.push(RasaItem {
+            kind: String::from(kind),
+            name,
+            examples,
+            keywords,
+        });
+    }
+    Ok(items)
+}
+
+fn read_rasa(s: &str) -> Result<Vec<IntentRecord>> {
+    let items = rasa_items(s)?;
+    let mut records: Vec<IntentRecord> = Vec::with_capacity(items.len());
+    let mut regexes: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut entries: HashMap<String, Vec<EnumEntry>> = HashMap::new();
+    let mut synonyms: Vec<(&str, &str)> = Vec::new();
+    // Entity annotations are turned into slots after all items are read
+    let mut annotated: Vec<(usize, Vec<String>)> = Vec::new();
+    for item in items.iter() {
+        match item.kind.as_str() {
+            "intent" => {
+                let mut r = IntentRecord {
+                    name: item.name.clone(),
+                    keywords: item.keywords.clone(),
+                    ..Default::default()
+                };
+                let mut entities: Vec<String> = Vec::new();
+                for e in item.examples.iter() {
+                    let (text, spans) = parse_rasa_example(e);
+                    let mut slot_spans = Vec::with_capacity(spans.len());
+                    for (span, synonym_of) in spans.into_iter() {
+                        let value_text: String = text
+                            .chars()
+                            .skip(span.start)
+                            .take(span.end - span.start)
+                            .collect();
+                        let list = entries.entry(span.slot.clone()).or_default();
+                        match &synonym_of {
+                            Some(v) => push_enum_value(list, v, Some(&value_text)),
+                            None => push_enum_value(list, &value_text, None),
+                        }
+                        if !entities.contains(&span.slot) {
+                            entities.push(span.slot.clone());
+                        }
+                        slot_spans.push(span);
+                    }
+                    r.phrases.push((text, slot_spans));
+                }
+                annotated.push((records.len(), entities));
+                records.push(r);
+            }
+            "regex" => regexes
+                .entry(item.name.as_str())
+                .or_default()
+                .extend(item.examples.iter().map(|e| e.as_str())),
+            "lookup" => {
+                let list = entries.entry(item.name.clone()).or_default();
+                item.examples
+                    .iter()
+                    .for_each(|e| push_enum_value(list, e, None));
+            }
+            "synonym" => item
+                .examples
+                .iter()
+                .for_each(|e| synonyms.push((item.name.as_str(), e.as_str()))),
+            _ => {}
+        }
+    }
+    // Regexes named after intents belong to the intents
+    for r in records.iter_mut() {
+        if let Some(patterns) = regexes.remove(r.name.as_str()) {
+            r.regexes.extend(patterns.into_iter().map(String::from));
+        }
+    }
+    for (value, synonym) in synonyms.into_iter() {
+        for list in entries.values_mut() {
+            if list.iter().any(|e| e.value.eq(value)) {
+                push_enum_value(list, value, Some(synonym));
+            }
+        }
+    }
+    // Annotated synonyms are not values of their own
+    for list in entries.values_mut() {
+        let synonyms: Vec<String> = list
+            .iter()
+            .flat_map(|e| e.synonyms.iter().map(|s| s.to_lowercase()))
+            .collect();
+        list.retain(|e| !synonyms.contains(&e.value.to_lowercase()));
+    }
+    for (idx, entities) in annotated.into_iter() {
+        for name in entities.into_iter() {
+            let entity_type = match regexes.get(name.as_str()) {
+                Some(patterns) => EntityType::Regex(join_patterns(patterns)),
+                None => EntityType::Enum(entries.get(&name).cloned().unwrap_or_default()),
+            };
+            records[idx].slots.push(IntentSlot {
+                name,
+                entity_type,
+                var_name: String::new(),
+            });
+        }
+    }
+    Ok(records)
+}
+
+// Quoted if it would be read as something else, like booleans or numbers
+fn yaml_scalar(s: &str) -> String {
+    let lower = s.to_lowercase();
+    let plain = !s.is_empty()
+        && s.parse::<f64>().is_err()
+        && !matches!(
+            lower.as_str(),
+            "yes" | "no" | "y" | "n" | "true" | "false" | "on" | "off" | "null" | "~"
+        )
+        && !s.starts_with(|c: char| " -?:,[]{}#&*!|>'\"%@`".contains(c))
+        && !s.ends_with(' ')
+        && !s.contains(": ")
+        && !s.contains(" #");
+    if plain {
+        String::from(s)
+    } else {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+fn rasa_example(text: &str, spans: &[SlotSpan]) -> String {
+    let chars: Vec<char> = text.chars().filter(|c| *c != '\n' && *c != '\r').collect();
+    let mut spans: Vec<&SlotSpan> = spans.iter().filter(|s| s.end <= chars.len()).collect();
+    spans.sort_by_key(|s| s.start);
+    let mut s = String::with_capacity(text.len() + 32);
+    let mut last = 0;
+    for span in spans.into_iter() {
+        if span.start < last || span.start >= span.end {
+            continue;
+        }
+        s.extend(&chars[last..span.start]);
+        s.push('[');
+        s.extend(&chars[span.start..span.end]);
+        s.push_str("](");
+        s.push_str(&span.slot);
+        s.push(')');
+        last = span.end;
+    }
+    s.extend(&chars[last..]);
+    s
+}
+
+fn push_rasa_item(s: &mut String, kind: &str, name: &str, examples: &[&str]) {
+    s.push_str(&format!("- {kind}: {}\n  examples: |\n", yaml_scalar(name)));
+    for e in examples.iter() {
+        s.push_str(&format!("    - {}\n", e.replace(['\n', '\r'], " ")));
+    }
+}
+
+// Keywords are kept in metadata of intents, which Rasa ignores
+fn write_rasa(records: &[IntentRecord]) -> String {
+    let mut s = String::with_capacity(records.len() * 1024);
+    s.push_str("version: \"3.1\"\n\nnlu:\n");
+    // Entities of the same name in different intents are written once
+    let mut entities: BTreeMap<&str, &EntityType> = BTreeMap::new();
+    for r in records.iter() {
+        s.push_str(&format!("- intent: {}\n", yaml_scalar(&r.name)));
+        if !r.keywords.is_empty() {
+            s.push_str("  metadata:\n    keywords:\n");
+            for k in r.keywords.iter() {
+                s.push_str(&format!("    - {}\n", yaml_scalar(k)));
+            }
+        }
+        s.push_str("  examples: |\n");
+        for (text, spans) in r.phrases.iter() {
+            s.push_str(&format!("    - {}\n", rasa_example(text, spans)));
+        }
+        if !r.regexes.is_empty() {
+            let patterns: Vec<&str> = r.regexes.iter().map(|p| p.as_str()).collect();
+            push_rasa_item(&mut s, "regex", &r.name, &patterns);
+        }
+        for slot in r.slots.iter() {
+            entities.entry(&slot.name).or_insert(&slot.entity_type);
+        }
+    }
+    for (name, entity_type) in entities.into_iter() {
+        match entity_type {
+            EntityType::Regex(p) => push_rasa_item(&mut s, "regex", name, &[p.as_str()]),
+            EntityType::Enum(list) => {
+                let values: Vec<&str> = list.iter().map(|e| e.value.as_str()).collect();
+                push_rasa_item(&mut s, "lookup", name, &values);
+                for e in list.iter().filter(|e| !e.synonyms.is_empty()) {
+                    let synonyms: Vec<&str> = e.synonyms.iter().map(|v| v.as_str()).collect();
+                    push_rasa_item(&mut s, "synonym", &e.value, &synonyms);
+                }
+            }
+            EntityType::Number | EntityType::Date => {}
+        }
+    }
+    s
+}
+
+fn read_zip_json(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Value> {
+    let Some(b) = parser::read_zip_bytes(archive, name)? else {
+        return Err(zip::result::ZipError::FileNotFound.into());
+    };
+    let b = b.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&b);
+    Ok(serde_json::from_slice(b)?)
+}
+
+struct DialogflowAgent<'a> {
+    archive: ZipArchive<Cursor<&'a [u8]>>,
+    // Agents may be zipped with or without their root directory
+    root: String,
+    // File names without the root directory
+    names: Vec<String>,
+}
+
+impl DialogflowAgent<'_> {
+    fn read_json(&mut self, name: &str) -> Result<Value> {
+        read_zip_json(&mut self.archive, &format!("{}{name}", &self.root))
+    }
+
+    // Types of system entities other than numbers and dates are unknown
+    fn entity_type(&mut self, data_type: &str) -> Result<Option<EntityType>> {
+        let t = data_type.trim_start_matches('@');
+        if t.starts_with("sys.number")
+            || matches!(t, "sys.cardinal" | "sys.ordinal" | "sys.percentage")
+        {
+            return Ok(Some(EntityType::Number));
+        }
+        if matches!(t, "sys.date" | "sys.date-time") {
+            return Ok(Some(EntityType::Date));
+        }
+        let entity_file = format!("entities/{t}.json");
+        if t.starts_with("sys.") || !self.names.contains(&entity_file) {
+            return Ok(None);
+        }
+        let entity = self.read_json(&entity_file)?;
+        let prefix = format!("entities/{t}_entries_");
+        let entry_files: Vec<String> = self
+            .names
+            .iter()
+            .filter(|n| n.starts_with(&prefix))
+            .cloned()
+            .collect();
+        let mut list: Vec<EnumEntry> = Vec::new();
+        for name in entry_files.iter() {
+            let entries = self.read_json(name)?;
+            for e in entries.as_array().into_iter().flatten() {
+                let value = e["value"].as_str().unwrap_or_default();
+                if value.is_empty() {
+                    continue;
+                }
+                push_enum_value(&mut list, value, None);
+                for s in e["synonyms"].as_array().into_iter().flatten() {
+                    push_enum_value(&mut list, value, s.as_str());
+                }
+            }
+        }
+        if entity["isRegexp"].as_bool().unwrap_or(false) {
+            let patterns: Vec<&str> = list.iter().map(|e| e.value.as_str()).collect();
+            return Ok(Some(EntityType::Regex(join_patterns(&patterns))));
+        }
+        Ok(Some(EntityType::Enum(list)))
+    }
+}
+
+fn json_strings(v: &Value) -> Vec<String> {
+    v.as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|s| s.as_str().map(String::from))
+        .collect()
+}
+
+fn read_dialogflow(b: &[u8]) -> Result<Vec<IntentRecord>> {
+    let archive = ZipArchive::new(Cursor::new(b))?;
+    let names: Vec<String> = archive.file_names().map(String::from).collect();
+    let root = names.iter().find_map(|n| {
+        n.strip_suffix("agent.json")
+            .filter(|r| r.is_empty() || r.ends_with('/'))
+    });
+    let Some(root) = root else {
+        return Err(Error::WithMessage(String::from(
+            "agent.json is missing, it's not a Dialogflow ES agent.",
+        )));
+    };
+    let root = String::from(root);
+    let names = names
+        .iter()
+        .filter_map(|n| n.strip_prefix(root.as_str()).map(String::from))
+        .collect();
+    let mut agent = DialogflowAgent {
+        archive,
+        root,
+        names,
+    };
+    let mut intent_files: Vec<String> = agent
+        .names
+        .iter()
+        .filter(|n| n.starts_with("intents/") && n.ends_with(".json") && !n.contains("_usersays_"))
+        .cloned()
+        .collect();
+    intent_files.sort();
+    let mut records: Vec<IntentRecord> = Vec::with_capacity(intent_files.len());
+    for file in intent_files.iter() {
+        let intent = agent.read_json(file)?;
+        let Some(name) = intent["name"].as_str().filter(|n| !n.is_empty()) else {
+            continue;
+        };
+        let mut r = IntentRecord {
+            name: String::from(name),
+            keywords: json_strings(&intent["keywords"]),
+            regexes: json_strings(&intent["regexes"]),
+            ..Default::default()
+        };
+        let mut data_types: Vec<(String, String)> = Vec::new();
+        for p in intent["responses"][0]["parameters"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            if let (Some(n), Some(t)) = (p["name"].as_str(), p["dataType"].as_str()) {
+                data_types.push((String::from(n), String::from(t)));
+            }
+        }
+        // Values annotated in training phrases, for slots of unknown entity types
+        let mut annotated: HashMap<String, Vec<EnumEntry>> = HashMap::new();
+        let prefix = format!("{}_usersays_", file.trim_end_matches(".json"));
+        let usersays_files: Vec<String> = agent
+            .names
+            .iter()
+            .filter(|n| n.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for usersays in usersays_files.iter() {
+            let samples = agent.read_json(usersays)?;
+            for sample in samples.as_array().into_iter().flatten() {
+                let mut text = String::with_capacity(64);
+                let mut spans: Vec<SlotSpan> = Vec::new();
+                for part in sample["data"].as_array().into_iter().flatten() {
+                    let t = part["text"].as_str().unwrap_or_default();
+                    let start = text.chars().count();
+                    text.push_str(t);
+                    let alias = part["alias"].as_str().unwrap_or_default();
+                    if alias.is_empty() || t.trim().is_empty() {
+                        continue;
+                    }
+                    spans.push(SlotSpan {
+                        slot: String::from(alias),
+                        start,
+                        end: start + t.chars().count(),
+                    });
+                    push_enum_value(annotated.entry(String::from(alias)).or_default(), t, None);
+                    if let Some(meta) = part["meta"].as_str()
+                        && data_types.iter().all(|(n, _)| n.ne(alias))
+                    {
+                        data_types.push((String::from(alias), String::from(meta)));
+                    }
+                }
+                if !text.trim().is_empty() && r.phrases.iter().all(|(p, _)| p.ne(&text)) {
+                    r.phrases.push((text, spans));
+                }
+            }
+        }
+        for (slot_name, data_type) in data_types.into_iter() {
+            let entity_type = match agent.entity_type(&data_type)? {
+                Some(t) => t,
+                None => match annotated.remove(&slot_name) {
+                    Some(list) => EntityType::Enum(list),
+                    None => continue,
+                },
+            };
+            r.slots.push(IntentSlot {
+                name: slot_name,
+                entity_type,
+                var_name: String::new(),
+            });
+        }
+        records.push(r);
+    }
+    Ok(records)
+}
+
+// Names of files and entities in Dialogflow agents
+fn dialogflow_name(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn dialogflow_parts(text: &str, spans: &[SlotSpan], data_types: &HashMap<&str, String>) -> Value {
+    let chars: Vec<char> = text.chars().collect();
+    let mut spans: Vec<&SlotSpan> = spans.iter().filter(|s| s.end <= chars.len()).collect();
+    spans.sort_by_key(|s| s.start);
+    let mut parts: Vec<Value> = Vec::with_capacity(spans.len() * 2 + 1);
+    let mut last = 0;
+    for span in spans.into_iter() {
+        if span.start < last || span.start >= span.end {
+            continue;
+        }
+        if span.start > last {
+            let t: String = chars[last..span.start].iter().collect();
+            parts.push(json!({ "text": t, "userDefined": false }));
+        }
+        let t: String = chars[span.start..span.end].iter().collect();
+        let meta = data_types
+            .get(span.slot.as_str())
+            .map_or("@sys.any", |t| t.as_str());
+        parts.push(json!({ "text": t, "alias": &span.slot, "meta": meta, "userDefined": true }));
+        last = span.end;
+    }
+    if last < chars.len() {
+        let t: String = chars[last..].iter().collect();
+        parts.push(json!({ "text": t, "userDefined": false }));
+    }
+    Value::Array(parts)
+}
+
+fn put_json(zip: &mut ZipWriter<Cursor<Vec<u8>>>, name: &str, v: &Value) -> Result<()> {
+    zip.start_file(name, SimpleFileOptions::default())?;
+    zip.write_all(&serde_json::to_vec_pretty(v)?)?;
+    Ok(())
+}
+
+// Keywords and regexes are kept in intent files as extra fields, which Dialogflow ignores
+fn write_dialogflow(records: &[IntentRecord]) -> Result<Vec<u8>> {
+    let lang = if *crate::web::server::IS_EN {
+        "en"
+    } else {
+        "zh-cn"
+    };
+    let mut zip = ZipWriter::new(Cursor::new(Vec::with_capacity(records.len() * 4096)));
+    put_json(&mut zip, "package.json", &json!({ "version": "1.0.0" }))?;
+    put_json(
+        &mut zip,
+        "agent.json",
+        &json!({ "language": lang, "supportedLanguages": [], "enableOnePlatformResponses": true }),
+    )?;
+    // Entity names and what they are, slots of different types in intents get different names
+    let mut entities: BTreeMap<String, &EntityType> = BTreeMap::new();
+    for r in records.iter() {
+        let mut data_types: HashMap<&str, String> = HashMap::with_capacity(r.slots.len());
+        for slot in r.slots.iter() {
+            let data_type = match &slot.entity_type {
+                EntityType::Number => String::from("@sys.number"),
+                EntityType::Date => String::from("@sys.date"),
+                t => {
+                    let base = dialogflow_name(&slot.name);
+                    let mut name = base.clone();
+                    let mut n = 1;
+                    while let Some(existing) = entities.get(&name)
+                        && serde_json::to_string(existing)? != serde_json::to_string(t)?
+                    {
+                        n += 1;
+                        name = format!("{base}_{n}");
+                    }
+                    entities.insert(name.clone(), t);
+                    format!("@{name}")
+                }
+            };
+            data_types.insert(slot.name.as_str(), data_type);
+        }
+        let parameters: Vec<Value> = r
+            .slots
+            .iter()
+            .map(|slot| {
+                json!({
+                    "id": scru128::new_string(),
+                    "name": &slot.name,
+                    "required": false,
+                    "dataType": &data_types[slot.name.as_str()],
+                    "value": format!("${}", &slot.name),
+                    "isList": false,
+                })
+            })
+            .collect();
+        let intent = json!({
+            "id": scru128::new_string(),
+            "name": &r.name,
+            "auto": true,
+            "contexts": [],
+            "responses": [{
+                "resetContexts": false,
+                "affectedContexts": [],
+                "parameters": parameters,
+                "messages": [],
+                "speech": [],
+            }],
+            "priority": 500000,
+            "webhookUsed": false,
+            "fallbackIntent": false,
+            "events": [],
+            "keywords": &r.keywords,
+            "regexes": &r.regexes,
+        });
+        let file = dialogflow_name(&r.name);
+        put_json(&mut zip, &format!("intents/{file}.json"), &intent)?;
+        let usersays: Vec<Value> = r
+            .phrases
+            .iter()
+            .map(|(text, spans)| {
+                json!({
+                    "id": scru128::new_string(),
+                    "data": dialogflow_parts(text, spans, &data_types),
+                    "isTemplate": false,
+                    "count": 0,
+                })
+            })
+            .collect();
+        put_json(
+            &mut zip,
+            &format!("intents/{file}_usersays_{lang}.json"),
+            &Value::Array(usersays),
+        )?;
+    }
+    for (name, entity_type) in entities.into_iter() {
+        let (is_regexp, entries) = match entity_type {
+            EntityType::Regex(p) => (true, vec![json!({ "value": p, "synonyms": [p] })]),
+            EntityType::Enum(list) => {
+                let entries = list
+                    .iter()
+                    .map(|e| {
+                        let mut synonyms = vec![e.value.clone()];
+                        synonyms.extend(e.synonyms.iter().cloned());
+                        json!({ "value": &e.value, "synonyms": synonyms })
+                    })
+                    .collect();
+                (false, entries)
+            }
+            EntityType::Number | EntityType::Date => continue,
+        };
+        let entity = json!({
+            "id": scru128::new_string(),
+            "name": &name,
+            "isOverridable": true,
+            "isEnum": false,
+            "isRegexp": is_regexp,
+            "automatedExpansion": false,
+            "allowFuzzyExtraction": false,
+        });
+        put_json(&mut zip, &format!("entities/{name}.json"), &entity)?;
+        put_json(
+            &mut zip,
+            &format!("entities/{name}_entries_{lang}.json"),
+            &Value::Array(entries),
+        )?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
@@ -1,4 +1,8 @@
 pub(crate) mod crud;
 pub(crate) mod detector;
 pub(crate) mod dto;
+pub(crate) mod eval;
+pub(crate) mod intent_file;
 pub(crate) mod phrase;
+pub(crate) mod regex_cache;
+pub(crate) mod slot;
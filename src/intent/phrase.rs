@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::OnceLock;
 use std::vec::Vec;
 
@@ -5,13 +6,25 @@ use std::vec::Vec;
 // use sqlx::{Row, Sqlite};
 
 use super::dto::IntentPhraseData;
-use crate::ai::embedding::embedding;
+use crate::ai::embedding::{embedding, embedding_batch};
+use crate::db::ann;
+use crate::kb::search;
 use crate::result::{Error, Result};
 
 // type SqliteConnPool = sqlx::Pool<Sqlite>;
 
 // static DATA_SOURCE: OnceCell<SqliteConnPool> = OnceCell::new();
 static DATA_SOURCE: OnceLock<turso::Database> = OnceLock::new();
+static ANN: ann::Store = ann::Store {
+    name: "phrase",
+    table_suffix: "",
+    column: "phrase_vec",
+};
+// Phrases picked by the vector index for each intent wanted, which are then compared exactly
+const ANN_CANDIDATES: usize = 10;
+// Phrases embedded in one request when importing
+const EMBEDDING_BATCH: usize = 32;
+const NEGATIVE_TABLE_SUFFIX: &str = "_negative";
 // static DATA_SOURCES: OnceLock<Mutex<HashMap<String, SqliteConnPool>>> = OnceLock::new();
 // static INDEXES: LazyLock<Mutex<HashMap<String, usearch::Index>>> =
 //     LazyLock::new(|| Mutex::new(HashMap::with_capacity(32)));
@@ -66,38 +79,45 @@ pub(crate) async fn init_datasource() -> Result<()> {
 //     Ok(())
 // }
 
-pub(crate) async fn search(robot_id: &str, vectors: &Vec<f32>) -> Result<Vec<(String, f64)>> {
-    let conn = DATA_SOURCE.get().unwrap().connect()?;
-    //*
-    let sql = format!(
-        "SELECT intent_name, vector_distance_cos(phrase_vec, vector32(?1)) AS distance FROM {robot_id}",
-    );
-    let mut results = conn.query(&sql, [serde_json::to_string(vectors)?]).await?;
-    while let Some(r) = results.next().await? {
-        log::info!(
-            "intent_name = {}, distance = {}",
-            r.get_value(0)?.as_text().unwrap(),
-            r.get_value(1)?.as_real().unwrap()
-        );
-    }
-    //*/
+// Nearest intents with the distance of their closest phrase
+pub(crate) async fn search(
+    robot_id: &str,
+    vectors: &Vec<f32>,
+    top_n: usize,
+) -> Result<Vec<(String, String, f64)>> {
+    let db = DATA_SOURCE.get().unwrap();
+    let conn = db.connect()?;
+    let k = top_n.max(1) * ANN_CANDIDATES;
+    let filter = match ann::search(&ANN, db, robot_id, vectors, k) {
+        Some(ids) => format!(
+            "WHERE id IN ({})",
+            ids.iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        ),
+        None => String::new(),
+    };
     let sql = format!(
-        "SELECT intent_id, intent_name, vector_distance_cos(phrase_vec, vector32(?1)) AS distance FROM {robot_id} ORDER BY distance ASC LIMIT 1",
+        "SELECT intent_id, intent_name, MIN(vector_distance_cos(phrase_vec, vector32(?1))) AS distance FROM {robot_id} {filter} GROUP BY intent_id, intent_name ORDER BY distance ASC LIMIT ?2",
     );
     // log::info!("sql = {} {}", &sql, serde_json::to_string(vectors)?);
-    let mut results = conn.query(&sql, [serde_json::to_string(vectors)?]).await?;
+    let mut results = conn
+        .query(&sql, (serde_json::to_string(vectors)?, top_n as i64))
+        .await?;
     // let results = sqlx::query::<Sqlite>(&sql)
     //     .bind(serde_json::to_string(vectors)?)
     //     .fetch_all(DATA_SOURCE.get().unwrap())
     //     .await?;
-    let mut names = Vec::with_capacity(1);
+    let mut intents = Vec::with_capacity(top_n);
     while let Some(r) = results.next().await? {
-        names.push((
+        intents.push((
+            r.get_value(0)?.as_text().unwrap().to_string(),
             r.get_value(1)?.as_text().unwrap().to_string(),
-            r.get_value(2)?.as_real().unwrap().clone(),
+            *r.get_value(2)?.as_real().unwrap(),
         ));
     }
-    Ok(names)
+    Ok(intents)
 }
 
 // fn update_idx(robot_id: &str, key: u64, vec: &[f32]) -> Result<()> {
@@ -183,6 +203,12 @@ pub(crate) async fn add(
         //     .await?;
         let id = conn.last_insert_rowid();
         log::info!("last_insert_rowid = {}", id);
+        ann::upsert(
+            &ANN,
+            DATA_SOURCE.get().unwrap(),
+            robot_id,
+            vec![(id, vectors.0)],
+        );
         Ok(id)
     } else {
         let sql =
@@ -193,10 +219,73 @@ pub(crate) async fn add(
             (phrase, serde_json::to_string(&vectors.0)?, vec_row_id),
         )
         .await?;
+        ann::upsert(
+            &ANN,
+            DATA_SOURCE.get().unwrap(),
+            robot_id,
+            vec![(vec_row_id, vectors.0)],
+        );
         Ok(vec_row_id)
     }
 }
 
+// Phrases are embedded in batches and inserted in one transaction, ids are in the order of phrases
+pub(crate) async fn add_all(
+    robot_id: &str,
+    intent_id: &str,
+    intent_name: &str,
+    phrases: &[&str],
+) -> Result<Vec<i64>> {
+    if phrases.is_empty() {
+        return Ok(vec![]);
+    }
+    if intent_name.is_empty() {
+        return Err(Error::WithMessage(String::from("Intent name is empty")));
+    }
+    let mut vectors: Vec<Vec<f32>> = Vec::with_capacity(phrases.len());
+    for batch in phrases.chunks(EMBEDDING_BATCH) {
+        let v = embedding_batch(robot_id, batch).await?;
+        if let Some(idx) = v.iter().position(|v| v.is_empty()) {
+            let err = format!("{} embedding data is empty", batch[idx]);
+            log::warn!("{}", &err);
+            return Err(Error::WithMessage(err));
+        }
+        vectors.extend(v);
+    }
+    let mut conn = DATA_SOURCE.get().unwrap().connect()?;
+    let tx = conn.transaction().await?;
+    let sql = format!(
+        "CREATE TABLE IF NOT EXISTS {robot_id} (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            intent_id TEXT NOT NULL,
+            intent_name TEXT NOT NULL,
+            phrase TEXT NOT NULL,
+            phrase_vec F32_BLOB({}) NOT NULL
+        )",
+        vectors[0].len()
+    );
+    tx.execute(&sql, ()).await?;
+    let sql = format!(
+        "INSERT INTO {robot_id} (intent_id, intent_name, phrase, phrase_vec)VALUES(?1, ?2, ?3, vector32(?4))",
+    );
+    let mut stmt = tx.prepare(&sql).await?;
+    let mut indexed: Vec<(i64, Vec<f32>)> = Vec::with_capacity(phrases.len());
+    for (phrase, vector) in phrases.iter().zip(vectors) {
+        stmt.execute((
+            intent_id,
+            intent_name,
+            *phrase,
+            serde_json::to_string(&vector)?,
+        ))
+        .await?;
+        indexed.push((tx.last_insert_rowid(), vector));
+    }
+    tx.commit().await?;
+    let ids = indexed.iter().map(|(id, _)| *id).collect();
+    ann::upsert(&ANN, DATA_SOURCE.get().unwrap(), robot_id, indexed);
+    Ok(ids)
+}
+
 pub(crate) async fn batch_add(
     robot_id: &str,
     intent_id: &str,
@@ -210,6 +299,127 @@ pub(crate) async fn batch_add(
     Ok(())
 }
 
+// Negative phrases are kept apart from the indexed ones, they're few and only compared
+// with the intents found by the search
+pub(crate) async fn add_negative(
+    robot_id: &str,
+    vec_row_id: Option<i64>,
+    intent_id: &str,
+    phrase: &str,
+) -> Result<i64> {
+    let vectors = embedding(robot_id, phrase).await?;
+    if vectors.0.is_empty() {
+        let err = format!("{phrase} embedding data is empty");
+        log::warn!("{}", &err);
+        return Err(Error::WithMessage(err));
+    }
+    let conn = DATA_SOURCE.get().unwrap().connect()?;
+    if let Some(vec_row_id) = vec_row_id {
+        let sql = format!(
+            "UPDATE {robot_id}{NEGATIVE_TABLE_SUFFIX} SET phrase = ?1, phrase_vec = vector32(?2) WHERE id = ?3",
+        );
+        conn.execute(
+            &sql,
+            (phrase, serde_json::to_string(&vectors.0)?, vec_row_id),
+        )
+        .await?;
+        return Ok(vec_row_id);
+    }
+    let sql = format!(
+        "CREATE TABLE IF NOT EXISTS {robot_id}{NEGATIVE_TABLE_SUFFIX} (
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            intent_id TEXT NOT NULL,
+            phrase TEXT NOT NULL,
+            phrase_vec F32_BLOB({}) NOT NULL
+        )",
+        vectors.0.len()
+    );
+    conn.execute(&sql, ()).await?;
+    let sql = format!(
+        "INSERT INTO {robot_id}{NEGATIVE_TABLE_SUFFIX} (intent_id, phrase, phrase_vec)VALUES(?1, ?2, vector32(?3))",
+    );
+    conn.execute(
+        &sql,
+        (intent_id, phrase, serde_json::to_string(&vectors.0)?),
+    )
+    .await?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub(crate) async fn remove_negatives(robot_id: &str, ids: &[i64]) -> Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let sql = format!(
+        "DELETE FROM {robot_id}{NEGATIVE_TABLE_SUFFIX} WHERE id IN ({})",
+        ids.iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    );
+    DATA_SOURCE
+        .get()
+        .unwrap()
+        .connect()?
+        .execute(&sql, ())
+        .await?;
+    Ok(())
+}
+
+// Similarity of the closest negative phrase of each intent
+pub(crate) async fn negative_similarities(
+    robot_id: &str,
+    vectors: &Vec<f32>,
+    intent_ids: &[&str],
+) -> Result<HashMap<String, f64>> {
+    let table = format!("{robot_id}{NEGATIVE_TABLE_SUFFIX}");
+    closest_similarities(&table, vectors, intent_ids).await
+}
+
+// Similarity of the closest phrase of each intent
+pub(crate) async fn similarities(
+    robot_id: &str,
+    vectors: &Vec<f32>,
+    intent_ids: &[&str],
+) -> Result<HashMap<String, f64>> {
+    closest_similarities(robot_id, vectors, intent_ids).await
+}
+
+async fn closest_similarities(
+    table: &str,
+    vectors: &Vec<f32>,
+    intent_ids: &[&str],
+) -> Result<HashMap<String, f64>> {
+    let mut similarities = HashMap::with_capacity(intent_ids.len());
+    if intent_ids.is_empty() {
+        return Ok(similarities);
+    }
+    let sql = format!(
+        "SELECT intent_id, MIN(vector_distance_cos(phrase_vec, vector32(?1))) AS distance FROM {table} WHERE intent_id IN ({}) GROUP BY intent_id",
+        search::placeholders(intent_ids.len(), 2)
+    );
+    let mut params: Vec<turso::Value> = Vec::with_capacity(intent_ids.len() + 1);
+    params.push(turso::Value::Text(serde_json::to_string(vectors)?));
+    params.extend(
+        intent_ids
+            .iter()
+            .map(|id| turso::Value::Text(String::from(*id))),
+    );
+    let conn = DATA_SOURCE.get().unwrap().connect()?;
+    let mut results = conn.query(&sql, params).await?;
+    while let Some(r) = results.next().await? {
+        similarities.insert(
+            r.get_value(0)?.as_text().unwrap().to_string(),
+            1f64 - *r.get_value(1)?.as_real().unwrap(),
+        );
+    }
+    Ok(similarities)
+}
+
+pub(crate) async fn rebuild_index(robot_id: &str) -> Result<()> {
+    ann::rebuild(&ANN, DATA_SOURCE.get().unwrap(), robot_id).await
+}
+
 pub(crate) async fn remove(robot_id: &str, id: i64) -> Result<()> {
     // INDEXES.lock()?.get(robot_id).and_then(|idx| {idx.remove(id as u64); None::<()>});
     let sql = format!("DELETE FROM {robot_id} WHERE id = ?1");
@@ -223,17 +433,39 @@ pub(crate) async fn remove(robot_id: &str, id: i64) -> Result<()> {
     //     .bind(id)
     //     .execute(DATA_SOURCE.get().unwrap())
     //     .await?;
+    ann::remove(&ANN, DATA_SOURCE.get().unwrap(), robot_id, &[id]);
+    Ok(())
+}
+
+pub(crate) async fn remove_all(robot_id: &str, ids: &[i64]) -> Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let sql = format!(
+        "DELETE FROM {robot_id} WHERE id IN ({})",
+        ids.iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    );
+    let db = DATA_SOURCE.get().unwrap();
+    db.connect()?.execute(&sql, ()).await?;
+    ann::remove(&ANN, db, robot_id, ids);
     Ok(())
 }
 
 pub(crate) async fn remove_by_intent_id(robot_id: &str, intent_id: &str) -> Result<()> {
+    let db = DATA_SOURCE.get().unwrap();
+    let conn = db.connect()?;
+    let sql = format!("SELECT id FROM {robot_id} WHERE intent_id = ?1");
+    let mut rows = conn.query(&sql, [intent_id]).await?;
+    let mut ids: Vec<i64> = Vec::with_capacity(32);
+    while let Some(row) = rows.next().await? {
+        ids.push(*row.get_value(0)?.as_integer().unwrap());
+    }
     let sql = format!("DELETE FROM {robot_id} WHERE intent_id = ?1");
-    DATA_SOURCE
-        .get()
-        .unwrap()
-        .connect()?
-        .execute(&sql, [intent_id])
-        .await?;
+    conn.execute(&sql, [intent_id]).await?;
+    ann::remove(&ANN, db, robot_id, &ids);
     // match sqlx::query::<Sqlite>(&sql)
     //     .bind(intent_id)
     //     .execute(DATA_SOURCE.get().unwrap())
@@ -262,6 +494,14 @@ pub(crate) async fn remove_tables(robot_id: &str) -> Result<()> {
         .connect()?
         .execute(&sql, ())
         .await?;
+    let sql = format!("DROP TABLE IF EXISTS {robot_id}{NEGATIVE_TABLE_SUFFIX}");
+    DATA_SOURCE
+        .get()
+        .unwrap()
+        .connect()?
+        .execute(&sql, ())
+        .await?;
+    ann::remove_index(&ANN, robot_id)?;
     // match sqlx::query::<Sqlite>(&sql)
     //     .execute(DATA_SOURCE.get().unwrap())
     //     .await
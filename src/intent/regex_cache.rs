@@ -0,0 +1,26 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+use regex::Regex;
+
+use crate::result::Result;
+
+// Patterns come from intents and slots, so there are not many of them
+const MAX_CACHED: usize = 4096;
+
+static CACHE: LazyLock<Mutex<HashMap<String, Regex>>> =
+    LazyLock::new(|| Mutex::new(HashMap::with_capacity(128)));
+
+pub(crate) fn get(pattern: &str) -> Result<Regex> {
+    if let Some(re) = CACHE.lock()?.get(pattern) {
+        return Ok(re.clone());
+    }
+    let re = Regex::new(pattern)?;
+    let mut cache = CACHE.lock()?;
+    // Patterns of edited intents are left behind, so it starts over instead of growing
+    if cache.len() >= MAX_CACHED {
+        cache.clear();
+    }
+    cache.insert(String::from(pattern), re.clone());
+    Ok(re)
+}
@@ -0,0 +1,303 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use super::dto::{EntityType, IntentDetail, IntentSlot, SlotSpan, SlotValue};
+use super::regex_cache;
+use crate::flow::rt::collector::{self, CollectType};
+use crate::result::{Error, Result};
+use crate::variable::dto::VariableValue;
+
+static NUMBER_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d+(\.\d+)?").unwrap());
+
+// Parts of the utterance which the date collector understands
+static DATE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)\d{4}\s*[\-/.年]\s*\d{1,2}\s*[\-/.月]\s*\d{1,2}(?:\s*[日号])?|\d{1,2}\s*月\s*\d{1,2}\s*[日号]|day after tomorrow|day before yesterday|tomorrow|yesterday|today|后天|前天|明天|明日|昨天|昨日|今天|今日|in\s+\d{1,3}\s+days?|\d{1,3}\s*天[以之]?后|next\s+(?:monday|tuesday|wednesday|thursday|friday|saturday|sunday)|下个?(?:周|星期|礼拜)[一二三四五六日天]",
+    )
+    .unwrap()
+});
+
+struct Candidate {
+    slot_idx: usize,
+    // Byte offsets
+    start: usize,
+    end: usize,
+    value: VariableValue,
+    // Number of words around it which are the same as in annotated phrases
+    cues: usize,
+}
+
+pub(crate) fn validate(slots: &[IntentSlot]) -> Result<()> {
+    let mut names = HashSet::with_capacity(slots.len());
+    for slot in slots.iter() {
+        if slot.name.trim().is_empty() {
+            return Err(Error::WithMessage(String::from("Slot name is empty.")));
+        }
+        if !names.insert(slot.name.as_str()) {
+            return Err(Error::WithMessage(format!(
+                "Duplicate slot name: {}",
+                &slot.name
+            )));
+        }
+        match &slot.entity_type {
+            EntityType::Regex(r) => {
+                Regex::new(r)?;
+            }
+            EntityType::Enum(entries) if entries.iter().all(|e| e.value.is_empty()) => {
+                return Err(Error::WithMessage(format!(
+                    "Slot {} has no enum value.",
+                    &slot.name
+                )));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Spans are sorted, in the phrase, known slots and not overlapping each other
+pub(crate) fn check_spans(
+    phrase: &str,
+    mut spans: Vec<SlotSpan>,
+    slots: &[IntentSlot],
+) -> Result<Vec<SlotSpan>> {
+    let len = phrase.chars().count();
+    spans.sort_by_key(|sp| sp.start);
+    for (i, sp) in spans.iter().enumerate() {
+        if sp.start >= sp.end || sp.end > len {
+            return Err(Error::WithMessage(format!(
+                "Invalid span {}..{} of slot {}",
+                sp.start, sp.end, &sp.slot
+            )));
+        }
+        if slots.iter().all(|s| s.name.ne(&sp.slot)) {
+            return Err(Error::WithMessage(format!("Unknown slot: {}", &sp.slot)));
+        }
+        if i > 0 && spans[i - 1].end > sp.start {
+            return Err(Error::WithMessage(String::from(
+                "Spans overlap each other.",
+            )));
+        }
+    }
+    Ok(spans)
+}
+
+// Moves the spans to where their values are in the edited phrase, the ones whose values are
+// gone are dropped
+pub(crate) fn relocate_spans(old: &str, new: &str, spans: &[SlotSpan]) -> Vec<SlotSpan> {
+    let mut relocated: Vec<SlotSpan> = Vec::with_capacity(spans.len());
+    for sp in spans.iter() {
+        let (Some(start), Some(end)) = (char_to_byte(old, sp.start), char_to_byte(old, sp.end))
+        else {
+            continue;
+        };
+        let value = &old[start..end];
+        if value.is_empty() {
+            continue;
+        }
+        let found = new.match_indices(value).find_map(|(idx, _)| {
+            let start = new[..idx].chars().count();
+            let end = start + value.chars().count();
+            relocated
+                .iter()
+                .all(|r| end <= r.start || r.end <= start)
+                .then_some((start, end))
+        });
+        if let Some((start, end)) = found {
+            relocated.push(SlotSpan {
+                slot: sp.slot.clone(),
+                start,
+                end,
+            });
+        }
+    }
+    relocated.sort_by_key(|sp| sp.start);
+    relocated
+}
+
+// Each slot gets at most one value, and values don't overlap each other
+pub(crate) fn extract(detail: &IntentDetail, s: &str) -> Vec<SlotValue> {
+    let mut candidates: Vec<Candidate> = Vec::with_capacity(8);
+    for (slot_idx, slot) in detail.slots.iter().enumerate() {
+        let (before_cues, after_cues) = annotated_cues(detail, &slot.name);
+        for (start, end, value) in find(&slot.entity_type, s).into_iter() {
+            let mut cues = 0;
+            if cue_before(&s[..start]).is_some_and(|c| before_cues.contains(&c)) {
+                cues += 1;
+            }
+            if cue_after(&s[end..]).is_some_and(|c| after_cues.contains(&c)) {
+                cues += 1;
+            }
+            candidates.push(Candidate {
+                slot_idx,
+                start,
+                end,
+                value,
+                cues,
+            });
+        }
+    }
+    // Prefers the ones in the same context as annotations, then the longer ones
+    candidates.sort_by(|a, b| {
+        b.cues
+            .cmp(&a.cues)
+            .then((b.end - b.start).cmp(&(a.end - a.start)))
+            .then(a.start.cmp(&b.start))
+    });
+    let mut filled = vec![false; detail.slots.len()];
+    let mut taken: Vec<(usize, usize)> = Vec::with_capacity(detail.slots.len());
+    let mut values: Vec<SlotValue> = Vec::with_capacity(detail.slots.len());
+    for c in candidates.into_iter() {
+        if filled[c.slot_idx] || taken.iter().any(|(st, en)| c.start < *en && *st < c.end) {
+            continue;
+        }
+        filled[c.slot_idx] = true;
+        taken.push((c.start, c.end));
+        let slot = &detail.slots[c.slot_idx];
+        values.push(SlotValue {
+            name: slot.name.clone(),
+            var_name: String::from(slot.var_name()),
+            value: c.value,
+            start: s[..c.start].chars().count(),
+            end: s[..c.end].chars().count(),
+        });
+    }
+    values.sort_by_key(|v| v.start);
+    values
+}
+
+fn find(entity_type: &EntityType, s: &str) -> Vec<(usize, usize, VariableValue)> {
+    match entity_type {
+        EntityType::Number => NUMBER_REGEX
+            .find_iter(s)
+            .filter_map(|m| {
+                let n = m.as_str().parse::<f64>().ok()?;
+                Some((m.start(), m.end(), VariableValue::Num(n)))
+            })
+            .collect(),
+        EntityType::Date => DATE_REGEX
+            .find_iter(s)
+            .filter_map(|m| {
+                let v = collector::collect(m.as_str(), &CollectType::DateTime)?;
+                Some((m.start(), m.end(), v))
+            })
+            .collect(),
+        EntityType::Enum(entries) => {
+            let mut values: HashMap<String, &str> = HashMap::with_capacity(entries.len() * 2);
+            for entry in entries.iter() {
+                let terms = std::iter::once(&entry.value).chain(entry.synonyms.iter());
+                for term in terms.filter(|t| !t.trim().is_empty()) {
+                    values
+                        .entry(term.trim().to_lowercase())
+                        .or_insert(entry.value.as_str());
+                }
+            }
+            // One regex for all terms, longer ones first so they win at the same position
+            let mut terms: Vec<&String> = values.keys().collect();
+            terms.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+            let alternatives: Vec<String> = terms
+                .iter()
+                .map(|t| {
+                    // Latin terms must be whole words. A boundary is only added next to a word
+                    // character, `\b` never matches after the last `+` of `c++`
+                    let is_word =
+                        |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+                    let mut alternative = regex::escape(t);
+                    if t.is_ascii() && is_word(t.chars().next()) {
+                        alternative.insert_str(0, r"\b");
+                    }
+                    if t.is_ascii() && is_word(t.chars().next_back()) {
+                        alternative.push_str(r"\b");
+                    }
+                    alternative
+                })
+                .collect();
+            let Ok(re) = regex_cache::get(&format!("(?i){}", alternatives.join("|"))) else {
+                return vec![];
+            };
+            re.find_iter(s)
+                .filter_map(|m| {
+                    let v = values.get(&m.as_str().to_lowercase())?;
+                    Some((m.start(), m.end(), VariableValue::Str(String::from(*v))))
+                })
+                .collect()
+        }
+        EntityType::Regex(r) => {
+            let Ok(re) = regex_cache::get(r) else {
+                log::warn!("Invalid slot regex {r}");
+                return vec![];
+            };
+            re.captures_iter(s)
+                .filter_map(|cap| {
+                    let m = cap.get(1).or(cap.get(0))?;
+                    if m.is_empty() {
+                        return None;
+                    }
+                    let v = VariableValue::Str(String::from(m.as_str()));
+                    Some((m.start(), m.end(), v))
+                })
+                .collect()
+        }
+    }
+}
+
+// Words right before and after the annotated values of the slot
+fn annotated_cues(detail: &IntentDetail, slot_name: &str) -> (HashSet<String>, HashSet<String>) {
+    let mut before = HashSet::new();
+    let mut after = HashSet::new();
+    for phrase in detail.phrases.iter() {
+        for span in phrase.spans.iter().filter(|sp| sp.slot.eq(slot_name)) {
+            let (Some(start), Some(end)) = (
+                char_to_byte(&phrase.phrase, span.start),
+                char_to_byte(&phrase.phrase, span.end),
+            ) else {
+                continue;
+            };
+            if let Some(c) = cue_before(&phrase.phrase[..start]) {
+                before.insert(c);
+            }
+            if let Some(c) = cue_after(&phrase.phrase[end..]) {
+                after.insert(c);
+            }
+        }
+    }
+    (before, after)
+}
+
+fn char_to_byte(s: &str, char_idx: usize) -> Option<usize> {
+    if char_idx == s.chars().count() {
+        return Some(s.len());
+    }
+    s.char_indices().nth(char_idx).map(|(i, _)| i)
+}
+
+// A word for alphabetic languages, otherwise a single char
+fn cue_before(s: &str) -> Option<String> {
+    let s = s.trim_end();
+    let last = s.chars().last()?;
+    if last.is_ascii_alphanumeric() {
+        let word = s
+            .rsplit(|c: char| !c.is_ascii_alphanumeric())
+            .next()
+            .unwrap_or_default();
+        Some(word.to_lowercase())
+    } else {
+        Some(last.to_lowercase().collect())
+    }
+}
+
+fn cue_after(s: &str) -> Option<String> {
+    let s = s.trim_start();
+    let first = s.chars().next()?;
+    if first.is_ascii_alphanumeric() {
+        let word = s
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .next()
+            .unwrap_or_default();
+        Some(word.to_lowercase())
+    } else {
+        Some(first.to_lowercase().collect())
+    }
+}
@@ -0,0 +1,434 @@
+use std::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+
+use super::doc::CHUNK_SEPARATOR;
+use crate::ai::embedding::SentenceEmbeddingProvider;
+use crate::ai::huggingface;
+use crate::man::settings::{self, DocChunking};
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub(crate) enum ChunkStrategy {
+    // Never crosses headings, packs paragraphs and splits them by sentences when too long
+    Structure,
+    // Packs sentences regardless of headings and paragraphs
+    Sentence,
+    // Fixed token windows
+    FixedTokens,
+}
+
+pub(crate) struct Chunk {
+    pub(crate) text: String,
+    // Headings from the top level down to the section of this chunk, joined by " > "
+    pub(crate) heading_path: String,
+}
+
+pub(crate) enum TokenCounter {
+    Tokenizer(Box<Tokenizer>),
+    // Remote embedding models don't provide tokenizers, every CJK character or four letters
+    // are counted as a token
+    Estimate,
+}
+
+impl TokenCounter {
+    pub(crate) fn for_robot(robot_id: &str) -> Self {
+        let m = match settings::get_settings(robot_id) {
+            Ok(Some(s)) => match s.sentence_embedding_provider.provider {
+                SentenceEmbeddingProvider::HuggingFace(m) => m,
+                _ => return Self::Estimate,
+            },
+            _ => return Self::Estimate,
+        };
+        match huggingface::load_tokenizer(m.get_info().repository) {
+            Ok(t) => Self::Tokenizer(Box::new(t)),
+            Err(e) => {
+                log::warn!("Loading tokenizer failed, token count will be estimated. Err: {e:?}");
+                Self::Estimate
+            }
+        }
+    }
+
+    // Byte ranges of tokens
+    fn spans(&self, s: &str) -> Vec<(usize, usize)> {
+        if let Self::Tokenizer(t) = self {
+            match t.encode(s, false) {
+                Ok(e) => {
+                    return e
+                        .get_offsets()
+                        .iter()
+                        .filter(|(start, end)| end > start)
+                        .copied()
+                        .collect();
+                }
+                Err(e) => log::warn!("Tokenizing failed: {e:?}"),
+            }
+        }
+        estimate_spans(s)
+    }
+
+    fn count(&self, s: &str) -> usize {
+        if let Self::Tokenizer(t) = self
+            && let Ok(e) = t.encode(s, false)
+        {
+            return e.len();
+        }
+        estimate_spans(s).len()
+    }
+}
+
+fn estimate_spans(s: &str) -> Vec<(usize, usize)> {
+    let mut spans: Vec<(usize, usize)> = Vec::with_capacity(s.len() / 3);
+    let mut word_start: Option<usize> = None;
+    for (i, c) in s.char_indices() {
+        if c.is_ascii_alphanumeric() {
+            let start = *word_start.get_or_insert(i);
+            if i + 1 - start >= 4 {
+                spans.push((start, i + 1));
+                word_start = None;
+            }
+            continue;
+        }
+        if let Some(start) = word_start.take() {
+            spans.push((start, i));
+        }
+        if !c.is_whitespace() {
+            spans.push((i, i + c.len_utf8()));
+        }
+    }
+    if let Some(start) = word_start {
+        spans.push((start, s.len()));
+    }
+    spans
+}
+
+// Sentences keep their trailing punctuation, line breaks end sentences as well
+fn split_sentences(p: &str) -> Vec<&str> {
+    let mut sentences: Vec<&str> = Vec::with_capacity(8);
+    let mut start = 0;
+    let mut chars = p.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let is_end = match c {
+            '。' | '！' | '？' | '；' | '\n' => true,
+            '.' | '!' | '?' | ';' => chars.peek().is_none_or(|(_, n)| n.is_whitespace()),
+            _ => false,
+        };
+        if is_end {
+            let end = i + c.len_utf8();
+            if !p[start..end].trim().is_empty() {
+                sentences.push(&p[start..end]);
+            }
+            start = end;
+        }
+    }
+    if !p[start..].trim().is_empty() {
+        sentences.push(&p[start..]);
+    }
+    sentences
+}
+
+fn heading_of(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if (1..=6).contains(&level) && line[level..].starts_with(' ') {
+        Some((level, line[level..].trim()))
+    } else {
+        None
+    }
+}
+
+struct ChunkBuilder<'a> {
+    counter: &'a TokenCounter,
+    max_tokens: usize,
+    overlap_tokens: usize,
+    headings: Vec<(usize, String)>,
+    units: Vec<(String, usize)>,
+    tokens: usize,
+    chunks: Vec<Chunk>,
+}
+
+impl ChunkBuilder<'_> {
+    fn heading_path(&self) -> String {
+        let path: Vec<&str> = self.headings.iter().map(|(_, t)| t.as_str()).collect();
+        path.join(" > ")
+    }
+
+    fn heading(&mut self, level: usize, title: &str) {
+        self.flush(false);
+        self.headings.retain(|(l, _)| *l < level);
+        self.headings.push((level, String::from(title)));
+    }
+
+    fn paragraph_break(&mut self) {
+        if !self.units.is_empty() {
+            self.units.push((String::from("\n\n"), 0));
+        }
+    }
+
+    fn push(&mut self, unit: &str) {
+        let n = self.counter.count(unit);
+        if n > self.max_tokens {
+            self.flush(false);
+            let heading_path = self.heading_path();
+            for text in token_windows(self.counter, unit, self.max_tokens, self.overlap_tokens) {
+                self.chunks.push(Chunk {
+                    text,
+                    heading_path: heading_path.clone(),
+                });
+            }
+            return;
+        }
+        if self.tokens + n > self.max_tokens {
+            self.flush(true);
+            // Overlap is dropped if it leaves no room for this unit
+            if self.tokens + n > self.max_tokens {
+                self.units.clear();
+                self.tokens = 0;
+            }
+        }
+        self.units.push((String::from(unit), n));
+        self.tokens += n;
+    }
+
+    fn flush(&mut self, keep_overlap: bool) {
+        let text: String = self.units.iter().map(|(u, _)| u.as_str()).collect();
+        let text = text.trim();
+        if !text.is_empty() {
+            self.chunks.push(Chunk {
+                text: String::from(text),
+                heading_path: self.heading_path(),
+            });
+        }
+        let mut kept = 0;
+        let mut tokens = 0;
+        if keep_overlap {
+            for (_, n) in self.units.iter().rev() {
+                if tokens + n > self.overlap_tokens || kept + 1 >= self.units.len() {
+                    break;
+                }
+                tokens += n;
+                kept += 1;
+            }
+        }
+        self.units.drain(..self.units.len() - kept);
+        self.tokens = tokens;
+    }
+}
+
+fn token_windows(
+    counter: &TokenCounter,
+    s: &str,
+    max_tokens: usize,
+    overlap: usize,
+) -> Vec<String> {
+    let spans = counter.spans(s);
+    let step = max_tokens.saturating_sub(overlap).max(1);
+    let mut windows: Vec<String> = Vec::with_capacity(spans.len() / step + 1);
+    let mut i = 0;
+    while i < spans.len() {
+        let j = (i + max_tokens).min(spans.len());
+        if let Some(w) = s.get(spans[i].0..spans[j - 1].1)
+            && !w.trim().is_empty()
+        {
+            windows.push(String::from(w.trim()));
+        }
+        if j == spans.len() {
+            break;
+        }
+        i += step;
+    }
+    windows
+}
+
+pub(crate) fn chunk(text: &str, config: &DocChunking, counter: &TokenCounter) -> Vec<Chunk> {
+    let mut b = ChunkBuilder {
+        counter,
+        max_tokens: config.max_tokens,
+        overlap_tokens: config.overlap_tokens,
+        headings: Vec::with_capacity(6),
+        units: Vec::with_capacity(32),
+        tokens: 0,
+        chunks: Vec::with_capacity(text.len() / 512 + 1),
+    };
+    // Separated parts are always chunked on their own
+    for part in text.split(CHUNK_SEPARATOR) {
+        match config.strategy {
+            ChunkStrategy::FixedTokens => {
+                for text in token_windows(counter, part, config.max_tokens, config.overlap_tokens) {
+                    b.chunks.push(Chunk {
+                        text,
+                        heading_path: String::new(),
+                    });
+                }
+            }
+            ChunkStrategy::Sentence => {
+                for s in split_sentences(part) {
+                    b.push(s);
+                }
+                b.flush(false);
+            }
+            ChunkStrategy::Structure => {
+                let mut paragraph = String::with_capacity(1024);
+                for line in part.lines() {
+                    let heading = heading_of(line.trim_start());
+                    if line.trim().is_empty() || heading.is_some() {
+                        for s in split_sentences(&paragraph) {
+                            b.push(s);
+                        }
+                        b.paragraph_break();
+                        paragraph.clear();
+                    }
+                    if let Some((level, title)) = heading {
+                        b.heading(level, title);
+                    } else if !line.trim().is_empty() {
+                        paragraph.push_str(line);
+                        paragraph.push('\n');
+                    }
+                }
+                for s in split_sentences(&paragraph) {
+                    b.push(s);
+                }
+                b.flush(false);
+            }
+        }
+    }
+    b.chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(strategy: ChunkStrategy, max_tokens: usize, overlap_tokens: usize) -> DocChunking {
+        DocChunking {
+            strategy,
+            max_tokens,
+            overlap_tokens,
+        }
+    }
+
+    fn texts(chunks: &[Chunk]) -> Vec<&str> {
+        chunks.iter().map(|c| c.text.as_str()).collect()
+    }
+
+    #[test]
+    fn estimate_spans_of_mixed_text() {
+        let s = "Hello 世界, abcdefgh!";
+        let tokens: Vec<&str> = estimate_spans(s)
+            .into_iter()
+            .map(|(b, e)| &s[b..e])
+            .collect();
+        assert_eq!(
+            tokens,
+            vec!["Hell", "o", "世", "界", ",", "abcd", "efgh", "!"]
+        );
+        assert!(estimate_spans(" \n\t").is_empty());
+        assert_eq!(TokenCounter::Estimate.count("我们 test"), 3);
+    }
+
+    #[test]
+    fn sentences() {
+        assert_eq!(
+            split_sentences("你好。Hi there! Pi is 3.14; ok\nnext"),
+            vec!["你好。", "Hi there!", " Pi is 3.14;", " ok\n", "next"]
+        );
+    }
+
+    #[test]
+    fn heading_paths() {
+        let text = "# Guide\n\nIntro.\n\n## Install\n\nRun it.\n\n### Linux\n\nUse apt.\n\n\
+                    ## Usage\n\nType.\n\n# FAQ\n\nAsk.";
+        let chunks = chunk(
+            text,
+            &config(ChunkStrategy::Structure, 256, 0),
+            &TokenCounter::Estimate,
+        );
+        let paths: Vec<(&str, &str)> = chunks
+            .iter()
+            .map(|c| (c.heading_path.as_str(), c.text.as_str()))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("Guide", "Intro."),
+                ("Guide > Install", "Run it."),
+                ("Guide > Install > Linux", "Use apt."),
+                ("Guide > Usage", "Type."),
+                ("FAQ", "Ask."),
+            ]
+        );
+    }
+
+    #[test]
+    fn paragraphs_are_packed_and_parts_are_not() {
+        let text = "First line.\n\nSecond line.\u{1e}Third line.";
+        let chunks = chunk(
+            text,
+            &config(ChunkStrategy::Structure, 256, 0),
+            &TokenCounter::Estimate,
+        );
+        assert_eq!(
+            texts(&chunks),
+            vec!["First line.\n\nSecond line.", "Third line."]
+        );
+    }
+
+    #[test]
+    fn overlap() {
+        let text = "一二。三四。五六。七八。";
+        let chunks = chunk(
+            text,
+            &config(ChunkStrategy::Sentence, 6, 3),
+            &TokenCounter::Estimate,
+        );
+        assert_eq!(
+            texts(&chunks),
+            vec!["一二。三四。", "三四。五六。", "五六。七八。"]
+        );
+        // Without overlap every sentence is in one chunk only
+        let chunks = chunk(
+            text,
+            &config(ChunkStrategy::Sentence, 6, 0),
+            &TokenCounter::Estimate,
+        );
+        assert_eq!(texts(&chunks), vec!["一二。三四。", "五六。七八。"]);
+        // Overlap is dropped when it leaves no room for the next sentence
+        let chunks = chunk(
+            "一二。三四五六。",
+            &config(ChunkStrategy::Sentence, 6, 3),
+            &TokenCounter::Estimate,
+        );
+        assert_eq!(texts(&chunks), vec!["一二。", "三四五六。"]);
+    }
+
+    #[test]
+    fn long_sentences_are_windowed() {
+        let chunks = chunk(
+            "# T\n\n一二三四五六七八九十",
+            &config(ChunkStrategy::Structure, 4, 1),
+            &TokenCounter::Estimate,
+        );
+        assert_eq!(texts(&chunks), vec!["一二三四", "四五六七", "七八九十"]);
+        assert!(chunks.iter().all(|c| c.heading_path == "T"));
+    }
+
+    #[test]
+    fn windows() {
+        let c = &TokenCounter::Estimate;
+        assert_eq!(
+            token_windows(c, "abcdefgh ij kl", 2, 0),
+            vec!["abcdefgh", "ij kl"]
+        );
+        assert_eq!(token_windows(c, "一二三", 2, 1), vec!["一二", "二三"]);
+        // Overlap as large as the window still moves forward
+        assert_eq!(token_windows(c, "一二三", 2, 5), vec!["一二", "二三"]);
+        assert_eq!(token_windows(c, "一二", 8, 2), vec!["一二"]);
+        assert!(token_windows(c, "  ", 8, 2).is_empty());
+        let chunks = chunk(
+            "# Title\n一二三",
+            &config(ChunkStrategy::FixedTokens, 4, 0),
+            c,
+        );
+        assert_eq!(texts(&chunks), vec!["# Title\n一", "二三"]);
+        assert!(chunks.iter().all(|c| c.heading_path.is_empty()));
+    }
+}
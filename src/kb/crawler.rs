@@ -0,0 +1,689 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use std::vec::Vec;
+
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use redb::TableDefinition;
+use regex::Regex;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Client, StatusCode, Url, redirect};
+use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{doc, ingest, parser};
+use crate::db;
+use crate::man::clock::now_secs;
+use crate::man::settings;
+use crate::result::{Error, Result};
+
+const SOURCE_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("kb_web_sources");
+// Crawled pages of each source
+const PAGE_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("kb_web_pages");
+
+// Nested sitemaps of a sitemap index fetched at most
+const MAX_SITEMAPS: usize = 20;
+// How often sources are checked for scheduled re-crawls
+const SCHEDULE_CHECK_SECS: u64 = 600;
+// Larger pages are skipped
+const MAX_PAGE_BYTES: usize = 10 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 10;
+// Crawl-delay of robots.txt is capped, so that a site can not stall crawling
+const MAX_CRAWL_DELAY_SECS: f64 = 60.0;
+
+// Sources being crawled, a source is never crawled twice at the same time
+static CRAWLING: LazyLock<Mutex<HashSet<String>>> =
+    LazyLock::new(|| Mutex::new(HashSet::with_capacity(8)));
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct WebSource {
+    #[serde(default)]
+    pub(crate) id: String,
+    #[serde(rename = "robotId", default)]
+    pub(crate) robot_id: String,
+    // A page of the site, or a sitemap.xml
+    pub(crate) url: String,
+    #[serde(rename = "maxDepth")]
+    pub(crate) max_depth: u8,
+    #[serde(rename = "maxPages")]
+    pub(crate) max_pages: u32,
+    // Regular expressions matched against page URLs
+    #[serde(rename = "includePatterns", default)]
+    pub(crate) include_patterns: Vec<String>,
+    #[serde(rename = "excludePatterns", default)]
+    pub(crate) exclude_patterns: Vec<String>,
+    // Waiting time between two requests
+    #[serde(rename = "delayMillis")]
+    pub(crate) delay_millis: u32,
+    // Zero disables re-crawling on a schedule
+    #[serde(rename = "recrawlIntervalHours")]
+    pub(crate) recrawl_interval_hours: u32,
+    #[serde(rename = "lastCrawledAt", default)]
+    pub(crate) last_crawled_at: u64,
+    #[serde(default)]
+    pub(crate) pages: usize,
+    #[serde(default)]
+    pub(crate) crawling: bool,
+    #[serde(default)]
+    pub(crate) err: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct WebPage {
+    url: String,
+    #[serde(rename = "docId")]
+    doc_id: i64,
+    hash: String,
+}
+
+#[derive(Default)]
+struct Robots {
+    // Path prefixes, and whether they are allowed
+    rules: Vec<(String, bool)>,
+    crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+    // The longest matching rule wins, Allow wins a tie
+    fn allows(&self, path: &str) -> bool {
+        let mut matched: Option<(usize, bool)> = None;
+        for (prefix, allow) in self.rules.iter() {
+            if !path.starts_with(prefix.as_str()) {
+                continue;
+            }
+            let better = matched.is_none_or(|(len, allowed)| {
+                prefix.len() > len || (prefix.len() == len && *allow && !allowed)
+            });
+            if better {
+                matched = Some((prefix.len(), *allow));
+            }
+        }
+        matched.is_none_or(|(_, allowed)| allowed)
+    }
+}
+
+struct UrlFilter {
+    host: String,
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    robots: Robots,
+}
+
+impl UrlFilter {
+    fn allows(&self, u: &Url) -> bool {
+        if u.host_str() != Some(self.host.as_str()) {
+            return false;
+        }
+        if !self.robots.allows(u.path()) {
+            return false;
+        }
+        let s = u.as_str();
+        (self.include.is_empty() || self.include.iter().any(|r| r.is_match(s)))
+            && !self.exclude.iter().any(|r| r.is_match(s))
+    }
+}
+
+fn sha256_hex(s: &str) -> String {
+    let digest = Sha256::digest(s.as_bytes());
+    let mut h = String::with_capacity(digest.len() * 2);
+    for b in digest.iter() {
+        let _ = write!(h, "{b:02x}");
+    }
+    h
+}
+
+fn compile_patterns(patterns: &[String]) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .filter(|p| !p.trim().is_empty())
+        .map(|p| {
+            Regex::new(p.trim())
+                .map_err(|e| Error::WithMessage(format!("Invalid pattern `{p}`: {e}")))
+        })
+        .collect()
+}
+
+fn parse_url(s: &str) -> Result<Url> {
+    let u = Url::parse(s.trim()).map_err(|e| Error::WithMessage(format!("Invalid URL: {e}")))?;
+    if !matches!(u.scheme(), "http" | "https") || u.host_str().is_none() {
+        return Err(Error::WithMessage(String::from(
+            "Only http and https URLs are supported.",
+        )));
+    }
+    Ok(u)
+}
+
+fn is_crawling(source_id: &str) -> bool {
+    CRAWLING.lock().is_ok_and(|s| s.contains(source_id))
+}
+
+pub(crate) fn init_table() -> Result<()> {
+    db::init_table(SOURCE_TABLE)?;
+    db::init_table(PAGE_TABLE)
+}
+
+pub(crate) fn list(robot_id: &str) -> Result<Vec<WebSource>> {
+    let mut sources: Vec<WebSource> = db::get_all(SOURCE_TABLE)?;
+    sources.retain(|s| s.robot_id.eq(robot_id));
+    for s in sources.iter_mut() {
+        s.crawling = is_crawling(&s.id);
+    }
+    Ok(sources)
+}
+
+fn get_source(robot_id: &str, source_id: &str) -> Result<WebSource> {
+    match db::query::<_, _, _, WebSource>(SOURCE_TABLE, source_id)? {
+        Some(s) if s.robot_id.eq(robot_id) => Ok(s),
+        _ => Err(Error::WithMessage(format!(
+            "Web source {source_id} not found."
+        ))),
+    }
+}
+
+pub(crate) fn save(mut s: WebSource) -> Result<WebSource> {
+    parse_url(&s.url)?;
+    compile_patterns(&s.include_patterns)?;
+    compile_patterns(&s.exclude_patterns)?;
+    if s.max_depth > 10 {
+        return Err(Error::WithMessage(String::from(
+            "Max depth can not be greater than 10.",
+        )));
+    }
+    if s.max_pages == 0 {
+        return Err(Error::WithMessage(String::from(
+            "Max pages must be greater than 0.",
+        )));
+    }
+    if s.id.is_empty() {
+        s.id = scru128::new_string();
+        s.last_crawled_at = 0;
+        s.pages = 0;
+        s.err = String::new();
+    } else {
+        // Crawling status is kept
+        let old = get_source(&s.robot_id, &s.id)?;
+        s.last_crawled_at = old.last_crawled_at;
+        s.pages = old.pages;
+        s.err = old.err;
+    }
+    s.crawling = false;
+    db::write(SOURCE_TABLE, &s.id, &s)?;
+    Ok(s)
+}
+
+pub(crate) async fn delete(robot_id: &str, source_id: &str) -> Result<()> {
+    get_source(robot_id, source_id)?;
+    if is_crawling(source_id) {
+        return Err(Error::WithMessage(String::from(
+            "The source is being crawled, please try again later.",
+        )));
+    }
+    let pages: Vec<WebPage> = db::query(PAGE_TABLE, source_id)?.unwrap_or_default();
+    for p in pages.iter() {
+        remove_doc(robot_id, p.doc_id).await?;
+    }
+    db::remove(PAGE_TABLE, source_id)?;
+    db::remove(SOURCE_TABLE, source_id)
+}
+
+async fn remove_doc(robot_id: &str, doc_id: i64) -> Result<()> {
+    ingest::stop_doc_jobs(robot_id, doc_id)?;
+    doc::delete(robot_id, doc_id).await
+}
+
+// Crawling runs in background, the status is reported by listing sources
+pub(crate) fn start_crawl(robot_id: &str, source_id: &str) -> Result<()> {
+    let s = get_source(robot_id, source_id)?;
+    if is_crawling(source_id) {
+        return Err(Error::WithMessage(String::from(
+            "The source is being crawled.",
+        )));
+    }
+    tokio::spawn(async move {
+        crawl_source(s).await;
+    });
+    Ok(())
+}
+
+// Removes the source from crawling ones, even if crawling panicked
+struct CrawlingGuard(String);
+
+impl Drop for CrawlingGuard {
+    fn drop(&mut self) {
+        let mut crawling = CRAWLING.lock().unwrap_or_else(|e| e.into_inner());
+        crawling.remove(&self.0);
+    }
+}
+
+async fn crawl_source(mut s: WebSource) {
+    {
+        let mut crawling = CRAWLING.lock().unwrap_or_else(|e| e.into_inner());
+        if !crawling.insert(s.id.clone()) {
+            return;
+        }
+    }
+    let _guard = CrawlingGuard(s.id.clone());
+    log::info!("Crawling {}", &s.url);
+    match crawl(&s).await {
+        Ok(pages) => {
+            s.pages = pages;
+            s.err.clear();
+        }
+        Err(e) => {
+            log::warn!("Crawling {} failed {e:?}", &s.url);
+            s.err = format!("{e:?}");
+        }
+    }
+    s.last_crawled_at = now_secs();
+    // The source may have been modified while crawling
+    if let Ok(Some(mut latest)) = db::query::<_, _, _, WebSource>(SOURCE_TABLE, s.id.as_str()) {
+        latest.last_crawled_at = s.last_crawled_at;
+        latest.pages = s.pages;
+        latest.err = s.err;
+        if let Err(e) = db::write(SOURCE_TABLE, &latest.id, &latest) {
+            log::error!("{e:?}");
+        }
+    }
+}
+
+// Re-crawls sources whose interval has elapsed
+pub(crate) async fn schedule_recrawl() {
+    let mut interval = tokio::time::interval(Duration::from_secs(SCHEDULE_CHECK_SECS));
+    loop {
+        interval.tick().await;
+        let sources: Vec<WebSource> = match db::get_all(SOURCE_TABLE) {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("{e:?}");
+                continue;
+            }
+        };
+        let now = now_secs();
+        for s in sources.into_iter() {
+            if s.recrawl_interval_hours > 0
+                && now - s.last_crawled_at.min(now) >= s.recrawl_interval_hours as u64 * 3600
+                && !is_crawling(&s.id)
+            {
+                tokio::spawn(crawl_source(s));
+            }
+        }
+    }
+}
+
+// Addresses of the network the server runs in, such as cloud metadata services
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // Shared address space of carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_internal(IpAddr::V4(v4)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+            }
+        },
+    }
+}
+
+// Resolves hosts to public addresses only, it's checked on every connection so that a host
+// can't be pointed at an internal address once crawling started
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = String::from(name.as_str());
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|a| !is_internal(a.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{host} does not resolve to a public address").into());
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+// Redirects are only followed within the host of the source
+fn get_client(host: &str, allow_internal: bool) -> Result<Client> {
+    let host = String::from(host);
+    let policy = redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("Too many redirects")
+        } else if attempt.url().host_str() == Some(host.as_str()) {
+            attempt.follow()
+        } else {
+            attempt.stop()
+        }
+    });
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_millis(5000))
+        .read_timeout(Duration::from_millis(30000))
+        .redirect(policy)
+        .no_proxy();
+    if !allow_internal {
+        builder = builder.dns_resolver(Arc::new(PublicResolver));
+    }
+    Ok(builder.build()?)
+}
+
+struct FetchedPage {
+    // The URL after redirects
+    url: Url,
+    content_type: String,
+    body: String,
+}
+
+async fn fetch(client: &Client, u: &Url) -> Result<Option<FetchedPage>> {
+    let user_agent = format!("dialogflowai/{}", crate::web::server::VERSION);
+    let mut res = client
+        .get(u.clone())
+        .header("User-Agent", user_agent)
+        .send()
+        .await?;
+    // The server may be unavailable for a while, so the page is not taken as gone
+    if res.status().is_server_error() || res.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(Error::WithMessage(format!(
+            "Fetching {u} returned {}",
+            res.status()
+        )));
+    }
+    if !res.status().is_success() {
+        log::info!("Fetching {u} returned {}", res.status());
+        return Ok(None);
+    }
+    if res
+        .content_length()
+        .is_some_and(|l| l > MAX_PAGE_BYTES as u64)
+    {
+        log::info!("Skipped {u} as it is too large");
+        return Ok(None);
+    }
+    let url = res.url().clone();
+    let content_type = res
+        .headers()
+        .get("Content-Type")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+        .unwrap_or_default();
+    let mut body: Vec<u8> = Vec::with_capacity(16384);
+    while let Some(chunk) = res.chunk().await? {
+        if body.len() + chunk.len() > MAX_PAGE_BYTES {
+            log::info!("Skipped {u} as it is too large");
+            return Ok(None);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Some(FetchedPage {
+        url,
+        content_type,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    }))
+}
+
+// Only the groups for all user agents are followed
+fn parse_robots(s: &str) -> Robots {
+    let mut robots = Robots::default();
+    let mut applies = false;
+    let mut in_agents = false;
+    for line in s.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "user-agent" => {
+                if !in_agents {
+                    applies = false;
+                }
+                in_agents = true;
+                applies = applies || value == "*";
+            }
+            "allow" | "disallow" => {
+                in_agents = false;
+                if applies && !value.is_empty() {
+                    let allow = key.trim().eq_ignore_ascii_case("allow");
+                    robots.rules.push((String::from(value), allow));
+                }
+            }
+            "crawl-delay" => {
+                in_agents = false;
+                if applies && let Ok(secs) = value.parse::<f64>() {
+                    robots.crawl_delay =
+                        Duration::try_from_secs_f64(secs.min(MAX_CRAWL_DELAY_SECS)).ok();
+                }
+            }
+            _ => in_agents = false,
+        }
+    }
+    robots
+}
+
+fn xml_locs(xml: &str) -> Result<(bool, Vec<String>)> {
+    let mut reader = Reader::from_str(xml);
+    let mut is_index = false;
+    let mut in_loc = false;
+    let mut locs: Vec<String> = Vec::with_capacity(128);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) => match e.local_name().as_ref() {
+                b"sitemapindex" => is_index = true,
+                b"loc" => {
+                    in_loc = true;
+                    locs.push(String::new());
+                }
+                _ => {}
+            },
+            Ok(Event::End(ref e)) if e.local_name().as_ref() == b"loc" => in_loc = false,
+            Ok(Event::Text(e)) if in_loc => {
+                if let Some(l) = locs.last_mut() {
+                    l.push_str(e.decode()?.trim());
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(Error::WithMessage(format!(
+                    "Invalid sitemap at position {}: {e:?}",
+                    reader.error_position()
+                )));
+            }
+            _ => {}
+        }
+    }
+    Ok((is_index, locs))
+}
+
+async fn sitemap_urls(client: &Client, u: &Url, filter: &UrlFilter) -> Result<Vec<Url>> {
+    let mut sitemaps: VecDeque<Url> = VecDeque::from([u.clone()]);
+    let mut fetched = 0usize;
+    let mut urls: Vec<Url> = Vec::with_capacity(128);
+    while let Some(sitemap) = sitemaps.pop_front() {
+        if fetched >= MAX_SITEMAPS {
+            break;
+        }
+        fetched += 1;
+        let Some(page) = fetch(client, &sitemap).await? else {
+            continue;
+        };
+        let (is_index, locs) = xml_locs(&page.body)?;
+        let locs = locs.iter().filter_map(|l| Url::parse(l).ok());
+        if is_index {
+            sitemaps.extend(locs.filter(|l| l.host_str() == Some(filter.host.as_str())));
+        } else {
+            urls.extend(locs.filter(|l| filter.allows(l)));
+        }
+    }
+    Ok(urls)
+}
+
+// Links without fragments, as pages are identified by their URLs
+fn extract_links(html: &Html, base: &Url) -> Vec<Url> {
+    let selector = Selector::parse("a[href]").unwrap();
+    html.select(&selector)
+        .filter_map(|a| a.value().attr("href"))
+        .filter_map(|href| base.join(href).ok())
+        .filter(|u| matches!(u.scheme(), "http" | "https"))
+        .map(|mut u| {
+            u.set_fragment(None);
+            u
+        })
+        .collect()
+}
+
+// Returns the number of crawled pages
+async fn crawl(s: &WebSource) -> Result<usize> {
+    let start = parse_url(&s.url)?;
+    let host = String::from(start.host_str().unwrap_or_default());
+    let allow_internal = settings::get_settings(&s.robot_id)?
+        .is_some_and(|s| s.web_crawling.allow_private_addresses);
+    // Addresses in URLs are not resolved, so they are checked here
+    if !allow_internal
+        && let Some(ip) = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .ok()
+            .filter(|ip| is_internal(*ip))
+    {
+        return Err(Error::WithMessage(format!(
+            "Crawling {ip} is refused as it's an internal address"
+        )));
+    }
+    let client = get_client(&host, allow_internal)?;
+    let mut robots_url = start.clone();
+    robots_url.set_path("/robots.txt");
+    robots_url.set_query(None);
+    let robots = match fetch(&client, &robots_url).await {
+        Ok(Some(page)) => parse_robots(&page.body),
+        _ => Robots::default(),
+    };
+    let delay = Duration::from_millis(s.delay_millis as u64);
+    let delay = robots.crawl_delay.map_or(delay, |d| d.max(delay));
+    let filter = UrlFilter {
+        host,
+        include: compile_patterns(&s.include_patterns)?,
+        exclude: compile_patterns(&s.exclude_patterns)?,
+        robots,
+    };
+    // Pages listed in a sitemap are crawled without following their links
+    let mut queue: VecDeque<(Url, u8)> = VecDeque::with_capacity(128);
+    if start.path().ends_with(".xml") {
+        for u in sitemap_urls(&client, &start, &filter).await?.into_iter() {
+            queue.push_back((u, s.max_depth));
+        }
+    } else {
+        queue.push_back((start, 0));
+    }
+    let mut visited: HashSet<String> = queue
+        .iter()
+        .map(|(u, _)| String::from(u.as_str()))
+        .collect();
+    let old_pages: Vec<WebPage> = db::query(PAGE_TABLE, s.id.as_str())?.unwrap_or_default();
+    let mut old_pages: HashMap<String, WebPage> =
+        old_pages.into_iter().map(|p| (p.url.clone(), p)).collect();
+    let mut pages: Vec<WebPage> = Vec::with_capacity(old_pages.len().max(16));
+    let mut first = true;
+    // Pages linked from a page which failed are not reached, so they are not taken as gone
+    let mut failed = false;
+    while let Some((u, depth)) = queue.pop_front() {
+        if pages.len() >= s.max_pages as usize {
+            break;
+        }
+        if !first && !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        first = false;
+        let page = match fetch(&client, &u).await {
+            Ok(Some(r)) => r,
+            Ok(None) => continue,
+            Err(e) => {
+                // The page is kept until it can be fetched again
+                log::warn!("Fetching {u} failed {e:?}");
+                failed = true;
+                if let Some(old) = old_pages.remove(u.as_str()) {
+                    pages.push(old);
+                }
+                continue;
+            }
+        };
+        if !page.content_type.is_empty() && !page.content_type.contains("html") {
+            continue;
+        }
+        // Redirected within the host, to a page which may be excluded or already crawled
+        if page.url != u
+            && (!filter.allows(&page.url) || !visited.insert(String::from(page.url.as_str())))
+        {
+            continue;
+        }
+        let html = page.body;
+        let base = page.url;
+        let follow_links = depth < s.max_depth;
+        // Pages may be large, they are parsed once and off the async workers
+        let (links, text) = tokio::task::spawn_blocking(move || {
+            let html = Html::parse_document(&html);
+            let links = if follow_links {
+                extract_links(&html, &base)
+            } else {
+                vec![]
+            };
+            (links, parser::html_text(&html))
+        })
+        .await?;
+        for link in links.into_iter() {
+            if filter.allows(&link) && visited.insert(String::from(link.as_str())) {
+                queue.push_back((link, depth + 1));
+            }
+        }
+        if text.trim().is_empty() {
+            continue;
+        }
+        let url = String::from(u.as_str());
+        let hash = sha256_hex(&text);
+        // Only changed pages are embedded again
+        if let Some(old) = old_pages.remove(&url) {
+            if old.hash.eq(&hash) {
+                pages.push(old);
+                continue;
+            }
+            remove_doc(&s.robot_id, old.doc_id).await?;
+        }
+        let job = ingest::submit(&s.robot_id, &url, text.len(), &text).await?;
+        pages.push(WebPage {
+            url,
+            doc_id: job.doc_id,
+            hash,
+        });
+        // Saved as it goes, so that a failure doesn't leave untracked documents
+        let mut tracked: Vec<&WebPage> = pages.iter().collect();
+        tracked.extend(old_pages.values());
+        db::write(PAGE_TABLE, &s.id, &tracked)?;
+    }
+    if queue.is_empty() && !failed {
+        // Pages no longer on the site
+        for p in old_pages.into_values() {
+            remove_doc(&s.robot_id, p.doc_id).await?;
+        }
+    } else {
+        // Crawling stopped at the page limit or a page failed, pages not reached are kept
+        pages.extend(old_pages.into_values());
+    }
+    db::write(PAGE_TABLE, &s.id, &pages)?;
+    Ok(pages.len())
+}
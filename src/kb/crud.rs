@@ -1,17 +1,22 @@
 use std::collections::HashMap;
-use std::path::Path;
 
 use axum::{
     Json,
     extract::{Multipart, Query},
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
 };
 
-use super::doc;
-use super::dto::{DocData, QuestionAnswerPair};
+use super::crawler::{self, WebSource};
+use super::dto::{
+    DocData, IngestJobQuery, QnAExportQuery, QnAImportReport, QuestionAnswerPair, WebSourceQuery,
+};
+use super::ingest::IngestJob;
+use super::parser;
+use super::qa_file::{self, QnAFileFormat};
 use crate::result::{Error, Result};
 use crate::robot::dto::RobotQuery;
-use crate::web::server::to_res;
+use crate::web::server::{to_err_res, to_res};
 
 pub(crate) async fn list_doc(Query(q): Query<RobotQuery>) -> impl IntoResponse {
     let r = super::doc::list(&q.robot_id).await;
@@ -29,54 +34,32 @@ pub(crate) async fn upload_doc(
     to_res(r)
 }
 
-async fn save_doc(robot_id: &str, mut multipart: Multipart) -> Result<String> {
-    let p = Path::new(".")
-        .join("data")
-        .join(robot_id)
-        .join("kb")
-        .join("docs")
-        .join("upload");
-    if !p.exists() {
-        std::fs::create_dir_all(&p)?;
-    }
-    loop {
-        let field = multipart.next_field().await?;
-        if field.is_none() {
-            return Err(Error::WithMessage(String::from("File not found.")));
-        }
-        let field = field.unwrap();
-        let Some(file_name) = field.file_name() else {
-            return Err(Error::WithMessage(String::from("File name is missing.")));
-        };
-        let file_name = file_name.to_string();
-        let Some(content_type) = field.content_type() else {
-            return Err(Error::WithMessage(String::from("Content type is missing.")));
-        };
-        let content_type = content_type.to_string();
-        let data = field.bytes().await?;
-
-        log::info!(
-            "Length of `{file_name}`: `{content_type}` is {} bytes",
-            data.len()
-        );
-
-        let text = match content_type.as_str() {
-            "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => {
-                doc::parse_docx(data.to_vec())?
-            }
-            _ => return Err(Error::WithMessage(String::from("Unsupported format"))),
-        };
-        log::info!("Extract text: {text}");
-        super::doc::save(robot_id, &file_name, data.len(), &text).await?;
-        return Ok(text);
-    }
+async fn save_doc(robot_id: &str, mut multipart: Multipart) -> Result<IngestJob> {
+    let Some(field) = multipart.next_field().await? else {
+        return Err(Error::WithMessage(String::from("File not found.")));
+    };
+    let Some(file_name) = field.file_name() else {
+        return Err(Error::WithMessage(String::from("File name is missing.")));
+    };
+    let file_name = file_name.to_string();
+    // Format is told by the file extension if content type is missing
+    let content_type = field.content_type().unwrap_or_default().to_string();
+    parser::check_format(&file_name, &content_type)?;
+    let data = field.bytes().await?;
+
+    log::info!(
+        "Length of `{file_name}`: `{content_type}` is {} bytes",
+        data.len()
+    );
+
+    super::ingest::submit_file(robot_id, &file_name, &content_type, &data).await
 }
 
 pub(crate) async fn update_doc(
     Query(q): Query<RobotQuery>,
     Json(doc): Json<DocData>,
 ) -> impl IntoResponse {
-    let r = super::doc::update(&q.robot_id, doc.id, &doc.doc_content).await;
+    let r = super::ingest::update_doc(&q.robot_id, doc.id, &doc.doc_content).await;
     to_res(r)
 }
 
@@ -85,10 +68,51 @@ pub(crate) async fn delete_doc(
     Query(q): Query<RobotQuery>,
     Json(doc): Json<DocData>,
 ) -> impl IntoResponse {
-    let r = super::doc::delete(&q.robot_id, doc.id).await;
+    let r = match super::ingest::stop_doc_jobs(&q.robot_id, doc.id) {
+        Ok(_) => super::doc::delete(&q.robot_id, doc.id).await,
+        Err(e) => Err(e),
+    };
+    to_res(r)
+}
+
+pub(crate) async fn list_ingest_jobs(Query(q): Query<RobotQuery>) -> impl IntoResponse {
+    to_res(super::ingest::list(&q.robot_id))
+}
+
+pub(crate) async fn ingest_job_status(Query(q): Query<IngestJobQuery>) -> impl IntoResponse {
+    to_res(super::ingest::status(&q.robot_id, &q.job_id))
+}
+
+pub(crate) async fn retry_ingest_job(Query(q): Query<IngestJobQuery>) -> impl IntoResponse {
+    to_res(super::ingest::retry(&q.robot_id, &q.job_id))
+}
+
+pub(crate) async fn cancel_ingest_job(Query(q): Query<IngestJobQuery>) -> impl IntoResponse {
+    let r = super::ingest::cancel(&q.robot_id, &q.job_id).await;
+    to_res(r)
+}
+
+pub(crate) async fn list_web_sources(Query(q): Query<RobotQuery>) -> impl IntoResponse {
+    to_res(crawler::list(&q.robot_id))
+}
+
+pub(crate) async fn save_web_source(
+    Query(q): Query<RobotQuery>,
+    Json(mut s): Json<WebSource>,
+) -> impl IntoResponse {
+    s.robot_id = q.robot_id;
+    to_res(crawler::save(s))
+}
+
+pub(crate) async fn delete_web_source(Query(q): Query<WebSourceQuery>) -> impl IntoResponse {
+    let r = crawler::delete(&q.robot_id, &q.source_id).await;
     to_res(r)
 }
 
+pub(crate) async fn crawl_web_source(Query(q): Query<WebSourceQuery>) -> impl IntoResponse {
+    to_res(crawler::start_crawl(&q.robot_id, &q.source_id))
+}
+
 pub(crate) async fn list_qa(Query(q): Query<RobotQuery>) -> impl IntoResponse {
     let r = super::qa::list(&q.robot_id).await;
     to_res(r)
@@ -111,6 +135,54 @@ pub(crate) async fn delete_qa(
     to_res(r)
 }
 
+pub(crate) async fn import_qa(
+    Query(q): Query<RobotQuery>,
+    multipart: Multipart,
+) -> impl IntoResponse {
+    let r = import_qa_file(&q.robot_id, multipart).await;
+    to_res(r)
+}
+
+async fn import_qa_file(robot_id: &str, mut multipart: Multipart) -> Result<QnAImportReport> {
+    let Some(field) = multipart.next_field().await? else {
+        return Err(Error::WithMessage(String::from("File not found.")));
+    };
+    let Some(file_name) = field.file_name() else {
+        return Err(Error::WithMessage(String::from("File name is missing.")));
+    };
+    let file_name = file_name.to_string();
+    let content_type = field.content_type().unwrap_or_default().to_string();
+    let data = field.bytes().await?;
+    log::info!(
+        "Importing QnA from `{file_name}`: `{content_type}`, {} bytes",
+        data.len()
+    );
+    let rows = qa_file::read(&file_name, &content_type, data.as_ref())?;
+    super::qa::import(robot_id, rows).await
+}
+
+pub(crate) async fn export_qa(Query(q): Query<QnAExportQuery>) -> Response {
+    let Some(format) = QnAFileFormat::from_name(&q.format) else {
+        let e = Error::WithMessage(format!("Unsupported export format `{}`", &q.format));
+        return to_err_res(StatusCode::BAD_REQUEST, e);
+    };
+    let r = match super::qa::export(&q.robot_id).await {
+        Ok(records) => qa_file::write(format, &records),
+        Err(e) => Err(e),
+    };
+    match r {
+        Ok(b) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, format.content_type().parse().unwrap());
+            // Robot id comes from the client, so it's kept out of the header
+            let disposition = format!("attachment; filename=\"qa.{}\"", format.extension());
+            headers.insert(header::CONTENT_DISPOSITION, disposition.parse().unwrap());
+            (StatusCode::OK, headers, b).into_response()
+        }
+        Err(e) => to_err_res(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
 pub(crate) async fn qa_dryrun(Query(q): Query<HashMap<String, String>>) -> impl IntoResponse {
     let r = q.get("robotId");
     let t = q.get("text");
@@ -1,25 +1,38 @@
 // use std::fs::File;
 // use std::io::Read;
 // use std::path::Path;
-use std::io::{Cursor, Read};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
 use std::sync::OnceLock;
 use std::vec::Vec;
 
 // use futures_util::StreamExt;
+use lopdf::content::Content;
+use lopdf::{Document, Encoding, Object, ObjectId};
 use quick_xml::Reader;
 use quick_xml::events::Event;
 // use sqlx::{Row, Sqlite};
 // use text_splitter::{ChunkConfig, TextSplitter};
 use zip::ZipArchive;
 
-use super::dto::DocData;
+use super::chunker::{self, TokenCounter};
+use super::dto::{DocCandidate, DocData};
+use super::rag::{self, Passage};
+use super::search;
 use crate::ai::embedding;
+use crate::db::ann;
+use crate::man::settings::DocChunking;
 use crate::result::{Error, Result};
 
 // type SqliteConnPool = sqlx::Pool<Sqlite>;
 
 // static DATA_SOURCE: OnceCell<SqliteConnPool> = OnceCell::new();
 static DATA_SOURCE: OnceLock<turso::Database> = OnceLock::new();
+static ANN: ann::Store = ann::Store {
+    name: "doc",
+    table_suffix: "_vec",
+    column: "chunk_vec",
+};
 // static DATA_SOURCES: OnceLock<Mutex<HashMap<String, SqliteConnPool>>> = OnceLock::new();
 
 pub(crate) async fn init_datasource() -> Result<()> {
@@ -70,6 +83,15 @@ pub(crate) async fn init_tables(robot_id: &str) -> Result<()> {
     Ok(())
 }
 
+pub(crate) async fn remove_tables(robot_id: &str) -> Result<()> {
+    let conn = DATA_SOURCE.get().unwrap().connect()?;
+    for suffix in ["", "_vec", "_terms", "_term_stats"] {
+        let sql = format!("DROP TABLE IF EXISTS {robot_id}{suffix}");
+        conn.execute(&sql, ()).await?;
+    }
+    ann::remove_index(&ANN, robot_id)
+}
+
 // crate::sqlite_trans! {
 //     fn save2(robot_id: &str,
 //         file_name: &str,
@@ -107,16 +129,17 @@ pub(super) async fn list(robot_id: &str) -> Result<Vec<DocData>> {
     Ok(results)
 }
 
+// Chunks are embedded later by an ingestion job
 pub(super) async fn save(
     robot_id: &str,
     file_name: &str,
     file_size: usize,
     doc_content: &str,
-) -> Result<()> {
+) -> Result<i64> {
     let sql = format!(
         "INSERT INTO {robot_id}(file_name, file_size, doc_content, created_at)VALUES(?1, ?2, ?3, unixepoch())"
     );
-    let mut conn = DATA_SOURCE.get().unwrap().connect()?;
+    let conn = DATA_SOURCE.get().unwrap().connect()?;
     conn.execute(
         &sql,
         (
@@ -128,94 +151,266 @@ pub(super) async fn save(
     .await?;
     let doc_id = conn.last_insert_rowid();
     // log::info!("doc_id={}", doc_id);
-    let tx = conn.transaction().await?;
-    save_doc_embedding(&tx, robot_id, doc_id, doc_content).await?;
-    tx.commit().await?;
-    Ok(())
+    Ok(doc_id)
+}
+
+pub(super) async fn get_content(robot_id: &str, doc_id: i64) -> Result<Option<String>> {
+    let sql = format!("SELECT doc_content FROM {robot_id} WHERE id = ?1");
+    let conn = DATA_SOURCE.get().unwrap().connect()?;
+    let mut rows = conn.query(&sql, [doc_id]).await?;
+    match rows.next().await? {
+        Some(row) => Ok(row.get_value(0)?.as_text().map(String::from)),
+        None => Ok(None),
+    }
+}
+
+async fn chunk_ids(conn: &turso::Connection, robot_id: &str, doc_id: i64) -> Result<Vec<i64>> {
+    if !vec_table_ready(conn, robot_id).await? {
+        return Ok(vec![]);
+    }
+    let sql = format!("SELECT id FROM {robot_id}_vec WHERE doc_id = ?1");
+    let mut rows = conn.query(&sql, [doc_id]).await?;
+    let mut ids: Vec<i64> = Vec::with_capacity(64);
+    while let Some(row) = rows.next().await? {
+        ids.push(*row.get_value(0)?.as_integer().unwrap());
+    }
+    Ok(ids)
 }
 
-pub(super) async fn update(robot_id: &str, doc_id: i64, doc_content: &str) -> Result<()> {
-    let mut conn = DATA_SOURCE.get().unwrap().connect()?;
+// Chunks of the old content are removed, the new content is embedded by an ingestion job.
+// Returns the file name, or None if the document doesn't exist
+pub(super) async fn update(
+    robot_id: &str,
+    doc_id: i64,
+    doc_content: &str,
+) -> Result<Option<String>> {
+    let db = DATA_SOURCE.get().unwrap();
+    let mut conn = db.connect()?;
     let tx = conn.transaction().await?;
+    let sql = format!("SELECT file_name FROM {robot_id} WHERE id = ?1");
+    let mut rows = tx.query(&sql, [doc_id]).await?;
+    let file_name = match rows.next().await? {
+        Some(row) => row.get_value(0)?.as_text().map(String::from),
+        None => None,
+    };
+    drop(rows);
+    let Some(file_name) = file_name else {
+        tx.rollback().await?;
+        return Ok(None);
+    };
     let sql = format!("UPDATE {robot_id} SET doc_content = ?1 WHERE id = ?2");
-    let r = tx.execute(&sql, (doc_content, doc_id)).await?;
-    if r > 0 {
+    tx.execute(&sql, (doc_content, doc_id)).await?;
+    let removed = chunk_ids(&tx, robot_id, doc_id).await?;
+    if !removed.is_empty() {
         let sql = format!("DELETE FROM {robot_id}_vec WHERE doc_id = ?1");
         tx.execute(&sql, [doc_id]).await?;
-        save_doc_embedding(&tx, robot_id, doc_id, doc_content).await?;
-        tx.commit().await?;
-    } else {
-        tx.rollback().await?;
     }
+    search::remove_terms(&tx, robot_id, doc_id).await?;
+    tx.commit().await?;
+    ann::remove(&ANN, db, robot_id, &removed);
+    Ok(Some(file_name))
+}
+
+// Content of a document is stored after its upload was parsed by the ingestion job
+pub(super) async fn set_content(robot_id: &str, doc_id: i64, doc_content: &str) -> Result<()> {
+    let sql = format!("UPDATE {robot_id} SET doc_content = ?1 WHERE id = ?2");
+    let conn = DATA_SOURCE.get().unwrap().connect()?;
+    conn.execute(&sql, (doc_content, doc_id)).await?;
     Ok(())
 }
 
 pub(crate) async fn delete(robot_id: &str, doc_id: i64) -> Result<()> {
-    let mut conn = DATA_SOURCE.get().unwrap().connect()?;
+    let db = DATA_SOURCE.get().unwrap();
+    let mut conn = db.connect()?;
     let tx = conn.transaction().await?;
+    let removed = chunk_ids(&tx, robot_id, doc_id).await?;
     let sql = format!("DELETE FROM {robot_id}_vec WHERE doc_id = ?1");
     let _r = tx.execute(&sql, [doc_id]).await?;
+    search::remove_terms(&tx, robot_id, doc_id).await?;
     let sql = format!("DELETE FROM {robot_id} WHERE id = ?1");
     let _r = tx.execute(&sql, [doc_id]).await?;
     tx.commit().await?;
+    ann::remove(&ANN, db, robot_id, &removed);
+    Ok(())
+}
+
+// Tables created by older versions don't have all the columns, returns false if no table yet
+async fn vec_table_ready(conn: &turso::Connection, robot_id: &str) -> Result<bool> {
+    let sql = format!("PRAGMA table_info({robot_id}_vec)");
+    let mut rows = conn.query(&sql, ()).await?;
+    let mut columns: Vec<String> = Vec::with_capacity(8);
+    while let Some(row) = rows.next().await? {
+        if let Some(name) = row.get_value(1)?.as_text() {
+            columns.push(String::from(name));
+        }
+    }
+    if columns.is_empty() {
+        return Ok(false);
+    }
+    let added = [("page", "INTEGER"), ("heading_path", "TEXT"), ("chunk_idx", "INTEGER")];
+    for (column, column_type) in added {
+        if !columns.iter().any(|c| c.eq(column)) {
+            let sql = format!("ALTER TABLE {robot_id}_vec ADD COLUMN {column} {column_type}");
+            conn.execute(&sql, ()).await?;
+        }
+    }
+    Ok(true)
+}
+
+// Chunks saved before keyword search was added are indexed when the terms table is created
+async fn ensure_terms_index(conn: &turso::Connection, robot_id: &str) -> Result<()> {
+    if !search::ensure_terms_table(conn, robot_id).await? || !vec_table_ready(conn, robot_id).await?
+    {
+        return Ok(());
+    }
+    let sql = format!("SELECT id, doc_id, chunk_text, heading_path FROM {robot_id}_vec");
+    let mut rows = conn.query(&sql, ()).await?;
+    let mut chunks: Vec<(i64, i64, String)> = Vec::with_capacity(128);
+    while let Some(row) = rows.next().await? {
+        let heading_path = row.get_value(3)?.as_text().map(String::from);
+        let text = format!(
+            "{}\n{}",
+            heading_path.unwrap_or_default(),
+            row.get_value(2)?.as_text().unwrap()
+        );
+        chunks.push((
+            *row.get_value(0)?.as_integer().unwrap(),
+            *row.get_value(1)?.as_integer().unwrap(),
+            text,
+        ));
+    }
+    for (chunk_id, doc_id, text) in chunks.iter() {
+        search::index_terms(conn, robot_id, *doc_id, *chunk_id, text).await?;
+    }
     Ok(())
 }
 
-fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
-    let words: Vec<&str> = text.split_whitespace().collect();
-    let mut chunks = Vec::new();
-    let mut start = 0;
+pub(super) struct DocChunk {
+    page: Option<i64>,
+    text: String,
+    heading_path: String,
+}
 
-    while start < words.len() {
-        let end = std::cmp::min(start + chunk_size, words.len());
-        let chunk = words[start..end].join(" ");
-        chunks.push(chunk);
+impl DocChunk {
+    // Heading path gives the chunk its context
+    fn embedding_text(&self) -> String {
+        if self.heading_path.is_empty() {
+            self.text.clone()
+        } else {
+            format!("{}\n{}", &self.heading_path, &self.text)
+        }
+    }
+}
 
-        if end == words.len() {
-            break;
+pub(super) fn split(
+    doc_content: &str,
+    config: &DocChunking,
+    counter: &TokenCounter,
+) -> Vec<DocChunk> {
+    let paged = doc_content.contains(PAGE_SEPARATOR);
+    let mut chunks: Vec<DocChunk> = Vec::with_capacity(doc_content.len() / 512 + 1);
+    for (idx, page_content) in doc_content.split(PAGE_SEPARATOR).enumerate() {
+        let page = if paged { Some(idx as i64 + 1) } else { None };
+        for chunk in chunker::chunk(page_content, config, counter).into_iter() {
+            chunks.push(DocChunk {
+                page,
+                text: chunk.text,
+                heading_path: chunk.heading_path,
+            });
         }
-        start = end.saturating_sub(overlap);
     }
     chunks
 }
 
-async fn save_doc_embedding(
-    tx: &turso::transaction::Transaction<'_>,
+// Returns the ids of the chunks of the index saved before, e.g. by a job stopped by a crash
+async fn remove_chunk(
+    conn: &turso::Connection,
     robot_id: &str,
     doc_id: i64,
-    doc_content: &str,
-) -> Result<()> {
-    let chunks = chunk_text(doc_content, 500, 70);
-    let mut created_table = false;
-    for chunk in chunks.iter() {
-        let r = embedding::embedding(robot_id, chunk).await?;
-        if !created_table {
-            let sql = format!(
-                "CREATE TABLE IF NOT EXISTS {robot_id}_vec (
-                    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-                    doc_id INTEGER NOT NULL,
-                    chunk_text TEXT NOT NULL,
-                    chunk_vec F32_BLOB({}) NOT NULL
-                );",
-                r.0.len()
-            );
-            tx.execute(&sql, ()).await?;
-            created_table = true;
-        }
+    chunk_idx: i64,
+) -> Result<Vec<i64>> {
+    if !vec_table_ready(conn, robot_id).await? {
+        return Ok(vec![]);
+    }
+    let sql = format!("SELECT id FROM {robot_id}_vec WHERE doc_id = ?1 AND chunk_idx = ?2");
+    let mut rows = conn.query(&sql, (doc_id, chunk_idx)).await?;
+    let mut ids: Vec<i64> = Vec::with_capacity(1);
+    while let Some(row) = rows.next().await? {
+        ids.push(*row.get_value(0)?.as_integer().unwrap());
+    }
+    drop(rows);
+    let sql = format!("DELETE FROM {robot_id}_vec WHERE id = ?1");
+    for id in ids.iter() {
+        conn.execute(&sql, [*id]).await?;
+        search::remove_unit_terms(conn, robot_id, *id).await?;
+    }
+    Ok(ids)
+}
+
+// Vector of the chunk is passed with its dimensions, returns the id of the chunk
+async fn insert_chunk(
+    conn: &turso::Connection,
+    robot_id: &str,
+    doc_id: i64,
+    chunk_idx: i64,
+    chunk: &DocChunk,
+    chunk_vec: (usize, turso::Value),
+) -> Result<i64> {
+    if !vec_table_ready(conn, robot_id).await? {
         let sql = format!(
-            "INSERT INTO {robot_id}_vec(doc_id, chunk_text, chunk_vec) VALUES(?1, ?2, vector32(?3));"
+            "CREATE TABLE IF NOT EXISTS {robot_id}_vec (
+                id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                doc_id INTEGER NOT NULL,
+                chunk_text TEXT NOT NULL,
+                chunk_vec F32_BLOB({}) NOT NULL,
+                page INTEGER,
+                heading_path TEXT,
+                chunk_idx INTEGER
+            );",
+            chunk_vec.0
         );
-        tx.execute(
-            &sql,
-            (
-                doc_id,
-                turso::Value::Text(String::from(chunk)),
-                embedding::vec_to_db(&r.0),
-            ),
-        )
-        .await?;
-        // log::info!("Embedding id={}", conn.last_insert_rowid());
+        conn.execute(&sql, ()).await?;
     }
+    let sql = format!(
+        "INSERT INTO {robot_id}_vec(doc_id, chunk_text, chunk_vec, page, heading_path, chunk_idx) VALUES(?1, ?2, vector32(?3), ?4, ?5, ?6);"
+    );
+    conn.execute(
+        &sql,
+        (
+            doc_id,
+            turso::Value::Text(chunk.text.clone()),
+            chunk_vec.1,
+            chunk.page.map_or(turso::Value::Null, turso::Value::Integer),
+            turso::Value::Text(chunk.heading_path.clone()),
+            chunk_idx,
+        ),
+    )
+    .await?;
+    let chunk_id = conn.last_insert_rowid();
+    search::index_terms(conn, robot_id, doc_id, chunk_id, &chunk.embedding_text()).await?;
+    Ok(chunk_id)
+}
+
+// Each chunk is saved in its own transaction, so the progress of a job survives failures.
+// It's embedded before the transaction, and replaces the chunk of the same index if any
+pub(super) async fn save_chunk(
+    robot_id: &str,
+    doc_id: i64,
+    chunk_idx: usize,
+    chunk: &DocChunk,
+) -> Result<()> {
+    let r = embedding::embedding(robot_id, &chunk.embedding_text()).await?;
+    let chunk_idx = chunk_idx as i64;
+    let db = DATA_SOURCE.get().unwrap();
+    let mut conn = db.connect()?;
+    let tx = conn.transaction().await?;
+    ensure_terms_index(&tx, robot_id).await?;
+    let removed = remove_chunk(&tx, robot_id, doc_id, chunk_idx).await?;
+    let chunk_vec = (r.0.len(), embedding::vec_to_db(&r.0));
+    let chunk_id = insert_chunk(&tx, robot_id, doc_id, chunk_idx, chunk, chunk_vec).await?;
+    tx.commit().await?;
+    ann::remove(&ANN, db, robot_id, &removed);
+    ann::upsert(&ANN, db, robot_id, vec![(chunk_id, r.0)]);
     Ok(())
 }
 
@@ -226,26 +421,50 @@ pub(super) fn parse_docx(b: Vec<u8>) -> Result<String> {
     let mut doc_text = String::with_capacity(3096);
     let reader = Cursor::new(b);
     let mut archive = ZipArchive::new(reader)?;
-    let mut zip_file = archive.by_name("word/document.xml")?;
-    let mut cache = String::with_capacity(zip_file.size() as usize);
-    zip_file.read_to_string(&mut cache)?;
+    let Some(cache) = super::parser::read_zip_entry(&mut archive, "word/document.xml")? else {
+        return Err(zip::result::ZipError::FileNotFound.into());
+    };
 
     // 创建 XML 解析器
     let mut reader = Reader::from_str(&cache);
     reader.config_mut().trim_text(false);
     let mut in_paragraph = false;
+    let mut paragraph = String::with_capacity(256);
+    let mut heading_level = 0usize;
 
     // 读取 XML 内容
     loop {
         match reader.read_event() {
             Ok(Event::Start(ref e)) if e.name().as_ref() == b"w:p" => in_paragraph = true,
             Ok(Event::End(ref e)) if e.name().as_ref() == b"w:p" => {
-                doc_text.push('\n');
+                // Headings are kept as Markdown headings for the chunker
+                let text = paragraph.trim();
+                if !text.is_empty() {
+                    if heading_level > 0 {
+                        doc_text.push_str(&"#".repeat(heading_level));
+                        doc_text.push(' ');
+                    }
+                    doc_text.push_str(text);
+                    doc_text.push_str("\n\n");
+                }
+                paragraph.clear();
+                heading_level = 0;
                 in_paragraph = false;
             }
-            Ok(Event::Empty(ref e)) if e.name().as_ref() == b"w:p" => doc_text.push('\n'),
+            Ok(Event::Start(ref e) | Event::Empty(ref e))
+                if in_paragraph && e.name().as_ref() == b"w:pStyle" =>
+            {
+                if let Some(a) = e.attributes().flatten().find(|a| a.key.as_ref() == b"w:val") {
+                    let style = String::from_utf8_lossy(&a.value).to_lowercase();
+                    heading_level = style
+                        .strip_prefix("heading")
+                        .and_then(|l| l.trim().parse::<usize>().ok())
+                        .filter(|l| (1..=6).contains(l))
+                        .unwrap_or(0);
+                }
+            }
             Ok(Event::Text(e)) if in_paragraph => {
-                doc_text.push_str(&e.decode()?);
+                paragraph.push_str(&e.decode()?);
             }
             Ok(Event::Eof) => break,
             Err(e) => panic!("Error at position {}: {:?}", reader.error_position(), e),
@@ -255,68 +474,746 @@ pub(super) fn parse_docx(b: Vec<u8>) -> Result<String> {
     Ok(doc_text)
 }
 
-fn parse_pdf() {}
+// Pages of PDF documents are separated by form feed, so that chunks can keep their page numbers
+pub(super) const PAGE_SEPARATOR: char = '\u{c}';
+// Parts that must not be chunked together, like rows of spreadsheets or sections of Markdown
+pub(super) const CHUNK_SEPARATOR: char = '\u{1e}';
+// Lines within this ratio of page height from the top or bottom may be headers or footers
+const PDF_MARGIN_RATIO: f32 = 0.08;
+
+type Matrix = [f32; 6];
+
+const IDENTITY: Matrix = [1., 0., 0., 1., 0., 0.];
+
+fn mul(m1: &Matrix, m2: &Matrix) -> Matrix {
+    [
+        m1[0] * m2[0] + m1[1] * m2[2],
+        m1[0] * m2[1] + m1[1] * m2[3],
+        m1[2] * m2[0] + m1[3] * m2[2],
+        m1[2] * m2[1] + m1[3] * m2[3],
+        m1[4] * m2[0] + m1[5] * m2[2] + m2[4],
+        m1[4] * m2[1] + m1[5] * m2[3] + m2[5],
+    ]
+}
+
+fn num(o: &Object) -> f32 {
+    o.as_float().unwrap_or(0.)
+}
+
+fn to_matrix(args: &[Object]) -> Matrix {
+    [
+        num(&args[0]),
+        num(&args[1]),
+        num(&args[2]),
+        num(&args[3]),
+        num(&args[4]),
+        num(&args[5]),
+    ]
+}
+
+// Glyph widths are not available without parsing font programs, so they are estimated in
+// units of font size
+fn estimate_width(text: &str) -> f32 {
+    text.chars()
+        .map(|c| if c.is_ascii() { 0.5 } else { 1. })
+        .sum()
+}
+
+struct TextRun {
+    x: f32,
+    y: f32,
+    width: f32,
+    size: f32,
+    text: String,
+}
+
+struct TextState {
+    ctm: Matrix,
+    ctm_stack: Vec<Matrix>,
+    tm: Matrix,
+    tlm: Matrix,
+    font_size: f32,
+    leading: f32,
+    char_spacing: f32,
+    word_spacing: f32,
+    h_scale: f32,
+    runs: Vec<TextRun>,
+}
+
+impl TextState {
+    fn new() -> Self {
+        TextState {
+            ctm: IDENTITY,
+            ctm_stack: Vec::with_capacity(8),
+            tm: IDENTITY,
+            tlm: IDENTITY,
+            font_size: 0.,
+            leading: 0.,
+            char_spacing: 0.,
+            word_spacing: 0.,
+            h_scale: 1.,
+            runs: Vec::with_capacity(256),
+        }
+    }
+
+    fn move_line(&mut self, tx: f32, ty: f32) {
+        self.tlm = mul(&[1., 0., 0., 1., tx, ty], &self.tlm);
+        self.tm = self.tlm;
+    }
+
+    fn next_line(&mut self) {
+        self.move_line(0., -self.leading);
+    }
+
+    fn advance(&mut self, tx: f32) {
+        self.tm = mul(&[1., 0., 0., 1., tx, 0.], &self.tm);
+    }
+
+    fn show(&mut self, text: String) {
+        let trm = mul(&self.tm, &self.ctm);
+        let chars = text.chars().count() as f32;
+        let spaces = text.chars().filter(|c| *c == ' ').count() as f32;
+        let advance = (estimate_width(&text) * self.font_size
+            + self.char_spacing * chars
+            + self.word_spacing * spaces)
+            * self.h_scale;
+        let size = self.font_size * (trm[2] * trm[2] + trm[3] * trm[3]).sqrt();
+        if size > 0. && !text.trim().is_empty() {
+            self.runs.push(TextRun {
+                x: trm[4],
+                y: trm[5],
+                width: advance * (trm[0] * trm[0] + trm[1] * trm[1]).sqrt(),
+                size,
+                text,
+            });
+        }
+        self.advance(advance);
+    }
+}
+
+fn decode_pdf_text(encoding: Option<&Encoding>, bytes: &[u8]) -> String {
+    match encoding {
+        Some(e) => Document::decode_text(e, bytes).unwrap_or_else(|e| {
+            log::warn!("Decoding PDF text failed: {e:?}");
+            String::new()
+        }),
+        // Falls back to Latin-1
+        None => bytes.iter().map(|b| *b as char).collect(),
+    }
+}
+
+fn extract_page_runs(doc: &Document, page_id: ObjectId) -> Result<Vec<TextRun>> {
+    let mut encodings: BTreeMap<Vec<u8>, Encoding> = BTreeMap::new();
+    for (name, font) in doc.get_page_fonts(page_id)?.into_iter() {
+        match font.get_font_encoding(doc) {
+            Ok(e) => {
+                encodings.insert(name, e);
+            }
+            Err(e) => log::warn!("Unsupported font encoding: {e:?}"),
+        }
+    }
+    let content = Content::decode(&doc.get_page_content(page_id)?)?;
+    let mut state = TextState::new();
+    let mut encoding: Option<&Encoding> = None;
+    for op in content.operations.iter() {
+        let args = &op.operands;
+        match (op.operator.as_str(), args.len()) {
+            ("q", _) => state.ctm_stack.push(state.ctm),
+            ("Q", _) => {
+                if let Some(m) = state.ctm_stack.pop() {
+                    state.ctm = m;
+                }
+            }
+            ("cm", 6) => state.ctm = mul(&to_matrix(args), &state.ctm),
+            ("BT", _) => {
+                state.tm = IDENTITY;
+                state.tlm = IDENTITY;
+            }
+            ("Tf", 2) => {
+                encoding = args[0].as_name().ok().and_then(|n| encodings.get(n));
+                state.font_size = num(&args[1]);
+            }
+            ("TL", 1) => state.leading = num(&args[0]),
+            ("Tc", 1) => state.char_spacing = num(&args[0]),
+            ("Tw", 1) => state.word_spacing = num(&args[0]),
+            ("Tz", 1) => state.h_scale = num(&args[0]) / 100.,
+            ("Td", 2) => state.move_line(num(&args[0]), num(&args[1])),
+            ("TD", 2) => {
+                state.leading = -num(&args[1]);
+                state.move_line(num(&args[0]), num(&args[1]));
+            }
+            ("Tm", 6) => {
+                state.tlm = to_matrix(args);
+                state.tm = state.tlm;
+            }
+            ("T*", _) => state.next_line(),
+            ("Tj", 1) => {
+                if let Ok(b) = args[0].as_str() {
+                    state.show(decode_pdf_text(encoding, b));
+                }
+            }
+            ("'", 1) => {
+                state.next_line();
+                if let Ok(b) = args[0].as_str() {
+                    state.show(decode_pdf_text(encoding, b));
+                }
+            }
+            ("\"", 3) => {
+                state.word_spacing = num(&args[0]);
+                state.char_spacing = num(&args[1]);
+                state.next_line();
+                if let Ok(b) = args[2].as_str() {
+                    state.show(decode_pdf_text(encoding, b));
+                }
+            }
+            ("TJ", 1) => {
+                let Ok(items) = args[0].as_array() else {
+                    continue;
+                };
+                for item in items.iter() {
+                    match item.as_str() {
+                        Ok(b) => state.show(decode_pdf_text(encoding, b)),
+                        // Adjustment in thousandths of text space unit
+                        Err(_) => {
+                            let tx = -num(item) / 1000. * state.font_size * state.h_scale;
+                            state.advance(tx);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(state.runs)
+}
+
+struct PdfLine {
+    x0: f32,
+    x1: f32,
+    y: f32,
+    text: String,
+}
+
+fn is_cjk_boundary(prev: &str, next: &str) -> bool {
+    prev.chars().last().is_some_and(|c| !c.is_ascii())
+        && next.chars().next().is_some_and(|c| !c.is_ascii())
+}
+
+// Groups runs into lines from top to bottom, a line is split where the gap is wide enough to be
+// a column gutter
+fn runs_to_lines(mut runs: Vec<TextRun>) -> Vec<PdfLine> {
+    runs.sort_by(|a, b| b.y.total_cmp(&a.y).then(a.x.total_cmp(&b.x)));
+    let mut rows: Vec<Vec<TextRun>> = Vec::with_capacity(64);
+    for r in runs.into_iter() {
+        match rows.last_mut() {
+            Some(row) if (row[0].y - r.y).abs() < row[0].size.max(r.size) * 0.5 => row.push(r),
+            _ => rows.push(vec![r]),
+        }
+    }
+    let mut lines: Vec<PdfLine> = Vec::with_capacity(rows.len());
+    for mut row in rows.into_iter() {
+        row.sort_by(|a, b| a.x.total_cmp(&b.x));
+        let mut line: Option<PdfLine> = None;
+        for r in row.into_iter() {
+            if let Some(l) = line.as_mut() {
+                let gap = r.x - l.x1;
+                if gap < r.size * 1.5 {
+                    if gap > r.size * 0.2
+                        && !l.text.ends_with(char::is_whitespace)
+                        && !r.text.starts_with(char::is_whitespace)
+                        && !is_cjk_boundary(&l.text, &r.text)
+                    {
+                        l.text.push(' ');
+                    }
+                    l.text.push_str(&r.text);
+                    l.x1 = l.x1.max(r.x + r.width);
+                    continue;
+                }
+                lines.push(line.take().unwrap());
+            }
+            line = Some(PdfLine {
+                x0: r.x,
+                x1: r.x + r.width,
+                y: r.y,
+                text: r.text,
+            });
+        }
+        if let Some(l) = line {
+            lines.push(l);
+        }
+    }
+    for l in lines.iter_mut() {
+        let t = l.text.split_whitespace().collect::<Vec<&str>>().join(" ");
+        l.text = t;
+    }
+    lines
+}
+
+// Looks for a vertical band in the middle of the page that no narrow line crosses
+fn find_gutter(lines: &[PdfLine], left: f32, width: f32) -> Option<f32> {
+    const BUCKETS: usize = 100;
+    if width <= 0. {
+        return None;
+    }
+    let narrow: Vec<&PdfLine> = lines
+        .iter()
+        .filter(|l| l.x1 - l.x0 < width * 0.6)
+        .collect();
+    if narrow.len() < 6 {
+        return None;
+    }
+    let bucket = |x: f32| (((x - left) / width * BUCKETS as f32) as usize).min(BUCKETS - 1);
+    let mut covered = [false; BUCKETS];
+    for l in narrow.iter() {
+        for c in covered[bucket(l.x0)..=bucket(l.x1)].iter_mut() {
+            *c = true;
+        }
+    }
+    let mut best: Option<(usize, usize)> = None;
+    let mut start: Option<usize> = None;
+    for (i, c) in covered.iter().enumerate().take(BUCKETS * 7 / 10 + 1).skip(BUCKETS * 3 / 10) {
+        match (c, start) {
+            (false, None) => start = Some(i),
+            (true, Some(s)) => {
+                if best.is_none_or(|(bs, be)| i - s > be - bs) {
+                    best = Some((s, i));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start
+        && best.is_none_or(|(bs, be)| BUCKETS * 7 / 10 + 1 - s > be - bs)
+    {
+        best = Some((s, BUCKETS * 7 / 10 + 1));
+    }
+    let (s, e) = best?;
+    let gutter = left + (s + e) as f32 / 2. / BUCKETS as f32 * width;
+    let left_lines = narrow.iter().filter(|l| l.x1 <= gutter).count();
+    let right_lines = narrow.iter().filter(|l| l.x0 >= gutter).count();
+    if left_lines >= 3 && right_lines >= 3 {
+        Some(gutter)
+    } else {
+        None
+    }
+}
+
+// Lines crossing the gutter split the page into bands, columns are read one after another
+// within each band
+fn order_lines(lines: Vec<PdfLine>, gutter: Option<f32>) -> Vec<PdfLine> {
+    let Some(g) = gutter else {
+        return lines;
+    };
+    let mut ordered: Vec<PdfLine> = Vec::with_capacity(lines.len());
+    let mut left: Vec<PdfLine> = Vec::with_capacity(32);
+    let mut right: Vec<PdfLine> = Vec::with_capacity(32);
+    for l in lines.into_iter() {
+        if l.x1 <= g {
+            left.push(l);
+        } else if l.x0 >= g {
+            right.push(l);
+        } else {
+            ordered.append(&mut left);
+            ordered.append(&mut right);
+            ordered.push(l);
+        }
+    }
+    ordered.append(&mut left);
+    ordered.append(&mut right);
+    ordered
+}
+
+fn page_box(doc: &Document, page_id: ObjectId) -> [f32; 4] {
+    let mut id = page_id;
+    // MediaBox can be inherited from ancestors
+    for _ in 0..8 {
+        let Ok(dict) = doc.get_dictionary(id) else {
+            break;
+        };
+        if let Ok(o) = dict.get(b"MediaBox")
+            && let Ok((_, o)) = doc.dereference(o)
+            && let Ok(a) = o.as_array()
+            && a.len() == 4
+        {
+            return [num(&a[0]), num(&a[1]), num(&a[2]), num(&a[3])];
+        }
+        match dict.get(b"Parent").and_then(|p| p.as_reference()) {
+            Ok(p) => id = p,
+            Err(_) => break,
+        }
+    }
+    [0., 0., 612., 792.]
+}
+
+struct PdfPage {
+    bottom: f32,
+    height: f32,
+    lines: Vec<PdfLine>,
+}
+
+fn in_margin(bottom: f32, height: f32, l: &PdfLine) -> bool {
+    let y = l.y - bottom;
+    y < height * PDF_MARGIN_RATIO || y > height * (1. - PDF_MARGIN_RATIO)
+}
+
+fn is_page_number(text: &str) -> bool {
+    if !text.chars().any(|c| c.is_ascii_digit()) {
+        return false;
+    }
+    let rest = text
+        .chars()
+        .filter(|c| !c.is_ascii_digit() && !c.is_whitespace() && !"-–—|/.()[]".contains(*c))
+        .collect::<String>()
+        .to_lowercase();
+    matches!(rest.as_str(), "" | "p" | "page" | "pageof" | "第页" | "第页共页")
+}
+
+// Page numbers in headers and footers differ from page to page
+fn header_footer_key(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_ascii_digit() { '#' } else { c })
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn remove_headers_and_footers(pages: &mut [PdfPage]) {
+    let mut counts: HashMap<String, usize> = HashMap::with_capacity(32);
+    for p in pages.iter() {
+        let mut keys: Vec<String> = p
+            .lines
+            .iter()
+            .filter(|l| in_margin(p.bottom, p.height, l))
+            .map(|l| header_footer_key(&l.text))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        for k in keys.into_iter() {
+            *counts.entry(k).or_default() += 1;
+        }
+    }
+    // Repeating lines can only be told apart from content with enough pages
+    let total = pages.len();
+    let repeated = |k: &str| total > 2 && counts.get(k).is_some_and(|c| c * 2 >= total);
+    for p in pages.iter_mut() {
+        let (bottom, height) = (p.bottom, p.height);
+        p.lines.retain(|l| {
+            !in_margin(bottom, height, l)
+                || !(is_page_number(&l.text) || repeated(&header_footer_key(&l.text)))
+        });
+    }
+}
+
+pub(super) fn parse_pdf(b: Vec<u8>) -> Result<String> {
+    let doc = Document::load_mem(&b)?;
+    let page_ids = doc.get_pages();
+    let mut pages: Vec<PdfPage> = Vec::with_capacity(page_ids.len());
+    for (page_num, page_id) in page_ids.into_iter() {
+        let [x0, y0, x1, y1] = page_box(&doc, page_id);
+        let runs = extract_page_runs(&doc, page_id).unwrap_or_else(|e| {
+            log::warn!("Extracting text from PDF page {page_num} failed: {e:?}");
+            vec![]
+        });
+        let lines = runs_to_lines(runs);
+        let gutter = find_gutter(&lines, x0, x1 - x0);
+        pages.push(PdfPage {
+            bottom: y0,
+            height: y1 - y0,
+            lines: order_lines(lines, gutter),
+        });
+    }
+    remove_headers_and_footers(&mut pages);
+    let mut doc_text = String::with_capacity(b.len() / 4);
+    for (i, p) in pages.iter().enumerate() {
+        if i > 0 {
+            doc_text.push(PAGE_SEPARATOR);
+        }
+        for l in p.lines.iter() {
+            doc_text.push_str(&l.text);
+            doc_text.push('\n');
+        }
+    }
+    Ok(doc_text)
+}
+
+// Hybrid search, vector distance and BM25 rankings are fused by reciprocal rank
+pub(crate) async fn search(
+    robot_id: &str,
+    query: &str,
+    recall_distance: f64,
+    top_k: usize,
+) -> Result<Vec<DocCandidate>> {
+    let db = DATA_SOURCE.get().unwrap();
+    let conn = db.connect()?;
+    if !vec_table_ready(&conn, robot_id).await? {
+        return Ok(vec![]);
+    }
+    let r = embedding::embedding(robot_id, query).await?;
+    let query_vec = embedding::vec_to_db(&r.0);
+    let nearest = ann::search(&ANN, db, robot_id, &r.0, search::CANDIDATES);
+    let filter = nearest.as_ref().map_or(String::new(), |ids| {
+        format!("AND id IN ({})", search::placeholders(ids.len(), 4))
+    });
+    let sql = format!(
+        "SELECT id, vector_distance_cos(chunk_vec, vector32(?1)) AS distance FROM {robot_id}_vec WHERE distance < ?2 {filter} ORDER BY distance ASC LIMIT ?3"
+    );
+    let mut params: Vec<turso::Value> = vec![
+        query_vec.clone(),
+        turso::Value::Real(recall_distance),
+        turso::Value::Integer(search::CANDIDATES as i64),
+    ];
+    params.extend(
+        nearest
+            .iter()
+            .flatten()
+            .map(|id| turso::Value::Integer(*id)),
+    );
+    let mut rows = conn.query(&sql, params).await?;
+    let mut vector_ranked: Vec<i64> = Vec::with_capacity(search::CANDIDATES);
+    while let Some(row) = rows.next().await? {
+        vector_ranked.push(*row.get_value(0)?.as_integer().unwrap());
+    }
+    let keyword_hits: Vec<search::KeywordHit> =
+        search::keyword_search(&conn, robot_id, query, search::CANDIDATES).await?;
+    let keyword_ranked: Vec<i64> = keyword_hits.iter().map(|h| h.unit_id).collect();
+    let mut fused = search::fuse(&[vector_ranked, keyword_ranked]);
+    let reranker = search::reranker(robot_id)?;
+    fused.truncate(search::fusion_limit(reranker.as_ref(), top_k));
+    if fused.is_empty() {
+        return Ok(vec![]);
+    }
+    let sql = format!(
+        "SELECT v.id, v.doc_id, d.file_name, v.chunk_text, v.page, v.heading_path, vector_distance_cos(v.chunk_vec, vector32(?1)) FROM {robot_id}_vec v INNER JOIN {robot_id} d ON v.doc_id = d.id WHERE v.id IN ({})",
+        search::placeholders(fused.len(), 2)
+    );
+    let mut params: Vec<turso::Value> = Vec::with_capacity(fused.len() + 1);
+    params.push(query_vec);
+    params.extend(fused.iter().map(|(id, _)| turso::Value::Integer(*id)));
+    let mut rows = conn.query(&sql, params).await?;
+    let mut candidates: Vec<DocCandidate> = Vec::with_capacity(fused.len());
+    while let Some(row) = rows.next().await? {
+        let chunk_id = *row.get_value(0)?.as_integer().unwrap();
+        candidates.push(DocCandidate {
+            chunk_id,
+            doc_id: *row.get_value(1)?.as_integer().unwrap(),
+            file_name: String::from(row.get_value(2)?.as_text().unwrap()),
+            chunk_text: String::from(row.get_value(3)?.as_text().unwrap()),
+            page: row.get_value(4)?.as_integer().copied(),
+            heading_path: row
+                .get_value(5)?
+                .as_text()
+                .map(String::from)
+                .unwrap_or_default(),
+            distance: *row.get_value(6)?.as_real().unwrap_or(&1.),
+            keyword_score: keyword_hits
+                .iter()
+                .find(|h| h.unit_id == chunk_id)
+                .map(|h| h.score),
+            score: fused
+                .iter()
+                .find(|(id, _)| *id == chunk_id)
+                .map_or(0., |(_, s)| *s),
+            rerank_score: None,
+        });
+    }
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+    search::rerank(robot_id, reranker.as_ref(), query, &mut candidates, top_k).await;
+    Ok(candidates)
+}
+
+impl search::Rerankable for DocCandidate {
+    fn rerank_text(&self) -> String {
+        if self.heading_path.is_empty() {
+            self.chunk_text.clone()
+        } else {
+            format!("{}\n{}", &self.heading_path, &self.chunk_text)
+        }
+    }
+
+    fn set_rerank_score(&mut self, score: f32) {
+        self.rerank_score = Some(score);
+    }
+
+    fn rerank_score(&self) -> f32 {
+        self.rerank_score.unwrap_or_default()
+    }
+}
+
+pub(super) fn cite(c: &DocCandidate) -> String {
+    let mut source = c.file_name.clone();
+    if !c.heading_path.is_empty() {
+        source.push_str(" > ");
+        source.push_str(&c.heading_path);
+    }
+    if let Some(p) = c.page {
+        if *crate::web::server::IS_EN {
+            source.push_str(&format!(" (p. {p})"));
+        } else {
+            source.push_str(&format!("（第{p}页）"));
+        }
+    }
+    source
+}
 
 pub(crate) async fn search_doc(
     robot_id: &str,
     query: &str,
     recall_distance: f64,
+    top_k: usize,
     connect_timeout: u32,
     read_timeout: u32,
 ) -> Result<Option<String>> {
-    let r = embedding::embedding(robot_id, query).await?;
-    // log::info!("{:?}", &r.0);
-    let sql = format!(
-        "SELECT chunk_text, vector_distance_cos(chunk_vec, vector32(?1)) AS distance FROM {robot_id}_vec WHERE distance < ?2 ORDER BY distance ASC LIMIT 1"
-    );
-    let conn = DATA_SOURCE.get().unwrap().connect()?;
-    let mut rows = conn
-        .query(
-            &sql,
-            [
-                embedding::vec_to_db(&r.0),
-                turso::Value::Real(recall_distance),
-            ],
-        )
-        .await?;
-    if let Some(row) = rows.next().await? {
+    let candidates = search(robot_id, query, recall_distance, top_k).await?;
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+    for c in candidates.iter() {
         log::info!(
-            "{} {}",
-            recall_distance,
-            row.get_value(1)?.as_real().unwrap(),
+            "Doc candidate {} distance {} keyword {:?} score {} rerank {:?}",
+            c.chunk_id,
+            c.distance,
+            c.keyword_score,
+            c.score,
+            c.rerank_score
         );
-        let prompts = vec![
-            crate::ai::completion::Prompt {
-                role: String::from("system"),
-                content: String::from(
-                    "你是一个专业的文档助手。请根据提供的文档内容回答问题。\
-                                如果文档内容中没有相关信息，请明确说明。\
-                                回答要基于文档内容，不要编造信息。",
-                ),
-            },
-            crate::ai::completion::Prompt {
-                role: String::from("user"),
-                content: format!(
-                    "文档内容：\n{}\n\n问题：{}",
-                    row.get_value(0)?.as_text().unwrap(),
-                    query
-                ),
-            },
-        ];
-        let mut s = String::with_capacity(1024);
-        if let Err(e) = crate::ai::chat::chat(
-            robot_id,
-            Some(prompts),
-            Some(connect_timeout),
-            Some(read_timeout),
-            crate::ai::chat::ResultSender::StrBuf(&mut s),
-        )
-        .await
-        {
-            log::error!("LlmChatNode response failed, err: {:?}", &e);
-        } else {
-            return Ok(Some(s));
+    }
+    let passages: Vec<Passage> = candidates.into_iter().map(Passage::from).collect();
+    let prompts = rag::build_prompts(&passages, &[], query);
+    let mut s = String::with_capacity(1024);
+    if let Err(e) = crate::ai::chat::chat(
+        robot_id,
+        Some(prompts),
+        Some(connect_timeout),
+        Some(read_timeout),
+        crate::ai::chat::ResultSender::StrBuf(&mut s),
+    )
+    .await
+    {
+        log::error!("LlmChatNode response failed, err: {:?}", &e);
+        return Ok(None);
+    }
+    if rag::is_no_answer(&s) {
+        return Ok(None);
+    }
+    s.push_str(&rag::sources(&s, &passages));
+    Ok(Some(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use lopdf::content::Operation;
+    use lopdf::{Stream, dictionary};
+
+    use super::*;
+    use crate::kb::chunker::ChunkStrategy;
+
+    // Every page has lines of (y, text) in Helvetica 12
+    fn pdf_of(pages: &[Vec<(i64, &str)>]) -> Vec<u8> {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Helvetica",
+            "Encoding" => "WinAnsiEncoding",
+        });
+        let resources_id = doc.add_object(dictionary! {
+            "Font" => dictionary! { "F1" => font_id },
+        });
+        let mut kids: Vec<Object> = Vec::with_capacity(pages.len());
+        for lines in pages.iter() {
+            let mut operations = Vec::with_capacity(lines.len() * 4);
+            for (y, text) in lines.iter() {
+                operations.push(Operation::new("BT", vec![]));
+                operations.push(Operation::new("Tf", vec!["F1".into(), 12.into()]));
+                operations.push(Operation::new("Td", vec![72.into(), (*y).into()]));
+                operations.push(Operation::new("Tj", vec![Object::string_literal(*text)]));
+                operations.push(Operation::new("ET", vec![]));
+            }
+            let content = Content { operations };
+            let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+            let page_id = doc.add_object(dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "Contents" => content_id,
+            });
+            kids.push(page_id.into());
         }
+        let count = kids.len() as i64;
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => kids,
+                "Count" => count,
+                "Resources" => resources_id,
+                "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+        let mut b: Vec<u8> = Vec::with_capacity(4096);
+        doc.save_to(&mut b).unwrap();
+        b
+    }
+
+    #[test]
+    fn pdf_pages_are_separated() {
+        let b = pdf_of(&[
+            vec![
+                (760, "ACME Handbook"),
+                (700, "Refund policy"),
+                (680, "Within 7 days"),
+                (30, "Page 1"),
+            ],
+            vec![(760, "ACME Handbook"), (30, "Page 2")],
+            vec![(760, "ACME Handbook"), (700, "Shipping"), (30, "Page 3")],
+        ]);
+        let text = parse_pdf(b).unwrap();
+        assert_eq!(text, "Refund policy\nWithin 7 days\n\u{c}\u{c}Shipping\n");
+    }
+
+    #[test]
+    fn pdf_headers_are_kept_with_few_pages() {
+        let b = pdf_of(&[
+            vec![(760, "ACME Handbook"), (700, "Refund policy"), (30, "2")],
+            vec![(760, "ACME Handbook"), (700, "Shipping")],
+        ]);
+        let text = parse_pdf(b).unwrap();
+        assert_eq!(
+            text,
+            "ACME Handbook\nRefund policy\n\u{c}ACME Handbook\nShipping\n"
+        );
+    }
+
+    #[test]
+    fn chunks_keep_page_numbers() {
+        let config = DocChunking {
+            strategy: ChunkStrategy::Structure,
+            max_tokens: 256,
+            overlap_tokens: 0,
+        };
+        let counter = TokenCounter::Estimate;
+        let pages = |s: &str| -> Vec<(Option<i64>, String)> {
+            split(s, &config, &counter)
+                .into_iter()
+                .map(|c| (c.page, c.text))
+                .collect()
+        };
+        // Empty pages have no chunks but still count
+        assert_eq!(
+            pages("Refund policy.\n\u{c}\u{c}Shipping.\n\nTracking.\n"),
+            vec![
+                (Some(1), String::from("Refund policy.")),
+                (Some(3), String::from("Shipping.\n\nTracking.")),
+            ]
+        );
+        assert_eq!(
+            pages("Not paged.\n"),
+            vec![(None, String::from("Not paged."))]
+        );
     }
-    Ok(None)
 }
@@ -24,6 +24,54 @@ pub(crate) struct QuestionData {
     pub(super) vec_row_id: Option<u64>,
 }
 
+// A row of imported or exported files
+#[derive(Deserialize, Serialize)]
+pub(crate) struct QnARecord {
+    pub(super) question: String,
+    #[serde(rename = "similarQuestions", default)]
+    pub(super) similar_questions: Vec<String>,
+    pub(super) answer: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct QnAExportQuery {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    pub(crate) format: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IngestJobQuery {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "jobId")]
+    pub(crate) job_id: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WebSourceQuery {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "sourceId")]
+    pub(crate) source_id: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct QnAImportError {
+    pub(crate) row: usize,
+    pub(crate) message: String,
+}
+
+#[derive(Default, Serialize)]
+pub(crate) struct QnAImportReport {
+    pub(crate) total: usize,
+    pub(crate) imported: usize,
+    pub(crate) skipped: usize,
+    pub(crate) errors: Vec<QnAImportError>,
+    // Rows imported with some of their similar questions left out
+    pub(crate) warnings: Vec<QnAImportError>,
+}
+
 #[derive(Deserialize, Serialize)] // , sqlx::FromRow
 pub(crate) struct DocData {
     pub(crate) id: i64,
@@ -34,3 +82,36 @@ pub(crate) struct DocData {
     #[serde(rename = "docContent")]
     pub(crate) doc_content: String,
 }
+
+#[derive(Serialize)]
+pub(crate) struct DocCandidate {
+    #[serde(rename = "chunkId")]
+    pub(crate) chunk_id: i64,
+    #[serde(rename = "docId")]
+    pub(crate) doc_id: i64,
+    #[serde(rename = "fileName")]
+    pub(crate) file_name: String,
+    #[serde(rename = "chunkText")]
+    pub(crate) chunk_text: String,
+    pub(crate) page: Option<i64>,
+    #[serde(rename = "headingPath")]
+    pub(crate) heading_path: String,
+    pub(crate) distance: f64,
+    #[serde(rename = "keywordScore")]
+    pub(crate) keyword_score: Option<f64>,
+    // Fused by reciprocal rank
+    pub(crate) score: f64,
+    #[serde(rename = "rerankScore")]
+    pub(crate) rerank_score: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct QnACandidate {
+    pub(crate) qa: QuestionAnswerPair,
+    pub(crate) distance: f64,
+    #[serde(rename = "keywordScore")]
+    pub(crate) keyword_score: Option<f64>,
+    pub(crate) score: f64,
+    #[serde(rename = "rerankScore")]
+    pub(crate) rerank_score: Option<f32>,
+}
@@ -0,0 +1,415 @@
+use std::path::{Path, PathBuf};
+use std::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use super::chunker::TokenCounter;
+use super::{doc, parser};
+use crate::man::clock::now_secs;
+use crate::man::job::{Job, JobQueue, JobStatus};
+use crate::man::settings::{self, DocChunking};
+use crate::result::{Error, Result};
+
+const MAX_RUNNING_JOBS: usize = 4;
+// Finished jobs are removed after 30 days
+const JOB_RETENTION_SECS: u64 = 30 * 86400;
+
+static QUEUE: JobQueue = JobQueue::new("kb_ingest_jobs", "Ingestion", MAX_RUNNING_JOBS);
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct IngestJob {
+    pub(crate) id: String,
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "docId")]
+    pub(crate) doc_id: i64,
+    #[serde(rename = "fileName")]
+    pub(crate) file_name: String,
+    pub(crate) status: JobStatus,
+    // The document was saved for this job, so it's removed if the job is cancelled. Jobs which
+    // embed an existing document again leave it in place. Older jobs are taken as the latter
+    #[serde(rename = "createsDoc", default)]
+    creates_doc: bool,
+    // Settings when the job was submitted, so chunk indexes stay the same for retries
+    chunking: DocChunking,
+    // Uploaded file waiting to be parsed, removed once its text was saved
+    #[serde(rename = "uploadPath", default)]
+    upload_path: String,
+    #[serde(rename = "contentType", default)]
+    content_type: String,
+    #[serde(rename = "totalChunks")]
+    pub(crate) total_chunks: usize,
+    #[serde(rename = "embeddedChunks")]
+    pub(crate) embedded_chunks: usize,
+    #[serde(rename = "failedChunks")]
+    pub(crate) failed_chunks: Vec<usize>,
+    // Chunks after this one have not been processed yet
+    #[serde(rename = "nextChunk")]
+    next_chunk: usize,
+    // Failed chunks being embedded again
+    #[serde(rename = "retryChunks")]
+    retry_chunks: Vec<usize>,
+    pub(crate) err: String,
+    #[serde(rename = "createdAt")]
+    pub(crate) created_at: u64,
+    #[serde(rename = "updatedAt")]
+    pub(crate) updated_at: u64,
+}
+
+impl Job for IngestJob {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn status(&self) -> JobStatus {
+        self.status
+    }
+
+    fn set_status(&mut self, status: JobStatus) {
+        self.status = status;
+    }
+
+    fn set_err(&mut self, err: String) {
+        self.err = err;
+    }
+
+    fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    fn updated_at(&self) -> u64 {
+        self.updated_at
+    }
+
+    fn set_updated_at(&mut self, t: u64) {
+        self.updated_at = t;
+    }
+}
+
+pub(crate) fn init_table() -> Result<()> {
+    QUEUE.init_table()
+}
+
+fn update<F: FnOnce(&mut IngestJob) -> Result<()>>(job_id: &str, f: F) -> Result<IngestJob> {
+    QUEUE.update(job_id, f)
+}
+
+// Unfinished jobs are resumed from where they stopped
+pub(crate) fn start_worker() -> Result<()> {
+    QUEUE.start_worker::<IngestJob, _, _>(|job_id| async move { run(&job_id).await })
+}
+
+fn upload_dir(robot_id: &str) -> PathBuf {
+    Path::new(".")
+        .join("data")
+        .join(robot_id)
+        .join("kb")
+        .join("docs")
+        .join("upload")
+}
+
+fn remove_upload(job: &IngestJob) {
+    if !job.upload_path.is_empty()
+        && let Err(e) = std::fs::remove_file(&job.upload_path)
+    {
+        log::warn!("Removing {} failed {e:?}", &job.upload_path);
+    }
+}
+
+fn new_job(
+    robot_id: &str,
+    doc_id: i64,
+    file_name: &str,
+    upload_path: String,
+    content_type: &str,
+    creates_doc: bool,
+) -> Result<IngestJob> {
+    let now = now_secs();
+    let job = IngestJob {
+        id: scru128::new_string(),
+        robot_id: String::from(robot_id),
+        doc_id,
+        file_name: String::from(file_name),
+        status: JobStatus::Pending,
+        creates_doc,
+        chunking: settings::get_settings(robot_id)?
+            .map(|s| s.doc_chunking)
+            .unwrap_or_default(),
+        upload_path,
+        content_type: String::from(content_type),
+        total_chunks: 0,
+        embedded_chunks: 0,
+        failed_chunks: vec![],
+        next_chunk: 0,
+        retry_chunks: vec![],
+        err: String::new(),
+        created_at: now,
+        updated_at: now,
+    };
+    Ok(job)
+}
+
+fn create_job(
+    robot_id: &str,
+    doc_id: i64,
+    file_name: &str,
+    upload_path: String,
+    content_type: &str,
+) -> Result<IngestJob> {
+    if let Err(e) = QUEUE.prune::<IngestJob>(JOB_RETENTION_SECS) {
+        log::warn!("Pruning ingestion jobs failed {e:?}");
+    }
+    let job = new_job(robot_id, doc_id, file_name, upload_path, content_type, true)?;
+    QUEUE.submit(&job)?;
+    Ok(job)
+}
+
+// Content is already extracted, e.g. a crawled page
+pub(crate) async fn submit(
+    robot_id: &str,
+    file_name: &str,
+    file_size: usize,
+    doc_content: &str,
+) -> Result<IngestJob> {
+    let doc_id = doc::save(robot_id, file_name, file_size, doc_content).await?;
+    create_job(robot_id, doc_id, file_name, String::new(), "")
+}
+
+// Uploaded files are saved to disk and parsed by the job, so large files don't hold the request
+pub(crate) async fn submit_file(
+    robot_id: &str,
+    file_name: &str,
+    content_type: &str,
+    data: &[u8],
+) -> Result<IngestJob> {
+    parser::check_format(file_name, content_type)?;
+    let p = upload_dir(robot_id);
+    if !p.exists() {
+        tokio::fs::create_dir_all(&p).await?;
+    }
+    let p = p.join(scru128::new_string());
+    tokio::fs::write(&p, data).await?;
+    let upload_path = p.to_string_lossy().into_owned();
+    let doc_id = match doc::save(robot_id, file_name, data.len(), "").await {
+        Ok(id) => id,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&p).await;
+            return Err(e);
+        }
+    };
+    create_job(robot_id, doc_id, file_name, upload_path, content_type)
+}
+
+// The content is saved right away, then chunks are embedded again by a job
+pub(crate) async fn update_doc(
+    robot_id: &str,
+    doc_id: i64,
+    doc_content: &str,
+) -> Result<IngestJob> {
+    // The job is saved before the lock is released, so concurrent updates find the document busy.
+    // It's queued once the content was saved
+    let job = {
+        let _guard = QUEUE.lock();
+        let busy = list(robot_id)?
+            .iter()
+            .any(|j| j.doc_id == doc_id && j.status.is_unfinished());
+        if busy {
+            return Err(Error::WithMessage(String::from(
+                "The document is being ingested, please try again later.",
+            )));
+        }
+        let job = new_job(robot_id, doc_id, "", String::new(), "", false)?;
+        QUEUE.save(&job)?;
+        job
+    };
+    let file_name = match doc::update(robot_id, doc_id, doc_content).await {
+        Ok(Some(file_name)) => file_name,
+        r => {
+            QUEUE.remove(&job.id)?;
+            return match r {
+                Err(e) => Err(e),
+                _ => Err(Error::WithMessage(format!("Document {doc_id} not found."))),
+            };
+        }
+    };
+    let job = update(&job.id, |j| {
+        j.file_name = file_name;
+        Ok(())
+    })?;
+    QUEUE.enqueue(&job.id)?;
+    Ok(job)
+}
+
+async fn parse_upload(job: &IngestJob) -> Result<()> {
+    let b = tokio::fs::read(&job.upload_path).await?;
+    let file_name = job.file_name.clone();
+    let content_type = job.content_type.clone();
+    let text =
+        tokio::task::spawn_blocking(move || parser::parse(&file_name, &content_type, b)).await??;
+    log::info!(
+        "Extracted {} chars from {}",
+        text.chars().count(),
+        &job.file_name
+    );
+    doc::set_content(&job.robot_id, job.doc_id, &text).await?;
+    remove_upload(job);
+    update(&job.id, |j| {
+        j.upload_path.clear();
+        Ok(())
+    })?;
+    Ok(())
+}
+
+async fn run(job_id: &str) -> Result<()> {
+    // Cancelled jobs are still in the queue
+    let mut started = false;
+    let job = update(job_id, |j| {
+        if j.status == JobStatus::Pending {
+            j.status = JobStatus::Running;
+            started = true;
+        }
+        Ok(())
+    })?;
+    if !started {
+        return Ok(());
+    }
+    if !job.upload_path.is_empty() {
+        parse_upload(&job).await?;
+    }
+    let Some(doc_content) = doc::get_content(&job.robot_id, job.doc_id).await? else {
+        return Err(Error::WithMessage(String::from("Document was deleted.")));
+    };
+    let counter = TokenCounter::for_robot(&job.robot_id);
+    let chunks = doc::split(&doc_content, &job.chunking, &counter);
+    let total_chunks = chunks.len();
+    let mut job = update(job_id, |j| {
+        j.total_chunks = total_chunks;
+        Ok(())
+    })?;
+    let mut pending: Vec<usize> = job.retry_chunks.clone();
+    pending.extend(job.next_chunk..total_chunks);
+    // Chunks may change if the embedding model was replaced
+    pending.retain(|idx| *idx < total_chunks);
+    for idx in pending.into_iter() {
+        let r = doc::save_chunk(&job.robot_id, job.doc_id, idx, &chunks[idx]).await;
+        if let Err(e) = &r {
+            log::warn!("Embedding chunk {idx} of {} failed {e:?}", &job.file_name);
+        }
+        job = update(job_id, |j| {
+            if let Some(pos) = j.retry_chunks.iter().position(|i| *i == idx) {
+                j.retry_chunks.remove(pos);
+            } else {
+                j.next_chunk = idx + 1;
+            }
+            match &r {
+                Ok(_) => j.embedded_chunks += 1,
+                Err(e) => {
+                    j.failed_chunks.push(idx);
+                    j.err = format!("{e:?}");
+                }
+            }
+            Ok(())
+        })?;
+        if job.status == JobStatus::Cancelled {
+            log::info!("Ingestion job {job_id} was cancelled");
+            return remove_created_doc(&job).await;
+        }
+    }
+    let job = update(job_id, |j| {
+        if j.status != JobStatus::Running {
+            return Ok(());
+        }
+        if j.failed_chunks.is_empty() {
+            j.status = JobStatus::Completed;
+            j.err.clear();
+        } else {
+            j.status = JobStatus::Failed;
+        }
+        Ok(())
+    })?;
+    if job.status == JobStatus::Cancelled {
+        remove_created_doc(&job).await?;
+    }
+    Ok(())
+}
+
+async fn remove_created_doc(job: &IngestJob) -> Result<()> {
+    if job.creates_doc {
+        doc::delete(&job.robot_id, job.doc_id).await?;
+    }
+    Ok(())
+}
+
+fn get_job(robot_id: &str, job_id: &str) -> Result<IngestJob> {
+    match QUEUE.get::<IngestJob>(job_id)? {
+        Some(j) if j.robot_id.eq(robot_id) => Ok(j),
+        _ => Err(Error::WithMessage(format!(
+            "Ingestion job {job_id} not found."
+        ))),
+    }
+}
+
+pub(crate) fn list(robot_id: &str) -> Result<Vec<IngestJob>> {
+    let mut jobs: Vec<IngestJob> = QUEUE.get_all()?;
+    jobs.retain(|j| j.robot_id.eq(robot_id));
+    jobs.sort_by_key(|j| std::cmp::Reverse(j.created_at));
+    Ok(jobs)
+}
+
+pub(crate) fn status(robot_id: &str, job_id: &str) -> Result<IngestJob> {
+    get_job(robot_id, job_id)
+}
+
+// Only failed chunks are embedded again, as well as those not processed yet
+pub(crate) fn retry(robot_id: &str, job_id: &str) -> Result<IngestJob> {
+    get_job(robot_id, job_id)?;
+    let job = update(job_id, |j| {
+        if j.status != JobStatus::Failed {
+            return Err(Error::WithMessage(String::from(
+                "Only failed jobs can be retried.",
+            )));
+        }
+        j.retry_chunks.append(&mut j.failed_chunks);
+        j.status = JobStatus::Pending;
+        j.err.clear();
+        Ok(())
+    })?;
+    QUEUE.enqueue(job_id)?;
+    Ok(job)
+}
+
+// The running job stops after the current chunk. The document and its chunks are removed if
+// the job created it
+pub(crate) async fn cancel(robot_id: &str, job_id: &str) -> Result<IngestJob> {
+    get_job(robot_id, job_id)?;
+    let mut previous = JobStatus::Pending;
+    let job = update(job_id, |j| {
+        if !j.status.is_unfinished() {
+            return Err(Error::WithMessage(String::from(
+                "Only pending or running jobs can be cancelled.",
+            )));
+        }
+        previous = j.status;
+        j.status = JobStatus::Cancelled;
+        Ok(())
+    })?;
+    if previous == JobStatus::Pending {
+        remove_upload(&job);
+        remove_created_doc(&job).await?;
+    }
+    Ok(job)
+}
+
+// The document is being deleted, unfinished jobs of it are stopped
+pub(crate) fn stop_doc_jobs(robot_id: &str, doc_id: i64) -> Result<()> {
+    for job in list(robot_id)?.iter() {
+        if job.doc_id == doc_id && job.status.is_unfinished() {
+            let job = update(&job.id, |j| {
+                j.status = JobStatus::Cancelled;
+                Ok(())
+            })?;
+            remove_upload(&job);
+        }
+    }
+    Ok(())
+}
@@ -1,4 +1,11 @@
+pub(crate) mod chunker;
+pub(crate) mod crawler;
 pub(crate) mod crud;
 pub(crate) mod doc;
 pub(crate) mod dto;
+pub(crate) mod ingest;
+pub(crate) mod parser;
 pub(crate) mod qa;
+pub(crate) mod qa_file;
+pub(crate) mod rag;
+pub(crate) mod search;
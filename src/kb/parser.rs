@@ -0,0 +1,834 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek};
+use std::sync::LazyLock;
+use std::vec::Vec;
+
+use quick_xml::Reader;
+use quick_xml::events::{BytesRef, BytesStart, Event};
+use regex::Regex;
+use scraper::{ElementRef, Html, Node, Selector};
+use zip::ZipArchive;
+
+use super::doc::{self, CHUNK_SEPARATOR, PAGE_SEPARATOR};
+use crate::result::{Error, Result};
+
+// Documents are inflated in memory, a crafted archive may declare anything
+const MAX_ZIP_ENTRY_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_COLUMNS: usize = 16384;
+
+#[derive(Clone, Copy)]
+enum DocFormat {
+    Docx,
+    Pdf,
+    Markdown,
+    Html,
+    Text,
+    Xlsx,
+    Pptx,
+}
+
+impl DocFormat {
+    fn from_mime(content_type: &str) -> Option<Self> {
+        // Ignores parameters like `; charset=utf-8`
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        match mime.to_ascii_lowercase().as_str() {
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => {
+                Some(Self::Docx)
+            }
+            "application/pdf" => Some(Self::Pdf),
+            "text/markdown" | "text/x-markdown" => Some(Self::Markdown),
+            "text/html" | "application/xhtml+xml" => Some(Self::Html),
+            "text/plain" => Some(Self::Text),
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => {
+                Some(Self::Xlsx)
+            }
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation" => {
+                Some(Self::Pptx)
+            }
+            _ => None,
+        }
+    }
+
+    fn from_extension(file_name: &str) -> Option<Self> {
+        let (_, ext) = file_name.rsplit_once('.')?;
+        match ext.to_ascii_lowercase().as_str() {
+            "docx" => Some(Self::Docx),
+            "pdf" => Some(Self::Pdf),
+            "md" | "markdown" => Some(Self::Markdown),
+            "html" | "htm" | "xhtml" => Some(Self::Html),
+            "txt" | "text" => Some(Self::Text),
+            "xlsx" => Some(Self::Xlsx),
+            "pptx" => Some(Self::Pptx),
+            _ => None,
+        }
+    }
+}
+
+// Browsers send `application/octet-stream` for many formats, so the extension is checked as well
+fn doc_format(file_name: &str, content_type: &str) -> Result<DocFormat> {
+    DocFormat::from_mime(content_type)
+        .or_else(|| DocFormat::from_extension(file_name))
+        .ok_or_else(|| Error::WithMessage(format!(
+            "Unsupported document format of `{file_name}` ({content_type}), supported formats are DOCX, PDF, Markdown, HTML, TXT, XLSX and PPTX"
+        )))
+}
+
+// Uploads are rejected early, parsing is done later by the ingestion job
+pub(super) fn check_format(file_name: &str, content_type: &str) -> Result<()> {
+    doc_format(file_name, content_type).map(|_| ())
+}
+
+pub(super) fn parse(file_name: &str, content_type: &str, b: Vec<u8>) -> Result<String> {
+    match doc_format(file_name, content_type)? {
+        DocFormat::Docx => doc::parse_docx(b),
+        DocFormat::Pdf => doc::parse_pdf(b),
+        DocFormat::Markdown => Ok(parse_markdown(&decode_text(&b))),
+        DocFormat::Html => Ok(parse_html(&decode_text(&b))),
+        DocFormat::Text => Ok(decode_text(&b)),
+        DocFormat::Xlsx => parse_xlsx(&b),
+        DocFormat::Pptx => parse_pptx(&b),
+    }
+}
+
+// Text files are either UTF-8 or GBK, GB18030 is a superset of GBK
+pub(crate) fn decode_text(b: &[u8]) -> String {
+    let b = b.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(b);
+    match std::str::from_utf8(b) {
+        Ok(s) => String::from(s),
+        Err(_) => {
+            let (s, had_errors) = encoding_rs::GB18030.decode_without_bom_handling(b);
+            if had_errors {
+                log::warn!("Text is neither UTF-8 nor GBK, some characters were replaced");
+            }
+            s.into_owned()
+        }
+    }
+}
+
+// Writes headings as Markdown headings, the chunker follows them to build heading paths
+struct SectionWriter {
+    out: String,
+}
+
+impl SectionWriter {
+    fn new() -> Self {
+        SectionWriter {
+            out: String::with_capacity(4096),
+        }
+    }
+
+    fn heading(&mut self, level: usize, title: &str) {
+        let title = title.trim();
+        if title.is_empty() {
+            return;
+        }
+        self.paragraph_end();
+        self.out.push_str(&"#".repeat(level.clamp(1, 6)));
+        self.out.push(' ');
+        self.out.push_str(title);
+        self.out.push_str("\n\n");
+    }
+
+    fn line(&mut self, s: &str) {
+        self.out.push_str(s);
+        self.out.push('\n');
+    }
+
+    fn paragraph_end(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn finish(self) -> String {
+        self.out
+    }
+}
+
+static MD_ATX_HEADING: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^ {0,3}(#{1,6})\s+(.*?)\s*#*\s*$").unwrap());
+static MD_SETEXT_UNDERLINE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^ {0,3}(=+|-+)\s*$").unwrap());
+static MD_TABLE_DELIMITER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*\|?\s*:?-+:?\s*(\|\s*:?-+:?\s*)*\|?\s*$").unwrap());
+static MD_IMAGE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"!\[([^\]]*)\]\([^)]*\)").unwrap());
+static MD_LINK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\[([^\]]+)\]\([^)]*\)").unwrap());
+static MD_INLINE_MARK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\*\*|__|~~|`|<[^>]+>").unwrap());
+static MD_QUOTE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\s*>\s?").unwrap());
+
+fn clean_markdown_line(line: &str) -> String {
+    let s = MD_QUOTE.replace(line, "");
+    let s = MD_IMAGE.replace_all(&s, "$1");
+    let s = MD_LINK.replace_all(&s, "$1");
+    MD_INLINE_MARK.replace_all(&s, "").into_owned()
+}
+
+pub(super) fn parse_markdown(s: &str) -> String {
+    let lines: Vec<&str> = s.lines().collect();
+    let mut w = SectionWriter::new();
+    let mut fence: Option<&str> = None;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+        let trimmed = line.trim_start();
+        if let Some(f) = fence {
+            if trimmed.starts_with(f) {
+                fence = None;
+            } else {
+                w.line(line);
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fence = Some(&trimmed[..3]);
+            continue;
+        }
+        if let Some(c) = MD_ATX_HEADING.captures(line) {
+            w.heading(c[1].len(), &clean_markdown_line(&c[2]));
+            continue;
+        }
+        if !trimmed.is_empty()
+            && let Some(next) = lines.get(i)
+            && let Some(c) = MD_SETEXT_UNDERLINE.captures(next)
+        {
+            let level = if c[1].starts_with('=') { 1 } else { 2 };
+            w.heading(level, &clean_markdown_line(line));
+            i += 1;
+            continue;
+        }
+        if MD_TABLE_DELIMITER.is_match(line) && line.contains('-') {
+            continue;
+        }
+        w.line(&clean_markdown_line(line));
+    }
+    w.finish()
+}
+
+const HTML_SKIPPED: &[&str] = &[
+    "script", "style", "nav", "header", "footer", "noscript", "aside", "form", "iframe", "svg",
+    "template", "button", "select",
+];
+
+const HTML_BLOCKS: &[&str] = &[
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "ul",
+    "ol",
+    "li",
+    "table",
+    "tr",
+    "blockquote",
+    "pre",
+    "dl",
+    "dt",
+    "dd",
+    "figure",
+    "figcaption",
+    "br",
+    "hr",
+];
+
+fn flush_html_line(w: &mut SectionWriter, line: &mut String) {
+    let s = line.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if !s.is_empty() {
+        w.line(&s);
+    }
+    line.clear();
+}
+
+// html5ever does not limit nesting, the walk keeps its own stack so deep documents can't
+// overflow the thread stack
+fn walk_html(el: ElementRef, w: &mut SectionWriter, line: &mut String) {
+    // Elements are pushed again with `true` to be closed once their children are written
+    let mut stack = Vec::with_capacity(64);
+    stack.extend(el.children().rev().map(|n| (n, false)));
+    while let Some((node, closing)) = stack.pop() {
+        match node.value() {
+            Node::Text(t) => line.push_str(t),
+            Node::Element(e) if closing => {
+                let name = e.name();
+                if HTML_BLOCKS.contains(&name) {
+                    flush_html_line(w, line);
+                    if name != "li" && name != "tr" {
+                        w.paragraph_end();
+                    }
+                } else if name == "td" || name == "th" {
+                    line.push_str(" | ");
+                }
+            }
+            Node::Element(e) => {
+                let name = e.name();
+                if HTML_SKIPPED.contains(&name) {
+                    continue;
+                }
+                let Some(child) = ElementRef::wrap(node) else {
+                    continue;
+                };
+                let heading = match name {
+                    "h1" => 1,
+                    "h2" => 2,
+                    "h3" => 3,
+                    "h4" => 4,
+                    "h5" => 5,
+                    "h6" => 6,
+                    _ => 0,
+                };
+                if heading > 0 {
+                    flush_html_line(w, line);
+                    let title = child.text().collect::<Vec<&str>>().join(" ");
+                    let title = title.split_whitespace().collect::<Vec<&str>>().join(" ");
+                    w.heading(heading, &title);
+                    continue;
+                }
+                if HTML_BLOCKS.contains(&name) {
+                    flush_html_line(w, line);
+                }
+                if name == "li" {
+                    line.push_str("- ");
+                }
+                stack.push((node, true));
+                stack.extend(node.children().rev().map(|n| (n, false)));
+            }
+            _ => {}
+        }
+    }
+}
+
+pub(super) fn parse_html(s: &str) -> String {
+    html_text(&Html::parse_document(s))
+}
+
+pub(super) fn html_text(html: &Html) -> String {
+    let main = Selector::parse("main, article").unwrap();
+    let body = Selector::parse("body").unwrap();
+    let root = html
+        .select(&main)
+        .next()
+        .or_else(|| html.select(&body).next())
+        .unwrap_or_else(|| html.root_element());
+    let mut w = SectionWriter::new();
+    let mut line = String::with_capacity(256);
+    walk_html(root, &mut w, &mut line);
+    flush_html_line(&mut w, &mut line);
+    w.finish()
+}
+
+// Sizes in zip headers can't be trusted, entries are read up to the cap whatever they declare
+pub(crate) fn read_zip_bytes<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<Option<Vec<u8>>> {
+    let f = match archive.by_name(name) {
+        Ok(f) => f,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let too_large = || {
+        Error::WithMessage(format!(
+            "{name} in the archive is larger than {MAX_ZIP_ENTRY_SIZE} bytes"
+        ))
+    };
+    if f.size() > MAX_ZIP_ENTRY_SIZE {
+        return Err(too_large());
+    }
+    let mut b = Vec::with_capacity(f.size() as usize);
+    f.take(MAX_ZIP_ENTRY_SIZE + 1).read_to_end(&mut b)?;
+    if b.len() as u64 > MAX_ZIP_ENTRY_SIZE {
+        return Err(too_large());
+    }
+    Ok(Some(b))
+}
+
+pub(crate) fn read_zip_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<Option<String>> {
+    match read_zip_bytes(archive, name)? {
+        Some(b) => String::from_utf8(b)
+            .map(Some)
+            .map_err(|e| Error::WithMessage(format!("{name} is not UTF-8 text: {e}"))),
+        None => Ok(None),
+    }
+}
+
+fn get_attr(e: &BytesStart, name: &[u8]) -> Option<String> {
+    e.attributes()
+        .flatten()
+        .find(|a| a.key.as_ref() == name)
+        .map(|a| String::from_utf8_lossy(&a.value).into_owned())
+}
+
+fn push_ref(s: &mut String, e: &BytesRef) -> Result<()> {
+    if let Ok(Some(c)) = e.resolve_char_ref() {
+        s.push(c);
+    } else if let Some(r) = quick_xml::escape::resolve_predefined_entity(&e.decode()?) {
+        s.push_str(r);
+    }
+    Ok(())
+}
+
+fn xml_err(reader: &Reader<&[u8]>, e: quick_xml::Error) -> Error {
+    Error::WithMessage(format!(
+        "Invalid XML at position {}: {e:?}",
+        reader.error_position()
+    ))
+}
+
+struct Relationship {
+    rel_type: String,
+    target: String,
+}
+
+fn parse_rels(xml: &str) -> Result<HashMap<String, Relationship>> {
+    let mut rels = HashMap::with_capacity(16);
+    let mut reader = Reader::from_str(xml);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e))
+                if e.local_name().as_ref() == b"Relationship" =>
+            {
+                if let (Some(id), Some(target)) = (get_attr(e, b"Id"), get_attr(e, b"Target")) {
+                    let rel_type = get_attr(e, b"Type").unwrap_or_default();
+                    rels.insert(id, Relationship { rel_type, target });
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(xml_err(&reader, e)),
+            _ => {}
+        }
+    }
+    Ok(rels)
+}
+
+// Relationship targets are relative to the folder of the part, or absolute to the package root
+fn resolve_target(base_dir: &str, target: &str) -> String {
+    if let Some(t) = target.strip_prefix('/') {
+        return String::from(t);
+    }
+    let mut parts: Vec<&str> = base_dir.split('/').filter(|p| !p.is_empty()).collect();
+    for p in target.split('/') {
+        match p {
+            ".." => {
+                parts.pop();
+            }
+            "." | "" => {}
+            p => parts.push(p),
+        }
+    }
+    parts.join("/")
+}
+
+fn rels_path(part: &str) -> String {
+    match part.rsplit_once('/') {
+        Some((dir, name)) => format!("{dir}/_rels/{name}.rels"),
+        None => format!("_rels/{part}.rels"),
+    }
+}
+
+fn part_dir(part: &str) -> &str {
+    part.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+// Text of DrawingML paragraphs, fields like slide numbers are skipped
+fn drawing_text(xml: &str) -> Result<String> {
+    let mut s = String::with_capacity(1024);
+    let mut reader = Reader::from_str(xml);
+    let mut in_text = false;
+    let mut field_depth = 0;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) => match e.local_name().as_ref() {
+                b"t" => in_text = true,
+                b"fld" => field_depth += 1,
+                _ => {}
+            },
+            Ok(Event::End(ref e)) => match e.local_name().as_ref() {
+                b"t" => in_text = false,
+                b"fld" => field_depth -= 1,
+                b"p" if !s.is_empty() && !s.ends_with('\n') => s.push('\n'),
+                _ => {}
+            },
+            Ok(Event::Empty(ref e)) if e.local_name().as_ref() == b"br" => s.push('\n'),
+            Ok(Event::Text(e)) if in_text && field_depth == 0 => s.push_str(&e.decode()?),
+            Ok(Event::GeneralRef(e)) if in_text && field_depth == 0 => push_ref(&mut s, &e)?,
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(xml_err(&reader, e)),
+            _ => {}
+        }
+    }
+    Ok(s)
+}
+
+pub(super) fn parse_pptx(b: &[u8]) -> Result<String> {
+    let mut archive = ZipArchive::new(Cursor::new(b))?;
+    let Some(presentation) = read_zip_entry(&mut archive, "ppt/presentation.xml")? else {
+        return Err(Error::WithMessage(String::from(
+            "ppt/presentation.xml is missing",
+        )));
+    };
+    let rels = match read_zip_entry(&mut archive, "ppt/_rels/presentation.xml.rels")? {
+        Some(xml) => parse_rels(&xml)?,
+        None => HashMap::new(),
+    };
+    // Slides are listed in presentation order
+    let mut slides: Vec<String> = Vec::with_capacity(32);
+    let mut reader = Reader::from_str(&presentation);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e))
+                if e.local_name().as_ref() == b"sldId" =>
+            {
+                if let Some(rel) = get_attr(e, b"r:id").and_then(|id| rels.get(&id)) {
+                    slides.push(resolve_target("ppt", &rel.target));
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(xml_err(&reader, e)),
+            _ => {}
+        }
+    }
+    let mut doc_text = String::with_capacity(b.len() / 4);
+    for (i, slide) in slides.iter().enumerate() {
+        if i > 0 {
+            doc_text.push(PAGE_SEPARATOR);
+        }
+        if let Some(xml) = read_zip_entry(&mut archive, slide)? {
+            doc_text.push_str(&drawing_text(&xml)?);
+        }
+        let slide_rels = match read_zip_entry(&mut archive, &rels_path(slide))? {
+            Some(xml) => parse_rels(&xml)?,
+            None => continue,
+        };
+        let notes = slide_rels
+            .values()
+            .find(|r| r.rel_type.ends_with("/notesSlide"))
+            .map(|r| resolve_target(part_dir(slide), &r.target));
+        if let Some(notes) = notes
+            && let Some(xml) = read_zip_entry(&mut archive, &notes)?
+        {
+            let text = drawing_text(&xml)?;
+            if !text.trim().is_empty() {
+                doc_text.push_str("Notes:\n");
+                doc_text.push_str(&text);
+            }
+        }
+    }
+    Ok(doc_text)
+}
+
+fn shared_strings(xml: &str) -> Result<Vec<String>> {
+    let mut strings: Vec<String> = Vec::with_capacity(256);
+    let mut reader = Reader::from_str(xml);
+    let mut in_text = false;
+    // Phonetic hints are not part of the value
+    let mut in_phonetic = false;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) => match e.local_name().as_ref() {
+                b"si" => strings.push(String::new()),
+                b"t" => in_text = true,
+                b"rPh" => in_phonetic = true,
+                _ => {}
+            },
+            Ok(Event::End(ref e)) => match e.local_name().as_ref() {
+                b"t" => in_text = false,
+                b"rPh" => in_phonetic = false,
+                _ => {}
+            },
+            Ok(Event::Empty(ref e)) if e.local_name().as_ref() == b"si" => {
+                strings.push(String::new())
+            }
+            Ok(Event::Text(e)) if in_text && !in_phonetic => {
+                if let Some(s) = strings.last_mut() {
+                    s.push_str(&e.decode()?);
+                }
+            }
+            Ok(Event::GeneralRef(e)) if in_text && !in_phonetic => {
+                if let Some(s) = strings.last_mut() {
+                    push_ref(s, &e)?;
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(xml_err(&reader, e)),
+            _ => {}
+        }
+    }
+    Ok(strings)
+}
+
+// "AB12" -> 27, None if it's beyond the last column of Excel
+fn column_index(cell_ref: &str) -> Option<usize> {
+    let n = cell_ref
+        .chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .try_fold(0usize, |n, c| {
+            n.checked_mul(26)?
+                .checked_add(c.to_ascii_uppercase() as usize - 'A' as usize + 1)
+        })?;
+    (1..=MAX_COLUMNS).contains(&n).then(|| n - 1)
+}
+
+pub(super) fn column_name(mut idx: usize) -> String {
+    let mut name = Vec::with_capacity(3);
+    loop {
+        name.push((b'A' + (idx % 26) as u8) as char);
+        if idx < 26 {
+            break;
+        }
+        idx = idx / 26 - 1;
+    }
+    name.iter().rev().collect()
+}
+
+// Cells of each row as (column index, value)
+pub(super) type SheetRows = Vec<(u32, Vec<(usize, String)>)>;
+
+fn sheet_rows(xml: &str, shared: &[String]) -> Result<SheetRows> {
+    let mut rows: SheetRows = Vec::with_capacity(128);
+    let mut reader = Reader::from_str(xml);
+    let mut cell_col = 0usize;
+    let mut cell_type = String::new();
+    let mut value = String::new();
+    let mut in_value = false;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) => match e.local_name().as_ref() {
+                b"row" => {
+                    let n = get_attr(e, b"r").and_then(|r| r.parse().ok());
+                    let n = n.unwrap_or_else(|| rows.last().map_or(1, |(n, _)| n + 1));
+                    rows.push((n, Vec::with_capacity(16)));
+                }
+                b"c" => {
+                    let next_col = rows
+                        .last()
+                        .and_then(|(_, cells)| cells.last())
+                        .map_or(0, |(c, _)| c + 1);
+                    cell_col = get_attr(e, b"r")
+                        .and_then(|r| column_index(&r))
+                        .unwrap_or(next_col);
+                    cell_type = get_attr(e, b"t").unwrap_or_default();
+                    value.clear();
+                }
+                b"v" | b"t" => in_value = true,
+                _ => {}
+            },
+            Ok(Event::End(ref e)) => match e.local_name().as_ref() {
+                b"v" | b"t" => in_value = false,
+                b"c" => {
+                    let v = match cell_type.as_str() {
+                        "s" => value
+                            .trim()
+                            .parse::<usize>()
+                            .ok()
+                            .and_then(|i| shared.get(i))
+                            .cloned()
+                            .unwrap_or_default(),
+                        "b" => String::from(if value.trim() == "1" { "TRUE" } else { "FALSE" }),
+                        _ => value.clone(),
+                    };
+                    if !v.trim().is_empty()
+                        && let Some((_, cells)) = rows.last_mut()
+                    {
+                        cells.push((cell_col, String::from(v.trim())));
+                    }
+                }
+                _ => {}
+            },
+            Ok(Event::Text(e)) if in_value => value.push_str(&e.decode()?),
+            Ok(Event::GeneralRef(e)) if in_value => push_ref(&mut value, &e)?,
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(xml_err(&reader, e)),
+            _ => {}
+        }
+    }
+    rows.retain(|(_, cells)| !cells.is_empty());
+    Ok(rows)
+}
+
+// Non-empty rows of every sheet, in the order of the workbook
+pub(super) fn xlsx_sheets(b: &[u8]) -> Result<Vec<(String, SheetRows)>> {
+    let mut archive = ZipArchive::new(Cursor::new(b))?;
+    let Some(workbook) = read_zip_entry(&mut archive, "xl/workbook.xml")? else {
+        return Err(Error::WithMessage(String::from("xl/workbook.xml is missing")));
+    };
+    let rels = match read_zip_entry(&mut archive, "xl/_rels/workbook.xml.rels")? {
+        Some(xml) => parse_rels(&xml)?,
+        None => HashMap::new(),
+    };
+    let shared = match read_zip_entry(&mut archive, "xl/sharedStrings.xml")? {
+        Some(xml) => shared_strings(&xml)?,
+        None => vec![],
+    };
+    let mut sheets: Vec<(String, String)> = Vec::with_capacity(8);
+    let mut reader = Reader::from_str(&workbook);
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e))
+                if e.local_name().as_ref() == b"sheet" =>
+            {
+                let name = get_attr(e, b"name").unwrap_or_default();
+                if let Some(rel) = get_attr(e, b"r:id").and_then(|id| rels.get(&id)) {
+                    sheets.push((name, resolve_target("xl", &rel.target)));
+                }
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(xml_err(&reader, e)),
+            _ => {}
+        }
+    }
+    let mut result: Vec<(String, SheetRows)> = Vec::with_capacity(sheets.len());
+    for (sheet_name, path) in sheets.into_iter() {
+        let Some(xml) = read_zip_entry(&mut archive, &path)? else {
+            continue;
+        };
+        result.push((sheet_name, sheet_rows(&xml, &shared)?));
+    }
+    Ok(result)
+}
+
+// The first non-empty row of each sheet is taken as the header, every other row becomes a chunk
+pub(super) fn parse_xlsx(b: &[u8]) -> Result<String> {
+    let mut doc_text = String::with_capacity(b.len());
+    for (sheet_name, rows) in xlsx_sheets(b)?.into_iter() {
+        let mut rows = rows.into_iter();
+        let Some((_, header)) = rows.next() else {
+            continue;
+        };
+        let header: HashMap<usize, String> = header.into_iter().collect();
+        for (row_num, cells) in rows {
+            if !doc_text.is_empty() {
+                doc_text.push(CHUNK_SEPARATOR);
+            }
+            doc_text.push_str(&format!("{sheet_name} #{row_num}\n"));
+            for (col, v) in cells.iter() {
+                match header.get(col) {
+                    Some(h) => doc_text.push_str(h),
+                    None => doc_text.push_str(&column_name(*col)),
+                }
+                doc_text.push_str(": ");
+                doc_text.push_str(v);
+                doc_text.push('\n');
+            }
+        }
+    }
+    Ok(doc_text)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+
+    use super::*;
+
+    fn zip_of(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut w = ZipWriter::new(Cursor::new(Vec::new()));
+        let options =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for (name, content) in entries.iter() {
+            w.start_file(*name, options).unwrap();
+            w.write_all(content.as_bytes()).unwrap();
+        }
+        w.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn markdown_headings() {
+        let s = parse_markdown(
+            "# Title #\nIntro with a [link](http://a) and **bold**\n\nSection\n---\n```\n# not a heading\n```\n| a | b |\n|---|---|\n",
+        );
+        assert_eq!(
+            s,
+            "# Title\n\nIntro with a link and bold\n\n## Section\n\n# not a heading\n| a | b |\n"
+        );
+    }
+
+    #[test]
+    fn html_skips_navigation_and_scripts() {
+        let s = parse_html(
+            "<html><body><nav>Home | About</nav><script>var a = 1;</script><h2>Refund  policy</h2><p>Within <b>7</b> days</p><ul><li>One</li><li>Two</li></ul><footer>Copyright</footer></body></html>",
+        );
+        assert_eq!(s, "## Refund policy\n\nWithin 7 days\n\n- One\n- Two\n\n");
+    }
+
+    #[test]
+    fn deeply_nested_html() {
+        let mut s = "<span>".repeat(200_000);
+        s.push_str("deep");
+        let text = std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(move || parse_html(&s))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(text, "deep\n");
+    }
+
+    #[test]
+    fn gbk_text() {
+        let (b, _, _) = encoding_rs::GBK.encode("退款政策");
+        assert!(std::str::from_utf8(&b).is_err());
+        assert_eq!(decode_text(&b), "退款政策");
+        assert_eq!(decode_text(b"\xEF\xBB\xBFplain"), "plain");
+    }
+
+    #[test]
+    fn xlsx_rows_use_header_names() {
+        let b = zip_of(&[
+            (
+                "xl/workbook.xml",
+                r#"<workbook xmlns:r="r"><sheets><sheet name="Prices" r:id="rId1"/></sheets></workbook>"#,
+            ),
+            (
+                "xl/_rels/workbook.xml.rels",
+                r#"<Relationships><Relationship Id="rId1" Type="t/worksheet" Target="worksheets/sheet1.xml"/></Relationships>"#,
+            ),
+            (
+                "xl/sharedStrings.xml",
+                "<sst><si><t>Item</t></si><si><t>Price</t></si><si><t>Tea</t></si></sst>",
+            ),
+            (
+                "xl/worksheets/sheet1.xml",
+                r#"<worksheet><sheetData><row r="1"><c r="A1" t="s"><v>0</v></c><c r="B1" t="s"><v>1</v></c></row><row r="3"><c r="A3" t="s"><v>2</v></c><c r="B3"><v>12.5</v></c><c r="C3" t="b"><v>1</v></c></row></sheetData></worksheet>"#,
+            ),
+        ]);
+        assert_eq!(
+            parse_xlsx(&b).unwrap(),
+            "Prices #3\nItem: Tea\nPrice: 12.5\nC: TRUE\n"
+        );
+    }
+
+    #[test]
+    fn pptx_slides_with_notes() {
+        let slide = |text: &str| format!("<p:sld><a:p><a:r><a:t>{text}</a:t></a:r></a:p></p:sld>");
+        let b = zip_of(&[
+            (
+                "ppt/presentation.xml",
+                r#"<p:presentation xmlns:r="r"><p:sldIdLst><p:sldId r:id="rId2"/><p:sldId r:id="rId1"/></p:sldIdLst></p:presentation>"#,
+            ),
+            (
+                "ppt/_rels/presentation.xml.rels",
+                r#"<Relationships><Relationship Id="rId1" Type="t/slide" Target="slides/slide1.xml"/><Relationship Id="rId2" Type="t/slide" Target="slides/slide2.xml"/></Relationships>"#,
+            ),
+            ("ppt/slides/slide1.xml", &slide("Second")),
+            ("ppt/slides/slide2.xml", &slide("First")),
+            (
+                "ppt/slides/_rels/slide2.xml.rels",
+                r#"<Relationships><Relationship Id="rId1" Type="t/notesSlide" Target="../notesSlides/notesSlide1.xml"/></Relationships>"#,
+            ),
+            ("ppt/notesSlides/notesSlide1.xml", &slide("Say hello")),
+        ]);
+        assert_eq!(
+            parse_pptx(&b).unwrap(),
+            format!("First\nNotes:\nSay hello\n{PAGE_SEPARATOR}Second\n")
+        );
+    }
+}
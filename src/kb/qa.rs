@@ -1,11 +1,17 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::OnceLock;
 use std::vec::Vec;
 
 // use futures_util::StreamExt;
 // use sqlx::{Row, Sqlite};
 
-use super::dto::{QuestionAnswerPair, QuestionData};
+use super::dto::{
+    QnACandidate, QnAImportError, QnAImportReport, QnARecord, QuestionAnswerPair, QuestionData,
+};
+use super::qa_file::QnARow;
+use super::search;
 use crate::ai::embedding;
+use crate::db::ann;
 use crate::result::{Error, Result};
 
 // type SqliteConnPool = sqlx::Pool<Sqlite>;
@@ -13,8 +19,16 @@ use crate::result::{Error, Result};
 // // static DATA_SOURCE: OnceCell<SqliteConnPool> = OnceCell::new();
 // static DATA_SOURCE: OnceLock<SqliteConnPool> = OnceLock::new();
 static DATA_SOURCE: OnceLock<turso::Database> = OnceLock::new();
+static ANN: ann::Store = ann::Store {
+    name: "qa",
+    table_suffix: "_vec",
+    column: "qa_vec",
+};
 // static DATA_SOURCES: OnceLock<Mutex<HashMap<String, SqliteConnPool>>> = OnceLock::new();
 
+// Questions embedded in one request when importing
+const EMBEDDING_BATCH: usize = 32;
+
 pub(crate) async fn init_datasource() -> Result<()> {
     let p = std::path::Path::new(".").join("data");
     if !p.exists() {
@@ -70,29 +84,68 @@ pub(crate) async fn init_tables(robot_id: &str) -> Result<()> {
     Ok(())
 }
 
+pub(crate) async fn remove_tables(robot_id: &str) -> Result<()> {
+    let conn = DATA_SOURCE.get().unwrap().connect()?;
+    for suffix in ["", "_vec", "_terms", "_term_stats"] {
+        let sql = format!("DROP TABLE IF EXISTS {robot_id}{suffix}");
+        conn.execute(&sql, ()).await?;
+    }
+    ann::remove_index(&ANN, robot_id)
+}
+
 pub(crate) async fn list(robot_id: &str) -> Result<Vec<QuestionAnswerPair>> {
     let conn = DATA_SOURCE.get().unwrap().connect()?;
     let sql = format!("SELECT qa_data FROM {robot_id} ORDER BY created_at DESC",);
     let mut rows = conn.query(&sql, ()).await?;
     let mut d: Vec<QuestionAnswerPair> = Vec::with_capacity(10);
     while let Some(row) = rows.next().await? {
-        d.push(serde_json::from_str(dbg!(
-            row.get_value(0)?.as_text().unwrap()
-        ))?);
+        d.push(serde_json::from_str(row.get_value(0)?.as_text().unwrap())?);
     }
     Ok(d)
 }
 
 pub(crate) async fn save(robot_id: &str, mut d: QuestionAnswerPair) -> Result<i64> {
+    let texts: Vec<&str> = questions_of(&d).collect();
+    let vectors = embed(robot_id, &texts).await?;
     let mut conn = DATA_SOURCE.get().unwrap().connect()?;
     let tx = conn.transaction().await?;
+    ensure_terms_index(&tx, robot_id).await?;
+    let mut indexed: Vec<(i64, Vec<f32>)> = Vec::with_capacity(vectors.len());
+    let record_id = write(&tx, robot_id, &mut d, vectors, &mut indexed).await?;
+    tx.commit().await?;
+    ann::upsert(&ANN, DATA_SOURCE.get().unwrap(), robot_id, indexed);
+    Ok(record_id)
+}
+
+fn questions_of(d: &QuestionAnswerPair) -> impl Iterator<Item = &str> {
+    std::iter::once(d.question.question.as_str())
+        .chain(d.similar_questions.iter().map(|q| q.question.as_str()))
+}
+
+async fn embed(robot_id: &str, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+    let vectors = embedding::embedding_batch(robot_id, texts).await?;
+    if let Some(idx) = vectors.iter().position(|v| v.is_empty()) {
+        let err = format!("{} embedding data is empty", texts[idx]);
+        log::warn!("{}", &err);
+        return Err(Error::WithMessage(err));
+    }
+    Ok(vectors)
+}
+
+// Vectors are of the question and then the similar questions, saved ones are added to `indexed`
+async fn write(
+    tx: &turso::Connection,
+    robot_id: &str,
+    d: &mut QuestionAnswerPair,
+    vectors: Vec<Vec<f32>>,
+    indexed: &mut Vec<(i64, Vec<f32>)>,
+) -> Result<i64> {
     let record_id: i64;
     if d.id.is_none() {
         let sql = format!("INSERT INTO {robot_id}(qa_data, created_at)VALUES(?, unixepoch())");
         let mut stmt = tx.prepare(&sql).await?;
         stmt.execute((serde_json::to_string(&d)?,)).await?;
-        // record_id = conn.last_insert_rowid();
-        record_id = time::UtcDateTime::now().unix_timestamp() - 1760025600000;
+        record_id = tx.last_insert_rowid();
         d.id = Some(record_id);
     } else {
         let sql = format!("UPDATE {robot_id} SET qa_data = ? WHERE id = ?");
@@ -112,15 +165,8 @@ pub(crate) async fn save(robot_id: &str, mut d: QuestionAnswerPair) -> Result<i6
 
     let mut insert_stmt = Option::None::<turso::Statement>;
     let mut update_stmt = Option::None::<turso::Statement>;
-    for q in questions.iter_mut() {
-        let vectors = embedding::embedding(robot_id, &q.question).await?;
-        if vectors.0.is_empty() {
-            let err = format!("{} embedding data is empty", &q.question);
-            log::warn!("{}", &err);
-            return Err(Error::WithMessage(err));
-        }
-
-        log::info!("vectors.0.len() = {}", vectors.0.len());
+    for (q, vector) in questions.iter_mut().zip(vectors) {
+        log::info!("vectors.0.len() = {}", vector.len());
         if q.vec_row_id.is_none() {
             if !created_table {
                 let sql = format!(
@@ -130,7 +176,7 @@ pub(crate) async fn save(robot_id: &str, mut d: QuestionAnswerPair) -> Result<i6
                         qa_vec F32_BLOB({}) NOT NULL
                     );
                     ",
-                    vectors.0.len(),
+                    vector.len(),
                 );
                 tx.execute(&sql, ()).await?;
                 created_table = true;
@@ -142,16 +188,16 @@ pub(crate) async fn save(robot_id: &str, mut d: QuestionAnswerPair) -> Result<i6
                 );
                 insert_stmt = Some(tx.prepare(&sql).await?);
             }
-            let id = insert_stmt
+            insert_stmt
                 .as_mut()
                 .unwrap()
-                .execute((record_id, embedding::vec_to_db(&vectors.0)))
+                .execute((record_id, embedding::vec_to_db(&vector)))
                 .await?;
-            q.vec_row_id = Some(id);
+            q.vec_row_id = Some(tx.last_insert_rowid() as u64);
         } else {
             if update_stmt.is_none() {
                 let sql = format!(
-                    "UPDATE {robot_id}_vec SET qa_vec = vector32(?1) WHERE qa_id = ?2",
+                    "UPDATE {robot_id}_vec SET qa_vec = vector32(?1) WHERE id = ?2",
                     //  ON CONFLICT(rowid) DO UPDATE SET qa_vec = excluded.qa_vec;
                 );
                 update_stmt = Some(tx.prepare(&sql).await?);
@@ -159,54 +205,361 @@ pub(crate) async fn save(robot_id: &str, mut d: QuestionAnswerPair) -> Result<i6
             update_stmt
                 .as_mut()
                 .unwrap()
-                .execute((embedding::vec_to_db(&vectors.0), q.vec_row_id.unwrap()))
+                .execute((embedding::vec_to_db(&vector), q.vec_row_id.unwrap()))
                 .await?;
         }
+        indexed.push((q.vec_row_id.unwrap() as i64, vector));
+    }
+    // Vector row ids were just assigned
+    let sql = format!("UPDATE {robot_id} SET qa_data = ?1 WHERE id = ?2");
+    tx.execute(&sql, (serde_json::to_string(&d)?, record_id))
+        .await?;
+    search::remove_terms(tx, robot_id, record_id).await?;
+    search::index_terms(tx, robot_id, record_id, 0, &d.question.question).await?;
+    for (idx, q) in d.similar_questions.iter().enumerate() {
+        search::index_terms(tx, robot_id, record_id, idx as i64 + 1, &q.question).await?;
     }
-    tx.commit().await?;
     Ok(record_id)
 }
 
+// Case and spaces are ignored when looking for duplicated questions
+fn normalize_question(s: &str) -> String {
+    s.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+// Rows are validated and deduplicated one by one, valid rows are saved all together
+pub(crate) async fn import(robot_id: &str, rows: Vec<QnARow>) -> Result<QnAImportReport> {
+    let mut report = QnAImportReport {
+        total: rows.len(),
+        ..Default::default()
+    };
+    // None for questions already in the knowledge base, otherwise the row it's imported from
+    let mut known: HashMap<String, Option<usize>> = HashMap::with_capacity(rows.len() * 2);
+    for d in list(robot_id).await?.iter() {
+        for q in questions_of(d) {
+            known.insert(normalize_question(q), None);
+        }
+    }
+    let mut pairs: Vec<(usize, QuestionAnswerPair)> = Vec::with_capacity(rows.len());
+    for (row, r) in rows.into_iter() {
+        let r = r.and_then(|r| {
+            if r.question.trim().is_empty() {
+                Err(String::from("Question is empty"))
+            } else if r.answer.trim().is_empty() {
+                Err(String::from("Answer is empty"))
+            } else {
+                Ok(r)
+            }
+        });
+        let r = r.and_then(|r| match known.get(&normalize_question(&r.question)) {
+            Some(None) => Err(format!("Question `{}` already exists", r.question.trim())),
+            Some(Some(n)) => Err(format!("Question `{}` duplicates row {n}", r.question.trim())),
+            None => Ok(r),
+        });
+        let r = match r {
+            Ok(r) => r,
+            Err(message) => {
+                report.skipped += 1;
+                report.errors.push(QnAImportError { row, message });
+                continue;
+            }
+        };
+        known.insert(normalize_question(&r.question), Some(row));
+        let mut similar_questions: Vec<QuestionData> =
+            Vec::with_capacity(r.similar_questions.len());
+        for q in r.similar_questions.iter() {
+            let n = normalize_question(q);
+            if n.is_empty() {
+                continue;
+            }
+            // The row is still imported, without the duplicated similar question
+            let message = match known.get(&n) {
+                Some(None) => format!("Similar question `{}` already exists", q.trim()),
+                Some(Some(other)) if *other == row => {
+                    format!("Similar question `{}` is repeated", q.trim())
+                }
+                Some(Some(other)) => {
+                    format!("Similar question `{}` duplicates row {other}", q.trim())
+                }
+                None => {
+                    known.insert(n, Some(row));
+                    similar_questions.push(QuestionData {
+                        question: String::from(q.trim()),
+                        vec_row_id: None,
+                    });
+                    continue;
+                }
+            };
+            report.warnings.push(QnAImportError { row, message });
+        }
+        pairs.push((
+            row,
+            QuestionAnswerPair {
+                id: None,
+                question: QuestionData {
+                    question: String::from(r.question.trim()),
+                    vec_row_id: None,
+                },
+                similar_questions,
+                answer: String::from(r.answer.trim()),
+            },
+        ));
+    }
+    // All rows are embedded in batches first, then saved in one transaction
+    let mut embedded: Vec<(usize, QuestionAnswerPair, Vec<Vec<f32>>)> =
+        Vec::with_capacity(pairs.len());
+    let mut batch: Vec<(usize, QuestionAnswerPair)> = Vec::with_capacity(EMBEDDING_BATCH);
+    let mut batch_len = 0usize;
+    let mut pairs = pairs.into_iter().peekable();
+    while let Some((row, d)) = pairs.next() {
+        batch_len += 1 + d.similar_questions.len();
+        batch.push((row, d));
+        if batch_len < EMBEDDING_BATCH && pairs.peek().is_some() {
+            continue;
+        }
+        batch_len = 0;
+        let rows = embed_rows(robot_id, std::mem::take(&mut batch), &mut report).await;
+        embedded.extend(rows);
+    }
+    if embedded.is_empty() {
+        report.errors.sort_by_key(|e| e.row);
+        return Ok(report);
+    }
+    let rows: Vec<usize> = embedded.iter().map(|(row, _, _)| *row).collect();
+    match write_rows(robot_id, embedded).await {
+        Ok(_) => report.imported += rows.len(),
+        Err(e) => {
+            log::warn!("Saving imported rows failed {e:?}");
+            report.skipped += rows.len();
+            for row in rows.into_iter() {
+                let message = format!("{e:?}");
+                report.errors.push(QnAImportError { row, message });
+            }
+        }
+    }
+    report.errors.sort_by_key(|e| e.row);
+    Ok(report)
+}
+
+// If the batch can't be embedded, rows are embedded one by one so only the failed ones are skipped
+async fn embed_rows(
+    robot_id: &str,
+    batch: Vec<(usize, QuestionAnswerPair)>,
+    report: &mut QnAImportReport,
+) -> Vec<(usize, QuestionAnswerPair, Vec<Vec<f32>>)> {
+    let texts: Vec<&str> = batch.iter().flat_map(|(_, d)| questions_of(d)).collect();
+    if let Ok(vectors) = embed(robot_id, &texts).await {
+        let mut vectors = vectors.into_iter();
+        return batch
+            .into_iter()
+            .map(|(row, d)| {
+                let n = 1 + d.similar_questions.len();
+                let pair_vectors = vectors.by_ref().take(n).collect();
+                (row, d, pair_vectors)
+            })
+            .collect();
+    }
+    let mut embedded = Vec::with_capacity(batch.len());
+    for (row, d) in batch.into_iter() {
+        let texts: Vec<&str> = questions_of(&d).collect();
+        match embed(robot_id, &texts).await {
+            Ok(vectors) => embedded.push((row, d, vectors)),
+            Err(e) => {
+                report.skipped += 1;
+                let message = format!("{e:?}");
+                report.errors.push(QnAImportError { row, message });
+            }
+        }
+    }
+    embedded
+}
+
+async fn write_rows(
+    robot_id: &str,
+    embedded: Vec<(usize, QuestionAnswerPair, Vec<Vec<f32>>)>,
+) -> Result<()> {
+    let mut conn = DATA_SOURCE.get().unwrap().connect()?;
+    let tx = conn.transaction().await?;
+    ensure_terms_index(&tx, robot_id).await?;
+    let mut indexed: Vec<(i64, Vec<f32>)> = Vec::with_capacity(embedded.len() * 2);
+    for (_, mut d, vectors) in embedded.into_iter() {
+        write(&tx, robot_id, &mut d, vectors, &mut indexed).await?;
+    }
+    tx.commit().await?;
+    ann::upsert(&ANN, DATA_SOURCE.get().unwrap(), robot_id, indexed);
+    Ok(())
+}
+
+pub(crate) async fn export(robot_id: &str) -> Result<Vec<QnARecord>> {
+    let records = list(robot_id)
+        .await?
+        .into_iter()
+        .map(|d| QnARecord {
+            question: d.question.question,
+            similar_questions: d.similar_questions.into_iter().map(|q| q.question).collect(),
+            answer: d.answer,
+        })
+        .collect();
+    Ok(records)
+}
+
 pub(crate) async fn delete(robot_id: &str, d: QuestionAnswerPair) -> Result<()> {
     let mut conn = DATA_SOURCE.get().unwrap().connect()?;
     let tx = conn.transaction().await?;
     let id = d.id.unwrap();
+    let sql = format!("SELECT id FROM {robot_id}_vec WHERE qa_id = ?1");
+    let mut rows = tx.query(&sql, [id]).await?;
+    let mut vec_row_ids: Vec<i64> = Vec::with_capacity(8);
+    while let Some(row) = rows.next().await? {
+        vec_row_ids.push(*row.get_value(0)?.as_integer().unwrap());
+    }
     let sql = format!("DELETE FROM {robot_id}_vec WHERE qa_id = ?1");
     let mut stmt = tx.prepare(&sql).await?;
     let id = turso::Value::Integer(id as i64);
     stmt.execute([id.clone()]).await?;
+    search::remove_terms(&tx, robot_id, d.id.unwrap()).await?;
     let sql = format!("DELETE FROM {robot_id} WHERE id = ?1");
     let mut stmt = tx.prepare(&sql).await?;
     stmt.execute([id]).await?;
     tx.commit().await?;
+    ann::remove(&ANN, DATA_SOURCE.get().unwrap(), robot_id, &vec_row_ids);
     Ok(())
 }
 
-pub(crate) async fn retrieve_answer(
+// Questions saved before keyword search was added are indexed when the terms table is created
+async fn ensure_terms_index(conn: &turso::Connection, robot_id: &str) -> Result<()> {
+    if !search::ensure_terms_table(conn, robot_id).await? {
+        return Ok(());
+    }
+    let sql = format!("SELECT id, qa_data FROM {robot_id}");
+    let mut rows = conn.query(&sql, ()).await?;
+    let mut pairs: Vec<(i64, QuestionAnswerPair)> = Vec::with_capacity(128);
+    while let Some(row) = rows.next().await? {
+        pairs.push((
+            *row.get_value(0)?.as_integer().unwrap(),
+            serde_json::from_str(row.get_value(1)?.as_text().unwrap())?,
+        ));
+    }
+    for (id, d) in pairs.iter() {
+        search::index_terms(conn, robot_id, *id, 0, &d.question.question).await?;
+        for (idx, q) in d.similar_questions.iter().enumerate() {
+            search::index_terms(conn, robot_id, *id, idx as i64 + 1, &q.question).await?;
+        }
+    }
+    Ok(())
+}
+
+// Hybrid search over questions and similar questions, a pair ranks by its best matched question
+pub(crate) async fn retrieve_answers(
     robot_id: &str,
     question: &str,
-) -> Result<(Option<QuestionAnswerPair>, f64)> {
+    recall_distance: f64,
+    top_k: usize,
+) -> Result<Vec<QnACandidate>> {
     let vectors = embedding::embedding(robot_id, question).await?;
     if vectors.0.is_empty() {
         let err = format!("{question} embedding data is empty");
         log::warn!("{}", &err);
         return Err(Error::WithMessage(err));
     }
-    let conn = DATA_SOURCE.get().unwrap().connect()?;
-
+    let db = DATA_SOURCE.get().unwrap();
+    let conn = db.connect()?;
+    if !search::table_exists(&conn, &format!("{robot_id}_vec")).await? {
+        return Ok(vec![]);
+    }
+    let query_vec = embedding::vec_to_db(&vectors.0);
+    // Similar questions of a pair take several of the nearest rows
+    let nearest = ann::search(&ANN, db, robot_id, &vectors.0, search::CANDIDATES * 3);
+    let filter = nearest.as_ref().map_or(String::new(), |ids| {
+        format!("WHERE id IN ({})", search::placeholders(ids.len(), 4))
+    });
     let sql = format!(
-        "
-        SELECT qa_data, v.distance FROM {robot_id} q INNER JOIN
-        (SELECT qa_id, vector_distance_cos(phrase_vec, vector32(?1)) AS distance FROM {robot_id}_vec ORDER BY distance ASC LIMIT 1) v
-        ON q.id = v.qa_id
-        "
+        "SELECT qa_id, MIN(vector_distance_cos(qa_vec, vector32(?1))) AS distance FROM {robot_id}_vec {filter} GROUP BY qa_id HAVING distance <= ?2 ORDER BY distance ASC LIMIT ?3"
     );
-    let mut results = conn.query(&sql, [embedding::vec_to_db(&vectors.0)]).await?;
-    if let Some(row) = results.next().await? {
-        return Ok((
-            Some(serde_json::from_str(row.get_value(0)?.as_text().unwrap())?),
-            row.get_value(1)?.as_real().unwrap().clone(),
-        ));
+    let mut params: Vec<turso::Value> = vec![
+        query_vec.clone(),
+        turso::Value::Real(recall_distance),
+        turso::Value::Integer(search::CANDIDATES as i64),
+    ];
+    params.extend(
+        nearest
+            .iter()
+            .flatten()
+            .map(|id| turso::Value::Integer(*id)),
+    );
+    let mut rows = conn.query(&sql, params).await?;
+    let mut vector_ranked: Vec<i64> = Vec::with_capacity(search::CANDIDATES);
+    while let Some(row) = rows.next().await? {
+        vector_ranked.push(*row.get_value(0)?.as_integer().unwrap());
+    }
+    // Hits are sorted by score, so the first hit of a pair is its best question
+    let mut keyword_hits: Vec<search::KeywordHit> =
+        search::keyword_search(&conn, robot_id, question, search::CANDIDATES).await?;
+    let mut seen: HashSet<i64> = HashSet::with_capacity(keyword_hits.len());
+    keyword_hits.retain(|h| seen.insert(h.owner_id));
+    let keyword_ranked: Vec<i64> = keyword_hits.iter().map(|h| h.owner_id).collect();
+    let mut fused = search::fuse(&[vector_ranked, keyword_ranked]);
+    let reranker = search::reranker(robot_id)?;
+    fused.truncate(search::fusion_limit(reranker.as_ref(), top_k));
+    if fused.is_empty() {
+        return Ok(vec![]);
+    }
+    let sql = format!(
+        "SELECT q.id, q.qa_data, v.distance FROM {robot_id} q INNER JOIN
+        (SELECT qa_id, MIN(vector_distance_cos(qa_vec, vector32(?1))) AS distance FROM {robot_id}_vec WHERE qa_id IN ({ids}) GROUP BY qa_id) v
+        ON q.id = v.qa_id WHERE q.id IN ({ids})",
+        ids = search::placeholders(fused.len(), 2)
+    );
+    let mut params: Vec<turso::Value> = Vec::with_capacity(fused.len() + 1);
+    params.push(query_vec);
+    params.extend(fused.iter().map(|(id, _)| turso::Value::Integer(*id)));
+    let mut rows = conn.query(&sql, params).await?;
+    let mut candidates: Vec<QnACandidate> = Vec::with_capacity(fused.len());
+    while let Some(row) = rows.next().await? {
+        let id = *row.get_value(0)?.as_integer().unwrap();
+        candidates.push(QnACandidate {
+            qa: serde_json::from_str(row.get_value(1)?.as_text().unwrap())?,
+            distance: *row.get_value(2)?.as_real().unwrap_or(&1.),
+            keyword_score: keyword_hits
+                .iter()
+                .find(|h| h.owner_id == id)
+                .map(|h| h.score),
+            score: fused
+                .iter()
+                .find(|(fused_id, _)| *fused_id == id)
+                .map_or(0., |(_, s)| *s),
+            rerank_score: None,
+        });
+    }
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+    search::rerank(robot_id, reranker.as_ref(), question, &mut candidates, top_k).await;
+    Ok(candidates)
+}
+
+impl search::Rerankable for QnACandidate {
+    fn rerank_text(&self) -> String {
+        format!("{}\n{}", &self.qa.question.question, &self.qa.answer)
+    }
+
+    fn set_rerank_score(&mut self, score: f32) {
+        self.rerank_score = Some(score);
+    }
+
+    fn rerank_score(&self) -> f32 {
+        self.rerank_score.unwrap_or_default()
+    }
+}
+
+pub(crate) async fn retrieve_answer(
+    robot_id: &str,
+    question: &str,
+) -> Result<(Option<QuestionAnswerPair>, f64)> {
+    let mut candidates = retrieve_answers(robot_id, question, 1.0, 1).await?;
+    match candidates.pop() {
+        Some(c) => Ok((Some(c.qa), c.distance)),
+        None => Ok((None, 1.0)),
     }
-    Ok((None, 1.0))
 }
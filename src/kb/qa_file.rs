@@ -0,0 +1,355 @@
+use std::io::{Cursor, Write};
+use std::vec::Vec;
+
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+use super::dto::QnARecord;
+use super::parser;
+use crate::result::{Error, Result};
+
+// Row number in the file and the record, or why the row can't be read
+pub(super) type QnARow = (usize, std::result::Result<QnARecord, String>);
+
+#[derive(Clone, Copy)]
+pub(crate) enum QnAFileFormat {
+    Csv,
+    Xlsx,
+    Json,
+}
+
+impl QnAFileFormat {
+    fn from_mime(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        match mime.to_ascii_lowercase().as_str() {
+            "text/csv" => Some(Self::Csv),
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => {
+                Some(Self::Xlsx)
+            }
+            "application/json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "csv" => Some(Self::Csv),
+            "xlsx" => Some(Self::Xlsx),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    fn from_extension(file_name: &str) -> Option<Self> {
+        let (_, ext) = file_name.rsplit_once('.')?;
+        Self::from_name(ext)
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Xlsx => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            Self::Json => "application/json",
+        }
+    }
+
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Xlsx => "xlsx",
+            Self::Json => "json",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Column {
+    Question,
+    SimilarQuestions,
+    Answer,
+}
+
+const HEADER: [&str; 3] = ["question", "similarQuestions", "answer"];
+
+fn column_of(name: &str) -> Option<Column> {
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '_' && *c != '-')
+        .flat_map(|c| c.to_lowercase())
+        .collect();
+    match name.as_str() {
+        "question" | "问题" => Some(Column::Question),
+        "similarquestions" | "similarquestion" | "相似问题" | "相似问法" => {
+            Some(Column::SimilarQuestions)
+        }
+        "answer" | "答案" | "回答" => Some(Column::Answer),
+        _ => None,
+    }
+}
+
+fn header_columns<'a>(
+    cells: impl Iterator<Item = (usize, &'a str)>,
+) -> Result<Vec<(usize, Column)>> {
+    let columns: Vec<(usize, Column)> = cells
+        .filter_map(|(idx, name)| column_of(name).map(|c| (idx, c)))
+        .collect();
+    let has = |col: Column| columns.iter().any(|(_, c)| *c == col);
+    if !has(Column::Question) || !has(Column::Answer) {
+        return Err(Error::WithMessage(String::from(
+            "The first row must be a header with `question` and `answer` columns, `similarQuestions` is optional.",
+        )));
+    }
+    Ok(columns)
+}
+
+// Similar questions are put in one cell, one question per line
+fn to_record<'a>(
+    columns: &[(usize, Column)],
+    cells: impl Iterator<Item = (usize, &'a str)>,
+) -> QnARecord {
+    let mut r = QnARecord {
+        question: String::new(),
+        similar_questions: vec![],
+        answer: String::new(),
+    };
+    for (idx, v) in cells {
+        match columns.iter().find(|(i, _)| *i == idx).map(|(_, c)| *c) {
+            Some(Column::Question) => r.question = String::from(v.trim()),
+            Some(Column::SimilarQuestions) => r.similar_questions.extend(
+                v.lines()
+                    .map(|l| l.trim())
+                    .filter(|l| !l.is_empty())
+                    .map(String::from),
+            ),
+            Some(Column::Answer) => r.answer = String::from(v.trim()),
+            None => {}
+        }
+    }
+    r
+}
+
+pub(super) fn read(file_name: &str, content_type: &str, b: &[u8]) -> Result<Vec<QnARow>> {
+    let format =
+        QnAFileFormat::from_mime(content_type).or_else(|| QnAFileFormat::from_extension(file_name));
+    match format {
+        Some(QnAFileFormat::Csv) => read_csv(&parser::decode_text(b)),
+        Some(QnAFileFormat::Xlsx) => read_xlsx(b),
+        Some(QnAFileFormat::Json) => read_json(b),
+        None => Err(Error::WithMessage(format!(
+            "Unsupported file format of `{file_name}` ({content_type}), supported formats are CSV, XLSX and JSON"
+        ))),
+    }
+}
+
+pub(super) fn write(format: QnAFileFormat, records: &[QnARecord]) -> Result<Vec<u8>> {
+    match format {
+        QnAFileFormat::Csv => Ok(write_csv(records).into_bytes()),
+        QnAFileFormat::Xlsx => write_xlsx(records),
+        QnAFileFormat::Json => Ok(serde_json::to_vec_pretty(records)?),
+    }
+}
+
+// Excel saves CSV with semicolons or tabs in some locales
+pub(crate) fn detect_delimiter(s: &str) -> char {
+    let first_line = s.lines().next().unwrap_or("");
+    [',', ';', '\t']
+        .into_iter()
+        .max_by_key(|d| first_line.matches(*d).count())
+        .unwrap_or(',')
+}
+
+// RFC 4180, quoted fields may contain delimiters, quotes and line breaks
+pub(crate) fn parse_csv(s: &str, delimiter: char) -> Result<Vec<Vec<String>>> {
+    let mut records: Vec<Vec<String>> = Vec::with_capacity(256);
+    let mut record: Vec<String> = Vec::with_capacity(3);
+    let mut field = String::with_capacity(256);
+    let mut in_quotes = false;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                field.push(c);
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            '\r' => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c if c == delimiter => record.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(Error::WithMessage(String::from(
+            "Invalid CSV, a quoted field is not closed.",
+        )));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    for f in records.iter_mut().flatten() {
+        if let Some(v) = f.strip_prefix('\'')
+            && v.starts_with(FORMULA_PREFIXES)
+        {
+            f.remove(0);
+        }
+    }
+    Ok(records)
+}
+
+fn read_csv(s: &str) -> Result<Vec<QnARow>> {
+    let mut records = parse_csv(s, detect_delimiter(s))?.into_iter().enumerate();
+    let Some((_, header)) = records.next() else {
+        return Ok(vec![]);
+    };
+    let columns = header_columns(header.iter().map(|h| h.as_str()).enumerate())?;
+    let rows = records
+        .filter(|(_, fields)| fields.iter().any(|f| !f.trim().is_empty()))
+        .map(|(idx, fields)| {
+            let cells = fields.iter().map(|f| f.as_str()).enumerate();
+            (idx + 1, Ok(to_record(&columns, cells)))
+        })
+        .collect();
+    Ok(rows)
+}
+
+// Only the first sheet is imported
+fn read_xlsx(b: &[u8]) -> Result<Vec<QnARow>> {
+    let Some((_, rows)) = parser::xlsx_sheets(b)?.into_iter().next() else {
+        return Ok(vec![]);
+    };
+    let mut rows = rows.into_iter();
+    let Some((_, header)) = rows.next() else {
+        return Ok(vec![]);
+    };
+    let columns = header_columns(header.iter().map(|(idx, h)| (*idx, h.as_str())))?;
+    let rows = rows
+        .map(|(row_num, cells)| {
+            let cells = cells.iter().map(|(idx, v)| (*idx, v.as_str()));
+            (row_num as usize, Ok(to_record(&columns, cells)))
+        })
+        .collect();
+    Ok(rows)
+}
+
+fn read_json(b: &[u8]) -> Result<Vec<QnARow>> {
+    let items: Vec<serde_json::Value> = serde_json::from_slice(b)?;
+    let rows = items
+        .into_iter()
+        .enumerate()
+        .map(|(idx, item)| {
+            let r = serde_json::from_value::<QnARecord>(item).map_err(|e| e.to_string());
+            (idx + 1, r)
+        })
+        .collect();
+    Ok(rows)
+}
+
+// Spreadsheets run cells starting with these as formulas
+const FORMULA_PREFIXES: [char; 6] = ['=', '+', '-', '@', '\t', '\r'];
+
+// Formulas are escaped with a leading quote, which is removed again when the file is imported
+pub(crate) fn csv_field(s: &str) -> String {
+    let escaped;
+    let s = if s.starts_with(FORMULA_PREFIXES) {
+        escaped = format!("'{s}");
+        escaped.as_str()
+    } else {
+        s
+    };
+    if s.contains([',', ';', '\t', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        String::from(s)
+    }
+}
+
+// With BOM, Excel opens the file as UTF-8
+fn write_csv(records: &[QnARecord]) -> String {
+    let mut s = String::with_capacity(records.len() * 256);
+    s.push('\u{feff}');
+    s.push_str(&HEADER.join(","));
+    s.push_str("\r\n");
+    for r in records.iter() {
+        s.push_str(&csv_field(&r.question));
+        s.push(',');
+        s.push_str(&csv_field(&r.similar_questions.join("\n")));
+        s.push(',');
+        s.push_str(&csv_field(&r.answer));
+        s.push_str("\r\n");
+    }
+    s
+}
+
+const CONTENT_TYPES_XML: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types"><Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/><Default Extension="xml" ContentType="application/xml"/><Override PartName="/xl/workbook.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.sheet.main+xml"/><Override PartName="/xl/worksheets/sheet1.xml" ContentType="application/vnd.openxmlformats-officedocument.spreadsheetml.worksheet+xml"/></Types>"#;
+
+const RELS_XML: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument" Target="xl/workbook.xml"/></Relationships>"#;
+
+const WORKBOOK_XML: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships"><sheets><sheet name="QnA" sheetId="1" r:id="rId1"/></sheets></workbook>"#;
+
+const WORKBOOK_RELS_XML: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet1.xml"/></Relationships>"#;
+
+// Control characters other than tabs and line breaks are not allowed in XML
+fn xml_text(s: &str) -> String {
+    let s: String = s
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    quick_xml::escape::escape(s.as_str()).into_owned()
+}
+
+fn push_row(sheet: &mut String, row_num: usize, cells: &[&str]) {
+    sheet.push_str(&format!("<row r=\"{row_num}\">"));
+    for (idx, v) in cells.iter().enumerate() {
+        sheet.push_str(&format!(
+            "<c r=\"{}{row_num}\" t=\"inlineStr\"><is><t xml:space=\"preserve\">{}</t></is></c>",
+            parser::column_name(idx),
+            xml_text(v)
+        ));
+    }
+    sheet.push_str("</row>");
+}
+
+// A workbook with a single sheet of inline strings, so shared strings and styles are not needed
+fn write_xlsx(records: &[QnARecord]) -> Result<Vec<u8>> {
+    let mut sheet = String::with_capacity(records.len() * 512);
+    sheet.push_str(r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#);
+    sheet.push_str(
+        r#"<worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"><sheetData>"#,
+    );
+    push_row(&mut sheet, 1, &HEADER);
+    for (idx, r) in records.iter().enumerate() {
+        let similar_questions = r.similar_questions.join("\n");
+        push_row(&mut sheet, idx + 2, &[&r.question, &similar_questions, &r.answer]);
+    }
+    sheet.push_str("</sheetData></worksheet>");
+    let mut zip = ZipWriter::new(Cursor::new(Vec::with_capacity(sheet.len() / 2)));
+    let options = SimpleFileOptions::default();
+    for (name, content) in [
+        ("[Content_Types].xml", CONTENT_TYPES_XML),
+        ("_rels/.rels", RELS_XML),
+        ("xl/workbook.xml", WORKBOOK_XML),
+        ("xl/_rels/workbook.xml.rels", WORKBOOK_RELS_XML),
+        ("xl/worksheets/sheet1.xml", sheet.as_str()),
+    ] {
+        zip.start_file(name, options)?;
+        zip.write_all(content.as_bytes())?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeSet;
+use std::sync::LazyLock;
+use std::vec::Vec;
+
+use regex::Regex;
+
+use super::doc;
+use super::dto::{DocCandidate, QnACandidate};
+use crate::ai::completion::Prompt;
+
+// The model answers with this marker when the passages don't contain the answer
+pub(crate) const NO_ANSWER: &str = "[NO_ANSWER]";
+
+static CITATION_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\[(\d{1,3})\]").unwrap());
+
+pub(crate) struct Passage {
+    pub(crate) source: String,
+    pub(crate) text: String,
+}
+
+impl From<QnACandidate> for Passage {
+    fn from(c: QnACandidate) -> Self {
+        Passage {
+            source: format!("QnA #{}", c.qa.id.unwrap_or_default()),
+            text: format!("Q: {}\nA: {}", &c.qa.question.question, &c.qa.answer),
+        }
+    }
+}
+
+impl From<DocCandidate> for Passage {
+    fn from(c: DocCandidate) -> Self {
+        Passage {
+            source: doc::cite(&c),
+            text: c.chunk_text,
+        }
+    }
+}
+
+fn system_prompt() -> String {
+    if *crate::web::server::IS_EN {
+        format!(
+            "You are a knowledge base assistant. \
+            Answer the question using only the numbered passages provided. \
+            Cite the passages you use inline with their numbers, like [1] or [2][3]. \
+            Do not make up information that is not in the passages. \
+            If the passages don't contain the answer, reply with {NO_ANSWER} and nothing else."
+        )
+    } else {
+        format!(
+            "你是一个知识库助手。请只根据提供的编号资料回答问题。\
+            回答中请用编号引用所依据的资料，例如 [1] 或 [2][3]。\
+            不要编造资料中没有的信息。\
+            如果资料中没有问题的答案，请只回复 {NO_ANSWER}，不要回复其他内容。"
+        )
+    }
+}
+
+pub(crate) fn build_prompts(
+    passages: &[Passage],
+    history: &[Prompt],
+    question: &str,
+) -> Vec<Prompt> {
+    let mut context = String::with_capacity(4096);
+    for (i, p) in passages.iter().enumerate() {
+        context.push_str(&format!("[{}] {}\n{}\n\n", i + 1, &p.source, &p.text));
+    }
+    let content = if *crate::web::server::IS_EN {
+        format!("Passages:\n{context}Question: {question}")
+    } else {
+        format!("资料：\n{context}问题：{question}")
+    };
+    let mut prompts: Vec<Prompt> = Vec::with_capacity(history.len() + 2);
+    prompts.push(Prompt {
+        role: String::from("system"),
+        content: system_prompt(),
+    });
+    prompts.extend_from_slice(history);
+    prompts.push(Prompt {
+        role: String::from("user"),
+        content,
+    });
+    prompts
+}
+
+pub(crate) fn is_no_answer(s: &str) -> bool {
+    s.trim().is_empty() || s.contains(NO_ANSWER)
+}
+
+// Passages cited like [1] or [2][3] are listed after the answer, so the numbers can be followed
+pub(crate) fn sources(answer: &str, passages: &[Passage]) -> String {
+    let cited: BTreeSet<usize> = CITATION_REGEX
+        .captures_iter(answer)
+        .filter_map(|c| c[1].parse::<usize>().ok())
+        .filter(|n| (1..=passages.len()).contains(n))
+        .collect();
+    if cited.is_empty() {
+        return String::new();
+    }
+    let mut s = String::from(if *crate::web::server::IS_EN {
+        "\n\nSources:"
+    } else {
+        "\n\n来源："
+    });
+    for n in cited.into_iter() {
+        s.push_str(&format!("\n[{n}] {}", &passages[n - 1].source));
+    }
+    s
+}
+
+// Removes the marker from streamed text, the end of the text is held back while it may be the
+// beginning of the marker
+#[derive(Default)]
+pub(crate) struct MarkerFilter {
+    pending: String,
+}
+
+impl MarkerFilter {
+    pub(crate) fn push(&mut self, s: &str) -> String {
+        self.pending.push_str(s);
+        if self.pending.contains(NO_ANSWER) {
+            self.pending = self.pending.replace(NO_ANSWER, "");
+        }
+        let held = (1..NO_ANSWER.len())
+            .rev()
+            .find(|n| self.pending.ends_with(&NO_ANSWER[..*n]))
+            .unwrap_or(0);
+        self.pending.drain(..self.pending.len() - held).collect()
+    }
+
+    pub(crate) fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_all(chunks: &[&str]) -> String {
+        let mut filter = MarkerFilter::default();
+        let mut s: String = chunks.iter().map(|c| filter.push(c)).collect();
+        s.push_str(&filter.finish());
+        s
+    }
+
+    #[test]
+    fn marker_is_removed_across_chunks() {
+        assert_eq!(filter_all(&["Not found ", "[NO_", "ANSWER]"]), "Not found ");
+        assert_eq!(filter_all(&["[NO_ANSWER]"]), "");
+        assert_eq!(filter_all(&["See [1", "] and [2]."]), "See [1] and [2].");
+    }
+
+    #[test]
+    fn partial_marker_is_held_back() {
+        let mut filter = MarkerFilter::default();
+        assert_eq!(filter.push("Answer [NO"), "Answer ");
+        assert_eq!(filter.push("te]"), "[NOte]");
+        assert_eq!(filter.finish(), "");
+    }
+
+    #[test]
+    fn cited_passages_are_listed() {
+        let passages: Vec<Passage> = ["a.pdf", "b.pdf", "c.pdf"]
+            .into_iter()
+            .map(|source| Passage {
+                source: String::from(source),
+                text: String::new(),
+            })
+            .collect();
+        let s = sources("Yes [3][1], see also [1] and [9].", &passages);
+        assert!(s.ends_with("\n[1] a.pdf\n[3] c.pdf"));
+        assert!(sources("No citation.", &passages).is_empty());
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+use std::vec::Vec;
+
+use crate::ai::rerank;
+use crate::man::settings::{self, RerankProvider};
+use crate::result::Result;
+
+// BM25 parameters
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+// Constant of reciprocal rank fusion, larger values flatten the differences between ranks
+const RRF_K: f64 = 60.;
+// Keyword hits matching less than this ratio of query terms are ignored, terms are weighted by
+// their IDF so that common words like "what" or "the" count little
+const MIN_KEYWORD_COVERAGE: f64 = 0.6;
+// Keyword hits scoring less than this are ignored, e.g. those matching only common words
+const MIN_KEYWORD_SCORE: f64 = 1.0;
+// Candidates fetched from each retriever before fusion
+pub(super) const CANDIDATES: usize = 20;
+
+const WORD_CONNECTORS: &[char] = &['-', '_', '.', '/'];
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{ac00}'..='\u{d7af}'
+        | '\u{f900}'..='\u{faff}')
+}
+
+// Codes like "AB-1234" are indexed as a whole as well as by their parts
+fn push_word(word: &mut String, tokens: &mut Vec<String>) {
+    let w = word.trim_end_matches(WORD_CONNECTORS);
+    if !w.is_empty() {
+        if w.contains(WORD_CONNECTORS) {
+            for part in w.split(WORD_CONNECTORS).filter(|p| !p.is_empty()) {
+                tokens.push(String::from(part));
+            }
+        }
+        tokens.push(String::from(w));
+    }
+    word.clear();
+}
+
+// CJK text has no spaces, so it's indexed by bigrams
+fn push_cjk(run: &mut Vec<char>, tokens: &mut Vec<String>) {
+    if run.len() == 1 {
+        tokens.push(run[0].to_string());
+    } else {
+        for w in run.windows(2) {
+            tokens.push(w.iter().collect());
+        }
+    }
+    run.clear();
+}
+
+pub(super) fn tokenize(s: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::with_capacity(s.len() / 3);
+    let mut word = String::with_capacity(32);
+    let mut cjk_run: Vec<char> = Vec::with_capacity(32);
+    for c in s.chars() {
+        if is_cjk(c) {
+            push_word(&mut word, &mut tokens);
+            cjk_run.push(c);
+            continue;
+        }
+        if !cjk_run.is_empty() {
+            push_cjk(&mut cjk_run, &mut tokens);
+        }
+        if c.is_alphanumeric() {
+            word.extend(c.to_lowercase());
+        } else if !word.is_empty() && WORD_CONNECTORS.contains(&c) {
+            word.push(c);
+        } else {
+            push_word(&mut word, &mut tokens);
+        }
+    }
+    push_word(&mut word, &mut tokens);
+    if !cjk_run.is_empty() {
+        push_cjk(&mut cjk_run, &mut tokens);
+    }
+    tokens
+}
+
+pub(super) async fn table_exists(conn: &turso::Connection, table: &str) -> Result<bool> {
+    let mut rows = conn
+        .query(
+            "SELECT name FROM sqlite_schema WHERE type = 'table' AND name = ?1",
+            [table],
+        )
+        .await?;
+    Ok(rows.next().await?.is_some())
+}
+
+// Returns true if the table was just created, so that existing data can be indexed
+pub(super) async fn ensure_terms_table(conn: &turso::Connection, table: &str) -> Result<bool> {
+    if table_exists(conn, &format!("{table}_terms")).await? {
+        ensure_term_stats(conn, table).await?;
+        return Ok(false);
+    }
+    let sql = format!(
+        "CREATE TABLE {table}_terms (
+            term TEXT NOT NULL,
+            owner_id INTEGER NOT NULL,
+            unit_id INTEGER NOT NULL,
+            tf INTEGER NOT NULL,
+            dl INTEGER NOT NULL
+        );
+        CREATE INDEX idx_{table}_terms_term ON {table}_terms (term);
+        CREATE INDEX idx_{table}_terms_owner ON {table}_terms (owner_id);"
+    );
+    conn.execute(&sql, ()).await?;
+    ensure_term_stats(conn, table).await?;
+    Ok(true)
+}
+
+// Number of indexed units and the sum of their lengths, kept along with the terms so that
+// searching doesn't scan the terms table. The terms table must exist
+async fn ensure_term_stats(conn: &turso::Connection, table: &str) -> Result<()> {
+    if table_exists(conn, &format!("{table}_term_stats")).await? {
+        return Ok(());
+    }
+    let sql = format!(
+        "CREATE TABLE IF NOT EXISTS {table}_term_stats (
+            id INTEGER NOT NULL PRIMARY KEY,
+            units INTEGER NOT NULL,
+            total_dl INTEGER NOT NULL
+        )"
+    );
+    conn.execute(&sql, ()).await?;
+    // Terms indexed before the statistics were kept are counted once
+    let sql = format!(
+        "INSERT OR IGNORE INTO {table}_term_stats(id, units, total_dl) SELECT 1, COUNT(*), COALESCE(SUM(dl), 0) FROM (SELECT DISTINCT owner_id, unit_id, dl FROM {table}_terms)"
+    );
+    conn.execute(&sql, ()).await?;
+    Ok(())
+}
+
+async fn update_term_stats(
+    conn: &turso::Connection,
+    table: &str,
+    units: i64,
+    total_dl: i64,
+) -> Result<()> {
+    let sql = format!(
+        "UPDATE {table}_term_stats SET units = units + ?1, total_dl = total_dl + ?2 WHERE id = 1"
+    );
+    conn.execute(&sql, (units, total_dl)).await?;
+    Ok(())
+}
+
+// Removes the terms of the units matched by `filter`, a condition on the terms table
+async fn remove_matched_terms(
+    conn: &turso::Connection,
+    table: &str,
+    filter: &str,
+    id: i64,
+) -> Result<()> {
+    if !table_exists(conn, &format!("{table}_terms")).await? {
+        return Ok(());
+    }
+    ensure_term_stats(conn, table).await?;
+    let sql = format!(
+        "SELECT COUNT(*), COALESCE(SUM(dl), 0) FROM (SELECT DISTINCT owner_id, unit_id, dl FROM {table}_terms WHERE {filter})"
+    );
+    let mut rows = conn.query(&sql, [id]).await?;
+    let (units, total_dl) = match rows.next().await? {
+        Some(row) => (
+            *row.get_value(0)?.as_integer().unwrap_or(&0),
+            *row.get_value(1)?.as_integer().unwrap_or(&0),
+        ),
+        None => (0, 0),
+    };
+    drop(rows);
+    if units == 0 {
+        return Ok(());
+    }
+    let sql = format!("DELETE FROM {table}_terms WHERE {filter}");
+    conn.execute(&sql, [id]).await?;
+    update_term_stats(conn, table, -units, -total_dl).await
+}
+
+// Units are the searched texts (chunks or questions), owners are what they belong to
+pub(super) async fn index_terms(
+    conn: &turso::Connection,
+    table: &str,
+    owner_id: i64,
+    unit_id: i64,
+    text: &str,
+) -> Result<()> {
+    let tokens = tokenize(text);
+    if tokens.is_empty() {
+        return Ok(());
+    }
+    let mut tf: HashMap<&str, i64> = HashMap::with_capacity(tokens.len());
+    for t in tokens.iter() {
+        *tf.entry(t.as_str()).or_insert(0) += 1;
+    }
+    let sql = format!(
+        "INSERT INTO {table}_terms(term, owner_id, unit_id, tf, dl)VALUES(?1, ?2, ?3, ?4, ?5)"
+    );
+    let mut stmt = conn.prepare(&sql).await?;
+    let dl = tokens.len() as i64;
+    for (term, n) in tf.into_iter() {
+        stmt.execute((term, owner_id, unit_id, n, dl)).await?;
+    }
+    update_term_stats(conn, table, 1, dl).await
+}
+
+pub(super) async fn remove_terms(
+    conn: &turso::Connection,
+    table: &str,
+    owner_id: i64,
+) -> Result<()> {
+    remove_matched_terms(conn, table, "owner_id = ?1", owner_id).await
+}
+
+// A unit is being replaced, e.g. a chunk saved again after a job was resumed
+pub(super) async fn remove_unit_terms(
+    conn: &turso::Connection,
+    table: &str,
+    unit_id: i64,
+) -> Result<()> {
+    remove_matched_terms(conn, table, "unit_id = ?1", unit_id).await
+}
+
+pub(super) struct KeywordHit {
+    pub(super) owner_id: i64,
+    pub(super) unit_id: i64,
+    pub(super) score: f64,
+}
+
+pub(crate) fn placeholders(n: usize, start: usize) -> String {
+    (start..start + n)
+        .map(|i| format!("?{i}"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+pub(super) async fn keyword_search(
+    conn: &turso::Connection,
+    table: &str,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<KeywordHit>> {
+    let mut terms = tokenize(query);
+    terms.sort();
+    terms.dedup();
+    if terms.is_empty() || !table_exists(conn, &format!("{table}_terms")).await? {
+        return Ok(vec![]);
+    }
+    ensure_term_stats(conn, table).await?;
+    let sql = format!("SELECT units, total_dl FROM {table}_term_stats WHERE id = 1");
+    let mut rows = conn.query(&sql, ()).await?;
+    let (units, total_dl) = match rows.next().await? {
+        Some(row) => (
+            *row.get_value(0)?.as_integer().unwrap_or(&0) as f64,
+            *row.get_value(1)?.as_integer().unwrap_or(&0) as f64,
+        ),
+        None => return Ok(vec![]),
+    };
+    drop(rows);
+    if units < 1. {
+        return Ok(vec![]);
+    }
+    let avg_dl = (total_dl / units).max(1.);
+    let params: Vec<turso::Value> = terms
+        .iter()
+        .map(|t| turso::Value::Text(t.clone()))
+        .collect();
+    let sql = format!(
+        "SELECT term, COUNT(*) FROM {table}_terms WHERE term IN ({}) GROUP BY term",
+        placeholders(terms.len(), 1)
+    );
+    let mut rows = conn.query(&sql, params.clone()).await?;
+    let mut idf: HashMap<String, f64> = HashMap::with_capacity(terms.len());
+    while let Some(row) = rows.next().await? {
+        let df = *row.get_value(1)?.as_integer().unwrap_or(&0) as f64;
+        if let Some(term) = row.get_value(0)?.as_text() {
+            idf.insert(
+                String::from(term),
+                (1. + (units - df + 0.5) / (df + 0.5)).ln(),
+            );
+        }
+    }
+    if idf.is_empty() {
+        return Ok(vec![]);
+    }
+    // Terms found nowhere weigh the most
+    let missing_idf = (1. + (units + 0.5) / 0.5).ln();
+    let total_idf: f64 = terms
+        .iter()
+        .map(|t| idf.get(t).copied().unwrap_or(missing_idf))
+        .sum();
+    let sql = format!(
+        "SELECT owner_id, unit_id, term, tf, dl FROM {table}_terms WHERE term IN ({})",
+        placeholders(terms.len(), 1)
+    );
+    let mut rows = conn.query(&sql, params).await?;
+    // Score and IDF of the matched terms of each unit
+    let mut scores: HashMap<(i64, i64), (f64, f64)> = HashMap::with_capacity(64);
+    while let Some(row) = rows.next().await? {
+        let owner_id = *row.get_value(0)?.as_integer().unwrap_or(&0);
+        let unit_id = *row.get_value(1)?.as_integer().unwrap_or(&0);
+        let Some(idf) = row
+            .get_value(2)?
+            .as_text()
+            .and_then(|t| idf.get(t))
+            .copied()
+        else {
+            continue;
+        };
+        let tf = *row.get_value(3)?.as_integer().unwrap_or(&0) as f64;
+        let dl = *row.get_value(4)?.as_integer().unwrap_or(&0) as f64;
+        let s = idf * tf * (K1 + 1.) / (tf + K1 * (1. - B + B * dl / avg_dl));
+        let e = scores.entry((owner_id, unit_id)).or_insert((0., 0.));
+        e.0 += s;
+        e.1 += idf;
+    }
+    let mut hits: Vec<KeywordHit> = scores
+        .into_iter()
+        .filter(|(_, (score, matched_idf))| {
+            *score >= MIN_KEYWORD_SCORE && *matched_idf / total_idf >= MIN_KEYWORD_COVERAGE
+        })
+        .map(|((owner_id, unit_id), (score, _))| KeywordHit {
+            owner_id,
+            unit_id,
+            score,
+        })
+        .collect();
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits.truncate(limit);
+    Ok(hits)
+}
+
+// Reciprocal rank fusion of ranked id lists, the result is sorted by fused score
+pub(super) fn fuse(ranked_lists: &[Vec<i64>]) -> Vec<(i64, f64)> {
+    let mut scores: HashMap<i64, f64> = HashMap::with_capacity(CANDIDATES * 2);
+    for list in ranked_lists.iter() {
+        for (rank, id) in list.iter().enumerate() {
+            *scores.entry(*id).or_insert(0.) += 1. / (RRF_K + rank as f64 + 1.);
+        }
+    }
+    let mut fused: Vec<(i64, f64)> = scores.into_iter().collect();
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1));
+    fused
+}
+
+pub(super) trait Rerankable {
+    fn rerank_text(&self) -> String;
+    fn set_rerank_score(&mut self, score: f32);
+    fn rerank_score(&self) -> f32;
+}
+
+// Settings of the reranker, if it's enabled for the robot
+pub(super) fn reranker(robot_id: &str) -> Result<Option<RerankProvider>> {
+    Ok(settings::get_settings(robot_id)?
+        .map(|s| s.rerank_provider)
+        .filter(|r| r.enabled))
+}
+
+// With reranking, more fused candidates are kept and the reranker picks the top k
+pub(super) fn fusion_limit(reranker: Option<&RerankProvider>, top_k: usize) -> usize {
+    reranker.map_or(top_k, |r| top_k.max(r.candidates as usize))
+}
+
+// Falls back to the fused order if the reranker fails
+pub(super) async fn rerank<T: Rerankable>(
+    robot_id: &str,
+    reranker: Option<&RerankProvider>,
+    query: &str,
+    candidates: &mut Vec<T>,
+    top_k: usize,
+) {
+    if let Some(r) = reranker
+        && !candidates.is_empty()
+    {
+        let texts: Vec<String> = candidates.iter().map(|c| c.rerank_text()).collect();
+        let documents: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+        match rerank::rerank(robot_id, r, query, &documents).await {
+            Ok(scores) => {
+                for (c, score) in candidates.iter_mut().zip(scores) {
+                    c.set_rerank_score(score);
+                }
+                candidates.retain(|c| c.rerank_score() >= r.min_score);
+                candidates.sort_by(|a, b| b.rerank_score().total_cmp(&a.rerank_score()));
+            }
+            Err(e) => log::warn!("Reranking failed, fused ranking is used. Err: {:?}", &e),
+        }
+    }
+    candidates.truncate(top_k);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn stats(conn: &turso::Connection) -> Result<(i64, i64)> {
+        let mut rows = conn
+            .query("SELECT units, total_dl FROM t_term_stats WHERE id = 1", ())
+            .await?;
+        let row = rows.next().await?.unwrap();
+        Ok((
+            *row.get_value(0)?.as_integer().unwrap(),
+            *row.get_value(1)?.as_integer().unwrap(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn keyword_hits_need_the_rare_terms() -> Result<()> {
+        let db = turso::Builder::new_local(":memory:").build().await?;
+        let conn = db.connect()?;
+        assert!(ensure_terms_table(&conn, "t").await?);
+        let texts = [
+            "What is the refund policy",
+            "What is the shipping time",
+            "Where is the store",
+            "What is the warranty",
+        ];
+        for (i, text) in texts.iter().enumerate() {
+            index_terms(&conn, "t", i as i64, 0, text).await?;
+        }
+        assert_eq!(stats(&conn).await?, (4, 18));
+        let hits = keyword_search(&conn, "t", "what is the refund policy?", 10).await?;
+        assert_eq!(hits.first().map(|h| h.owner_id), Some(0));
+        assert!(
+            keyword_search(&conn, "t", "what is the price", 10)
+                .await?
+                .is_empty()
+        );
+        remove_terms(&conn, "t", 0).await?;
+        assert_eq!(stats(&conn).await?, (3, 13));
+        assert!(
+            keyword_search(&conn, "t", "refund policy", 10)
+                .await?
+                .is_empty()
+        );
+        Ok(())
+    }
+}
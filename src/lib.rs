@@ -1,7 +1,16 @@
 // #[global_allocator]
 // static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 
+// Avoid musl's default allocator due to lackluster performance
+// https://nickb.dev/blog/default-musl-allocator-considered-harmful-to-performance
+// The heap of each thread is counted, so that scripts can be stopped once they allocate too much
+#[global_allocator]
+static GLOBAL: flow::rt::javascript::ScriptHeap<mimalloc::MiMalloc> =
+    flow::rt::javascript::ScriptHeap(mimalloc::MiMalloc);
+
 pub(crate) mod ai;
+pub(crate) mod analytics;
+pub(crate) mod auth;
 pub(crate) mod db;
 pub(crate) mod external;
 pub(crate) mod flow;
@@ -12,5 +21,6 @@ pub(crate) mod result;
 pub(crate) mod robot;
 // #[cfg(test)]
 // pub mod test;
+pub(crate) mod transcript;
 pub(crate) mod variable;
 pub mod web;
@@ -10,12 +10,6 @@ use tokio::runtime::Builder;
 
 use dialogflowai::web::server::start_app;
 
-// Avoid musl's default allocator due to lackluster performance
-// https://nickb.dev/blog/default-musl-allocator-considered-harmful-to-performance
-#[cfg(target_env = "musl")]
-#[global_allocator]
-static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
-
 fn main() -> Result<(), std::io::Error> {
     // dialogflow::web::t1();
     unsafe {
@@ -0,0 +1,204 @@
+use std::future::Future;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use redb::TableDefinition;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
+
+use super::clock::now_secs;
+use crate::db;
+use crate::result::{Error, Result};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) enum JobStatus {
+    Pending,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub(crate) fn is_unfinished(self) -> bool {
+        matches!(self, JobStatus::Pending | JobStatus::Running)
+    }
+}
+
+pub(crate) trait Job: DeserializeOwned + Serialize {
+    fn id(&self) -> &str;
+    fn status(&self) -> JobStatus;
+    fn set_status(&mut self, status: JobStatus);
+    fn set_err(&mut self, err: String);
+    fn created_at(&self) -> u64;
+    fn updated_at(&self) -> u64;
+    fn set_updated_at(&mut self, t: u64);
+    // Called when an unfinished job is queued again after a restart
+    fn restart(&mut self) {}
+}
+
+// Jobs saved in a table and run by a worker in the background
+pub(crate) struct JobQueue {
+    table: TableDefinition<'static, &'static str, &'static [u8]>,
+    // e.g. `Ingestion`, used in messages
+    name: &'static str,
+    // Ids of jobs waiting for the worker
+    sender: OnceLock<UnboundedSender<String>>,
+    // Serializes the read-modify-write of jobs between the worker and requests
+    lock: Mutex<()>,
+    running: Semaphore,
+}
+
+impl JobQueue {
+    pub(crate) const fn new(
+        table_name: &'static str,
+        name: &'static str,
+        concurrency: usize,
+    ) -> Self {
+        JobQueue {
+            table: TableDefinition::new(table_name),
+            name,
+            sender: OnceLock::new(),
+            lock: Mutex::new(()),
+            running: Semaphore::const_new(concurrency),
+        }
+    }
+
+    pub(crate) fn init_table(&self) -> Result<()> {
+        db::init_table(self.table)
+    }
+
+    // Held while jobs are checked and changed together
+    pub(crate) fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(|e| {
+            log::warn!("{:#?}", &e);
+            e.into_inner()
+        })
+    }
+
+    pub(crate) fn get<J: Job>(&self, job_id: &str) -> Result<Option<J>> {
+        db::query(self.table, job_id)
+    }
+
+    pub(crate) fn get_all<J: Job>(&self) -> Result<Vec<J>> {
+        db::get_all(self.table)
+    }
+
+    pub(crate) fn remove(&self, job_id: &str) -> Result<()> {
+        db::remove(self.table, job_id)
+    }
+
+    // Saves a job without queueing it
+    pub(crate) fn save<J: Job>(&self, job: &J) -> Result<()> {
+        db::write(self.table, job.id(), job)
+    }
+
+    // Saves a new job and queues it
+    pub(crate) fn submit<J: Job>(&self, job: &J) -> Result<()> {
+        self.save(job)?;
+        self.enqueue(job.id())
+    }
+
+    // Finished jobs not updated within `retention_secs` are removed
+    pub(crate) fn prune<J: Job>(&self, retention_secs: u64) -> Result<()> {
+        let cutoff = now_secs().saturating_sub(retention_secs);
+        let _guard = self.lock();
+        let jobs: Vec<J> = self.get_all()?;
+        for job in jobs.iter() {
+            if !job.status().is_unfinished() && job.updated_at() < cutoff {
+                self.remove(job.id())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn update<J, F>(&self, job_id: &str, f: F) -> Result<J>
+    where
+        J: Job,
+        F: FnOnce(&mut J) -> Result<()>,
+    {
+        let _guard = self.lock();
+        self.update_locked(job_id, f)
+    }
+
+    // Same as `update`, for callers already holding the lock
+    pub(crate) fn update_locked<J, F>(&self, job_id: &str, f: F) -> Result<J>
+    where
+        J: Job,
+        F: FnOnce(&mut J) -> Result<()>,
+    {
+        let Some(mut job): Option<J> = self.get(job_id)? else {
+            return Err(Error::WithMessage(format!(
+                "{} job {job_id} not found.",
+                self.name
+            )));
+        };
+        f(&mut job)?;
+        job.set_updated_at(now_secs());
+        db::write(self.table, job_id, &job)?;
+        Ok(job)
+    }
+
+    pub(crate) fn enqueue(&self, job_id: &str) -> Result<()> {
+        match self.sender.get() {
+            Some(q) => q
+                .send(String::from(job_id))
+                .map_err(|e| Error::WithMessage(format!("{e:?}"))),
+            None => Err(Error::WithMessage(format!(
+                "{} worker is not running.",
+                self.name
+            ))),
+        }
+    }
+
+    // Unfinished jobs are queued again, failed jobs keep the error unless they were cancelled
+    pub(crate) fn start_worker<J, F, Fut>(&'static self, run: F) -> Result<()>
+    where
+        J: Job,
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (sender, mut receiver) = unbounded_channel::<String>();
+        self.sender
+            .set(sender)
+            .map_err(|_| Error::WithMessage(format!("{} worker has been started.", self.name)))?;
+        let mut jobs: Vec<J> = self.get_all()?;
+        jobs.sort_by_key(|j| j.created_at());
+        for job in jobs.iter() {
+            if job.status().is_unfinished() {
+                self.update(job.id(), |j: &mut J| {
+                    j.set_status(JobStatus::Pending);
+                    j.restart();
+                    Ok(())
+                })?;
+                self.enqueue(job.id())?;
+            }
+        }
+        tokio::spawn(async move {
+            while let Some(job_id) = receiver.recv().await {
+                let Ok(permit) = self.running.acquire().await else {
+                    break;
+                };
+                let job = run(job_id.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = job.await {
+                        log::error!("{} job {job_id} failed {e:?}", self.name);
+                        let r = self.update(&job_id, |j: &mut J| {
+                            if j.status() != JobStatus::Cancelled {
+                                j.set_status(JobStatus::Failed);
+                                j.set_err(format!("{e:?}"));
+                            }
+                            Ok(())
+                        });
+                        if let Err(e) = r {
+                            log::error!("{e:?}");
+                        }
+                    }
+                    drop(permit);
+                });
+            }
+        });
+        Ok(())
+    }
+}
@@ -1 +1,3 @@
+pub(crate) mod clock;
+pub(crate) mod job;
 pub(crate) mod settings;
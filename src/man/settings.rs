@@ -12,8 +12,9 @@ use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
 use crate::ai::huggingface::HuggingFaceModel;
-use crate::ai::{asr, chat, completion, embedding, huggingface, tts};
+use crate::ai::{asr, chat, completion, embedding, huggingface, rerank, tts};
 use crate::db;
+use crate::kb::chunker::ChunkStrategy;
 use crate::result::{Error, Result};
 use crate::robot::dto::RobotQuery;
 use crate::web::server::{self, to_res};
@@ -69,6 +70,16 @@ pub(crate) struct Settings {
     pub(crate) smtp_timeout_sec: u16,
     #[serde(rename = "emailVerificationRegex")]
     pub(crate) email_verification_regex: String,
+    #[serde(rename = "docChunking", default)]
+    pub(crate) doc_chunking: DocChunking,
+    #[serde(rename = "rerankProvider", default)]
+    pub(crate) rerank_provider: RerankProvider,
+    #[serde(rename = "intentDetection", default)]
+    pub(crate) intent_detection: IntentDetection,
+    #[serde(rename = "webCrawling", default)]
+    pub(crate) web_crawling: WebCrawling,
+    #[serde(rename = "transcriptRetention", default)]
+    pub(crate) transcript_retention: TranscriptRetention,
 }
 
 // #[test]
@@ -190,6 +201,101 @@ pub(crate) struct TtsProvider {
     pub(crate) proxy_url: String,
 }
 
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct RerankProvider {
+    pub(crate) enabled: bool,
+    pub(crate) provider: rerank::RerankProvider,
+    #[serde(rename = "apiUrl")]
+    pub(crate) api_url: String,
+    #[serde(rename = "apiKey")]
+    pub(crate) api_key: String,
+    #[serde(rename = "connectTimeoutMillis")]
+    pub(crate) connect_timeout_millis: u32,
+    #[serde(rename = "readTimeoutMillis")]
+    pub(crate) read_timeout_millis: u32,
+    #[serde(rename = "proxyUrl")]
+    pub(crate) proxy_url: String,
+    // Number of retrieved candidates to be reranked
+    pub(crate) candidates: u8,
+    // Reranked candidates below this score are dropped
+    #[serde(rename = "minScore")]
+    pub(crate) min_score: f32,
+}
+
+impl Default for RerankProvider {
+    fn default() -> Self {
+        RerankProvider {
+            enabled: false,
+            provider: rerank::RerankProvider::HuggingFace(HuggingFaceModel::BgeRerankerBase),
+            api_url: String::new(),
+            api_key: String::new(),
+            connect_timeout_millis: 1000,
+            read_timeout_millis: 10000,
+            proxy_url: String::new(),
+            candidates: 20,
+            min_score: 0.,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct DocChunking {
+    pub(crate) strategy: ChunkStrategy,
+    #[serde(rename = "maxTokens")]
+    pub(crate) max_tokens: usize,
+    #[serde(rename = "overlapTokens")]
+    pub(crate) overlap_tokens: usize,
+}
+
+impl Default for DocChunking {
+    fn default() -> Self {
+        DocChunking {
+            strategy: ChunkStrategy::Structure,
+            max_tokens: 256,
+            overlap_tokens: 32,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct IntentDetection {
+    // Number of ranked intents kept in the detection result
+    #[serde(rename = "topN")]
+    pub(crate) top_n: u8,
+    // The top two intents are ambiguous if their scores differ less than this
+    #[serde(rename = "ambiguityMargin")]
+    pub(crate) ambiguity_margin: f32,
+}
+
+impl Default for IntentDetection {
+    fn default() -> Self {
+        IntentDetection {
+            top_n: 3,
+            ambiguity_margin: 0.05,
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub(crate) struct WebCrawling {
+    // Sites on loopback, private and link-local addresses are refused unless this is on
+    #[serde(rename = "allowPrivateAddresses")]
+    pub(crate) allow_private_addresses: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub(crate) struct TranscriptRetention {
+    // Sessions and turns older than this are removed, 0 keeps them forever
+    #[serde(rename = "retentionDays")]
+    pub(crate) retention_days: u32,
+}
+
+impl Default for TranscriptRetention {
+    fn default() -> Self {
+        TranscriptRetention { retention_days: 90 }
+    }
+}
+
 impl Default for GlobalSettings {
     fn default() -> Self {
         GlobalSettings {
@@ -273,6 +379,11 @@ impl Default for Settings {
             smtp_password: String::new(),
             smtp_timeout_sec: 60u16,
             email_verification_regex: String::new(),
+            doc_chunking: DocChunking::default(),
+            rerank_provider: RerankProvider::default(),
+            intent_detection: IntentDetection::default(),
+            web_crawling: WebCrawling::default(),
+            transcript_retention: TranscriptRetention::default(),
         }
     }
 }
@@ -350,6 +461,13 @@ pub(crate) async fn rest_save_global_settings(
 }
 
 pub(crate) fn save_settings(robot_id: &str, data: Settings) -> Result<()> {
+    if data.doc_chunking.max_tokens < 16
+        || data.doc_chunking.overlap_tokens >= data.doc_chunking.max_tokens
+    {
+        return Err(Error::WithMessage(String::from(
+            "Chunk size must be at least 16 tokens and greater than the overlap",
+        )));
+    }
     if let completion::TextGenerationProvider::HuggingFace(m) =
         &data.text_generation_provider.provider
     {
@@ -385,6 +503,15 @@ pub(crate) fn save_settings(robot_id: &str, data: Settings) -> Result<()> {
             }
         }
     }
+    if data.rerank_provider.enabled
+        && let rerank::RerankProvider::HuggingFace(m) = &data.rerank_provider.provider
+        && let Err(e) = rerank::replace_model_cache(robot_id, m)
+    {
+        log::warn!(
+            "Hugging face model files for reranking were incorrect. Err: {:?}",
+            &e
+        );
+    }
     db::write(TABLE, robot_id, &data)?;
     let mut l = SETTINGS_CACHE.lock()?;
     l.insert(String::from(robot_id), data);
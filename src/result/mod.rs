@@ -201,6 +201,12 @@ impl From<quick_xml::encoding::EncodingError> for Error {
     }
 }
 
+impl From<lopdf::Error> for Error {
+    fn from(err: lopdf::Error) -> Self {
+        Error::WithMessage(format!("Parsing PDF failed: {err:?}"))
+    }
+}
+
 impl From<turso::Error> for Error {
     fn from(err: turso::Error) -> Self {
         Error::WithMessage(format!("turso Error failed: {err:?}"))
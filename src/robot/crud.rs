@@ -75,6 +75,8 @@ async fn new(d: &RobotData, is_en: bool) -> Result<()> {
     mainflow::init(&d.robot_id)?;
     // Http 接口
     http::init(&d.robot_id)?;
+    // 会话记录
+    crate::transcript::crud::init(&d.robot_id)?;
     Ok(())
 }
 
@@ -84,11 +86,19 @@ fn persist(d: &RobotData) -> Result<()> {
 }
 
 pub(crate) async fn list() -> impl IntoResponse {
-    to_res::<Vec<RobotData>>(db::get_all(TABLE))
+    to_res(get_all())
+}
+
+pub(crate) fn get_all() -> Result<Vec<RobotData>> {
+    db::get_all(TABLE)
+}
+
+pub(crate) fn get(robot_id: &str) -> Result<Option<RobotData>> {
+    db::query(TABLE, robot_id)
 }
 
 pub(crate) async fn detail(Query(q): Query<RobotQuery>) -> impl IntoResponse {
-    to_res::<Option<RobotData>>(db::query(TABLE, q.robot_id.as_str()))
+    to_res(get(&q.robot_id))
 }
 
 pub(crate) async fn delete(Query(q): Query<RobotQuery>) -> impl IntoResponse {
@@ -120,6 +130,8 @@ fn delete_dirs(dir: &Path, cb: &dyn Fn(&DirEntry) -> Result<()>) -> Result<()> {
 
 async fn purge(robot_id: &str) -> Result<()> {
     crate::intent::phrase::remove_tables(robot_id).await?;
+    crate::kb::qa::remove_tables(robot_id).await?;
+    crate::kb::doc::remove_tables(robot_id).await?;
     // let root = &format!("{}{}", crate::intent::detector::SAVING_PATH_ROOT, robot_id);
     // let path = Path::new(&root);
     // if path.exists() {
@@ -169,5 +181,8 @@ async fn purge(robot_id: &str) -> Result<()> {
         robot_id,
         crate::flow::mainflow::crud::TABLE_SUFFIX,
     )?;
+    crate::transcript::crud::remove_tables(robot_id)?;
+    crate::analytics::crud::remove_tables(robot_id)?;
+    crate::auth::crud::remove_api_keys(robot_id)?;
     db::remove(TABLE, robot_id)
 }
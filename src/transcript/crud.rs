@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::vec::Vec;
+
+use axum::extract::Query;
+use axum::response::IntoResponse;
+use redb::{ReadableDatabase, ReadableTable, TableDefinition};
+
+use super::dto::{
+    SessionOutcome, TranscriptDetail, TranscriptDetailQuery, TranscriptPage, TranscriptQuery,
+    TranscriptSession, TranscriptTurn,
+};
+use crate::db;
+use crate::db_executor;
+use crate::flow::rt::dto::{AnswerContentType, AnswerData};
+use crate::flow::subflow::dto::NextActionType;
+use crate::man::settings;
+use crate::result::Result;
+use crate::web::server::to_res;
+
+pub(crate) const SESSION_TABLE_SUFFIX: &str = "_transcript_sessions";
+pub(crate) const TURN_TABLE_SUFFIX: &str = "_transcript_turns";
+// Keys of turns in time order, so that the turns of a period can be read without the others
+const TURN_TIME_TABLE_SUFFIX: &str = "_transcript_turn_times";
+// Sessions keyed by the time they were last active, so that they can be listed newest first
+const SESSION_TIME_TABLE_SUFFIX: &str = "_transcript_session_times";
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 200;
+const PRUNE_INTERVAL_MILLIS: u64 = 3600 * 1000;
+
+// When the transcripts of each robot were pruned last time
+static PRUNED_AT: LazyLock<Mutex<HashMap<String, u64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::with_capacity(16)));
+
+pub(crate) fn init(robot_id: &str) -> Result<()> {
+    db_executor!(db::init_table, robot_id, SESSION_TABLE_SUFFIX,)?;
+    db_executor!(db::init_table, robot_id, TURN_TABLE_SUFFIX,)?;
+    db_executor!(db::init_table, robot_id, TURN_TIME_TABLE_SUFFIX,)?;
+    db_executor!(db::init_table, robot_id, SESSION_TIME_TABLE_SUFFIX,)
+}
+
+pub(crate) fn remove_tables(robot_id: &str) -> Result<()> {
+    if let Ok(mut pruned_at) = PRUNED_AT.lock() {
+        pruned_at.remove(robot_id);
+    }
+    db_executor!(db::delete_table, robot_id, SESSION_TABLE_SUFFIX,)?;
+    db_executor!(db::delete_table, robot_id, TURN_TABLE_SUFFIX,)?;
+    db_executor!(db::delete_table, robot_id, TURN_TIME_TABLE_SUFFIX,)?;
+    db_executor!(db::delete_table, robot_id, SESSION_TIME_TABLE_SUFFIX,)
+}
+
+fn table_exists(read_txn: &redb::ReadTransaction, table_name: &str) -> Result<bool> {
+    let table: TableDefinition<&str, &[u8]> = TableDefinition::new(table_name);
+    match read_txn.open_table(table) {
+        Ok(_) => Ok(true),
+        Err(redb::TableError::TableDoesNotExist(_)) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Turns and sessions recorded before the time indexes were added are indexed once
+pub(crate) fn upgrade_tables() -> Result<()> {
+    let read_txn = db::DB.begin_read()?;
+    for robot in crate::robot::crud::get_all()? {
+        let time_table_name = format!("{}{TURN_TIME_TABLE_SUFFIX}", &robot.robot_id);
+        if !table_exists(&read_txn, &time_table_name)? {
+            log::info!("Indexing transcript turns of robot {}", &robot.robot_id);
+            let turns = get_all_turns(&robot.robot_id)?;
+            let time_table: TableDefinition<&str, &[u8]> = TableDefinition::new(&time_table_name);
+            let write_txn = db::DB.begin_write()?;
+            {
+                let mut times = write_txn.open_table(time_table)?;
+                for t in turns.iter() {
+                    let key = turn_key(&t.session_id, t.seq);
+                    let time_key = turn_time_key(t.timestamp, &key);
+                    times.insert(time_key.as_str(), key.as_bytes())?;
+                }
+            }
+            write_txn.commit()?;
+        }
+        let time_table_name = format!("{}{SESSION_TIME_TABLE_SUFFIX}", &robot.robot_id);
+        if !table_exists(&read_txn, &time_table_name)? {
+            log::info!("Indexing transcript sessions of robot {}", &robot.robot_id);
+            let sessions = get_sessions(&robot.robot_id)?;
+            let time_table: TableDefinition<&str, &[u8]> = TableDefinition::new(&time_table_name);
+            let write_txn = db::DB.begin_write()?;
+            {
+                let mut times = write_txn.open_table(time_table)?;
+                for s in sessions.iter() {
+                    let time_key = session_time_key(s.last_active_at, &s.session_id);
+                    times.insert(time_key.as_str(), s.session_id.as_bytes())?;
+                }
+            }
+            write_txn.commit()?;
+        }
+    }
+    Ok(())
+}
+
+fn turn_key(session_id: &str, seq: u32) -> String {
+    format!("{session_id}:{seq:08}")
+}
+
+fn turn_time_key(timestamp: u64, turn_key: &str) -> String {
+    format!("{timestamp:020}-{turn_key}")
+}
+
+fn session_time_key(last_active_at: u64, session_id: &str) -> String {
+    format!("{last_active_at:020}-{session_id}")
+}
+
+// The session is read in the write transaction, so that concurrent turns of a session get
+// different sequences. Returns the sequence of the turn
+pub(crate) fn record(robot_id: &str, mut turn: TranscriptTurn) -> Result<u32> {
+    let session_table_name = format!("{robot_id}{SESSION_TABLE_SUFFIX}");
+    let session_table: TableDefinition<&str, &[u8]> = TableDefinition::new(&session_table_name);
+    let turn_table_name = format!("{robot_id}{TURN_TABLE_SUFFIX}");
+    let turn_table: TableDefinition<&str, &[u8]> = TableDefinition::new(&turn_table_name);
+    let time_table_name = format!("{robot_id}{TURN_TIME_TABLE_SUFFIX}");
+    let time_table: TableDefinition<&str, &[u8]> = TableDefinition::new(&time_table_name);
+    let session_time_table_name = format!("{robot_id}{SESSION_TIME_TABLE_SUFFIX}");
+    let session_time_table: TableDefinition<&str, &[u8]> =
+        TableDefinition::new(&session_time_table_name);
+    let write_txn = db::DB.begin_write()?;
+    {
+        let mut sessions = write_txn.open_table(session_table)?;
+        let mut session_times = write_txn.open_table(session_time_table)?;
+        let session: Option<TranscriptSession> = match sessions.get(turn.session_id.as_str())? {
+            Some(v) => Some(serde_json::from_slice(v.value())?),
+            None => None,
+        };
+        let mut session = session.unwrap_or_else(|| TranscriptSession {
+            session_id: turn.session_id.clone(),
+            robot_id: String::from(robot_id),
+            main_flow_id: turn.main_flow_id.clone(),
+            started_at: turn.timestamp,
+            last_active_at: turn.timestamp,
+            turns: 0,
+            intents: Vec::with_capacity(4),
+            outcome: SessionOutcome::Ongoing,
+            last_node_id: String::new(),
+        });
+        if session.turns > 0 {
+            let time_key = session_time_key(session.last_active_at, &session.session_id);
+            session_times.remove(time_key.as_str())?;
+        }
+        turn.seq = session.turns;
+        session.turns += 1;
+        session.last_active_at = turn.timestamp;
+        if let Some(intent) = &turn.intent
+            && !session.intents.contains(intent)
+        {
+            session.intents.push(intent.clone());
+        }
+        if let Some(node_id) = turn.visited_nodes.last() {
+            session.last_node_id.clear();
+            session.last_node_id.push_str(node_id);
+        }
+        session.outcome = if turn.error.is_some() {
+            SessionOutcome::Error
+        } else {
+            match turn.next_action {
+                NextActionType::Terminate => SessionOutcome::Completed,
+                NextActionType::GotoExternalLink => SessionOutcome::Transferred,
+                _ => SessionOutcome::Ongoing,
+            }
+        };
+        let key = turn_key(&turn.session_id, turn.seq);
+        let mut turns = write_txn.open_table(turn_table)?;
+        turns.insert(key.as_str(), serde_json::to_vec(&turn)?.as_slice())?;
+        let mut times = write_txn.open_table(time_table)?;
+        let time_key = turn_time_key(turn.timestamp, &key);
+        times.insert(time_key.as_str(), key.as_bytes())?;
+        let time_key = session_time_key(session.last_active_at, &session.session_id);
+        session_times.insert(time_key.as_str(), session.session_id.as_bytes())?;
+        sessions.insert(
+            session.session_id.as_str(),
+            serde_json::to_vec(&session)?.as_slice(),
+        )?;
+    }
+    write_txn.commit()?;
+    if let Err(e) = prune(robot_id, turn.timestamp) {
+        log::warn!("Pruning transcripts failed, err: {e:?}");
+    }
+    Ok(turn.seq)
+}
+
+// Removes the turns recorded and the sessions last active before the retention period.
+// Runs at most once per interval for each robot
+fn prune(robot_id: &str, now: u64) -> Result<()> {
+    {
+        let mut pruned_at = PRUNED_AT.lock()?;
+        let last = pruned_at.entry(String::from(robot_id)).or_insert(0);
+        if now < *last + PRUNE_INTERVAL_MILLIS {
+            return Ok(());
+        }
+        *last = now;
+    }
+    let retention_days = settings::get_settings(robot_id)?.map_or(
+        settings::TranscriptRetention::default().retention_days,
+        |s| s.transcript_retention.retention_days,
+    );
+    if retention_days == 0 {
+        return Ok(());
+    }
+    let retention_millis = retention_days as u64 * 86400 * 1000;
+    let cutoff = format!("{:020}", now.saturating_sub(retention_millis));
+    let session_table_name = format!("{robot_id}{SESSION_TABLE_SUFFIX}");
+    let session_table: TableDefinition<&str, &[u8]> = TableDefinition::new(&session_table_name);
+    let turn_table_name = format!("{robot_id}{TURN_TABLE_SUFFIX}");
+    let turn_table: TableDefinition<&str, &[u8]> = TableDefinition::new(&turn_table_name);
+    let time_table_name = format!("{robot_id}{TURN_TIME_TABLE_SUFFIX}");
+    let time_table: TableDefinition<&str, &[u8]> = TableDefinition::new(&time_table_name);
+    let session_time_table_name = format!("{robot_id}{SESSION_TIME_TABLE_SUFFIX}");
+    let session_time_table: TableDefinition<&str, &[u8]> =
+        TableDefinition::new(&session_time_table_name);
+    let write_txn = db::DB.begin_write()?;
+    {
+        let mut turns = write_txn.open_table(turn_table)?;
+        let mut times = write_txn.open_table(time_table)?;
+        for r in times.extract_from_if(..cutoff.as_str(), |_, _| true)? {
+            let (_, key) = r?;
+            turns.remove(String::from_utf8_lossy(key.value()).as_ref())?;
+        }
+        let mut sessions = write_txn.open_table(session_table)?;
+        let mut session_times = write_txn.open_table(session_time_table)?;
+        for r in session_times.extract_from_if(..cutoff.as_str(), |_, _| true)? {
+            let (_, session_id) = r?;
+            sessions.remove(String::from_utf8_lossy(session_id.value()).as_ref())?;
+        }
+    }
+    write_txn.commit()?;
+    Ok(())
+}
+
+// Streamed answers are still empty when the turn is recorded, their text is filled in once
+// the stream has finished. Each item is the index of the answer and its text
+pub(crate) fn fill_streamed_answers(
+    robot_id: &str,
+    session_id: &str,
+    seq: u32,
+    streamed: Vec<(usize, String)>,
+) -> Result<()> {
+    let key = turn_key(session_id, seq);
+    let r: Option<TranscriptTurn> =
+        db_executor!(db::query, robot_id, TURN_TABLE_SUFFIX, key.as_str())?;
+    let Some(mut turn) = r else {
+        return Ok(());
+    };
+    for (idx, content) in streamed.into_iter() {
+        match turn.answers.get_mut(idx) {
+            Some(a) if a.content.is_empty() => a.content = content,
+            Some(_) => {}
+            None => turn.answers.push(AnswerData {
+                content,
+                content_type: AnswerContentType::TextPlain,
+                payload: None,
+            }),
+        }
+    }
+    db_executor!(db::write, robot_id, TURN_TABLE_SUFFIX, &key, &turn)
+}
+
+// Sessions are read newest first from the time index, starting from the requested start time,
+// and only the ones of the requested page are kept
+fn list_sessions(q: &TranscriptQuery) -> Result<TranscriptPage> {
+    let page_size = q
+        .page_size
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let page = q.page.unwrap_or(1).max(1);
+    let skip = (page - 1).saturating_mul(page_size);
+    let session_table_name = format!("{}{SESSION_TABLE_SUFFIX}", &q.robot_id);
+    let session_table: TableDefinition<&str, &[u8]> = TableDefinition::new(&session_table_name);
+    let time_table_name = format!("{}{SESSION_TIME_TABLE_SUFFIX}", &q.robot_id);
+    let time_table: TableDefinition<&str, &[u8]> = TableDefinition::new(&time_table_name);
+    let read_txn = db::DB.begin_read()?;
+    let (times, sessions) = match (
+        read_txn.open_table(time_table),
+        read_txn.open_table(session_table),
+    ) {
+        (Ok(times), Ok(sessions)) => (times, sessions),
+        (Err(redb::TableError::TableDoesNotExist(_)), _)
+        | (_, Err(redb::TableError::TableDoesNotExist(_))) => {
+            return Ok(TranscriptPage {
+                total: 0,
+                sessions: vec![],
+            });
+        }
+        (Err(e), _) | (_, Err(e)) => return Err(e.into()),
+    };
+    let begin = format!("{:020}", q.start_time.unwrap_or(0));
+    let mut total = 0usize;
+    let mut v: Vec<TranscriptSession> = Vec::with_capacity(page_size);
+    for r in times.range(begin.as_str()..)?.rev() {
+        let (_, session_id) = r?;
+        let session_id = String::from_utf8_lossy(session_id.value());
+        let Some(s) = sessions.get(session_id.as_ref())? else {
+            continue;
+        };
+        let s: TranscriptSession = serde_json::from_slice(s.value())?;
+        if q.main_flow_id
+            .as_ref()
+            .is_none_or(|id| s.main_flow_id.eq(id))
+            && q.end_time.is_none_or(|t| s.started_at <= t)
+            && q.intent.as_ref().is_none_or(|i| s.intents.contains(i))
+            && q.outcome.is_none_or(|o| s.outcome == o)
+        {
+            if total >= skip && v.len() < page_size {
+                v.push(s);
+            }
+            total += 1;
+        }
+    }
+    Ok(TranscriptPage { total, sessions: v })
+}
+
+pub(crate) fn get_turns(robot_id: &str, session_id: &str) -> Result<Vec<TranscriptTurn>> {
+    let begin = turn_key(session_id, 0);
+    let end = turn_key(session_id, u32::MAX);
+    let r: Result<Vec<TranscriptTurn>> = db_executor!(
+        db::range,
+        robot_id,
+        TURN_TABLE_SUFFIX,
+        begin.as_str()..=end.as_str()
+    );
+    match r {
+        Err(e) if db::is_table_not_exists(&e) => Ok(vec![]),
+        r => r,
+    }
+}
+
+fn get_all_turns(robot_id: &str) -> Result<Vec<TranscriptTurn>> {
+    let r: Result<Vec<TranscriptTurn>> = db_executor!(db::get_all, robot_id, TURN_TABLE_SUFFIX,);
+    match r {
+        Err(e) if db::is_table_not_exists(&e) => Ok(vec![]),
+        r => r,
+    }
+}
+
+// Turns recorded in [start, end), in time order
+pub(crate) fn get_turns_between(
+    robot_id: &str,
+    start: u64,
+    end: u64,
+) -> Result<Vec<TranscriptTurn>> {
+    let turn_table_name = format!("{robot_id}{TURN_TABLE_SUFFIX}");
+    let turn_table: TableDefinition<&str, &[u8]> = TableDefinition::new(&turn_table_name);
+    let time_table_name = format!("{robot_id}{TURN_TIME_TABLE_SUFFIX}");
+    let time_table: TableDefinition<&str, &[u8]> = TableDefinition::new(&time_table_name);
+    let read_txn = db::DB.begin_read()?;
+    let (times, turns) = match (
+        read_txn.open_table(time_table),
+        read_txn.open_table(turn_table),
+    ) {
+        (Ok(times), Ok(turns)) => (times, turns),
+        (Err(redb::TableError::TableDoesNotExist(_)), _)
+        | (_, Err(redb::TableError::TableDoesNotExist(_))) => return Ok(vec![]),
+        (Err(e), _) | (_, Err(e)) => return Err(e.into()),
+    };
+    let begin = format!("{start:020}");
+    let end = format!("{end:020}");
+    let mut v: Vec<TranscriptTurn> = Vec::with_capacity(64);
+    for r in times.range(begin.as_str()..end.as_str())? {
+        let (_, key) = r?;
+        let key = String::from_utf8_lossy(key.value());
+        if let Some(t) = turns.get(key.as_ref())? {
+            v.push(serde_json::from_slice(t.value())?);
+        }
+    }
+    Ok(v)
+}
+
+pub(crate) fn get_sessions(robot_id: &str) -> Result<Vec<TranscriptSession>> {
+    let r: Result<Vec<TranscriptSession>> =
+        db_executor!(db::get_all, robot_id, SESSION_TABLE_SUFFIX,);
+    match r {
+        Err(e) if db::is_table_not_exists(&e) => Ok(vec![]),
+        r => r,
+    }
+}
+
+fn get_detail(q: &TranscriptDetailQuery) -> Result<Option<TranscriptDetail>> {
+    let r: Result<Option<TranscriptSession>> = db_executor!(
+        db::query,
+        &q.robot_id,
+        SESSION_TABLE_SUFFIX,
+        q.session_id.as_str()
+    );
+    let session = match r {
+        Ok(Some(s)) => s,
+        Ok(None) => return Ok(None),
+        Err(e) if db::is_table_not_exists(&e) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let turns = get_turns(&q.robot_id, &q.session_id)?;
+    Ok(Some(TranscriptDetail { session, turns }))
+}
+
+pub(crate) async fn list(Query(q): Query<TranscriptQuery>) -> impl IntoResponse {
+    to_res(list_sessions(&q))
+}
+
+pub(crate) async fn detail(Query(q): Query<TranscriptDetailQuery>) -> impl IntoResponse {
+    to_res(get_detail(&q))
+}
@@ -0,0 +1,99 @@
+use std::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::flow::rt::dto::{AnswerData, CollectData};
+use crate::flow::subflow::dto::NextActionType;
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq, Serialize)]
+pub(crate) enum SessionOutcome {
+    Ongoing,
+    Completed,
+    Transferred,
+    Error,
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct TranscriptSession {
+    #[serde(rename = "sessionId")]
+    pub(crate) session_id: String,
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "mainFlowId")]
+    pub(crate) main_flow_id: String,
+    #[serde(rename = "startedAt")]
+    pub(crate) started_at: u64,
+    #[serde(rename = "lastActiveAt")]
+    pub(crate) last_active_at: u64,
+    pub(crate) turns: u32,
+    pub(crate) intents: Vec<String>,
+    pub(crate) outcome: SessionOutcome,
+    #[serde(rename = "lastNodeId")]
+    pub(crate) last_node_id: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct TranscriptTurn {
+    #[serde(rename = "sessionId")]
+    pub(crate) session_id: String,
+    pub(crate) seq: u32,
+    pub(crate) timestamp: u64,
+    #[serde(rename = "mainFlowId")]
+    pub(crate) main_flow_id: String,
+    #[serde(rename = "userInput")]
+    pub(crate) user_input: String,
+    #[serde(rename = "userInputTimeout")]
+    pub(crate) user_input_timeout: bool,
+    pub(crate) intent: Option<String>,
+    #[serde(rename = "visitedNodes")]
+    pub(crate) visited_nodes: Vec<String>,
+    #[serde(rename = "collectData")]
+    pub(crate) collect_data: Vec<CollectData>,
+    pub(crate) answers: Vec<AnswerData>,
+    #[serde(rename = "nextAction")]
+    pub(crate) next_action: NextActionType,
+    #[serde(rename = "latencyMillis")]
+    pub(crate) latency_millis: u64,
+    pub(crate) error: Option<String>,
+    // None if no knowledge base answer node was visited in this turn
+    #[serde(rename = "kbNoRecall", default)]
+    pub(crate) kb_no_recall: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TranscriptQuery {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "mainFlowId")]
+    pub(crate) main_flow_id: Option<String>,
+    // Unix timestamp in milliseconds
+    #[serde(rename = "startTime")]
+    pub(crate) start_time: Option<u64>,
+    #[serde(rename = "endTime")]
+    pub(crate) end_time: Option<u64>,
+    pub(crate) intent: Option<String>,
+    pub(crate) outcome: Option<SessionOutcome>,
+    pub(crate) page: Option<usize>,
+    #[serde(rename = "pageSize")]
+    pub(crate) page_size: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TranscriptDetailQuery {
+    #[serde(rename = "robotId")]
+    pub(crate) robot_id: String,
+    #[serde(rename = "sessionId")]
+    pub(crate) session_id: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TranscriptPage {
+    pub(crate) total: usize,
+    pub(crate) sessions: Vec<TranscriptSession>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TranscriptDetail {
+    pub(crate) session: TranscriptSession,
+    pub(crate) turns: Vec<TranscriptTurn>,
+}
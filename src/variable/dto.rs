@@ -5,6 +5,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::flow::rt::context::Context;
 use crate::flow::rt::dto::Request;
+use crate::flow::rt::javascript::{self, ScriptInput};
 
 #[derive(Deserialize, Serialize)]
 pub(crate) struct SimpleVariable {
@@ -69,8 +70,9 @@ impl Variable {
         }
         None
     }
-    fn get_data_from_res<'a, 'b>(
+    async fn get_data_from_res<'a, 'b>(
         &'b self,
+        req: &Request,
         ctx: &'a mut Context,
         s: &'b str,
     ) -> Option<&'a VariableValue> {
@@ -101,7 +103,23 @@ impl Variable {
                 //     s
                 // }
             }
-            VariableObtainValueExpressionType::JavaScript => s,
+            VariableObtainValueExpressionType::JavaScript => {
+                let input = ScriptInput {
+                    vars: &ctx.vars,
+                    user_input: &req.user_input,
+                    intent: req.user_input_intent.as_deref(),
+                    response: Some(s),
+                };
+                str_store =
+                    match javascript::eval_text(&self.obtain_value_expression, &input).await {
+                        Ok(r) => Some(r),
+                        Err(e) => {
+                            log::warn!("{:?}", &e);
+                            None
+                        }
+                    };
+                str_store.as_deref().unwrap_or(s)
+            }
             VariableObtainValueExpressionType::None => s,
         };
         // println!("{}", r);
@@ -256,7 +274,7 @@ impl Variable {
                     }
                 };
                 if let Some(c) = &cache {
-                    return self.get_data_from_res(ctx, c);
+                    return self.get_data_from_res(req, ctx, c).await;
                 }
                 if let Ok(Some(api)) =
                     crate::external::http::crud::get_detail(&req.robot_id, &self.var_associate_data)
@@ -273,7 +291,7 @@ impl Variable {
                                 // 下面这句，需要在get_data_from_res的上方，否则会报*ctx可变借用了两次，因为返回值，对ctx有引用
                                 ctx.none_persistent_data
                                     .insert(self.var_associate_data.clone(), s.clone());
-                                self.get_data_from_res(ctx, &s)
+                                self.get_data_from_res(req, ctx, &s).await
                             }
                             _ => None,
                         },
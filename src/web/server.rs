@@ -4,8 +4,8 @@ use std::vec::Vec;
 
 use axum::Router;
 use axum::extract::DefaultBodyLimit;
-use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header};
-use axum::response::{IntoResponse, Response};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri, header};
+use axum::response::{IntoResponse, Redirect, Response};
 use axum::routing::{get, post};
 use colored::Colorize;
 use futures::StreamExt;
@@ -15,6 +15,9 @@ use tower_http::limit::RequestBodyLimitLayer;
 
 use super::asset::ASSETS_MAP;
 use crate::ai::crud as ai;
+use crate::analytics::crud as analytics;
+use crate::auth::crud as auth;
+use crate::auth::guard;
 use crate::external::http::crud as http;
 use crate::flow::mainflow::crud as mainflow;
 use crate::flow::rt::facade as rt;
@@ -24,6 +27,7 @@ use crate::kb::crud as kb;
 use crate::man::settings;
 use crate::result::Error;
 use crate::robot::crud as robot;
+use crate::transcript::crud as transcript;
 use crate::variable::crud as variable;
 
 //https://stackoverflow.com/questions/27840394/how-can-a-rust-program-access-metadata-from-its-cargo-package
@@ -84,6 +88,7 @@ pub async fn start_app() {
             if argument.eq("-rs") {
                 s = settings::GlobalSettings::default();
                 settings::save_global_settings(&s).expect("Reset settings failed");
+                auth::reset_settings().expect("Reset authentication settings failed");
                 break;
             }
         }
@@ -121,8 +126,21 @@ pub async fn start_app() {
         port = settings.port;
     }
 
+    if !auth::get_settings().enabled
+        && !matches!(listening_ip.as_str(), "127.0.0.1" | "localhost" | "::1")
+    {
+        log::warn!(
+            "Authentication is disabled, anyone who can reach {} has full access",
+            &listening_ip
+        );
+    }
+
     let (sender, recv) = tokio::sync::oneshot::channel::<()>();
     tokio::spawn(crate::flow::rt::context::clean_expired_session(recv));
+    crate::kb::ingest::start_worker().expect("Failed starting document ingestion worker.");
+    crate::intent::eval::start_worker().expect("Failed starting intent evaluation worker.");
+    tokio::spawn(crate::kb::crawler::schedule_recrawl());
+    tokio::spawn(crate::db::ann::save_periodically());
 
     let r: Router = gen_router();
     let app = r.fallback(fallback);
@@ -195,7 +213,11 @@ pub async fn start_app() {
     // let addr = format!("{}:{}", settings.ip, settings.port);
     // let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     // let addr = SocketAddr::from((settings.ip, settings.port));
-    let serve = axum::serve(listener, app);
+    // Client addresses are used to lock out repeated failed sign-ins
+    let serve = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    );
     // log::info!("{:?}", serve.local_addr().unwrap());
     serve
         .with_graceful_shutdown(shutdown_signal(sender))
@@ -226,12 +248,36 @@ fn gen_router() -> Router {
         )
         .route(
             "/intent/phrase",
-            post(intent::add_phrase).delete(intent::remove_phrase),
+            post(intent::add_phrase)
+                .put(intent::update_phrase)
+                .delete(intent::remove_phrase),
         )
+        .route(
+            "/intent/negative-phrase",
+            post(intent::add_negative_phrase).delete(intent::remove_negative_phrase),
+        )
+        .route("/intent/scope", post(intent::save_scope))
+        .route("/intent/slots", post(intent::save_slots))
+        .route("/intent/phrase/spans", post(intent::save_phrase_spans))
         .route(
             "/intent/phrase/regenerate-all",
             get(intent::regenerate_embeddings),
         )
+        .route("/intent/import", post(intent::import))
+        .route("/intent/export", get(intent::export))
+        .route("/intent/eval/sets", get(intent::list_eval_sets))
+        .route(
+            "/intent/eval/set",
+            post(intent::save_eval_set).delete(intent::delete_eval_set),
+        )
+        .route("/intent/eval/set/upload", post(intent::upload_eval_set))
+        .route("/intent/eval/run", post(intent::run_eval))
+        .route("/intent/eval/jobs", get(intent::list_eval_jobs))
+        .route(
+            "/intent/eval/job",
+            get(intent::eval_job_status).delete(intent::delete_eval_job),
+        )
+        .route("/intent/eval/job/cancel", post(intent::cancel_eval_job))
         .route(
             "/variable",
             get(variable::list)
@@ -298,14 +344,55 @@ fn gen_router() -> Router {
                 .delete(kb::delete_doc),
         )
         .route("/kb/doc/upload", post(kb::upload_doc))
+        .route("/kb/doc/ingest/jobs", get(kb::list_ingest_jobs))
+        .route("/kb/doc/ingest/job", get(kb::ingest_job_status))
+        .route("/kb/doc/ingest/job/retry", post(kb::retry_ingest_job))
+        .route("/kb/doc/ingest/job/cancel", post(kb::cancel_ingest_job))
+        .route(
+            "/kb/web/source",
+            get(kb::list_web_sources)
+                .post(kb::save_web_source)
+                .delete(kb::delete_web_source),
+        )
+        .route("/kb/web/source/crawl", post(kb::crawl_web_source))
         .route("/kb/qa/dryrun", get(kb::qa_dryrun))
+        .route("/kb/qa/import", post(kb::import_qa))
+        .route("/kb/qa/export", get(kb::export_qa))
         .route("/management/settings/smtp/test", post(settings::smtp_test))
         .route("/flow/answer", post(rt::answer))
         .route("/flow/answer/sse", post(rt::answer_sse))
+        .route("/flow/ws", get(rt::answer_ws))
+        .route("/transcript", get(transcript::list))
+        .route("/transcript/detail", get(transcript::detail))
+        .route("/analytics/conversations", get(analytics::conversations))
+        .route("/analytics/drop-off", get(analytics::drop_off))
+        .route("/analytics/intents", get(analytics::intents))
+        .route("/analytics/knowledge-base", get(analytics::knowledge_base))
+        .route("/analytics/llm", get(analytics::llm))
         .route("/ai/text/generation", post(ai::gen_text))
+        .route("/auth/login", get(auth::login_page).post(auth::rest_login))
+        .route("/auth/logout", post(auth::rest_logout))
+        .route("/auth/me", get(auth::me))
+        .route(
+            "/auth/user",
+            get(auth::rest_list_users)
+                .post(auth::rest_save_user)
+                .delete(auth::rest_delete_user),
+        )
+        .route(
+            "/auth/api-key",
+            get(auth::list_api_keys)
+                .post(auth::rest_create_api_key)
+                .delete(auth::rest_delete_api_key),
+        )
+        .route(
+            "/auth/settings",
+            get(auth::rest_get_settings).post(auth::rest_save_settings),
+        )
         .route("/version.json", get(version))
         .route("/check-new-version.json", get(check_new_version))
         // .route("/o", get(subflow::output))
+        .layer(axum::middleware::from_fn(guard::authorize))
         .layer(DefaultBodyLimit::disable())
         .layer(RequestBodyLimitLayer::new(
             250 * 1024 * 1024, /* 250mb */
@@ -313,20 +400,26 @@ fn gen_router() -> Router {
         .layer(
             CorsLayer::new()
                 .allow_origin(AllowOrigin::predicate(
-                    |_origin: &HeaderValue, _request_parts| {
+                    |origin: &HeaderValue, _request_parts| {
                         // println!("{}", String::from_utf8_lossy(origin.as_bytes()));
-                        // origin.as_bytes().ends_with(b"localhost")
-                        true
+                        auth::is_origin_allowed(origin.as_bytes())
                     },
                 ))
-                .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+                .allow_headers([
+                    header::AUTHORIZATION,
+                    header::CONTENT_TYPE,
+                    HeaderName::from_static(guard::API_KEY_HEADER),
+                ])
                 .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::PUT]),
         )
 }
 
 // https://docs.rs/axum/0.6.18/axum/response/index.html
 
-async fn fallback(uri: Uri) -> Response {
+async fn fallback(uri: Uri, req_headers: HeaderMap) -> Response {
+    if matches!(uri.path(), "/" | "/index.html") && !guard::signed_in(&req_headers) {
+        return Redirect::to("/auth/login").into_response();
+    }
     let v = ASSETS_MAP.get(uri.path());
     if v.is_some() {
         let idx = v.unwrap();
@@ -423,6 +516,7 @@ async fn shutdown_signal(sender: tokio::sync::oneshot::Sender<()>) {
         Err(_) => log::info!("中断 ctx 失败"),
     };
 
+    crate::db::ann::save_all();
     // crate::intent::phrase::shutdown_db().await;
     // crate::kb::qa::shutdown_db().await;
     // crate::kb::doc::shutdown_db().await;
@@ -577,6 +671,19 @@ where
     (StatusCode::OK, header_map, data)
 }
 
+pub(crate) fn to_err_res(status: StatusCode, e: Error) -> Response {
+    let res: ResponseData<()> = ResponseData {
+        status: status.as_u16(),
+        data: None,
+        err: Some(e),
+    };
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(axum::body::Body::from(serde_json::to_string(&res).unwrap()))
+        .unwrap()
+}
+
 pub(crate) fn is_en(headers: &axum::http::HeaderMap) -> bool {
     let client_language = headers
         .get("Accept-Language")